@@ -0,0 +1,56 @@
+//! Host-side show file validator
+//!
+//! Run with `cargo +stable show-check <file.show>...`; prints each error as
+//! `file:line:column: message` and exits non-zero if any file is invalid.
+
+use std::process::ExitCode;
+
+use prop_relay_control::show::{parse_show, ShowStorage};
+
+fn main() -> ExitCode {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: show-check <file.show>...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut result = ExitCode::SUCCESS;
+    for path in &paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("{path}: {error}");
+                result = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        // The parser wants 'static text and storage, as on the device
+        let source: &'static str = Box::leak(source.into_boxed_str());
+        let storage = Box::leak(Box::new(ShowStorage::new()));
+
+        match parse_show(source, storage) {
+            Ok(show) => {
+                println!(
+                    "{path}: ok, {} program(s), {} trigger(s)",
+                    show.programs.len(),
+                    show.configs.len()
+                );
+                for config in show.configs {
+                    println!(
+                        "  {:?} {:?} -> {} (cooldown {}ms, {:?})",
+                        config.trigger,
+                        config.gesture,
+                        config.name,
+                        config.cooldown_ms,
+                        config.conflict
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!("{path}:{error}");
+                result = ExitCode::FAILURE;
+            }
+        }
+    }
+    result
+}
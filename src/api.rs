@@ -0,0 +1,632 @@
+//! REST API and operator dashboard for manual relay and sequence control
+//!
+//! Relays are numbered from 1 across all banks, like `R1` in show files.
+//! API responses are JSON; errors are `{"error": "..."}`.
+//!
+//! | Method | Path                            | Action                                      |
+//! | ------ | ------------------------------- | ------------------------------------------- |
+//! | GET    | `/`                             | Operator dashboard (HTML)                   |
+//! | GET    | `/api/status`                   | Inputs, relays, sequences and cooldowns     |
+//! | GET    | `/api/events`                   | `/api/status` as server-sent events         |
+//! | GET    | `/api/relays`                   | State of every relay                        |
+//! | GET    | `/api/relays/{n}`               | State of relay `n`                          |
+//! | PUT    | `/api/relays/{n}`               | Switch relay `n`: `{"state": "on"}`/`"off"` |
+//! | GET    | `/api/sequences`                | Sequences with cooldown remaining           |
+//! | GET    | `/api/sequences/{name}`         | One sequence                                |
+//! | POST   | `/api/sequences/{name}/trigger` | Start it, ignoring its input and cooldown   |
+//! | POST   | `/api/all-off`                  | Stop every sequence, switch all relays off  |
+//! | GET    | `/api/bluetooth`                | Whether the Bluetooth service is on         |
+//! | PUT    | `/api/bluetooth`                | Switch it: `{"state": "on"}`, then restart  |
+
+use core::fmt::{self, Write};
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::ble::BluetoothSwitch;
+use crate::bus::{BusError, BusRecovery};
+use crate::hardware::{RelayId, RelayState, MAX_BANKS};
+use crate::http::{parse_request, Method, Request, Status};
+use crate::input::InputStatus;
+use crate::relay::RelayController;
+use crate::runner::{SequenceRunner, StartOutcome};
+use crate::sequence::{SequenceConfig, SharedDispatcher};
+use crate::show::{MAX_SHOW_NAME_LEN, MAX_SHOW_TRIGGERS};
+
+/// Port the firmware serves the API on
+pub const HTTP_PORT: u16 = 80;
+/// Largest request (head and body) accepted
+pub const MAX_REQUEST: usize = 1024;
+/// Most sequence slots whose running names [`MAX_RESPONSE`] has room for
+pub const MAX_STATUS_SLOTS: usize = 8;
+/// Largest response body: a `/status` of a full show on [`MAX_BANKS`]
+/// banks, framed as a status event
+pub const MAX_RESPONSE: usize = "event: status\ndata: \n\n".len()
+    + r#"{"inputs":[],"relays":[],"running":[],"queued":,"sequences":[]}"#.len()
+    + U64_DIGITS
+    + 8 * INPUT_JSON
+    + MAX_BANKS * 8 * RELAY_JSON
+    + MAX_STATUS_SLOTS * (NAME_JSON + 1)
+    + MAX_SHOW_TRIGGERS * SEQUENCE_JSON;
+
+const U64_DIGITS: usize = 20;
+/// Longest JSON string for a show name: every byte escaped as `\u00XX`
+const NAME_JSON: usize = 2 + 6 * MAX_SHOW_NAME_LEN;
+const INPUT_JSON: usize =
+    r#"{"input":"DI8","high":false,"triggers":,"glitches":},"#.len() + 2 * U64_DIGITS;
+const RELAY_JSON: usize = r#"{"relay":128,"bank":15,"state":"off"},"#.len();
+const SEQUENCE_JSON: usize = r#"{"name":,"trigger":"DI8","cooldown_ms":,"remaining_ms":},"#.len()
+    + NAME_JSON
+    + 2 * U64_DIGITS;
+/// Interval between status events while nothing changes, so cooldown
+/// countdowns keep moving
+pub const STATUS_INTERVAL_MS: u64 = 250;
+
+/// Dashboard page served at `/`, self-contained
+pub const DASHBOARD_HTML: &str = include_str!("../web/dashboard.html");
+
+/// Resolved API endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route<'a> {
+    Dashboard,
+    Status,
+    Events,
+    Relays,
+    Relay(RelayId),
+    SetRelay(RelayId, RelayState),
+    Sequences,
+    Sequence(&'a str),
+    Trigger(&'a str),
+    AllOff,
+    Bluetooth,
+    SetBluetooth(RelayState),
+}
+
+/// How to answer a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// JSON body in the response buffer, with this status
+    Json(Status),
+    /// [`DASHBOARD_HTML`]
+    Dashboard,
+    /// Keep the connection open and stream status events
+    Events,
+}
+
+/// Failed request: status plus a message for the JSON body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failure(pub Status, pub &'static str);
+
+impl From<fmt::Error> for Failure {
+    fn from(_: fmt::Error) -> Self {
+        Failure(Status::InternalServerError, "response too large")
+    }
+}
+
+/// Map a request to its endpoint
+pub fn route<'a>(request: &Request<'a>) -> Result<Route<'a>, Failure> {
+    const NOT_FOUND: Failure = Failure(Status::NotFound, "no such endpoint");
+    const WRONG_METHOD: Failure = Failure(Status::MethodNotAllowed, "method not allowed");
+
+    let get = request.method == Method::Get;
+    let mut segments = request.segments();
+    match segments.next() {
+        None | Some("index.html") if get => return Ok(Route::Dashboard),
+        None | Some("index.html") => return Err(WRONG_METHOD),
+        Some("api") => {}
+        Some(_) => return Err(NOT_FOUND),
+    }
+    let path = (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    );
+    match path {
+        (Some("status"), None, None, None) if get => Ok(Route::Status),
+        (Some("events"), None, None, None) if get => Ok(Route::Events),
+        (Some("relays"), None, None, None) if get => Ok(Route::Relays),
+        (Some("relays"), Some(number), None, None) => {
+            let relay = parse_relay(number)?;
+            match request.method {
+                _ if get => Ok(Route::Relay(relay)),
+                Method::Put | Method::Post => Ok(Route::SetRelay(relay, parse_state(request)?)),
+                _ => Err(WRONG_METHOD),
+            }
+        }
+        (Some("sequences"), None, None, None) if get => Ok(Route::Sequences),
+        (Some("sequences"), Some(name), None, None) if get => Ok(Route::Sequence(name)),
+        (Some("sequences"), Some(name), Some("trigger"), None) => match request.method {
+            Method::Post => Ok(Route::Trigger(name)),
+            _ => Err(WRONG_METHOD),
+        },
+        (Some("all-off"), None, None, None) => match request.method {
+            Method::Post => Ok(Route::AllOff),
+            _ => Err(WRONG_METHOD),
+        },
+        (Some("bluetooth"), None, None, None) => match request.method {
+            _ if get => Ok(Route::Bluetooth),
+            Method::Put | Method::Post => Ok(Route::SetBluetooth(parse_state(request)?)),
+            _ => Err(WRONG_METHOD),
+        },
+        (Some("relays" | "sequences" | "status" | "events"), ..) if !get => Err(WRONG_METHOD),
+        _ => Err(NOT_FOUND),
+    }
+}
+
+fn parse_relay(number: &str) -> Result<RelayId, Failure> {
+    match number.parse::<u16>() {
+        Ok(number @ 1..) => RelayId::from_index(number - 1),
+        _ => None,
+    }
+    .ok_or(Failure(Status::NotFound, "no such relay"))
+}
+
+/// Relay state from a `{"state": ...}` body or a `?state=` query
+fn parse_state(request: &Request<'_>) -> Result<RelayState, Failure> {
+    let value = match core::str::from_utf8(request.body) {
+        Ok(body) if !body.trim().is_empty() => json_field(body, "state"),
+        Ok(_) => request.query_param("state"),
+        Err(_) => None,
+    };
+    match value {
+        Some("on" | "high" | "true" | "1") => Ok(RelayState::High),
+        Some("off" | "low" | "false" | "0") => Ok(RelayState::Low),
+        _ => Err(Failure(
+            Status::BadRequest,
+            "expected {\"state\": \"on\"|\"off\"}",
+        )),
+    }
+}
+
+/// Value of a top-level string or literal field of a flat JSON object
+fn json_field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = json.trim().strip_prefix('{')?;
+    loop {
+        rest = rest.trim_start().strip_prefix('"')?;
+        let (name, after) = rest.split_once('"')?;
+        rest = after.trim_start().strip_prefix(':')?.trim_start();
+        let (value, after) = match rest.strip_prefix('"') {
+            Some(string) => {
+                let (value, after) = string.split_once('"')?;
+                (value, after)
+            }
+            None => {
+                let end = rest.find([',', '}']).unwrap_or(rest.len());
+                (rest[..end].trim_end(), &rest[end..])
+            }
+        };
+        if name == key {
+            return Some(value);
+        }
+        rest = after.trim_start().strip_prefix(',')?;
+    }
+}
+
+/// Whether the percent-encoded path segment `encoded` decodes to `name`
+fn decodes_to(encoded: &str, name: &str) -> bool {
+    let mut bytes = encoded.bytes();
+    let mut expected = name.bytes();
+    loop {
+        let byte = match bytes.next() {
+            Some(b'%') => {
+                let hex = [bytes.next(), bytes.next()];
+                let [Some(high), Some(low)] = hex.map(|b| b.and_then(|b| (b as char).to_digit(16)))
+                else {
+                    return false;
+                };
+                (high * 16 + low) as u8
+            }
+            Some(byte) => byte,
+            None => return expected.next().is_none(),
+        };
+        if expected.next() != Some(byte) {
+            return false;
+        }
+    }
+}
+
+/// String written as a JSON string literal
+pub(crate) struct JsonStr<'a>(pub &'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+const fn state_name(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Request handler with everything the endpoints act on
+pub struct Api<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    inputs: &'a InputStatus,
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    dispatcher: &'a SharedDispatcher,
+    configs: &'a [SequenceConfig],
+    bluetooth: Option<&'a BluetoothSwitch>,
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> Api<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub const fn new(
+        inputs: &'a InputStatus,
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        dispatcher: &'a SharedDispatcher,
+        configs: &'a [SequenceConfig],
+    ) -> Self {
+        const { assert!(SLOTS <= MAX_STATUS_SLOTS) };
+        Self {
+            inputs,
+            relays,
+            runner,
+            dispatcher,
+            configs,
+            bluetooth: None,
+        }
+    }
+
+    /// Serve `/api/bluetooth` to switch the Bluetooth service
+    pub const fn with_bluetooth(mut self, switch: &'a BluetoothSwitch) -> Self {
+        self.bluetooth = Some(switch);
+        self
+    }
+
+    /// Answer a raw request; a JSON body goes into `body`
+    ///
+    /// `raw` is everything received; a request that is still incomplete
+    /// is answered as too large.
+    pub async fn respond<const N: usize>(
+        &self,
+        raw: &[u8],
+        body: &mut heapless::String<N>,
+    ) -> Reply {
+        body.clear();
+        let result = match parse_request(raw) {
+            Ok(request) => self.handle(&request, body).await,
+            Err(error) => Err(Failure(error.status(), "malformed request")),
+        };
+        match result {
+            Ok(reply) => reply,
+            Err(Failure(status, message)) => {
+                defmt::info!("HTTP {}: {}", status.code(), message);
+                body.clear();
+                let _ = write!(body, "{{\"error\":{}}}", JsonStr(message));
+                Reply::Json(status)
+            }
+        }
+    }
+
+    /// Write the whole controller state as one JSON object
+    ///
+    /// This is the body of `/api/status` and of every status event.
+    pub async fn write_status(&self, out: &mut impl Write) -> fmt::Result {
+        let inputs = self.inputs.snapshot();
+        out.write_str("{\"inputs\":[")?;
+        for (i, (triggers, glitches)) in inputs.triggers.iter().zip(inputs.glitches).enumerate() {
+            if i > 0 {
+                out.write_char(',')?;
+            }
+            write!(
+                out,
+                "{{\"input\":\"DI{}\",\"high\":{},\"triggers\":{},\"glitches\":{}}}",
+                i + 1,
+                (inputs.levels >> i) & 1 != 0,
+                triggers,
+                glitches
+            )?;
+        }
+        out.write_str("],")?;
+        self.write_relays(out).await?;
+        out.write_str(",\"running\":[")?;
+        for (i, name) in self.runner.running().into_iter().flatten().enumerate() {
+            if i > 0 {
+                out.write_char(',')?;
+            }
+            write!(out, "{}", JsonStr(name))?;
+        }
+        write!(out, "],\"queued\":{},", self.runner.queued_count())?;
+        self.write_sequences(out)?;
+        out.write_char('}')
+    }
+
+    async fn handle(&self, request: &Request<'_>, out: &mut impl Write) -> Result<Reply, Failure> {
+        match route(request)? {
+            Route::Dashboard => return Ok(Reply::Dashboard),
+            Route::Events => return Ok(Reply::Events),
+            Route::Status => self.write_status(out).await?,
+            Route::Relays => {
+                out.write_char('{')?;
+                self.write_relays(out).await?;
+                out.write_char('}')?;
+            }
+            Route::Relay(relay) => {
+                let on = self.relay_state(relay).await?;
+                write_relay(out, relay, on)?;
+            }
+            Route::SetRelay(relay, state) => {
+                self.relays
+                    .set_relay(relay, state)
+                    .await
+                    .map_err(|error| match error {
+                        BusError::NoSuchBank => Failure(Status::NotFound, "no such relay"),
+                        _ => Failure(Status::InternalServerError, "relay bus error"),
+                    })?;
+                write_relay(out, relay, state == RelayState::High)?;
+            }
+            Route::Sequences => {
+                out.write_char('{')?;
+                self.write_sequences(out)?;
+                out.write_char('}')?;
+            }
+            Route::Sequence(name) => {
+                let config = self.find(name)?;
+                self.write_sequence(out, config)?;
+            }
+            Route::Trigger(name) => {
+                let config = self.find(name)?;
+                let (outcome, handle) = match self.runner.start(config) {
+                    StartOutcome::Started(handle) => ("started", handle),
+                    StartOutcome::Queued(handle) => ("queued", handle),
+                    StartOutcome::Preempting(handle) => ("preempting", handle),
+                    StartOutcome::Rejected => {
+                        return Err(Failure(Status::Conflict, "relays busy"));
+                    }
+                };
+                defmt::info!("Sequence '{}' {} over HTTP", config.name, outcome);
+                write!(
+                    out,
+                    "{{\"name\":{},\"outcome\":\"{}\",\"handle\":{}}}",
+                    JsonStr(config.name),
+                    outcome,
+                    handle.id()
+                )?;
+            }
+            Route::AllOff => {
+                self.runner
+                    .abort(self.relays)
+                    .await
+                    .map_err(|_| Failure(Status::InternalServerError, "relay bus error"))?;
+                out.write_str("{\"all_off\":true}")?;
+            }
+            Route::Bluetooth => {
+                let switch = self.bluetooth()?;
+                write!(out, "{{\"state\":\"{}\"}}", state_name(switch.enabled()))?;
+            }
+            Route::SetBluetooth(state) => {
+                let enabled = state == RelayState::High;
+                let restarting = self.bluetooth()?.request(enabled);
+                write!(
+                    out,
+                    "{{\"state\":\"{}\",\"restarting\":{}}}",
+                    state_name(enabled),
+                    restarting
+                )?;
+            }
+        }
+        Ok(Reply::Json(Status::Ok))
+    }
+
+    fn bluetooth(&self) -> Result<&'a BluetoothSwitch, Failure> {
+        self.bluetooth
+            .ok_or(Failure(Status::NotFound, "no such endpoint"))
+    }
+
+    /// Whether `relay` is on, as last written
+    async fn relay_state(&self, relay: RelayId) -> Result<bool, Failure> {
+        match self.relays.bank_state(relay.bank).await {
+            Some(outputs) => Ok(outputs & relay.mask().bits() != 0),
+            None => Err(Failure(Status::NotFound, "no such relay")),
+        }
+    }
+
+    /// Sequence named by a (percent-encoded) path segment
+    fn find(&self, name: &str) -> Result<&'a SequenceConfig, Failure> {
+        self.configs
+            .iter()
+            .find(|config| decodes_to(name, config.name))
+            .ok_or(Failure(Status::NotFound, "no such sequence"))
+    }
+
+    /// `"relays": [...]` with every relay of every bank
+    async fn write_relays(&self, out: &mut impl Write) -> fmt::Result {
+        out.write_str("\"relays\":[")?;
+        for bank in 0..BANKS as u8 {
+            let outputs = self.relays.bank_state(bank).await.unwrap_or(0);
+            for relay in (0..8).filter_map(|channel| RelayId::new(bank, channel)) {
+                if relay.index() > 0 {
+                    out.write_char(',')?;
+                }
+                write_relay(out, relay, outputs & relay.mask().bits() != 0)?;
+            }
+        }
+        out.write_char(']')
+    }
+
+    /// `"sequences": [...]` with the cooldown left on each
+    fn write_sequences(&self, out: &mut impl Write) -> fmt::Result {
+        out.write_str("\"sequences\":[")?;
+        for (i, config) in self.configs.iter().enumerate() {
+            if i > 0 {
+                out.write_char(',')?;
+            }
+            self.write_sequence(out, config)?;
+        }
+        out.write_char(']')
+    }
+
+    fn write_sequence(&self, out: &mut impl Write, config: &SequenceConfig) -> fmt::Result {
+        let (cooldown_ms, remaining_ms) = self.dispatcher.lock(|dispatcher| {
+            let dispatcher = dispatcher.borrow();
+            (
+                dispatcher.mapping_cooldown_ms(config),
+                dispatcher.mapping_remaining_ms(config),
+            )
+        });
+        write!(
+            out,
+            "{{\"name\":{},\"trigger\":\"DI{}\",\"cooldown_ms\":{},\"remaining_ms\":{}}}",
+            JsonStr(config.name),
+            config.trigger as u8 + 1,
+            cooldown_ms,
+            remaining_ms
+        )
+    }
+}
+
+fn write_relay(out: &mut impl Write, relay: RelayId, on: bool) -> fmt::Result {
+    write!(
+        out,
+        "{{\"relay\":{},\"bank\":{},\"state\":\"{}\"}}",
+        relay.index() + 1,
+        relay.bank,
+        state_name(on)
+    )
+}
+
+/// Serve the API and dashboard on [`HTTP_PORT`], one connection at a time
+///
+/// Run several of these for concurrent clients; each needs its own socket.
+/// A worker streaming status events is busy until the client goes away.
+#[cfg(feature = "esp32s3")]
+pub async fn serve<I2C, const BANKS: usize, const SLOTS: usize>(
+    stack: embassy_net::Stack<'_>,
+    api: &Api<'_, I2C, BANKS, SLOTS>,
+) -> !
+where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_net::tcp::TcpSocket;
+    use embassy_time::Duration;
+    use embedded_io_async::Write as _;
+
+    use crate::http::{response_head, HttpError, CONTENT_HTML, CONTENT_JSON};
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request = [0; MAX_REQUEST];
+    let mut body = heapless::String::<MAX_RESPONSE>::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(5)));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            defmt::warn!("HTTP accept failed: {:?}", e);
+            continue;
+        }
+
+        // Read until the request is complete or the buffer is full
+        let mut len = 0;
+        let received = loop {
+            match parse_request(&request[..len]) {
+                Err(HttpError::Incomplete) if len < request.len() => {}
+                _ => break true,
+            }
+            match socket.read(&mut request[len..]).await {
+                Ok(0) | Err(_) => break false,
+                Ok(n) => len += n,
+            }
+        };
+        if !received {
+            socket.abort();
+            continue;
+        }
+
+        let (head, payload) = match api.respond(&request[..len], &mut body).await {
+            Reply::Json(status) => (
+                response_head(status, CONTENT_JSON, body.len()),
+                body.as_bytes(),
+            ),
+            Reply::Dashboard => (
+                response_head(Status::Ok, CONTENT_HTML, DASHBOARD_HTML.len()),
+                DASHBOARD_HTML.as_bytes(),
+            ),
+            Reply::Events => {
+                stream_status(&mut socket, api, &mut body).await;
+                socket.close();
+                let _ = socket.flush().await;
+                continue;
+            }
+        };
+        let sent = async {
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(payload).await?;
+            socket.flush().await
+        };
+        if let Err(e) = sent.await {
+            defmt::warn!("HTTP response failed: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+/// Send status events on `socket` until the client disconnects: one per
+/// input change, and at least every [`STATUS_INTERVAL_MS`]
+#[cfg(feature = "esp32s3")]
+async fn stream_status<I2C, const BANKS: usize, const SLOTS: usize, const N: usize>(
+    socket: &mut embassy_net::tcp::TcpSocket<'_>,
+    api: &Api<'_, I2C, BANKS, SLOTS>,
+    buffer: &mut heapless::String<N>,
+) where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_futures::select::select;
+    use embassy_time::Timer;
+    use embedded_io_async::Write as _;
+
+    use crate::http::{response_head, CONTENT_JSON, EVENT_STREAM_HEAD};
+
+    let Some(mut changes) = api.inputs.watch() else {
+        defmt::warn!("HTTP: too many status streams");
+        let body = "{\"error\":\"too many status streams\"}";
+        let head = response_head(Status::ServiceUnavailable, CONTENT_JSON, body.len());
+        let _ = socket.write_all(head.as_bytes()).await;
+        let _ = socket.write_all(body.as_bytes()).await;
+        return;
+    };
+    if socket
+        .write_all(EVENT_STREAM_HEAD.as_bytes())
+        .await
+        .is_err()
+    {
+        return;
+    }
+    loop {
+        buffer.clear();
+        let event = async {
+            buffer.write_str("event: status\ndata: ")?;
+            api.write_status(buffer).await?;
+            buffer.write_str("\n\n")
+        };
+        if event.await.is_err() {
+            defmt::warn!("HTTP: status does not fit {} bytes", N);
+            return;
+        }
+        let sent = async {
+            socket.write_all(buffer.as_bytes()).await?;
+            socket.flush().await
+        };
+        if sent.await.is_err() {
+            return;
+        }
+        select(changes.changed(), Timer::after_millis(STATUS_INTERVAL_MS)).await;
+    }
+}
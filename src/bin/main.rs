@@ -1,20 +1,62 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+use core::fmt::Write as _;
 use defmt::info;
+
+use bt_hci::controller::ExternalController;
 use embassy_executor::Spawner;
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_net_wiznet::chip::W5500;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{with_timeout, Delay, Duration, Timer};
+use embedded_hal_bus::spi::ExclusiveDevice;
 use esp_hal::clock::CpuClock;
-use esp_hal::gpio::{Input, InputConfig, Pull};
-use esp_hal::i2c::master::{Config as I2cConfig, I2c};
+use esp_hal::efuse::Efuse;
+use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull};
+use esp_hal::i2c::master::Config as I2cConfig;
+use esp_hal::rng::Rng;
+use esp_hal::spi::master::{Config as SpiConfig, Spi};
+use esp_hal::spi::Mode as SpiMode;
+use esp_hal::time::Rate;
 use esp_hal::timer::systimer::SystemTimer;
+use esp_hal::timer::timg::TimerGroup;
+use esp_hal::uart::{Config as UartConfig, Parity as UartParity, RxConfig, StopBits, Uart};
+use esp_storage::FlashStorage;
+use esp_wifi::ble::controller::BleConnector;
+use esp_wifi::wifi::{WifiController, WifiDevice};
+use esp_wifi::EspWifiController;
 use panic_rtt_target as _;
+use prop_relay_control::api::{self, Api};
+use prop_relay_control::ble::{self, BleControl, BluetoothSwitch, ShowUpload};
+use prop_relay_control::bus::{EspI2c, RetryPolicy};
+use prop_relay_control::captive::{self, AP_ADDRESS, AP_PREFIX_LEN};
+use prop_relay_control::dmx::{self, DmxControl};
+use prop_relay_control::gesture::{GestureEvent, GestureRecognizer};
 use prop_relay_control::hardware::DigitalInput;
-use prop_relay_control::input::{input_monitor_task, InputEventChannel};
+use prop_relay_control::input::{
+    input_monitor_task, InputEventChannel, InputEventKind, InputStatus, InputTriggers,
+    STATUS_WATCHERS,
+};
+use prop_relay_control::modbus::{self, ModbusServer, RtuTiming};
+use prop_relay_control::mqtt::{self, MqttClient, MqttConfig};
+use prop_relay_control::network::{link_task, LinkSupervisor};
+use prop_relay_control::osc::{self, OscControl};
 use prop_relay_control::relay::RelayController;
+use prop_relay_control::runner::{SequenceRunner, StartOutcome};
 use prop_relay_control::sequence::{
-    SequenceConfig, SequenceDispatcher, JUMP_SCARE, SNAKE_SEQUENCE,
+    SequenceConfig, SequenceDispatcher, SharedDispatcher, JUMP_SCARE, SNAKE_SEQUENCE,
+};
+use prop_relay_control::show::{parse_show, ShowStorage};
+use prop_relay_control::storage::{
+    ConfigStore, DmxSettings, FlashPartition, InputPull, InputSettings, ModbusRtuSettings,
+    MqttSettings, NetworkSettings, OscSettings, Parity, Settings, SharedConfigStore, WifiSettings,
+    MAX_SHOW_BYTES,
 };
 use prop_relay_control::tca9554::{Tca9554, TCA9554_ADDRESS};
+use prop_relay_control::wifi;
+use static_cell::{ConstStaticCell, StaticCell};
 
 extern crate alloc;
 
@@ -22,8 +64,76 @@ esp_bootloader_esp_idf::esp_app_desc!();
 
 // Global input event channel
 static INPUT_CHANNEL: InputEventChannel = embassy_sync::channel::Channel::new();
+// Input triggers to report over OSC
+static OSC_TRIGGERS: InputEventChannel = embassy_sync::channel::Channel::new();
+// Input events to publish over MQTT
+static MQTT_INPUTS: InputEventChannel = embassy_sync::channel::Channel::new();
+
+/// Number of sequences that can play at the same time
+const SEQUENCE_SLOTS: usize = 4;
+
+type Relays = RelayController<EspI2c<'static>>;
+
+static RELAYS: StaticCell<Relays> = StaticCell::new();
+static RUNNER: SequenceRunner<SEQUENCE_SLOTS> = SequenceRunner::new();
+static DISPATCHER: StaticCell<SharedDispatcher> = StaticCell::new();
+
+// Input triggers seen by `WaitInput` program instructions
+static INPUT_TRIGGERS: InputTriggers<SEQUENCE_SLOTS> = InputTriggers::new();
+
+// Input levels and trigger counts shown on the dashboard
+static INPUT_STATUS: InputStatus = InputStatus::new();
+
+/// Show file built into the firmware (see `prop_relay_control::show` for
+/// the format), used until one is saved to flash; `SEQUENCE_CONFIGS`
+/// below is only used if the show fails to parse
+const DEFAULT_SHOW: &str = include_str!("../../shows/default.show");
+
+static SHOW_STORAGE: ConstStaticCell<ShowStorage> = ConstStaticCell::new(ShowStorage::new());
+static SHOW_TEXT: ConstStaticCell<[u8; MAX_SHOW_BYTES]> = ConstStaticCell::new([0; MAX_SHOW_BYTES]);
+
+// Configuration partition, must match `partitions.csv`
+const CONFIG_PARTITION_OFFSET: u32 = 0x40_0000;
+const CONFIG_PARTITION_SIZE: u32 = 0x1_0000;
+
+static STORE: StaticCell<SharedConfigStore<FlashPartition>> = StaticCell::new();
+
+// A show uploaded over Bluetooth, and the parser storage to check it
+static UPLOAD_TEXT: ConstStaticCell<[u8; MAX_SHOW_BYTES]> =
+    ConstStaticCell::new([0; MAX_SHOW_BYTES]);
+static UPLOAD_CHECK: ConstStaticCell<ShowStorage> = ConstStaticCell::new(ShowStorage::new());
+
+/// Concurrent HTTP connections: every dashboard status stream holds one,
+/// two more stay free for API requests
+const HTTP_WORKERS: usize = 2 + STATUS_WATCHERS;
+/// Sockets of the network stack: DHCP, OSC, MQTT, Art-Net, sACN, Modbus
+/// and one per HTTP worker
+const NET_SOCKETS: usize = 6 + HTTP_WORKERS;
+
+type EthSpi = ExclusiveDevice<Spi<'static, esp_hal::Async>, Output<'static>, Delay>;
+type EthRunner =
+    embassy_net_wiznet::Runner<'static, W5500, EthSpi, Input<'static>, Output<'static>>;
 
-/// Sequence configuration registry
+static W5500_STATE: StaticCell<embassy_net_wiznet::State<2, 2>> = StaticCell::new();
+static NET_RESOURCES: StaticCell<StackResources<NET_SOCKETS>> = StaticCell::new();
+
+/// Time a cable gets to bring the Ethernet link up before Wi-Fi takes over
+const ETH_LINK_WAIT_MS: u64 = 5000;
+/// Sockets of the Wi-Fi setup network: DHCP server, DNS and setup page
+const AP_SOCKETS: usize = 3;
+
+/// HCI command slots of the Bluetooth controller
+const BLE_SLOTS: usize = 20;
+type BleController = ExternalController<BleConnector<'static>, BLE_SLOTS>;
+
+/// Bluetooth setting, switched over the HTTP API
+static BLUETOOTH: StaticCell<BluetoothSwitch> = StaticCell::new();
+
+static RADIO: StaticCell<EspWifiController<'static>> = StaticCell::new();
+static WIFI_RESOURCES: StaticCell<StackResources<NET_SOCKETS>> = StaticCell::new();
+static AP_RESOURCES: StaticCell<StackResources<AP_SOCKETS>> = StaticCell::new();
+
+/// Built-in sequence configuration registry (fallback for the show file)
 ///
 /// To add a new sequence:
 /// 1. Define the sequence steps in src/sequence.rs (or use an existing one)
@@ -40,8 +150,17 @@ static INPUT_CHANNEL: InputEventChannel = embassy_sync::channel::Channel::new();
 ///     SequenceStep::new(RelayOutput::Relay3, RelayState::Low, 0),
 /// ];
 ///
+/// // Relays that must switch together go in one step:
+/// const BOTH: RelayMask = RelayMask::of(&[RelayOutput::Relay3, RelayOutput::Relay4]);
+/// SequenceStep::group(BOTH, RelayState::High, 500),
+///
 /// // Then add to SEQUENCE_CONFIGS:
 /// SequenceConfig::new(DigitalInput::DI3, 4000, MY_SEQUENCE, "My Effect"),
+///
+/// // Sequences on different relays play in parallel; choose what happens
+/// // when one shares relays with a running sequence (default: Queue):
+/// SequenceConfig::new(DigitalInput::DI4, 0, MY_SEQUENCE, "Override")
+///     .with_conflict(ConflictPolicy::Preempt),
 /// ```
 const SEQUENCE_CONFIGS: &[SequenceConfig] = &[
     SequenceConfig::new(DigitalInput::DI1, 5000, JUMP_SCARE, "Jump Scare"),
@@ -56,7 +175,8 @@ async fn main(spawner: Spawner) {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
-    esp_alloc::heap_allocator!(size: 64 * 1024);
+    // esp-wifi takes about 72 KiB of this
+    esp_alloc::heap_allocator!(size: 144 * 1024);
 
     let timer0 = SystemTimer::new(peripherals.SYSTIMER);
     esp_hal_embassy::init(timer0.alarm0);
@@ -64,134 +184,789 @@ async fn main(spawner: Spawner) {
     info!("Prop Relay Controller starting...");
 
     // Initialize I2C for TCA9554 relay expander (async mode)
-    let i2c = I2c::new(peripherals.I2C0, I2cConfig::default())
-        .expect("Failed to create I2C")
-        .with_sda(peripherals.GPIO42)
-        .with_scl(peripherals.GPIO41)
-        .into_async();
+    let i2c = EspI2c::new(
+        peripherals.I2C0,
+        I2cConfig::default(),
+        peripherals.GPIO42.into(),
+        peripherals.GPIO41.into(),
+    )
+    .expect("Failed to create I2C");
 
     let tca9554 = Tca9554::new(i2c, TCA9554_ADDRESS);
-    let relay_controller = RelayController::new(tca9554);
+    // Read back every relay write so a NAK or glitch is retried rather
+    // than leaving a relay in the wrong state
+    let relay_controller: &'static Relays =
+        RELAYS.init(RelayController::new(tca9554).with_verify(RetryPolicy::default()));
 
-    if let Err(_) = relay_controller.init().await {
-        defmt::error!("Failed to initialize relay controller");
+    if let Err(e) = relay_controller.init().await {
+        defmt::error!("Failed to initialize relay controller: {:?}", e);
     }
 
-    // Initialize digital input pins (GPIO4-11)
+    // Seed random sequence timing and branches from the hardware RNG
+    let mut rng = Rng::new(peripherals.RNG);
+    RUNNER.reseed((rng.random() as u64) << 32 | rng.random() as u64);
+
     let input_cfg = InputConfig::default().with_pull(Pull::Up);
-    let di1 = Input::new(peripherals.GPIO4, input_cfg.clone());
-    let di2 = Input::new(peripherals.GPIO5, input_cfg.clone());
-    let di3 = Input::new(peripherals.GPIO6, input_cfg.clone());
-    let di4 = Input::new(peripherals.GPIO7, input_cfg.clone());
-    let di5 = Input::new(peripherals.GPIO8, input_cfg.clone());
-    let di6 = Input::new(peripherals.GPIO9, input_cfg.clone());
-    let di7 = Input::new(peripherals.GPIO10, input_cfg.clone());
-    let di8 = Input::new(peripherals.GPIO11, input_cfg.clone());
+
+    // Saved show and settings; hold BOOT (GPIO0) during power-up to erase
+    // them and start from the built-in defaults
+    let mut store = ConfigStore::new(FlashPartition::new(
+        FlashStorage::new(),
+        CONFIG_PARTITION_OFFSET,
+        CONFIG_PARTITION_SIZE,
+    ));
+    let boot = Input::new(peripherals.GPIO0, input_cfg.clone());
+    if boot.is_low() {
+        if let Err(e) = store.factory_reset() {
+            defmt::error!("Factory reset failed: {:?}", e);
+        }
+    }
+    let (settings, saved_show) = load_config(&mut store);
+    let configs = load_show(saved_show);
+    let store: &'static SharedConfigStore<FlashPartition> =
+        STORE.init(embassy_sync::mutex::Mutex::new(store));
+    let bluetooth: &'static BluetoothSwitch =
+        BLUETOOTH.init(BluetoothSwitch::new(settings.ble_enabled));
+    spawner
+        .spawn(bluetooth_setting_task(
+            bluetooth,
+            store,
+            settings,
+            saved_show.unwrap_or(DEFAULT_SHOW),
+        ))
+        .ok();
+
+    // W5500 Ethernet on SPI2; relays and inputs keep working without it
+    let spi = Spi::new(
+        peripherals.SPI2,
+        SpiConfig::default()
+            .with_frequency(Rate::from_mhz(20))
+            .with_mode(SpiMode::_0),
+    )
+    .expect("Failed to create SPI")
+    .with_sck(peripherals.GPIO15)
+    .with_mosi(peripherals.GPIO13)
+    .with_miso(peripherals.GPIO14)
+    .into_async();
+    let eth_cs = Output::new(peripherals.GPIO16, Level::High, OutputConfig::default());
+    let eth_spi = ExclusiveDevice::new(spi, eth_cs, Delay).expect("Failed to create SPI device");
+    let eth_int = Input::new(peripherals.GPIO12, input_cfg.clone());
+    let eth_rst = Output::new(peripherals.GPIO39, Level::High, OutputConfig::default());
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let stack = start_network(spawner, eth_spi, eth_int, eth_rst, settings.network, seed).await;
+
+    // Wi-Fi and Bluetooth share the radio
+    let wifi_seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let radio_timer = TimerGroup::new(peripherals.TIMG0).timer0;
+    let radio = match esp_wifi::init(radio_timer, rng) {
+        Ok(radio) => Some(&*RADIO.init(radio)),
+        Err(e) => {
+            defmt::error!(
+                "Radio failed to start, Wi-Fi and Bluetooth disabled: {:?}",
+                e
+            );
+            None
+        }
+    };
+
+    // Network services run on Ethernet if a cable is plugged in at power-up,
+    // else on Wi-Fi
+    let link_wait = Duration::from_millis(ETH_LINK_WAIT_MS);
+    let stack = match stack {
+        Some(stack) if with_timeout(link_wait, stack.wait_link_up()).await.is_ok() => Some(stack),
+        _ => match radio {
+            Some(radio) => {
+                info!("No Ethernet link, starting Wi-Fi");
+                let show = saved_show.unwrap_or(DEFAULT_SHOW);
+                let wifi = peripherals.WIFI;
+                start_wifi(spawner, radio, wifi, wifi_seed, settings, show, store)
+            }
+            None => None,
+        },
+    };
+
+    // Cooldowns are shared with the HTTP API
+    let dispatcher: &'static SharedDispatcher =
+        DISPATCHER.init(Mutex::new(RefCell::new(SequenceDispatcher::new(configs))));
+
+    if let Some(stack) = stack {
+        for _ in 0..HTTP_WORKERS {
+            spawner
+                .spawn(http_task(
+                    stack,
+                    relay_controller,
+                    dispatcher,
+                    configs,
+                    bluetooth,
+                ))
+                .ok();
+        }
+        info!("HTTP API listening on port {}", api::HTTP_PORT);
+        spawner
+            .spawn(osc_task(stack, relay_controller, configs, settings.osc))
+            .ok();
+        let mac = Efuse::read_base_mac_address();
+        spawner
+            .spawn(mqtt_task(
+                stack,
+                relay_controller,
+                configs,
+                settings.mqtt,
+                mac,
+            ))
+            .ok();
+        spawner
+            .spawn(dmx_task(stack, relay_controller, configs, settings.dmx))
+            .ok();
+        spawner
+            .spawn(modbus_task(stack, relay_controller, dispatcher, configs))
+            .ok();
+        info!("Modbus TCP listening on port {}", modbus::TCP_PORT);
+    }
+
+    // Modbus RTU slave on the RS485 port (UART1), independent of Ethernet
+    let rtu = settings.modbus_rtu;
+    if rtu.address != 0 && rtu.baud != 0 {
+        // Two stop bits without parity keep every character at 11 bits
+        let (parity, stop_bits) = match rtu.parity {
+            Parity::None => (UartParity::None, StopBits::_2),
+            Parity::Even => (UartParity::Even, StopBits::_1),
+            Parity::Odd => (UartParity::Odd, StopBits::_1),
+        };
+        // Hand over every byte as it arrives so frame gaps can be timed
+        let uart_config = UartConfig::default()
+            .with_baudrate(rtu.baud)
+            .with_parity(parity)
+            .with_stop_bits(stop_bits)
+            .with_rx(RxConfig::default().with_fifo_full_threshold(1));
+        match Uart::new(peripherals.UART1, uart_config) {
+            Ok(uart) => {
+                let uart = uart
+                    .with_tx(peripherals.GPIO17)
+                    .with_rx(peripherals.GPIO18)
+                    .into_async();
+                spawner
+                    .spawn(modbus_rtu_task(
+                        uart,
+                        relay_controller,
+                        dispatcher,
+                        configs,
+                        rtu,
+                    ))
+                    .ok();
+                info!("Modbus RTU slave {} at {} baud", rtu.address, rtu.baud);
+            }
+            Err(e) => defmt::error!("RS485 UART config rejected: {:?}", e),
+        }
+    }
+
+    // Bluetooth service for phones, independent of the network; off unless
+    // switched on for commissioning since it has no pairing
+    if !settings.ble_enabled {
+        info!("Bluetooth off (PUT /api/bluetooth to switch it on)");
+    } else if let Some(radio) = radio {
+        let controller = ExternalController::new(BleConnector::new(radio, peripherals.BT));
+        spawner
+            .spawn(ble_task(
+                controller,
+                relay_controller,
+                configs,
+                store,
+                settings,
+            ))
+            .ok();
+    }
+
+    // Initialize digital input pins (GPIO4-11), biased as configured
+    let pin_cfg = |input| {
+        let pull = match settings.input(input).pull {
+            InputPull::Up => Pull::Up,
+            InputPull::Down => Pull::Down,
+            InputPull::None => Pull::None,
+        };
+        InputConfig::default().with_pull(pull)
+    };
+    let di1 = Input::new(peripherals.GPIO4, pin_cfg(DigitalInput::DI1));
+    let di2 = Input::new(peripherals.GPIO5, pin_cfg(DigitalInput::DI2));
+    let di3 = Input::new(peripherals.GPIO6, pin_cfg(DigitalInput::DI3));
+    let di4 = Input::new(peripherals.GPIO7, pin_cfg(DigitalInput::DI4));
+    let di5 = Input::new(peripherals.GPIO8, pin_cfg(DigitalInput::DI5));
+    let di6 = Input::new(peripherals.GPIO9, pin_cfg(DigitalInput::DI6));
+    let di7 = Input::new(peripherals.GPIO10, pin_cfg(DigitalInput::DI7));
+    let di8 = Input::new(peripherals.GPIO11, pin_cfg(DigitalInput::DI8));
 
     info!("Hardware initialized, starting tasks...");
 
     // Spawn input monitor tasks for all 8 digital inputs
-    spawner.spawn(di1_monitor_task(di1)).ok();
-    spawner.spawn(di2_monitor_task(di2)).ok();
-    spawner.spawn(di3_monitor_task(di3)).ok();
-    spawner.spawn(di4_monitor_task(di4)).ok();
-    spawner.spawn(di5_monitor_task(di5)).ok();
-    spawner.spawn(di6_monitor_task(di6)).ok();
-    spawner.spawn(di7_monitor_task(di7)).ok();
-    spawner.spawn(di8_monitor_task(di8)).ok();
+    let debounce = |input| settings.debounce_ms(input);
+    spawner
+        .spawn(di1_monitor_task(
+            di1,
+            settings.input(DigitalInput::DI1),
+            debounce(DigitalInput::DI1),
+        ))
+        .ok();
+    spawner
+        .spawn(di2_monitor_task(
+            di2,
+            settings.input(DigitalInput::DI2),
+            debounce(DigitalInput::DI2),
+        ))
+        .ok();
+    spawner
+        .spawn(di3_monitor_task(
+            di3,
+            settings.input(DigitalInput::DI3),
+            debounce(DigitalInput::DI3),
+        ))
+        .ok();
+    spawner
+        .spawn(di4_monitor_task(
+            di4,
+            settings.input(DigitalInput::DI4),
+            debounce(DigitalInput::DI4),
+        ))
+        .ok();
+    spawner
+        .spawn(di5_monitor_task(
+            di5,
+            settings.input(DigitalInput::DI5),
+            debounce(DigitalInput::DI5),
+        ))
+        .ok();
+    spawner
+        .spawn(di6_monitor_task(
+            di6,
+            settings.input(DigitalInput::DI6),
+            debounce(DigitalInput::DI6),
+        ))
+        .ok();
+    spawner
+        .spawn(di7_monitor_task(
+            di7,
+            settings.input(DigitalInput::DI7),
+            debounce(DigitalInput::DI7),
+        ))
+        .ok();
+    spawner
+        .spawn(di8_monitor_task(
+            di8,
+            settings.input(DigitalInput::DI8),
+            debounce(DigitalInput::DI8),
+        ))
+        .ok();
+
+    // Spawn one sequence worker per runner slot
+    for slot in 0..SEQUENCE_SLOTS {
+        spawner
+            .spawn(sequence_slot_task(slot, relay_controller))
+            .ok();
+    }
 
     // Spawn main control task
-    spawner.spawn(control_task(relay_controller)).ok();
+    spawner.spawn(control_task(configs, dispatcher)).ok();
 
     info!("System ready - 8 input monitors active");
 }
 
+/// Bring up the W5500 and the IP stack, then keep the link supervised
+async fn start_network(
+    spawner: Spawner,
+    spi: EthSpi,
+    int: Input<'static>,
+    reset: Output<'static>,
+    settings: NetworkSettings,
+    seed: u64,
+) -> Option<Stack<'static>> {
+    // ESP-IDF assigns the Ethernet interface the base MAC + 3
+    let mut mac = Efuse::read_base_mac_address();
+    mac[5] = mac[5].wrapping_add(3);
+
+    let state = W5500_STATE.init(embassy_net_wiznet::State::new());
+    let (device, eth_runner) =
+        match embassy_net_wiznet::new::<2, 2, W5500, _, _, _>(mac, state, spi, int, reset).await {
+            Ok(driver) => driver,
+            Err(e) => {
+                defmt::error!(
+                    "W5500 not responding, networking disabled: {:?}",
+                    defmt::Debug2Format(&e)
+                );
+                return None;
+            }
+        };
+    let resources = NET_RESOURCES.init(StackResources::new());
+    let (stack, net_runner) =
+        embassy_net::new(device, embassy_net::Config::default(), resources, seed);
+
+    spawner.spawn(ethernet_task(eth_runner)).ok();
+    spawner.spawn(net_task(net_runner)).ok();
+    spawner
+        .spawn(network_link_task(stack, LinkSupervisor::new(settings, mac)))
+        .ok();
+    info!("Ethernet started, MAC {:02x}", mac);
+    Some(stack)
+}
+
+/// Bring up the Wi-Fi station and the setup access point; returns the
+/// station's stack
+fn start_wifi(
+    spawner: Spawner,
+    radio: &'static EspWifiController<'static>,
+    device: esp_hal::peripherals::WIFI<'static>,
+    seed: u64,
+    settings: Settings,
+    show: &'static str,
+    store: &'static SharedConfigStore<FlashPartition>,
+) -> Option<Stack<'static>> {
+    let (controller, interfaces) = match esp_wifi::wifi::new(radio, device) {
+        Ok(wifi) => wifi,
+        Err(e) => {
+            defmt::error!("Wi-Fi failed to start, networking disabled: {:?}", e);
+            return None;
+        }
+    };
+
+    let resources = WIFI_RESOURCES.init(StackResources::new());
+    let (stack, runner) = embassy_net::new(
+        interfaces.sta,
+        embassy_net::Config::default(),
+        resources,
+        seed,
+    );
+    let [a, b, c, d] = AP_ADDRESS;
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), AP_PREFIX_LEN),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    let resources = AP_RESOURCES.init(StackResources::new());
+    let (ap_stack, ap_runner) =
+        embassy_net::new(interfaces.ap, ap_config, resources, seed.rotate_left(32));
+
+    // The station uses the base MAC; name the setup network after it,
+    // e.g. `prop-setup-0a1b2c`
+    let mac = Efuse::read_base_mac_address();
+    let mut ap_ssid = heapless::String::<32>::new();
+    let _ = write!(
+        ap_ssid,
+        "prop-setup-{:02x}{:02x}{:02x}",
+        mac[3], mac[4], mac[5]
+    );
+
+    spawner.spawn(wifi_net_task(runner)).ok();
+    spawner.spawn(wifi_net_task(ap_runner)).ok();
+    spawner
+        .spawn(network_link_task(
+            stack,
+            LinkSupervisor::new(settings.network, mac),
+        ))
+        .ok();
+    spawner
+        .spawn(wifi_task(controller, settings.wifi, ap_ssid))
+        .ok();
+    spawner
+        .spawn(portal_task(ap_stack, store, settings, show))
+        .ok();
+    Some(stack)
+}
+
+/// Read the saved settings and show text, if any
+fn load_config(store: &mut ConfigStore<FlashPartition>) -> (Settings, Option<&'static str>) {
+    match store.load(SHOW_TEXT.take()) {
+        Ok(Some(config)) => {
+            info!("Loaded saved configuration #{}", config.sequence);
+            (config.settings, Some(config.show))
+        }
+        Ok(None) => {
+            info!("No saved configuration, using built-in defaults");
+            (Settings::DEFAULT, None)
+        }
+        Err(e) => {
+            defmt::error!("Failed to read configuration: {:?}", e);
+            (Settings::DEFAULT, None)
+        }
+    }
+}
+
+/// Parse the saved (or else built-in) show and hand its programs to the
+/// runner
+fn load_show(saved: Option<&'static str>) -> &'static [SequenceConfig] {
+    match parse_show(saved.unwrap_or(DEFAULT_SHOW), SHOW_STORAGE.take()) {
+        Ok(show) => {
+            info!(
+                "Show loaded: {} program(s), {} trigger(s)",
+                show.programs.len(),
+                show.configs.len()
+            );
+            RUNNER.set_library(show.programs);
+            show.configs
+        }
+        Err(e) => {
+            defmt::error!(
+                "Show file error at line {}, column {}: {}",
+                e.line,
+                e.column,
+                e.kind.message()
+            );
+            SEQUENCE_CONFIGS
+        }
+    }
+}
+
 // Input monitor tasks
 #[embassy_executor::task]
-async fn di1_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<4>(pin, DigitalInput::DI1, 100, &INPUT_CHANNEL).await
+async fn di1_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<4>(
+        pin,
+        DigitalInput::DI1,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn di2_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<5>(
+        pin,
+        DigitalInput::DI2,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn di3_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<6>(
+        pin,
+        DigitalInput::DI3,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn di4_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<7>(
+        pin,
+        DigitalInput::DI4,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
+}
+
+#[embassy_executor::task]
+async fn di5_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<8>(
+        pin,
+        DigitalInput::DI5,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
 }
 
 #[embassy_executor::task]
-async fn di2_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<5>(pin, DigitalInput::DI2, 100, &INPUT_CHANNEL).await
+async fn di6_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<9>(
+        pin,
+        DigitalInput::DI6,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
 }
 
 #[embassy_executor::task]
-async fn di3_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<6>(pin, DigitalInput::DI3, 100, &INPUT_CHANNEL).await
+async fn di7_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<10>(
+        pin,
+        DigitalInput::DI7,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
 }
 
 #[embassy_executor::task]
-async fn di4_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<7>(pin, DigitalInput::DI4, 100, &INPUT_CHANNEL).await
+async fn di8_monitor_task(pin: Input<'static>, settings: InputSettings, debounce_ms: u32) {
+    input_monitor_task::<11>(
+        pin,
+        DigitalInput::DI8,
+        settings,
+        debounce_ms,
+        &INPUT_CHANNEL,
+        &INPUT_STATUS,
+    )
+    .await
 }
 
+// Network tasks
 #[embassy_executor::task]
-async fn di5_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<8>(pin, DigitalInput::DI5, 100, &INPUT_CHANNEL).await
+async fn ethernet_task(runner: EthRunner) -> ! {
+    runner.run().await
 }
 
 #[embassy_executor::task]
-async fn di6_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<9>(pin, DigitalInput::DI6, 100, &INPUT_CHANNEL).await
+async fn net_task(
+    mut runner: embassy_net::Runner<'static, embassy_net_wiznet::Device<'static>>,
+) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn network_link_task(stack: Stack<'static>, supervisor: LinkSupervisor) -> ! {
+    link_task(stack, supervisor).await
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn wifi_net_task(mut runner: embassy_net::Runner<'static, WifiDevice<'static>>) -> ! {
+    runner.run().await
 }
 
 #[embassy_executor::task]
-async fn di7_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<10>(pin, DigitalInput::DI7, 100, &INPUT_CHANNEL).await
+async fn wifi_task(
+    mut controller: WifiController<'static>,
+    settings: WifiSettings,
+    ap_ssid: heapless::String<32>,
+) -> ! {
+    wifi::run(&mut controller, settings, &ap_ssid).await
 }
 
+/// Serve the setup page until it is submitted, then store the new
+/// credentials and restart to use them
 #[embassy_executor::task]
-async fn di8_monitor_task(pin: Input<'static>) {
-    input_monitor_task::<11>(pin, DigitalInput::DI8, 100, &INPUT_CHANNEL).await
+async fn portal_task(
+    stack: Stack<'static>,
+    store: &'static SharedConfigStore<FlashPartition>,
+    mut settings: Settings,
+    show: &'static str,
+) -> ! {
+    settings.wifi = captive::serve(stack).await;
+    if let Err(e) = store.lock().await.save(&settings, show) {
+        defmt::error!("Failed to save Wi-Fi settings: {:?}", e);
+    }
+    // Let the confirmation page reach the phone first
+    Timer::after_millis(500).await;
+    esp_hal::system::software_reset()
+}
+
+/// Store a Bluetooth on/off change requested over the API, then restart
+/// to apply it
+#[embassy_executor::task]
+async fn bluetooth_setting_task(
+    bluetooth: &'static BluetoothSwitch,
+    store: &'static SharedConfigStore<FlashPartition>,
+    mut settings: Settings,
+    show: &'static str,
+) -> ! {
+    settings.ble_enabled = bluetooth.changed().await;
+    if let Err(e) = store.lock().await.save(&settings, show) {
+        defmt::error!("Failed to save Bluetooth setting: {:?}", e);
+    }
+    // Let the API response reach the client first
+    Timer::after_millis(500).await;
+    esp_hal::system::software_reset()
+}
+
+#[embassy_executor::task(pool_size = HTTP_WORKERS)]
+async fn http_task(
+    stack: Stack<'static>,
+    relays: &'static Relays,
+    dispatcher: &'static SharedDispatcher,
+    configs: &'static [SequenceConfig],
+    bluetooth: &'static BluetoothSwitch,
+) -> ! {
+    let api =
+        Api::new(&INPUT_STATUS, relays, &RUNNER, dispatcher, configs).with_bluetooth(bluetooth);
+    api::serve(stack, &api).await
+}
+
+#[embassy_executor::task]
+async fn osc_task(
+    stack: Stack<'static>,
+    relays: &'static Relays,
+    configs: &'static [SequenceConfig],
+    settings: OscSettings,
+) -> ! {
+    let control = OscControl::new(relays, &RUNNER, configs);
+    osc::serve(stack, &control, settings, &OSC_TRIGGERS).await
+}
+
+#[embassy_executor::task]
+async fn mqtt_task(
+    stack: Stack<'static>,
+    relays: &'static Relays,
+    configs: &'static [SequenceConfig],
+    settings: MqttSettings,
+    mac: [u8; 6],
+) -> ! {
+    // Unique per board, e.g. `prop-0a1b2c`
+    let mut client_id = heapless::String::<16>::new();
+    let _ = write!(client_id, "prop-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    let mut client = MqttClient::new(MqttConfig::new(&client_id), relays, &RUNNER, configs);
+    mqtt::serve(stack, &mut client, settings, &MQTT_INPUTS).await
+}
+
+#[embassy_executor::task]
+async fn dmx_task(
+    stack: Stack<'static>,
+    relays: &'static Relays,
+    configs: &'static [SequenceConfig],
+    settings: DmxSettings,
+) -> ! {
+    let mut control = DmxControl::new(relays, &RUNNER, configs, settings);
+    dmx::serve(stack, &mut control).await
+}
+
+#[embassy_executor::task]
+async fn modbus_task(
+    stack: Stack<'static>,
+    relays: &'static Relays,
+    dispatcher: &'static SharedDispatcher,
+    configs: &'static [SequenceConfig],
+) -> ! {
+    let server = ModbusServer::new(&INPUT_STATUS, relays, &RUNNER, dispatcher, configs);
+    modbus::serve(stack, &server).await
+}
+
+#[embassy_executor::task]
+async fn modbus_rtu_task(
+    mut uart: Uart<'static, esp_hal::Async>,
+    relays: &'static Relays,
+    dispatcher: &'static SharedDispatcher,
+    configs: &'static [SequenceConfig],
+    settings: ModbusRtuSettings,
+) -> ! {
+    let server = ModbusServer::new(&INPUT_STATUS, relays, &RUNNER, dispatcher, configs);
+    let timing = RtuTiming::new(settings.baud);
+    modbus::serve_rtu(&mut uart, &server, settings.address, timing).await
+}
+
+#[embassy_executor::task]
+async fn ble_task(
+    controller: BleController,
+    relays: &'static Relays,
+    configs: &'static [SequenceConfig],
+    store: &'static SharedConfigStore<FlashPartition>,
+    settings: Settings,
+) -> ! {
+    let control = BleControl::new(&INPUT_STATUS, relays, &RUNNER, configs);
+    let capacity = store.lock().await.show_capacity();
+    let upload = ShowUpload::new(&mut UPLOAD_TEXT.take()[..capacity]);
+    let mac = Efuse::read_base_mac_address();
+    ble::serve(
+        controller,
+        mac,
+        &control,
+        upload,
+        UPLOAD_CHECK.take(),
+        store,
+        settings,
+    )
+    .await
+}
+
+// Sequence worker tasks
+#[embassy_executor::task(pool_size = SEQUENCE_SLOTS)]
+async fn sequence_slot_task(slot: usize, relays: &'static Relays) {
+    RUNNER.run_slot(slot, relays, &INPUT_TRIGGERS).await
 }
 
 // Main control task
 #[embassy_executor::task]
-async fn control_task(relay_controller: RelayController<I2c<'static, esp_hal::Async>>) {
-    info!("Control task started");
-    info!(
-        "Loaded {} sequence configuration(s)",
-        SEQUENCE_CONFIGS.len()
-    );
+async fn control_task(configs: &'static [SequenceConfig], dispatcher: &'static SharedDispatcher) {
+    use embassy_futures::select::{select, Either};
 
-    // Create sequence dispatcher with our configurations
-    let mut dispatcher = SequenceDispatcher::new(SEQUENCE_CONFIGS);
+    info!("Control task started");
+    info!("Loaded {} sequence configuration(s)", configs.len());
 
+    let mut gestures = GestureRecognizer::new(configs);
     loop {
-        // Wait for input events
-        let event = INPUT_CHANNEL.receive().await;
-        info!("Received input event: {:?} triggered", event.input);
+        // Wait for input events, or for a held input to complete a hold
+        let event = match gestures.deadline() {
+            Some(at) => match select(INPUT_CHANNEL.receive(), Timer::at(at)).await {
+                Either::First(event) => Some(event),
+                Either::Second(()) => None,
+            },
+            None => Some(INPUT_CHANNEL.receive().await),
+        };
+        let recognized = match event {
+            Some(event) => {
+                if event.kind == InputEventKind::Triggered {
+                    info!("Received input event: {:?} triggered", event.input);
+                    // Running programs may be waiting on this input, cooldown or not
+                    INPUT_TRIGGERS.notify(event.input);
+                    // Dropped when OSC is not running or falls behind
+                    let _ = OSC_TRIGGERS.try_send(event);
+                    let _ = MQTT_INPUTS.try_send(event);
+                }
+                gestures.feed(&event)
+            }
+            None => gestures.poll(),
+        };
 
-        // Check if this input is in cooldown
-        if dispatcher.is_cooling_down(event.input) {
-            let remaining = dispatcher.remaining_ms(event.input);
-            info!(
-                "Input {:?} cooling down, ignoring ({}ms remaining)",
-                event.input, remaining
-            );
-            continue;
+        for gesture in &recognized {
+            dispatch(configs, dispatcher, gesture);
         }
+    }
+}
 
-        // Find matching sequence configuration
-        if let Some(config) = dispatcher.find_config(SEQUENCE_CONFIGS, event.input) {
-            info!("Executing sequence: {}", config.name);
+/// Start the sequence mapped to a recognized gesture, cooldown permitting
+fn dispatch(
+    configs: &'static [SequenceConfig],
+    dispatcher: &SharedDispatcher,
+    event: &GestureEvent,
+) {
+    // Find matching sequence configuration
+    let Some(config) = dispatcher.lock(|d| d.borrow().find_gesture(configs, event)) else {
+        info!(
+            "No sequence mapped to {:?} {:?}",
+            event.input, event.gesture
+        );
+        return;
+    };
+
+    // Check if this mapping is in cooldown
+    if dispatcher.lock(|d| d.borrow().mapping_cooling_down(config)) {
+        let remaining = dispatcher.lock(|d| d.borrow().mapping_remaining_ms(config));
+        info!(
+            "Sequence '{}' cooling down, ignoring {:?} ({}ms remaining)",
+            config.name, event.gesture, remaining
+        );
+        return;
+    }
 
+    // Hand the sequence to the runner; this never blocks, so the next
+    // trigger is handled straight away
+    match RUNNER.start(config) {
+        StartOutcome::Rejected => {
+            info!("Sequence '{}' rejected, relays busy", config.name);
+        }
+        outcome => {
             // Mark triggered (start cooldown)
-            dispatcher.mark_triggered(event.input);
-
-            // Execute sequence
-            if let Err(_) = relay_controller.execute_sequence(config.sequence).await {
-                defmt::error!("Failed to execute sequence: {}", config.name);
-            }
-
+            dispatcher.lock(|d| d.borrow_mut().mark_mapping(config));
             info!(
-                "Sequence '{}' complete. Cooldown active for {}ms",
-                config.name, config.cooldown_ms
+                "Sequence '{}' {:?}. Cooldown active for {}ms",
+                config.name, outcome, config.cooldown_ms
             );
-        } else {
-            info!("No sequence mapped to {:?}", event.input);
         }
     }
 }
@@ -0,0 +1,725 @@
+//! Bluetooth LE GATT service for testing and setting up a prop from a
+//! phone, with no network needed
+//!
+//! The controller advertises as `prop-xxyyzz` (the end of its MAC) with
+//! one primary service, `7a6f0001-5c3e-4b8e-9d2a-3f1b6d0c8e20`. Its
+//! characteristics share that UUID but for the first group:
+//!
+//! | UUID       | Access              | Value                                          |
+//! | ---------- | ------------------- | ---------------------------------------------- |
+//! | `7a6f0002` | read, write, notify | Relays: outputs per bank, bit 0 = first relay  |
+//! | `7a6f0003` | read, notify        | Inputs: levels, bit 0 = DI1                    |
+//! | `7a6f0004` | read                | Sequences: names in show order, one per line   |
+//! | `7a6f0005` | write               | Trigger: name of the sequence to start         |
+//! | `7a6f0006` | write               | Upload: show file commands ([`UploadCommand`]) |
+//! | `7a6f0007` | read, notify        | Upload status ([`UploadStatus::encode`])       |
+//!
+//! Relays are written as `[relay, state]`: relay from 1 across all banks,
+//! like `R1` in show files, state 0 for off and anything else for on.
+//! `[0, 0]` stops every sequence and switches all relays off. Triggered
+//! sequences start at once, ignoring their cooldown.
+//!
+//! An uploaded show replaces the stored one; the controller then restarts
+//! to play it.
+//!
+//! Nothing here is paired or authenticated, so any phone in range could
+//! switch relays or replace the show. The service is therefore off unless
+//! [`Settings::ble_enabled`] is set: a technician switches it on over the
+//! wired API (`PUT /api/bluetooth`, see [`BluetoothSwitch`]) for
+//! commissioning and off again before guests arrive.
+
+use core::str;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_hal_async::i2c::I2c;
+
+use crate::bus::{BusError, BusRecovery};
+use crate::hardware::{RelayId, RelayState, MAX_BANKS};
+use crate::input::InputStatus;
+use crate::relay::RelayController;
+use crate::runner::{SequenceRunner, StartOutcome};
+use crate::sequence::SequenceConfig;
+use crate::show::{parse_show, ParseError, ShowStorage};
+use crate::storage::{ConfigStore, Flash, Settings};
+
+/// Longest write: a 247-byte ATT MTU less the 3-byte header
+pub const MAX_WRITE: usize = 244;
+/// Longest attribute value ATT allows
+pub const SEQUENCE_LIST_LEN: usize = 512;
+pub const UPLOAD_STATUS_LEN: usize = 12;
+/// Relay and input changes are notified this often at most
+pub const POLL_INTERVAL_MS: u64 = 100;
+
+/// Why a relay or trigger write was not acted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BleError {
+    /// Wrong length, or a name that is not UTF-8
+    Malformed,
+    NoSuchRelay,
+    NoSuchSequence,
+    /// Relays needed by the sequence are in use
+    Busy,
+    /// Relay bus failure
+    Bus,
+}
+
+/// The stored Bluetooth setting, and requests to change it
+///
+/// A change only takes effect once it is saved and the controller has
+/// restarted; [`Self::changed`] tells the task that does both.
+pub struct BluetoothSwitch {
+    enabled: bool,
+    requested: Signal<CriticalSectionRawMutex, bool>,
+}
+
+impl BluetoothSwitch {
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            requested: Signal::new(),
+        }
+    }
+
+    /// Setting the controller started with
+    pub const fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Ask for the service to be switched on or off; true if that is a change
+    pub fn request(&self, enabled: bool) -> bool {
+        if enabled == self.enabled {
+            return false;
+        }
+        self.requested.signal(enabled);
+        true
+    }
+
+    /// Wait until a change is requested; returns the new setting
+    pub async fn changed(&self) -> bool {
+        self.requested.wait().await
+    }
+}
+
+/// Write to the relays characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RelayWrite {
+    Set(RelayId, RelayState),
+    /// Stop every sequence and switch all relays off
+    AllOff,
+}
+
+impl RelayWrite {
+    pub fn parse(data: &[u8]) -> Result<Self, BleError> {
+        match *data {
+            [0, 0] => Ok(Self::AllOff),
+            [0, _] => Err(BleError::NoSuchRelay),
+            [relay, state] => {
+                let state = if state == 0 {
+                    RelayState::Low
+                } else {
+                    RelayState::High
+                };
+                RelayId::from_index(relay as u16 - 1)
+                    .map(|relay| Self::Set(relay, state))
+                    .ok_or(BleError::NoSuchRelay)
+            }
+            _ => Err(BleError::Malformed),
+        }
+    }
+}
+
+/// Relays, inputs and sequences as the GATT service presents them
+pub struct BleControl<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    inputs: &'a InputStatus,
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    configs: &'a [SequenceConfig],
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> BleControl<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub const fn new(
+        inputs: &'a InputStatus,
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        configs: &'a [SequenceConfig],
+    ) -> Self {
+        Self {
+            inputs,
+            relays,
+            runner,
+            configs,
+        }
+    }
+
+    /// Relays characteristic value: one byte per bank, bank 0 first
+    pub async fn relay_value(&self) -> heapless::Vec<u8, MAX_BANKS> {
+        let mut value = heapless::Vec::new();
+        for bank in 0..BANKS.min(MAX_BANKS) {
+            let outputs = self.relays.bank_state(bank as u8).await.unwrap_or(0);
+            let _ = value.push(outputs);
+        }
+        value
+    }
+
+    /// Inputs characteristic value
+    pub fn input_value(&self) -> u8 {
+        self.inputs.snapshot().levels
+    }
+
+    /// Sequences characteristic value; names that do not fit are left out
+    pub fn sequence_list(&self) -> heapless::Vec<u8, SEQUENCE_LIST_LEN> {
+        let mut value = heapless::Vec::new();
+        for config in self.configs {
+            let separator = if value.is_empty() { "" } else { "\n" };
+            if value.len() + separator.len() + config.name.len() > value.capacity() {
+                break;
+            }
+            let _ = value.extend_from_slice(separator.as_bytes());
+            let _ = value.extend_from_slice(config.name.as_bytes());
+        }
+        value
+    }
+
+    pub async fn write_relay(&self, data: &[u8]) -> Result<(), BleError> {
+        match RelayWrite::parse(data)? {
+            RelayWrite::Set(relay, state) => {
+                self.relays
+                    .set_relay(relay, state)
+                    .await
+                    .map_err(|error| match error {
+                        BusError::NoSuchBank => BleError::NoSuchRelay,
+                        _ => BleError::Bus,
+                    })
+            }
+            RelayWrite::AllOff => self
+                .runner
+                .abort(self.relays)
+                .await
+                .map_err(|_| BleError::Bus),
+        }
+    }
+
+    /// Start the sequence named by a trigger write
+    pub fn trigger(&self, data: &[u8]) -> Result<StartOutcome, BleError> {
+        let name = str::from_utf8(data).map_err(|_| BleError::Malformed)?;
+        let config = self
+            .configs
+            .iter()
+            .find(|config| config.name == name)
+            .ok_or(BleError::NoSuchSequence)?;
+        match self.runner.start(config) {
+            StartOutcome::Rejected => Err(BleError::Busy),
+            outcome => {
+                defmt::info!("Sequence '{}' {:?} over Bluetooth", config.name, outcome);
+                Ok(outcome)
+            }
+        }
+    }
+}
+
+/// Write to the upload characteristic
+///
+/// | Bytes                                 | Command                       |
+/// | ------------------------------------- | ----------------------------- |
+/// | `01`, length (u32 LE)                 | Start a show of this length   |
+/// | `02`, offset (u32 LE), text           | Next piece of the show        |
+/// | `03`                                  | Check and save the show       |
+/// | `04`                                  | Drop the upload               |
+///
+/// Pieces must arrive in order; a gap or repeat rejects the upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadCommand<'a> {
+    Begin(u32),
+    Data { offset: u32, text: &'a [u8] },
+    Commit,
+    Cancel,
+}
+
+impl<'a> UploadCommand<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, UploadError> {
+        let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        match data {
+            [1, rest @ ..] if rest.len() == 4 => Ok(Self::Begin(word(rest))),
+            [2, rest @ ..] if rest.len() >= 4 => Ok(Self::Data {
+                offset: word(rest),
+                text: &rest[4..],
+            }),
+            [3] => Ok(Self::Commit),
+            [4] => Ok(Self::Cancel),
+            _ => Err(UploadError::Malformed),
+        }
+    }
+}
+
+/// Why an upload was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UploadError {
+    /// Unknown command or wrong length
+    Malformed,
+    /// Data or commit without a begin
+    NotStarted,
+    /// Show larger than the controller stores
+    TooLarge,
+    /// Data not at the next offset
+    OutOfOrder,
+    /// Commit before the whole show arrived
+    Incomplete,
+    NotUtf8,
+    Show(ParseError),
+    /// Flash write failed
+    Storage,
+}
+
+impl UploadError {
+    /// Error code in the upload status
+    pub const fn code(&self) -> u8 {
+        match self {
+            Self::Malformed => 1,
+            Self::NotStarted => 2,
+            Self::TooLarge => 3,
+            Self::OutOfOrder => 4,
+            Self::Incomplete => 5,
+            Self::NotUtf8 => 6,
+            Self::Show(_) => 7,
+            Self::Storage => 8,
+        }
+    }
+}
+
+/// Where an upload stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UploadStatus {
+    Idle,
+    Receiving {
+        received: u32,
+        len: u32,
+    },
+    /// Stored; the controller restarts to load it
+    Saved {
+        len: u32,
+    },
+    Rejected(UploadError),
+}
+
+impl UploadStatus {
+    /// Upload status characteristic value
+    ///
+    /// | Byte  | Value                                                     |
+    /// | ----- | --------------------------------------------------------- |
+    /// | 0     | 0 idle, 1 receiving, 2 saved, 3 rejected                  |
+    /// | 1     | [`UploadError::code`] when rejected                       |
+    /// | 2     | Show error kind (`ParseErrorKind` order, from 0)          |
+    /// | 4-7   | Bytes received, or show error line (u32 LE)               |
+    /// | 8-11  | Show length, or show error column (u32 LE)                |
+    pub fn encode(&self) -> [u8; UPLOAD_STATUS_LEN] {
+        let (state, error, kind, first, second) = match *self {
+            Self::Idle => (0, 0, 0, 0, 0),
+            Self::Receiving { received, len } => (1, 0, 0, received, len),
+            Self::Saved { len } => (2, 0, 0, len, len),
+            Self::Rejected(error @ UploadError::Show(e)) => {
+                (3, error.code(), e.kind as u8, e.line, e.column)
+            }
+            Self::Rejected(error) => (3, error.code(), 0, 0, 0),
+        };
+        let mut value = [0; UPLOAD_STATUS_LEN];
+        value[..3].copy_from_slice(&[state, error, kind]);
+        value[4..8].copy_from_slice(&first.to_le_bytes());
+        value[8..].copy_from_slice(&second.to_le_bytes());
+        value
+    }
+}
+
+/// Assembles an uploaded show from upload characteristic writes
+pub struct ShowUpload<'a> {
+    buffer: &'a mut [u8],
+    status: UploadStatus,
+}
+
+impl<'a> ShowUpload<'a> {
+    /// Upload into `buffer`, which also caps the show length
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            status: UploadStatus::Idle,
+        }
+    }
+
+    pub const fn status(&self) -> UploadStatus {
+        self.status
+    }
+
+    /// Apply an upload write; `Ok(true)` once a commit finds the show
+    /// complete, ready for [`Self::take_text`]
+    ///
+    /// An error drops the upload so far.
+    pub fn write(&mut self, data: &[u8]) -> Result<bool, UploadError> {
+        let result = self.apply(data);
+        if let Err(error) = result {
+            self.status = UploadStatus::Rejected(error);
+        }
+        result
+    }
+
+    fn apply(&mut self, data: &[u8]) -> Result<bool, UploadError> {
+        let command = UploadCommand::parse(data)?;
+        if let UploadCommand::Begin(len) = command {
+            if len as usize > self.buffer.len() {
+                return Err(UploadError::TooLarge);
+            }
+            self.status = UploadStatus::Receiving { received: 0, len };
+            return Ok(false);
+        }
+        if command == UploadCommand::Cancel {
+            self.status = UploadStatus::Idle;
+            return Ok(false);
+        }
+        let UploadStatus::Receiving { received, len } = self.status else {
+            return Err(UploadError::NotStarted);
+        };
+        match command {
+            UploadCommand::Data { offset, text } => {
+                if offset != received {
+                    return Err(UploadError::OutOfOrder);
+                }
+                let end = received as usize + text.len();
+                if end > len as usize {
+                    return Err(UploadError::TooLarge);
+                }
+                self.buffer[received as usize..end].copy_from_slice(text);
+                self.status = UploadStatus::Receiving {
+                    received: end as u32,
+                    len,
+                };
+                Ok(false)
+            }
+            _ if received < len => Err(UploadError::Incomplete),
+            _ => match str::from_utf8(&self.buffer[..len as usize]) {
+                Ok(_) => Ok(true),
+                Err(_) => Err(UploadError::NotUtf8),
+            },
+        }
+    }
+
+    /// Hand over the committed show text, with the buffer it lives in
+    ///
+    /// Later uploads are refused as too large.
+    pub fn take_text(&mut self) -> Option<&'a str> {
+        let UploadStatus::Receiving { received, len } = self.status else {
+            return None;
+        };
+        if received != len {
+            return None;
+        }
+        let buffer = core::mem::take(&mut self.buffer);
+        str::from_utf8(&buffer[..len as usize]).ok()
+    }
+
+    /// Record the outcome of checking and saving the taken text
+    pub fn finish(&mut self, result: Result<(), UploadError>) {
+        self.status = match (result, self.status) {
+            (Ok(()), UploadStatus::Receiving { len, .. }) => UploadStatus::Saved { len },
+            (Ok(()), status) => status,
+            (Err(error), _) => UploadStatus::Rejected(error),
+        };
+    }
+}
+
+/// Check an uploaded show, then store it with `settings`
+///
+/// `storage` only backs the check; the show loads after a restart.
+pub fn save_show<F: Flash>(
+    text: &'static str,
+    storage: &'static mut ShowStorage,
+    store: &mut ConfigStore<F>,
+    settings: &Settings,
+) -> Result<(), UploadError> {
+    parse_show(text, storage).map_err(UploadError::Show)?;
+    match store.save(settings, text) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            defmt::error!("Failed to save uploaded show: {:?}", e);
+            Err(UploadError::Storage)
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+mod gatt {
+    use trouble_host::prelude::*;
+
+    use super::{MAX_WRITE, SEQUENCE_LIST_LEN, UPLOAD_STATUS_LEN};
+    use crate::hardware::MAX_BANKS;
+
+    #[gatt_server]
+    pub struct Server {
+        pub prop: PropService,
+    }
+
+    #[gatt_service(uuid = "7a6f0001-5c3e-4b8e-9d2a-3f1b6d0c8e20")]
+    pub struct PropService {
+        #[characteristic(uuid = "7a6f0002-5c3e-4b8e-9d2a-3f1b6d0c8e20", read, write, notify)]
+        pub relays: heapless::Vec<u8, MAX_BANKS>,
+        #[characteristic(uuid = "7a6f0003-5c3e-4b8e-9d2a-3f1b6d0c8e20", read, notify)]
+        pub inputs: u8,
+        #[characteristic(uuid = "7a6f0004-5c3e-4b8e-9d2a-3f1b6d0c8e20", read)]
+        pub sequences: heapless::Vec<u8, SEQUENCE_LIST_LEN>,
+        #[characteristic(uuid = "7a6f0005-5c3e-4b8e-9d2a-3f1b6d0c8e20", write)]
+        pub trigger: heapless::Vec<u8, MAX_WRITE>,
+        #[characteristic(uuid = "7a6f0006-5c3e-4b8e-9d2a-3f1b6d0c8e20", write)]
+        pub upload: heapless::Vec<u8, MAX_WRITE>,
+        #[characteristic(uuid = "7a6f0007-5c3e-4b8e-9d2a-3f1b6d0c8e20", read, notify)]
+        pub upload_status: [u8; UPLOAD_STATUS_LEN],
+    }
+}
+
+/// Upload state kept across connections
+#[cfg(feature = "esp32s3")]
+struct Setup<'a> {
+    upload: ShowUpload<'static>,
+    /// Parser storage for checking an upload; one check per boot
+    check: Option<&'static mut ShowStorage>,
+    store: &'a crate::storage::SharedConfigStore<crate::storage::FlashPartition>,
+    settings: Settings,
+}
+
+#[cfg(feature = "esp32s3")]
+impl Setup<'_> {
+    async fn write(&mut self, data: &[u8]) -> UploadStatus {
+        if let Ok(true) = self.upload.write(data) {
+            let result = match (self.upload.take_text(), self.check.take()) {
+                (Some(text), Some(storage)) => {
+                    let mut store = self.store.lock().await;
+                    save_show(text, storage, &mut store, &self.settings)
+                }
+                _ => Err(UploadError::TooLarge),
+            };
+            self.upload.finish(result);
+            match result {
+                Ok(()) => defmt::info!("Show uploaded over Bluetooth"),
+                Err(e) => defmt::warn!("Uploaded show rejected: {:?}", e),
+            }
+        }
+        self.upload.status()
+    }
+
+    /// Whether the check was used up; the controller must restart
+    const fn finished(&self) -> bool {
+        self.check.is_none()
+    }
+}
+
+/// Advertise the GATT service and serve one phone at a time, forever
+///
+/// `upload` receives shows; an uploaded show is checked in `check`, then
+/// saved to `store` along with `settings`. The controller restarts after
+/// a commit, saved or not.
+#[cfg(feature = "esp32s3")]
+pub async fn serve<C, I2C, const BANKS: usize, const SLOTS: usize>(
+    controller: C,
+    mac: [u8; 6],
+    control: &BleControl<'_, I2C, BANKS, SLOTS>,
+    upload: ShowUpload<'static>,
+    check: &'static mut ShowStorage,
+    store: &crate::storage::SharedConfigStore<crate::storage::FlashPartition>,
+    settings: Settings,
+) -> !
+where
+    C: bt_hci::controller::Controller,
+    I2C: I2c + BusRecovery,
+{
+    use core::fmt::Write as _;
+
+    use embassy_futures::select::{select, Either};
+    use embassy_time::Timer;
+    use trouble_host::prelude::*;
+
+    let mut name = heapless::String::<16>::new();
+    let _ = write!(name, "prop-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    // Static random address from the MAC, least significant byte first;
+    // the top two bits mark it static
+    let mut address = mac;
+    address.reverse();
+    address[5] |= 0xc0;
+
+    let mut resources: HostResources<1, 2, 251> = HostResources::new();
+    let stack =
+        trouble_host::new(controller, &mut resources).set_random_address(Address::random(address));
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+    let server = match gatt::Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: &name,
+        appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
+    })) {
+        Ok(server) => server,
+        Err(e) => {
+            defmt::error!("Bluetooth GATT server failed: {}", e);
+            core::future::pending().await
+        }
+    };
+    let service = &server.prop;
+    let _ = server.set(&service.sequences, &control.sequence_list());
+    let _ = server.set(&service.upload_status, &UploadStatus::Idle.encode());
+
+    let mut setup = Setup {
+        upload,
+        check: Some(check),
+        store,
+        settings,
+    };
+    let connections = async {
+        let mut adv_data = [0; 31];
+        let adv_len = AdStructure::encode_slice(
+            &[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::CompleteLocalName(name.as_bytes()),
+            ],
+            &mut adv_data,
+        )
+        .unwrap_or(0);
+        defmt::info!("Bluetooth advertising as '{}'", name.as_str());
+        loop {
+            let advertisement = Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..adv_len],
+                scan_data: &[],
+            };
+            let conn = match peripheral
+                .advertise(&Default::default(), advertisement)
+                .await
+            {
+                Ok(advertiser) => match advertiser.accept().await {
+                    Ok(conn) => conn.with_attribute_server(&server),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    defmt::warn!(
+                        "Bluetooth advertising failed: {:?}",
+                        defmt::Debug2Format(&e)
+                    );
+                    Timer::after_secs(1).await;
+                    continue;
+                }
+            };
+            defmt::info!("Bluetooth client connected");
+            select(
+                handle_events(&server, &conn, control, &mut setup),
+                follow(&server, &conn, control),
+            )
+            .await;
+            if setup.finished() {
+                // Let the status notification reach the phone first
+                Timer::after_millis(500).await;
+                esp_hal::system::software_reset();
+            }
+        }
+    };
+    if let Either::First(Err(e)) = select(runner.run(), connections).await {
+        defmt::error!("Bluetooth host stopped: {:?}", defmt::Debug2Format(&e));
+    }
+    core::future::pending().await
+}
+
+/// Act on writes until the phone disconnects or a show was committed
+#[cfg(feature = "esp32s3")]
+async fn handle_events<I2C, const BANKS: usize, const SLOTS: usize>(
+    server: &gatt::Server<'_>,
+    conn: &trouble_host::prelude::GattConnection<'_, '_>,
+    control: &BleControl<'_, I2C, BANKS, SLOTS>,
+    setup: &mut Setup<'_>,
+) where
+    I2C: I2c + BusRecovery,
+{
+    use trouble_host::prelude::*;
+
+    let service = &server.prop;
+    loop {
+        let event = match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => {
+                defmt::info!(
+                    "Bluetooth client disconnected: {:?}",
+                    defmt::Debug2Format(&reason)
+                );
+                return;
+            }
+            GattConnectionEvent::Gatt { event: Ok(event) } => event,
+            GattConnectionEvent::Gatt { event: Err(e) } => {
+                defmt::warn!("Bluetooth GATT error: {:?}", defmt::Debug2Format(&e));
+                continue;
+            }
+            _ => continue,
+        };
+        let mut status = None;
+        if let GattEvent::Write(write) = &event {
+            let handle = write.handle();
+            let data = write.data();
+            if handle == service.relays.handle {
+                if let Err(e) = control.write_relay(data).await {
+                    defmt::info!("Bluetooth relay write ignored: {:?}", e);
+                }
+            } else if handle == service.trigger.handle {
+                if let Err(e) = control.trigger(data) {
+                    defmt::info!("Bluetooth trigger ignored: {:?}", e);
+                }
+            } else if handle == service.upload.handle {
+                status = Some(setup.write(data).await);
+            }
+        }
+        match event.accept() {
+            Ok(reply) => reply.send().await,
+            Err(e) => defmt::warn!("Bluetooth reply failed: {:?}", defmt::Debug2Format(&e)),
+        }
+        if let Some(status) = status {
+            let value = status.encode();
+            let _ = server.set(&service.upload_status, &value);
+            let _ = service.upload_status.notify(conn, &value).await;
+            if setup.finished() {
+                return;
+            }
+        }
+    }
+}
+
+/// Notify relay and input changes until the phone disconnects
+#[cfg(feature = "esp32s3")]
+async fn follow<I2C, const BANKS: usize, const SLOTS: usize>(
+    server: &gatt::Server<'_>,
+    conn: &trouble_host::prelude::GattConnection<'_, '_>,
+    control: &BleControl<'_, I2C, BANKS, SLOTS>,
+) where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_time::Timer;
+
+    let service = &server.prop;
+    let mut relays = None;
+    let mut inputs = None;
+    loop {
+        let value = control.relay_value().await;
+        if relays.as_ref() != Some(&value) {
+            let _ = server.set(&service.relays, &value);
+            if service.relays.notify(conn, &value).await.is_err() {
+                return;
+            }
+            relays = Some(value);
+        }
+        let value = control.input_value();
+        if inputs != Some(value) {
+            let _ = server.set(&service.inputs, &value);
+            if service.inputs.notify(conn, &value).await.is_err() {
+                return;
+            }
+            inputs = Some(value);
+        }
+        Timer::after_millis(POLL_INTERVAL_MS).await;
+    }
+}
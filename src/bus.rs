@@ -0,0 +1,229 @@
+//! I2C error classification, retry policy, bus recovery and bus sharing
+
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_hal_async::i2c::{Error, ErrorKind, ErrorType, I2c, Operation};
+
+/// Structured I2C failure reported by the relay layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BusError {
+    /// Device did not acknowledge its address or a data byte
+    Nak,
+    /// Another master won arbitration
+    ArbitrationLoss,
+    /// Transaction did not complete within the retry policy's timeout
+    Timeout,
+    /// Output port read back differs from the value written
+    Mismatch { expected: u8, actual: u8 },
+    /// Any other bus fault (stuck line, overrun, controller error)
+    Bus,
+    /// No expander is configured for the bank addressed
+    NoSuchBank,
+}
+
+impl BusError {
+    pub fn from_i2c<E: Error>(error: E) -> Self {
+        match error.kind() {
+            ErrorKind::NoAcknowledge(_) => BusError::Nak,
+            ErrorKind::ArbitrationLoss => BusError::ArbitrationLoss,
+            _ => BusError::Bus,
+        }
+    }
+}
+
+/// Verified-write retry settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub attempts: u8,
+    /// Delay before the first retry, doubled on each further retry
+    pub backoff_ms: u32,
+    /// Upper bound for a single I2C transaction
+    pub timeout_ms: u32,
+}
+
+impl RetryPolicy {
+    pub const fn new(attempts: u8, backoff_ms: u32, timeout_ms: u32) -> Self {
+        Self {
+            attempts,
+            backoff_ms,
+            timeout_ms,
+        }
+    }
+
+    /// Backoff before retry number `retry` (1-based)
+    pub fn backoff_ms(&self, retry: u8) -> u32 {
+        self.backoff_ms
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3, 5, 50)
+    }
+}
+
+/// I2C bus that can free itself after a slave has been left holding SDA
+/// low (an interrupted transfer, a glitch during a read)
+#[allow(async_fn_in_trait)]
+pub trait BusRecovery {
+    /// Clock SCL up to 9 times until SDA is released, then issue a STOP
+    async fn recover_bus(&mut self);
+}
+
+/// Half an SCL period of the manual bus clear (100 kHz)
+#[cfg(feature = "esp32s3")]
+const BUS_CLEAR_HALF_PERIOD_US: u64 = 5;
+
+/// ESP32-S3 I2C master that clears a stuck bus by hand
+///
+/// Keeps its pins and config: a recovery takes the pins over as GPIOs for
+/// the 9-clock bus clear and a STOP, then hands them back to the
+/// controller with the config it was created with.
+#[cfg(feature = "esp32s3")]
+pub struct EspI2c<'d> {
+    /// Only `None` while a recovery reconnects the pins
+    i2c: Option<esp_hal::i2c::master::I2c<'d, esp_hal::Async>>,
+    sda: esp_hal::gpio::AnyPin<'d>,
+    scl: esp_hal::gpio::AnyPin<'d>,
+    config: esp_hal::i2c::master::Config,
+}
+
+#[cfg(feature = "esp32s3")]
+impl<'d> EspI2c<'d> {
+    pub fn new(
+        peripheral: impl esp_hal::i2c::master::Instance + 'd,
+        config: esp_hal::i2c::master::Config,
+        sda: esp_hal::gpio::AnyPin<'d>,
+        scl: esp_hal::gpio::AnyPin<'d>,
+    ) -> Result<Self, esp_hal::i2c::master::ConfigError> {
+        let mut bus = Self {
+            i2c: None,
+            sda,
+            scl,
+            config,
+        };
+        let i2c = esp_hal::i2c::master::I2c::new(peripheral, config)?.into_async();
+        bus.connect(i2c);
+        Ok(bus)
+    }
+
+    /// Route the pins to the controller
+    fn connect(&mut self, i2c: esp_hal::i2c::master::I2c<'d, esp_hal::Async>) {
+        // SAFETY: the pins are only driven as GPIOs during a recovery, while
+        // the controller is idle
+        let (sda, scl) = unsafe { (self.sda.clone_unchecked(), self.scl.clone_unchecked()) };
+        self.i2c = Some(i2c.with_sda(sda).with_scl(scl));
+    }
+
+    fn i2c(&mut self) -> &mut esp_hal::i2c::master::I2c<'d, esp_hal::Async> {
+        self.i2c.as_mut().expect("I2C pins not connected")
+    }
+
+    /// Clock SCL until the slave releases SDA, then send a STOP; returns
+    /// whether SDA is high afterwards
+    async fn clear_bus(&mut self) -> bool {
+        use embassy_time::Timer;
+        use esp_hal::gpio::{DriveMode, Flex, OutputConfig, Pull};
+
+        let half_period = || Timer::after_micros(BUS_CLEAR_HALF_PERIOD_US);
+        let open_drain = OutputConfig::default()
+            .with_drive_mode(DriveMode::OpenDrain)
+            .with_pull(Pull::Up);
+        let mut scl = Flex::new(self.scl.reborrow());
+        let mut sda = Flex::new(self.sda.reborrow());
+        for pin in [&mut scl, &mut sda] {
+            pin.apply_output_config(&open_drain);
+            pin.set_high();
+            pin.set_output_enable(true);
+            pin.set_input_enable(true);
+        }
+        half_period().await;
+
+        for _ in 0..9 {
+            if sda.is_high() {
+                break;
+            }
+            scl.set_low();
+            half_period().await;
+            scl.set_high();
+            half_period().await;
+        }
+
+        // STOP: SDA rises while SCL is high
+        scl.set_low();
+        sda.set_low();
+        half_period().await;
+        scl.set_high();
+        half_period().await;
+        sda.set_high();
+        half_period().await;
+        sda.is_high()
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+impl ErrorType for EspI2c<'_> {
+    type Error = esp_hal::i2c::master::Error;
+}
+
+#[cfg(feature = "esp32s3")]
+impl I2c for EspI2c<'_> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.i2c().transaction(address, operations).await
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+impl BusRecovery for EspI2c<'_> {
+    async fn recover_bus(&mut self) {
+        if !self.clear_bus().await {
+            defmt::error!("I2C bus clear failed, SDA still held low");
+        }
+        // Reconnect the pins and reset the controller's state machine
+        let i2c = self.i2c.take().expect("I2C pins not connected");
+        self.connect(i2c);
+        let config = self.config;
+        if self.i2c().apply_config(&config).is_err() {
+            defmt::error!("I2C reconfiguration failed during bus recovery");
+        }
+    }
+}
+
+/// Handle to an I2C bus shared by several devices
+///
+/// Each expander gets its own handle; the bus is locked for the duration
+/// of every transaction.
+pub struct SharedI2c<'a, M: RawMutex, BUS> {
+    bus: &'a Mutex<M, BUS>,
+}
+
+impl<'a, M: RawMutex, BUS> SharedI2c<'a, M, BUS> {
+    pub fn new(bus: &'a Mutex<M, BUS>) -> Self {
+        Self { bus }
+    }
+}
+
+impl<M: RawMutex, BUS: ErrorType> ErrorType for SharedI2c<'_, M, BUS> {
+    type Error = BUS::Error;
+}
+
+impl<M: RawMutex, BUS: I2c> I2c for SharedI2c<'_, M, BUS> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus.lock().await.transaction(address, operations).await
+    }
+}
+
+impl<M: RawMutex, BUS: BusRecovery> BusRecovery for SharedI2c<'_, M, BUS> {
+    async fn recover_bus(&mut self) {
+        self.bus.lock().await.recover_bus().await
+    }
+}
@@ -0,0 +1,526 @@
+//! Captive setup portal on the Wi-Fi access point
+//!
+//! A phone joining the setup network gets an address from [`DhcpServer`],
+//! every name it looks up resolves to the controller ([`dns_response`]),
+//! and every page it opens is the Wi-Fi setup form, so the phone's
+//! "sign in to network" prompt leads straight to it. Submitting the form
+//! yields the credentials to store.
+
+use core::fmt::Write;
+
+use crate::http::{Method, Request, Status};
+use crate::storage::WifiSettings;
+
+/// Address of the controller on the setup network, a /24
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+pub const AP_PREFIX_LEN: u8 = 24;
+/// Setup page as announced to DHCP clients (RFC 8910)
+pub const PORTAL_URL: &str = "http://192.168.4.1/";
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+pub const DNS_PORT: u16 = 53;
+/// Clients served at once, at 192.168.4.2 onwards
+pub const LEASES: usize = 8;
+pub const LEASE_TIME_S: u32 = 600;
+/// DHCP replies are padded to the BOOTP minimum
+pub const DHCP_REPLY_LEN: usize = 300;
+/// Largest DNS reply: a 512-byte query plus one answer
+pub const MAX_DNS_REPLY: usize = 528;
+
+pub const SETUP_HTML: &str = include_str!("../web/setup.html");
+/// Start of the short pages answering a submitted form
+const PAGE_HEAD: &str = "<!DOCTYPE html><meta name=\"viewport\" content=\"width=device-width\">";
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS: usize = 240;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const DECLINE: u8 = 4;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+/// DHCP server handing out addresses on the setup network
+///
+/// Leases are kept per client MAC until released; once every address is
+/// taken, the oldest lease goes to the next new client. Lease expiry is
+/// not tracked.
+pub struct DhcpServer {
+    leases: [Option<[u8; 6]>; LEASES],
+    /// When each lease was taken, counted in leases handed out
+    taken: [u32; LEASES],
+    handed_out: u32,
+}
+
+impl DhcpServer {
+    pub const fn new() -> Self {
+        Self {
+            leases: [None; LEASES],
+            taken: [0; LEASES],
+            handed_out: 0,
+        }
+    }
+
+    /// Answer the client message `request`; returns the length of the
+    /// reply to broadcast, `None` if there is nothing to answer
+    pub fn handle(&mut self, request: &[u8], reply: &mut [u8; DHCP_REPLY_LEN]) -> Option<usize> {
+        let header = request.get(..OPTIONS)?;
+        if header[0] != 1 || header[1] != 1 || header[2] != 6 || header[236..] != MAGIC_COOKIE {
+            return None;
+        }
+        let mac: [u8; 6] = header[28..34].try_into().ok()?;
+        let mut kind = None;
+        let mut requested = None;
+        let mut server = None;
+        for (code, data) in Options(&request[OPTIONS..]) {
+            match (code, data.len()) {
+                (53, 1) => kind = Some(data[0]),
+                (50, 4) => requested = data.try_into().ok(),
+                (54, 4) => server = <[u8; 4]>::try_from(data).ok(),
+                _ => {}
+            }
+        }
+        let (kind, address) = match kind? {
+            DISCOVER => (OFFER, self.lease(mac)?),
+            REQUEST if server.is_some_and(|server| server != AP_ADDRESS) => {
+                // The client took another server's offer
+                self.release(mac);
+                return None;
+            }
+            REQUEST => {
+                let requested = requested.or_else(|| {
+                    let ciaddr: [u8; 4] = header[12..16].try_into().ok()?;
+                    (ciaddr != [0; 4]).then_some(ciaddr)
+                })?;
+                // A client back from another network asks for its old
+                // address; the NAK sends it to discover again
+                if self.lease(mac) == Some(requested) {
+                    (ACK, requested)
+                } else {
+                    (NAK, [0; 4])
+                }
+            }
+            DECLINE | RELEASE => {
+                self.release(mac);
+                return None;
+            }
+            _ => return None,
+        };
+        Some(write_reply(header, kind, address, reply))
+    }
+
+    /// Address leased to `mac`, taking a free one, or else the oldest, if
+    /// it has none
+    fn lease(&mut self, mac: [u8; 6]) -> Option<[u8; 4]> {
+        let index = match self.leases.iter().position(|lease| *lease == Some(mac)) {
+            Some(index) => index,
+            None => {
+                let index = match self.leases.iter().position(Option::is_none) {
+                    Some(index) => index,
+                    None => (0..LEASES)
+                        .max_by_key(|&index| self.handed_out.wrapping_sub(self.taken[index]))?,
+                };
+                self.leases[index] = Some(mac);
+                self.taken[index] = self.handed_out;
+                self.handed_out = self.handed_out.wrapping_add(1);
+                index
+            }
+        };
+        let mut address = AP_ADDRESS;
+        address[3] += 1 + index as u8;
+        Some(address)
+    }
+
+    fn release(&mut self, mac: [u8; 6]) {
+        for lease in &mut self.leases {
+            if *lease == Some(mac) {
+                *lease = None;
+            }
+        }
+    }
+}
+
+impl Default for DhcpServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Code and data of each option up to the end option
+struct Options<'a>(&'a [u8]);
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&code, rest) = self.0.split_first()?;
+            match code {
+                0 => self.0 = rest,
+                255 => return None,
+                _ => {
+                    let (&len, rest) = rest.split_first()?;
+                    let (data, rest) = rest.split_at_checked(len as usize)?;
+                    self.0 = rest;
+                    return Some((code, data));
+                }
+            }
+        }
+    }
+}
+
+fn write_reply(
+    request: &[u8],
+    kind: u8,
+    address: [u8; 4],
+    reply: &mut [u8; DHCP_REPLY_LEN],
+) -> usize {
+    reply.fill(0);
+    reply[..4].copy_from_slice(&[2, 1, 6, 0]);
+    // Transaction id, then flags after the seconds field
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&address);
+    reply[24..44].copy_from_slice(&request[24..44]);
+    reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut at = OPTIONS;
+    let mut option = |code: u8, data: &[u8]| {
+        reply[at] = code;
+        reply[at + 1] = data.len() as u8;
+        reply[at + 2..at + 2 + data.len()].copy_from_slice(data);
+        at += 2 + data.len();
+    };
+    option(53, &[kind]);
+    option(54, &AP_ADDRESS);
+    if kind != NAK {
+        let mask = u32::MAX << (32 - AP_PREFIX_LEN);
+        option(51, &LEASE_TIME_S.to_be_bytes());
+        option(1, &mask.to_be_bytes());
+        option(3, &AP_ADDRESS);
+        option(6, &AP_ADDRESS);
+        option(114, PORTAL_URL.as_bytes());
+    }
+    reply[at] = 255;
+    DHCP_REPLY_LEN
+}
+
+/// Answer the DNS query `query` with `address` for any name; returns the
+/// reply length, `None` for anything but a standard query of one question
+///
+/// Questions other than A get an empty answer, so clients fall back to
+/// IPv4.
+pub fn dns_response(query: &[u8], address: [u8; 4], reply: &mut [u8]) -> Option<usize> {
+    let header = query.get(..12)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    // QR clear and opcode 0, exactly one question
+    if flags & 0xF800 != 0 || header[4..6] != [0, 1] {
+        return None;
+    }
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+    }
+    let question = query.get(12..end + 4)?;
+    let kind = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let answer = matches!(kind, 1 | 255);
+
+    let len = 12 + question.len() + if answer { 16 } else { 0 };
+    let reply = reply.get_mut(..len)?;
+    reply[..2].copy_from_slice(&header[..2]);
+    // Response, authoritative, recursion desired copied
+    let flags = 0x8400 | flags & 0x0100;
+    reply[2..4].copy_from_slice(&flags.to_be_bytes());
+    reply[4..12].copy_from_slice(&[0, 1, 0, answer as u8, 0, 0, 0, 0]);
+    reply[12..12 + question.len()].copy_from_slice(question);
+    if answer {
+        let record = &mut reply[12 + question.len()..];
+        // Name points back at the question; class IN, 60 s TTL
+        record[..12].copy_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        record[12..].copy_from_slice(&address);
+    }
+    Some(len)
+}
+
+/// Why a submitted setup form was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SetupError {
+    MissingSsid,
+    /// More than 32 bytes
+    SsidTooLong,
+    /// WPA passwords are 8 to 63 characters, or empty for an open network
+    BadPassword,
+    /// Broken percent-encoding or not UTF-8
+    BadEncoding,
+}
+
+impl SetupError {
+    pub const fn message(self) -> &'static str {
+        match self {
+            Self::MissingSsid => "Enter the network name.",
+            Self::SsidTooLong => "Network names are at most 32 bytes.",
+            Self::BadPassword => "Passwords are 8 to 63 characters, or empty for an open network.",
+            Self::BadEncoding => "The form could not be read.",
+        }
+    }
+}
+
+/// Credentials from the URL-encoded body of the setup form, fields
+/// `ssid` and `password`
+pub fn parse_setup_form(body: &[u8]) -> Result<WifiSettings, SetupError> {
+    let body = core::str::from_utf8(body).map_err(|_| SetupError::BadEncoding)?;
+    let mut ssid = heapless::String::<32>::new();
+    let mut password = heapless::String::<63>::new();
+    for pair in body.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "ssid" => decode_field(value, &mut ssid, SetupError::SsidTooLong)?,
+            "password" => decode_field(value, &mut password, SetupError::BadPassword)?,
+            _ => {}
+        }
+    }
+    if ssid.is_empty() {
+        return Err(SetupError::MissingSsid);
+    }
+    if (1..8).contains(&password.len()) {
+        return Err(SetupError::BadPassword);
+    }
+    WifiSettings::new(&ssid, &password).ok_or(SetupError::BadEncoding)
+}
+
+/// Percent-decode `value` into `out`; `too_long` when it does not fit
+fn decode_field<const N: usize>(
+    value: &str,
+    out: &mut heapless::String<N>,
+    too_long: SetupError,
+) -> Result<(), SetupError> {
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let (byte, tail) = match byte {
+            b'+' => (b' ', tail),
+            b'%' => {
+                let hex = tail.get(..2).ok_or(SetupError::BadEncoding)?;
+                let hex = core::str::from_utf8(hex).map_err(|_| SetupError::BadEncoding)?;
+                let byte = u8::from_str_radix(hex, 16).map_err(|_| SetupError::BadEncoding)?;
+                (byte, &tail[2..])
+            }
+            byte => (byte, tail),
+        };
+        bytes.push(byte).map_err(|_| too_long)?;
+        rest = tail;
+    }
+    let text = core::str::from_utf8(&bytes).map_err(|_| SetupError::BadEncoding)?;
+    if text.contains('\0') {
+        return Err(SetupError::BadEncoding);
+    }
+    out.clear();
+    out.push_str(text).map_err(|_| too_long)
+}
+
+/// What the portal answers a request with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortalReply {
+    /// The setup form, for any path: that is what makes it captive
+    Form,
+    /// Form accepted: store the credentials and restart
+    Saved(WifiSettings),
+    Refused(SetupError),
+}
+
+pub fn portal_route(request: &Request<'_>) -> PortalReply {
+    match (request.method, request.path) {
+        (Method::Post, "/wifi") => match parse_setup_form(request.body) {
+            Ok(settings) => PortalReply::Saved(settings),
+            Err(e) => PortalReply::Refused(e),
+        },
+        _ => PortalReply::Form,
+    }
+}
+
+/// Status and HTML body answering `reply`
+pub fn write_portal_page(
+    reply: &PortalReply,
+    out: &mut impl Write,
+) -> Result<Status, core::fmt::Error> {
+    match reply {
+        PortalReply::Form => {
+            out.write_str(SETUP_HTML)?;
+            Ok(Status::Ok)
+        }
+        PortalReply::Saved(settings) => {
+            out.write_str(PAGE_HEAD)?;
+            out.write_str("<p>Saved. The controller restarts and joins <b>")?;
+            write_escaped(settings.ssid(), out)?;
+            out.write_str("</b>.</p>")?;
+            Ok(Status::Ok)
+        }
+        PortalReply::Refused(e) => {
+            out.write_str(PAGE_HEAD)?;
+            write!(out, "<p>{}</p><p><a href=\"/\">Back</a></p>", e.message())?;
+            Ok(Status::BadRequest)
+        }
+    }
+}
+
+fn write_escaped(text: &str, out: &mut impl Write) -> core::fmt::Result {
+    for c in text.chars() {
+        match c {
+            '<' => out.write_str("&lt;")?,
+            '>' => out.write_str("&gt;")?,
+            '&' => out.write_str("&amp;")?,
+            '"' => out.write_str("&quot;")?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Run the DHCP server, DNS responder and setup page on the access point
+/// network until the form is submitted; returns the new credentials
+///
+/// Needs three sockets on `stack`.
+#[cfg(feature = "esp32s3")]
+pub async fn serve(stack: embassy_net::Stack<'_>) -> WifiSettings {
+    use embassy_futures::select::{select3, Either3};
+
+    match select3(serve_dhcp(stack), serve_dns(stack), serve_form(stack)).await {
+        Either3::Third(settings) => settings,
+        Either3::First(never) | Either3::Second(never) => never,
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+async fn serve_dhcp(stack: embassy_net::Stack<'_>) -> ! {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use embassy_net::{IpEndpoint, Ipv4Address};
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(DHCP_SERVER_PORT)
+        .expect("DHCP server socket already bound");
+
+    let mut server = DhcpServer::new();
+    let mut request = [0; 576];
+    let mut reply = [0; DHCP_REPLY_LEN];
+    let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), DHCP_CLIENT_PORT);
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        if let Some(len) = server.handle(&request[..len], &mut reply) {
+            if let Err(e) = socket.send_to(&reply[..len], broadcast).await {
+                defmt::warn!("DHCP reply failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+async fn serve_dns(stack: embassy_net::Stack<'_>) -> ! {
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DNS_PORT).expect("DNS socket already bound");
+
+    let mut query = [0; 512];
+    let mut reply = [0; MAX_DNS_REPLY];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = dns_response(&query[..len], AP_ADDRESS, &mut reply) {
+            let _ = socket.send_to(&reply[..len], meta.endpoint).await;
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+async fn serve_form(stack: embassy_net::Stack<'_>) -> WifiSettings {
+    use embassy_net::tcp::TcpSocket;
+    use embassy_time::Duration;
+    use embedded_io_async::Write as _;
+
+    use crate::api::{HTTP_PORT, MAX_REQUEST};
+    use crate::http::{parse_request, response_head, HttpError, CONTENT_HTML};
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request = [0; MAX_REQUEST];
+    let mut body = heapless::String::<256>::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(5)));
+        if socket.accept(HTTP_PORT).await.is_err() {
+            continue;
+        }
+        let mut len = 0;
+        let reply = loop {
+            match parse_request(&request[..len]) {
+                Ok(parsed) => break Some(portal_route(&parsed)),
+                Err(HttpError::Incomplete) if len < request.len() => {}
+                Err(_) => break Some(PortalReply::Form),
+            }
+            match socket.read(&mut request[len..]).await {
+                Ok(0) | Err(_) => break None,
+                Ok(n) => len += n,
+            }
+        };
+        let Some(reply) = reply else {
+            socket.abort();
+            continue;
+        };
+
+        let (status, payload) = match reply {
+            PortalReply::Form => (Status::Ok, SETUP_HTML),
+            _ => {
+                body.clear();
+                let status = write_portal_page(&reply, &mut body).unwrap_or(Status::Ok);
+                (status, body.as_str())
+            }
+        };
+        let head = response_head(status, CONTENT_HTML, payload.len());
+        let sent = async {
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(payload.as_bytes()).await?;
+            socket.flush().await
+        };
+        let _ = sent.await;
+        socket.close();
+        let _ = socket.flush().await;
+        if let PortalReply::Saved(settings) = reply {
+            defmt::info!("Wi-Fi set up for {}", settings);
+            return settings;
+        }
+    }
+}
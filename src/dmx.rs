@@ -0,0 +1,539 @@
+//! DMX over IP: Art-Net and sACN (E1.31) receiver
+//!
+//! One universe drives the controller. Starting at the configured start
+//! address there is one channel per relay, R1 first, which switches the
+//! relay on at or above the threshold. The channels after those, one per
+//! sequence in show order, start their sequence when the level rises
+//! through the trigger level; like OSC this ignores the input cooldown.
+//!
+//! Several consoles may send the same universe. Only the sources with the
+//! highest priority count (sACN carries one, Art-Net is taken as the sACN
+//! default of 100) and those are merged highest-takes-precedence per
+//! channel. A source drops out after [`SOURCE_TIMEOUT_MS`] of silence or
+//! when its sACN stream terminates; once the last one is gone the relays
+//! hold their last look or switch off, per [`DmxSettings::on_loss`].
+//!
+//! Relays are only written when their mapped level crosses the threshold,
+//! so the other control surfaces still work between DMX changes.
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::bus::BusRecovery;
+use crate::hardware::RelayMask;
+use crate::relay::RelayController;
+use crate::runner::{SequenceRunner, StartOutcome};
+use crate::sequence::SequenceConfig;
+use crate::storage::{DmxSettings, SignalLoss};
+
+pub const ARTNET_PORT: u16 = 6454;
+pub const SACN_PORT: u16 = 5568;
+/// E1.31 network data loss timeout, also applied to Art-Net
+pub const SOURCE_TIMEOUT_MS: u64 = 2500;
+/// Sources merged at the same time; more are ignored until one drops out
+pub const MAX_SOURCES: usize = 4;
+/// Most channels followed: every relay plus one per sequence
+pub const MAX_CHANNELS: usize = 64;
+/// Priority given to Art-Net sources
+pub const ARTNET_PRIORITY: u8 = 100;
+/// Largest Art-Net or sACN packet carrying a full universe
+pub const MAX_PACKET: usize = 638;
+
+const DMX_SLOTS: usize = 512;
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+const ARTNET_MIN_VERSION: u16 = 14;
+const ACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_DATA: u32 = 0x04;
+const VECTOR_FRAMING_DATA: u32 = 0x02;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+/// Offset of the first DMX slot (after the start code) in an sACN packet
+const SACN_DATA: usize = 126;
+const OPTION_PREVIEW: u8 = 0x80;
+const OPTION_TERMINATED: u8 = 0x40;
+
+/// Why a packet was not used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DmxError {
+    /// Valid packet, but not level data (ArtPoll, sync, preview, alternate
+    /// start code, ...)
+    NotDmx,
+    Malformed,
+    /// Art-Net older than protocol version 14
+    UnsupportedVersion,
+}
+
+/// Sender of a universe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SourceId {
+    /// Art-Net node, by IP address
+    ArtNet([u8; 4]),
+    /// sACN source, by component identifier
+    Sacn([u8; 16]),
+}
+
+/// Levels of one universe received from a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmxFrame<'a> {
+    pub universe: u16,
+    pub priority: u8,
+    /// 0 when the sender does not number its packets
+    pub sequence: u8,
+    /// Last packet of an sACN stream
+    pub terminated: bool,
+    /// Slot 1 first; may be shorter than 512
+    pub data: &'a [u8],
+}
+
+/// Decode an ArtDmx packet
+pub fn parse_artnet(packet: &[u8]) -> Result<DmxFrame<'_>, DmxError> {
+    let header = packet.get(..18).ok_or(DmxError::Malformed)?;
+    if &header[..8] != ARTNET_ID {
+        return Err(DmxError::Malformed);
+    }
+    if u16::from_le_bytes([header[8], header[9]]) != OP_DMX {
+        return Err(DmxError::NotDmx);
+    }
+    if u16::from_be_bytes([header[10], header[11]]) < ARTNET_MIN_VERSION {
+        return Err(DmxError::UnsupportedVersion);
+    }
+    let len = u16::from_be_bytes([header[16], header[17]]) as usize;
+    if !(2..=DMX_SLOTS).contains(&len) {
+        return Err(DmxError::Malformed);
+    }
+    Ok(DmxFrame {
+        universe: u16::from_le_bytes([header[14], header[15] & 0x7F]),
+        priority: ARTNET_PRIORITY,
+        sequence: header[12],
+        terminated: false,
+        data: packet.get(18..18 + len).ok_or(DmxError::Malformed)?,
+    })
+}
+
+/// Decode an E1.31 data packet and the component identifier of its source
+pub fn parse_sacn(packet: &[u8]) -> Result<([u8; 16], DmxFrame<'_>), DmxError> {
+    let header = packet.get(..SACN_DATA).ok_or(DmxError::Malformed)?;
+    let u16_at = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+    let u32_at = |at: usize| {
+        u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+    };
+    if u16_at(0) != 0x0010 || u16_at(2) != 0 || &header[4..16] != ACN_ID {
+        return Err(DmxError::Malformed);
+    }
+    if u32_at(18) != VECTOR_ROOT_DATA || u32_at(40) != VECTOR_FRAMING_DATA {
+        return Err(DmxError::NotDmx);
+    }
+    let options = header[112];
+    if header[117] != VECTOR_DMP_SET_PROPERTY
+        || header[118] != 0xA1
+        || u16_at(119) != 0
+        || u16_at(121) != 1
+    {
+        return Err(DmxError::Malformed);
+    }
+    let len = (u16_at(123) as usize)
+        .checked_sub(1)
+        .ok_or(DmxError::Malformed)?;
+    if len > DMX_SLOTS {
+        return Err(DmxError::Malformed);
+    }
+    let data = packet
+        .get(SACN_DATA..SACN_DATA + len)
+        .ok_or(DmxError::Malformed)?;
+    // Stream termination counts whatever the start code
+    let terminated = options & OPTION_TERMINATED != 0;
+    if !terminated && (options & OPTION_PREVIEW != 0 || header[125] != 0) {
+        return Err(DmxError::NotDmx);
+    }
+    let mut cid = [0; 16];
+    cid.copy_from_slice(&header[22..38]);
+    Ok((
+        cid,
+        DmxFrame {
+            universe: u16_at(113),
+            priority: header[108],
+            sequence: header[111],
+            terminated,
+            data,
+        },
+    ))
+}
+
+/// sACN multicast group of `universe`, 239.255.hi.lo
+pub const fn sacn_group(universe: u16) -> [u8; 4] {
+    let [high, low] = universe.to_be_bytes();
+    [239, 255, high, low]
+}
+
+struct Source {
+    id: SourceId,
+    priority: u8,
+    sequence: u8,
+    last_seen_ms: u64,
+    levels: [u8; MAX_CHANNELS],
+}
+
+/// Merges the sources of one universe into a window of channels
+pub struct DmxMerger {
+    universe: u16,
+    /// Slot index of the first channel followed
+    start: usize,
+    width: usize,
+    sources: heapless::Vec<Source, MAX_SOURCES>,
+}
+
+impl DmxMerger {
+    /// Follow `channels` channels of `universe` from `start_address` on
+    ///
+    /// The window is cut short at [`MAX_CHANNELS`] and at slot 512.
+    pub fn new(universe: u16, start_address: u16, channels: usize) -> Self {
+        let start = (start_address.clamp(1, DMX_SLOTS as u16) - 1) as usize;
+        Self {
+            universe,
+            start,
+            width: channels.min(MAX_CHANNELS).min(DMX_SLOTS - start),
+            sources: heapless::Vec::new(),
+        }
+    }
+
+    /// Number of channels followed
+    pub const fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn source_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Take in a frame from `id` received at `now_ms`; returns whether the
+    /// merged levels may have changed
+    ///
+    /// Other universes, out-of-order packets and new sources beyond
+    /// [`MAX_SOURCES`] are dropped.
+    pub fn receive(&mut self, id: SourceId, frame: &DmxFrame<'_>, now_ms: u64) -> bool {
+        if frame.universe != self.universe {
+            return false;
+        }
+        let known = self.sources.iter().position(|source| source.id == id);
+        if frame.terminated {
+            if let Some(index) = known {
+                defmt::info!("DMX source {} terminated", id);
+                self.sources.swap_remove(index);
+            }
+            return known.is_some();
+        }
+        let source = match known {
+            Some(index) => {
+                let source = &mut self.sources[index];
+                // E1.31 6.7.2: a packet up to 20 behind the last is stale
+                let step = frame.sequence.wrapping_sub(source.sequence) as i8;
+                if frame.sequence != 0 && source.sequence != 0 && (-20..=0).contains(&step) {
+                    return false;
+                }
+                source
+            }
+            None => {
+                let source = Source {
+                    id,
+                    priority: 0,
+                    sequence: 0,
+                    last_seen_ms: 0,
+                    levels: [0; MAX_CHANNELS],
+                };
+                if self.sources.push(source).is_err() {
+                    defmt::warn!("DMX source {} ignored, {} already merged", id, MAX_SOURCES);
+                    return false;
+                }
+                defmt::info!("DMX source {} on universe {}", id, self.universe);
+                self.sources.last_mut().unwrap()
+            }
+        };
+        source.priority = frame.priority;
+        source.sequence = frame.sequence;
+        source.last_seen_ms = now_ms;
+        let data = frame.data.get(self.start..).unwrap_or(&[]);
+        for (level, slot) in source.levels[..self.width]
+            .iter_mut()
+            .zip(data.iter().chain(core::iter::repeat(&0)))
+        {
+            *level = *slot;
+        }
+        true
+    }
+
+    /// Drop sources silent since `now_ms - SOURCE_TIMEOUT_MS`; returns
+    /// whether any were dropped
+    pub fn expire(&mut self, now_ms: u64) -> bool {
+        let before = self.sources.len();
+        self.sources.retain(|source| {
+            let alive = now_ms < source.last_seen_ms + SOURCE_TIMEOUT_MS;
+            if !alive {
+                defmt::warn!("DMX source {} timed out", source.id);
+            }
+            alive
+        });
+        self.sources.len() != before
+    }
+
+    /// When the next source times out, if any
+    pub fn next_expiry_ms(&self) -> Option<u64> {
+        self.sources
+            .iter()
+            .map(|source| source.last_seen_ms + SOURCE_TIMEOUT_MS)
+            .min()
+    }
+
+    /// Merged levels of the window, `None` without any source
+    pub fn levels(&self) -> Option<[u8; MAX_CHANNELS]> {
+        let top = self.sources.iter().map(|source| source.priority).max()?;
+        let mut merged = [0; MAX_CHANNELS];
+        for source in self.sources.iter().filter(|source| source.priority == top) {
+            for (merged, level) in merged.iter_mut().zip(source.levels) {
+                *merged = (*merged).max(level);
+            }
+        }
+        Some(merged)
+    }
+}
+
+/// Applies the merged universe to the relays and the sequence runner
+pub struct DmxControl<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    configs: &'a [SequenceConfig],
+    settings: DmxSettings,
+    merger: DmxMerger,
+    /// Levels last acted on, `None` without signal
+    applied: Option<[u8; MAX_CHANNELS]>,
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> DmxControl<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub fn new(
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        configs: &'a [SequenceConfig],
+        settings: DmxSettings,
+    ) -> Self {
+        let channels = relays.relay_count() + configs.len();
+        let merger = DmxMerger::new(settings.universe, settings.start_address, channels);
+        if merger.width() < channels {
+            defmt::warn!(
+                "DMX follows {} of {} channels, the rest are unmapped",
+                merger.width(),
+                channels
+            );
+        }
+        Self {
+            relays,
+            runner,
+            configs,
+            settings,
+            merger,
+            applied: None,
+        }
+    }
+
+    pub const fn merger(&self) -> &DmxMerger {
+        &self.merger
+    }
+
+    /// Whether any source is sending the universe
+    pub const fn has_signal(&self) -> bool {
+        self.applied.is_some()
+    }
+
+    pub async fn receive(&mut self, source: SourceId, frame: &DmxFrame<'_>, now_ms: u64) {
+        if self.merger.receive(source, frame, now_ms) {
+            self.update().await;
+        }
+    }
+
+    /// Drop timed-out sources, handling signal loss if none are left
+    pub async fn expire(&mut self, now_ms: u64) {
+        if self.merger.expire(now_ms) {
+            self.update().await;
+        }
+    }
+
+    async fn update(&mut self) {
+        let levels = self.merger.levels();
+        match (levels, self.applied) {
+            (None, None) => {}
+            (None, Some(_)) => match self.settings.on_loss {
+                SignalLoss::HoldLastLook => defmt::warn!("DMX signal lost, holding last look"),
+                SignalLoss::AllOff => {
+                    defmt::warn!("DMX signal lost, switching all off");
+                    if let Err(e) = self.runner.abort(self.relays).await {
+                        defmt::error!("DMX all-off failed: {}", e);
+                    }
+                }
+            },
+            (Some(levels), previous) => {
+                self.apply_relays(&levels, previous.as_ref()).await;
+                self.fire_sequences(&levels, previous.as_ref());
+            }
+        }
+        self.applied = levels;
+    }
+
+    /// Write the relays whose channel crossed the threshold, or every
+    /// mapped relay when the signal has just (re)appeared
+    async fn apply_relays(&self, levels: &[u8], previous: Option<&[u8; MAX_CHANNELS]>) {
+        let mapped = self.relays.relay_count().min(self.merger.width());
+        let on = |levels: &[u8], channel: usize| levels[channel] >= self.settings.threshold;
+        for bank in 0..BANKS {
+            let mut relays = 0;
+            let mut states = 0;
+            for channel in (bank * 8..bank * 8 + 8).take_while(|&channel| channel < mapped) {
+                let bit = 1 << (channel % 8);
+                if previous.is_none_or(|previous| on(previous, channel) != on(levels, channel)) {
+                    relays |= bit;
+                }
+                if on(levels, channel) {
+                    states |= bit;
+                }
+            }
+            if relays == 0 {
+                continue;
+            }
+            let result = self
+                .relays
+                .update_bank(bank as u8, RelayMask(relays), RelayMask(states))
+                .await;
+            if let Err(e) = result {
+                defmt::error!("DMX relay update on bank {} failed: {}", bank, e);
+            }
+        }
+    }
+
+    /// Start the sequences whose channel rose through the trigger level
+    fn fire_sequences(&self, levels: &[u8], previous: Option<&[u8; MAX_CHANNELS]>) {
+        let Some(previous) = previous else {
+            return;
+        };
+        let first = self.relays.relay_count();
+        let level = self.settings.trigger_level;
+        let channels = (first..self.merger.width()).zip(self.configs);
+        for (channel, config) in channels {
+            if previous[channel] < level && levels[channel] >= level {
+                match self.runner.start(config) {
+                    StartOutcome::Rejected => {
+                        defmt::warn!("Sequence '{}' rejected over DMX", config.name)
+                    }
+                    outcome => defmt::info!("Sequence '{}' {:?} over DMX", config.name, outcome),
+                }
+            }
+        }
+    }
+}
+
+/// Receive Art-Net and sACN, as enabled in `settings`, and drive `control`
+#[cfg(feature = "esp32s3")]
+pub async fn serve<I2C, const BANKS: usize, const SLOTS: usize>(
+    stack: embassy_net::Stack<'_>,
+    control: &mut DmxControl<'_, I2C, BANKS, SLOTS>,
+) -> !
+where
+    I2C: I2c + BusRecovery,
+{
+    use core::future::pending;
+
+    use embassy_futures::select::{select3, Either3};
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use embassy_net::IpAddress;
+    use embassy_time::{Instant, Timer};
+
+    let settings = control.settings;
+    if !settings.artnet && !settings.sacn {
+        defmt::info!("DMX disabled");
+        pending::<()>().await;
+    }
+
+    let mut artnet_meta = [PacketMetadata::EMPTY; 4];
+    let mut artnet_buffer = [0; 2 * MAX_PACKET];
+    let mut artnet = UdpSocket::new(
+        stack,
+        &mut artnet_meta,
+        &mut artnet_buffer,
+        &mut [],
+        &mut [],
+    );
+    let mut sacn_meta = [PacketMetadata::EMPTY; 4];
+    let mut sacn_buffer = [0; 2 * MAX_PACKET];
+    let mut sacn = UdpSocket::new(stack, &mut sacn_meta, &mut sacn_buffer, &mut [], &mut []);
+
+    let artnet_on = settings.artnet && bind(&mut artnet, ARTNET_PORT);
+    let sacn_on = settings.sacn && bind(&mut sacn, SACN_PORT);
+    if sacn_on {
+        let [a, b, c, d] = sacn_group(settings.universe);
+        if let Err(e) = stack.join_multicast_group(IpAddress::v4(a, b, c, d)) {
+            defmt::warn!("sACN multicast join failed, unicast only: {:?}", e);
+        }
+    }
+    defmt::info!(
+        "DMX universe {} at address {} (Art-Net {}, sACN {})",
+        settings.universe,
+        settings.start_address,
+        artnet_on,
+        sacn_on
+    );
+
+    let mut artnet_packet = [0; MAX_PACKET];
+    let mut sacn_packet = [0; MAX_PACKET];
+    loop {
+        let artnet_rx = async {
+            if !artnet_on {
+                pending::<()>().await;
+            }
+            artnet.recv_from(&mut artnet_packet).await
+        };
+        let sacn_rx = async {
+            if !sacn_on {
+                pending::<()>().await;
+            }
+            sacn.recv_from(&mut sacn_packet).await
+        };
+        let expiry = async {
+            match control.merger().next_expiry_ms() {
+                Some(ms) => Timer::at(Instant::from_millis(ms)).await,
+                None => pending().await,
+            }
+        };
+        let now_ms = || Instant::now().as_millis();
+        match select3(artnet_rx, sacn_rx, expiry).await {
+            Either3::First(Ok((len, meta))) => {
+                let IpAddress::Ipv4(sender) = meta.endpoint.addr;
+                match parse_artnet(&artnet_packet[..len]) {
+                    Ok(frame) => {
+                        let source = SourceId::ArtNet(sender.octets());
+                        control.receive(source, &frame, now_ms()).await;
+                    }
+                    Err(DmxError::NotDmx) => {}
+                    Err(e) => defmt::debug!("Art-Net packet ignored: {}", e),
+                }
+            }
+            Either3::Second(Ok((len, _))) => match parse_sacn(&sacn_packet[..len]) {
+                Ok((cid, frame)) => control.receive(SourceId::Sacn(cid), &frame, now_ms()).await,
+                Err(DmxError::NotDmx) => {}
+                Err(e) => defmt::debug!("sACN packet ignored: {}", e),
+            },
+            Either3::First(Err(e)) | Either3::Second(Err(e)) => {
+                defmt::warn!("DMX receive failed: {:?}", e)
+            }
+            Either3::Third(()) => control.expire(now_ms()).await,
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+fn bind(socket: &mut embassy_net::udp::UdpSocket<'_>, port: u16) -> bool {
+    match socket.bind(port) {
+        Ok(()) => true,
+        Err(e) => {
+            defmt::error!("DMX bind to port {} failed: {:?}", port, e);
+            false
+        }
+    }
+}
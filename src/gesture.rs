@@ -0,0 +1,234 @@
+//! Input gestures: holds, long presses, double triggers and patterns
+//!
+//! A [`GestureRecognizer`] follows the [`InputEvent`]s of the input
+//! monitors and reports a [`GestureEvent`] whenever the [`Gesture`] of a
+//! [`SequenceConfig`] completes, so a mapping can start on more than a
+//! plain trigger. Plain triggers are reported as [`Gesture::Trigger`].
+
+use embassy_time::Instant;
+
+use crate::hardware::DigitalInput;
+use crate::input::{InputEvent, InputEventKind};
+use crate::sequence::SequenceConfig;
+
+/// Mappings followed by one recognizer; later ones only match plain triggers
+pub const MAX_GESTURES: usize = 16;
+
+/// What a [`SequenceConfig`] waits for on its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub enum Gesture {
+    /// Every trigger of the input
+    #[default]
+    Trigger,
+    /// Input active for this many ms; fires while it is still held
+    Hold(u32),
+    /// Input released after being active for at least this many ms
+    Release(u32),
+    /// Second trigger within this many ms of the first
+    Double(u32),
+    /// These inputs trigger in order, then the mapping's own input
+    ///
+    /// A trigger of any other input of the pattern starts over; inputs
+    /// outside it are ignored.
+    Pattern {
+        after: &'static [DigitalInput],
+        /// From the first trigger to the last; 0 for no limit
+        within_ms: u32,
+    },
+}
+
+/// A completed gesture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct GestureEvent {
+    pub input: DigitalInput,
+    pub gesture: Gesture,
+    pub timestamp_ms: u64,
+}
+
+/// Gestures completed by one event or poll
+pub type GestureEvents = heapless::Vec<GestureEvent, MAX_GESTURES>;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct GestureState {
+    /// Hold: already fired during the current activation
+    fired: bool,
+    /// Double: the unpaired trigger; Pattern: the first trigger
+    since_ms: Option<u64>,
+    /// Pattern: inputs of `after` seen so far
+    step: usize,
+}
+
+/// Recognizes the gestures of a set of mappings
+///
+/// Feed it every [`InputEvent`] with [`Self::feed`], and call
+/// [`Self::poll`] at [`Self::deadline`] so holds fire while held.
+pub struct GestureRecognizer<'a> {
+    configs: &'a [SequenceConfig],
+    /// When each input became active, while it is
+    active_since: [Option<u64>; 8],
+    states: [GestureState; MAX_GESTURES],
+}
+
+impl<'a> GestureRecognizer<'a> {
+    pub fn new(configs: &'a [SequenceConfig]) -> Self {
+        Self {
+            configs: &configs[..configs.len().min(MAX_GESTURES)],
+            active_since: [None; 8],
+            states: [GestureState::default(); MAX_GESTURES],
+        }
+    }
+
+    /// Next time a hold completes, if an input is being held
+    pub fn deadline(&self) -> Option<Instant> {
+        self.configs
+            .iter()
+            .zip(&self.states)
+            .filter_map(|(config, state)| match config.gesture {
+                Gesture::Hold(ms) if !state.fired => {
+                    self.active_since[config.trigger as usize].map(|since| since + ms as u64)
+                }
+                _ => None,
+            })
+            .min()
+            .map(Instant::from_millis)
+    }
+
+    /// Holds completed by now
+    pub fn poll(&mut self) -> GestureEvents {
+        let mut events = GestureEvents::new();
+        self.expire(Instant::now().as_millis(), &mut events);
+        events
+    }
+
+    /// Gestures completed by `event`, holds due by its time first
+    pub fn feed(&mut self, event: &InputEvent) -> GestureEvents {
+        let mut events = GestureEvents::new();
+        let now = event.timestamp_ms;
+        self.expire(now, &mut events);
+
+        let input = event.input;
+        match event.kind {
+            InputEventKind::Activated => {
+                self.active_since[input as usize] = Some(now);
+                for (config, state) in self.configs.iter().zip(&mut self.states) {
+                    if config.trigger == input {
+                        state.fired = false;
+                    }
+                }
+            }
+            InputEventKind::Released => {
+                let Some(since) = self.active_since[input as usize].take() else {
+                    return events;
+                };
+                for config in self.configs {
+                    match config.gesture {
+                        Gesture::Release(ms)
+                            if config.trigger == input && now - since >= ms as u64 =>
+                        {
+                            push(&mut events, config, now)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            InputEventKind::Triggered => {
+                let _ = events.push(GestureEvent {
+                    input,
+                    gesture: Gesture::Trigger,
+                    timestamp_ms: now,
+                });
+                for (config, state) in self.configs.iter().zip(&mut self.states) {
+                    let done = match config.gesture {
+                        Gesture::Double(window_ms) if config.trigger == input => {
+                            double(state, now, window_ms)
+                        }
+                        Gesture::Pattern { after, within_ms } => {
+                            pattern(state, config.trigger, after, within_ms, input, now)
+                        }
+                        _ => false,
+                    };
+                    if done {
+                        push(&mut events, config, now);
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    fn expire(&mut self, now: u64, events: &mut GestureEvents) {
+        for (config, state) in self.configs.iter().zip(&mut self.states) {
+            let Gesture::Hold(ms) = config.gesture else {
+                continue;
+            };
+            let Some(since) = self.active_since[config.trigger as usize] else {
+                continue;
+            };
+            let at = since + ms as u64;
+            if !state.fired && now >= at {
+                state.fired = true;
+                push(events, config, at);
+            }
+        }
+    }
+}
+
+fn push(events: &mut GestureEvents, config: &SequenceConfig, timestamp_ms: u64) {
+    let _ = events.push(GestureEvent {
+        input: config.trigger,
+        gesture: config.gesture,
+        timestamp_ms,
+    });
+}
+
+/// Trigger of a double gesture; true on the second within `window_ms`
+fn double(state: &mut GestureState, now: u64, window_ms: u32) -> bool {
+    match state.since_ms {
+        Some(first) if now - first <= window_ms as u64 => {
+            state.since_ms = None;
+            true
+        }
+        _ => {
+            state.since_ms = Some(now);
+            false
+        }
+    }
+}
+
+/// Trigger of `input` during a pattern ending on `last`; true once complete
+fn pattern(
+    state: &mut GestureState,
+    last: DigitalInput,
+    after: &[DigitalInput],
+    within_ms: u32,
+    input: DigitalInput,
+    now: u64,
+) -> bool {
+    if input != last && !after.contains(&input) {
+        return false;
+    }
+    let expired =
+        matches!(state.since_ms, Some(first) if within_ms > 0 && now - first > within_ms as u64);
+    if expired {
+        state.step = 0;
+    }
+
+    let expected = after.get(state.step).copied().unwrap_or(last);
+    if input == expected {
+        if state.step == after.len() {
+            state.step = 0;
+            return true;
+        }
+        if state.step == 0 {
+            state.since_ms = Some(now);
+        }
+        state.step += 1;
+    } else if after.first() == Some(&input) {
+        // Wrong input, but it starts the pattern again
+        state.since_ms = Some(now);
+        state.step = 1;
+    } else {
+        state.step = 0;
+    }
+    false
+}
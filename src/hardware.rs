@@ -7,6 +7,7 @@
 /// - Buzzer: GPIO46
 
 /// Pin number constants
+#[allow(clippy::empty_line_after_doc_comments, clippy::doc_lazy_continuation)]
 pub mod pins {
     // Digital Inputs
     pub const DI1: u8 = 4;
@@ -61,9 +62,101 @@ pub enum RelayOutput {
     Relay8 = 7,
 }
 
+/// Most expanders one I2C bus can address: TCA9554 at 0x20-0x27 plus
+/// TCA9554A at 0x38-0x3F
+pub const MAX_BANKS: usize = 16;
+
+/// Relay on any expander bank
+///
+/// Bank 0 is the on-board TCA9554 (Relay1-Relay8); further banks are
+/// expansion boards on the same I2C bus. Relays can also be numbered
+/// globally: index = bank * 8 + channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RelayId {
+    pub bank: u8,
+    /// Output on the bank's expander (0-7)
+    pub channel: u8,
+}
+
+impl RelayId {
+    /// Output `channel` (0-7) of `bank`; `None` if either is out of range
+    pub const fn new(bank: u8, channel: u8) -> Option<Self> {
+        if bank as usize >= MAX_BANKS || channel > 7 {
+            return None;
+        }
+        Some(Self { bank, channel })
+    }
+
+    /// Relay with the global `index`; `None` past the last possible bank
+    pub const fn from_index(index: u16) -> Option<Self> {
+        if index as usize >= MAX_BANKS * 8 {
+            return None;
+        }
+        Some(Self {
+            bank: (index / 8) as u8,
+            channel: (index % 8) as u8,
+        })
+    }
+
+    pub const fn index(self) -> u16 {
+        self.bank as u16 * 8 + self.channel as u16
+    }
+
+    /// Mask selecting this relay within its bank
+    pub const fn mask(self) -> RelayMask {
+        RelayMask(1 << self.channel)
+    }
+}
+
+impl From<RelayOutput> for RelayId {
+    fn from(relay: RelayOutput) -> Self {
+        Self {
+            bank: 0,
+            channel: relay as u8,
+        }
+    }
+}
+
 /// Relay state
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum RelayState {
     High,
     Low,
 }
+
+/// Set of relays on one bank, one bit per output (bit 0 = Relay1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub struct RelayMask(pub u8);
+
+impl RelayMask {
+    pub const NONE: Self = Self(0x00);
+    pub const ALL: Self = Self(0xFF);
+
+    pub const fn of(relays: &[RelayOutput]) -> Self {
+        let mut bits = 0u8;
+        let mut i = 0;
+        while i < relays.len() {
+            bits |= 1 << relays[i] as u8;
+            i += 1;
+        }
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, relay: RelayOutput) -> bool {
+        self.0 & (1 << relay as u8) != 0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl From<RelayOutput> for RelayMask {
+    fn from(relay: RelayOutput) -> Self {
+        Self(1 << relay as u8)
+    }
+}
@@ -0,0 +1,213 @@
+//! Minimal HTTP/1.1 request parser and response head writer
+//!
+//! Covers what the REST API and dashboard need: one request per
+//! connection, request bodies sized by `Content-Length`, no chunked
+//! transfer encoding.
+
+use core::fmt::Write;
+use core::str;
+
+/// Request methods the API routes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    fn parse(token: &str) -> Option<Self> {
+        Some(match token {
+            "GET" => Self::Get,
+            "POST" => Self::Post,
+            "PUT" => Self::Put,
+            "DELETE" => Self::Delete,
+            _ => return None,
+        })
+    }
+}
+
+/// Response status codes used by the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    VersionNotSupported,
+}
+
+impl Status {
+    pub const fn code(self) -> u16 {
+        match self {
+            Self::Ok => 200,
+            Self::BadRequest => 400,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::Conflict => 409,
+            Self::PayloadTooLarge => 413,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::ServiceUnavailable => 503,
+            Self::VersionNotSupported => 505,
+        }
+    }
+
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::BadRequest => "Bad Request",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::Conflict => "Conflict",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::VersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+}
+
+/// Why a request could not be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HttpError {
+    /// Headers or body not fully received yet
+    Incomplete,
+    BadRequest,
+    /// Unknown method or a transfer encoding
+    NotImplemented,
+    VersionNotSupported,
+}
+
+impl HttpError {
+    /// Status to answer with; an incomplete request that fills the whole
+    /// receive buffer is too large
+    pub const fn status(self) -> Status {
+        match self {
+            Self::Incomplete => Status::PayloadTooLarge,
+            Self::BadRequest => Status::BadRequest,
+            Self::NotImplemented => Status::NotImplemented,
+            Self::VersionNotSupported => Status::VersionNotSupported,
+        }
+    }
+}
+
+/// Parsed request, borrowing from the receive buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Path without the query string, e.g. `/api/relays/1`
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Non-empty path segments: `/api/relays/1` gives `api`, `relays`, `1`
+    pub fn segments(&self) -> impl Iterator<Item = &'a str> {
+        self.path.split('/').filter(|segment| !segment.is_empty())
+    }
+
+    /// Value of `name` in the query string (`?name=value&...`)
+    pub fn query_param(&self, name: &str) -> Option<&'a str> {
+        self.query?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then_some(value)
+        })
+    }
+}
+
+/// Parse one request from the start of `buf`
+///
+/// Returns [`HttpError::Incomplete`] until the headers and the whole
+/// `Content-Length` body are in the buffer.
+pub fn parse_request(buf: &[u8]) -> Result<Request<'_>, HttpError> {
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Err(HttpError::Incomplete);
+    };
+    let head = str::from_utf8(&buf[..head_len]).map_err(|_| HttpError::BadRequest)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(version), None) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(HttpError::BadRequest);
+    };
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => {}
+        _ if version.starts_with("HTTP/") => return Err(HttpError::VersionNotSupported),
+        _ => return Err(HttpError::BadRequest),
+    }
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(HttpError::BadRequest);
+    }
+    let method = Method::parse(method).ok_or(HttpError::NotImplemented)?;
+    if !target.starts_with('/') {
+        return Err(HttpError::BadRequest);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().map_err(|_| HttpError::BadRequest)?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(HttpError::NotImplemented);
+        }
+    }
+
+    let body_start = head_len + 4;
+    let body = buf
+        .get(body_start..body_start.saturating_add(content_length))
+        .ok_or(HttpError::Incomplete)?;
+    Ok(Request {
+        method,
+        path,
+        query,
+        body,
+    })
+}
+
+pub const CONTENT_JSON: &str = "application/json";
+pub const CONTENT_HTML: &str = "text/html; charset=utf-8";
+
+/// Status line and headers of a response with `body_len` bytes
+pub fn response_head(status: Status, content_type: &str, body_len: usize) -> heapless::String<192> {
+    let mut head = heapless::String::new();
+    // 192 bytes hold the longest status line and headers
+    let _ = write!(
+        head,
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status.code(),
+        status.reason(),
+        content_type,
+        body_len
+    );
+    head
+}
+
+/// Head of a server-sent event stream; events follow until either side
+/// closes the connection
+pub const EVENT_STREAM_HEAD: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/event-stream\r\n\
+    Cache-Control: no-cache\r\n\
+    Connection: close\r\n\r\n";
@@ -1,55 +1,413 @@
 /// Digital input monitoring with debouncing and cooldown
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    watch::{self, Watch},
+};
+#[cfg(feature = "esp32s3")]
+use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
+#[cfg(feature = "esp32s3")]
 use esp_hal::gpio::Input;
 
 use crate::hardware::DigitalInput;
+use crate::sequence::ProgramError;
+use crate::storage::{InputSettings, TriggerMode};
 
-/// Input trigger event
+/// A held level triggers again this often, see [`TriggerMode::LevelHigh`]
+pub const LEVEL_REPEAT_MS: u64 = 1000;
+
+/// What an [`InputEvent`] reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InputEventKind {
+    /// The input triggered, as its [`TriggerMode`] says
+    Triggered,
+    /// The filtered level became active (high, unless inverted)
+    Activated,
+    Released,
+}
+
+/// Input trigger or level change
 #[derive(Debug, Clone, Copy)]
 pub struct InputEvent {
     pub input: DigitalInput,
     pub timestamp_ms: u64,
+    pub kind: InputEventKind,
 }
 
 /// Channel for input events (queue size: 16)
 pub type InputEventChannel = Channel<CriticalSectionRawMutex, InputEvent, 16>;
 
+/// Source of input triggers for `WaitInput` program instructions
+#[allow(async_fn_in_trait)]
+pub trait InputWait {
+    /// Return on the next trigger of `input`
+    async fn wait_for(&self, input: DigitalInput) -> Result<(), ProgramError>;
+}
+
+/// Trigger counters per input, shared with running programs
+///
+/// The control task calls [`Self::notify`] for every input event; up to
+/// `WAITERS` programs can wait on inputs at the same time.
+pub struct InputTriggers<const WAITERS: usize> {
+    counts: Watch<CriticalSectionRawMutex, [u32; 8], WAITERS>,
+}
+
+impl<const WAITERS: usize> Default for InputTriggers<WAITERS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const WAITERS: usize> InputTriggers<WAITERS> {
+    pub const fn new() -> Self {
+        Self {
+            counts: Watch::new(),
+        }
+    }
+
+    pub fn notify(&self, input: DigitalInput) {
+        self.counts.sender().send_modify(|counts| {
+            let counts = counts.get_or_insert([0; 8]);
+            counts[input as usize] = counts[input as usize].wrapping_add(1);
+        });
+    }
+
+    fn count(&self, input: DigitalInput) -> u32 {
+        self.counts
+            .try_get()
+            .map_or(0, |counts| counts[input as usize])
+    }
+}
+
+impl<const WAITERS: usize> InputWait for InputTriggers<WAITERS> {
+    async fn wait_for(&self, input: DigitalInput) -> Result<(), ProgramError> {
+        let Some(mut receiver) = self.counts.receiver() else {
+            defmt::error!("No input listener free for {:?}", input);
+            return Err(ProgramError::InputUnavailable);
+        };
+        let seen = self.count(input);
+        receiver
+            .changed_and(|counts| counts[input as usize] != seen)
+            .await;
+        Ok(())
+    }
+}
+
+/// Live input levels and trigger counts, for status displays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct InputSnapshot {
+    /// Current level per input after any inversion, bit 0 = DI1
+    pub levels: u8,
+    /// Accepted triggers per input since boot
+    pub triggers: [u32; 8],
+    /// Pulses per input too short to pass the debounce filter, since boot
+    pub glitches: [u32; 8],
+}
+
+impl InputSnapshot {
+    pub const fn is_high(&self, input: DigitalInput) -> bool {
+        self.levels & (1 << input as u8) != 0
+    }
+}
+
+/// Most status displays following [`InputStatus`] at the same time
+pub const STATUS_WATCHERS: usize = 2;
+
+/// Shared [`InputSnapshot`], updated by the input monitors
+pub struct InputStatus {
+    snapshot: Watch<CriticalSectionRawMutex, InputSnapshot, STATUS_WATCHERS>,
+}
+
+impl Default for InputStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputStatus {
+    pub const fn new() -> Self {
+        Self {
+            snapshot: Watch::new(),
+        }
+    }
+
+    pub fn set_level(&self, input: DigitalInput, high: bool) {
+        self.snapshot.sender().send_if_modified(|snapshot| {
+            let snapshot = snapshot.get_or_insert_with(InputSnapshot::default);
+            let bit = 1 << input as u8;
+            let levels = if high {
+                snapshot.levels | bit
+            } else {
+                snapshot.levels & !bit
+            };
+            let changed = levels != snapshot.levels;
+            snapshot.levels = levels;
+            changed
+        });
+    }
+
+    pub fn record_trigger(&self, input: DigitalInput) {
+        self.snapshot.sender().send_modify(|snapshot| {
+            let snapshot = snapshot.get_or_insert_with(InputSnapshot::default);
+            let count = &mut snapshot.triggers[input as usize];
+            *count = count.wrapping_add(1);
+        });
+    }
+
+    /// Set the glitch count of `input`, see [`Debouncer::glitches`]
+    pub fn set_glitches(&self, input: DigitalInput, glitches: u32) {
+        self.snapshot.sender().send_if_modified(|snapshot| {
+            let snapshot = snapshot.get_or_insert_with(InputSnapshot::default);
+            let count = &mut snapshot.glitches[input as usize];
+            let changed = *count != glitches;
+            *count = glitches;
+            changed
+        });
+    }
+
+    pub fn snapshot(&self) -> InputSnapshot {
+        self.snapshot.try_get().unwrap_or_default()
+    }
+
+    /// Follow changes; `None` when [`STATUS_WATCHERS`] are already watching
+    pub fn watch(
+        &self,
+    ) -> Option<watch::Receiver<'_, CriticalSectionRawMutex, InputSnapshot, STATUS_WATCHERS>> {
+        self.snapshot.receiver()
+    }
+}
+
+/// Stable-state debounce filter for one input
+///
+/// A new pin level counts once it has held for the debounce time without
+/// a single edge; shorter pulses, contact bounce included, are counted as
+/// glitches and otherwise ignored.
+pub struct Debouncer {
+    debounce: Duration,
+    /// Accepted level
+    level: bool,
+    /// Pin level as last seen
+    pin: bool,
+    /// Time of the last edge
+    edge_at: Instant,
+    glitches: u32,
+}
+
+impl Debouncer {
+    /// Start with the pin's current level accepted
+    pub fn new(debounce_ms: u32, pin_high: bool) -> Self {
+        Self {
+            debounce: Duration::from_millis(debounce_ms as u64),
+            level: pin_high,
+            pin: pin_high,
+            edge_at: Instant::now(),
+            glitches: 0,
+        }
+    }
+
+    /// Accepted level
+    pub const fn level(&self) -> bool {
+        self.level
+    }
+
+    /// Pulses rejected since start
+    pub const fn glitches(&self) -> u32 {
+        self.glitches
+    }
+
+    /// The pin changed and now reads `pin_high`
+    ///
+    /// An edge that leaves the pin at the accepted level ends a glitch,
+    /// even when the pulse was too short to read.
+    pub fn edge(&mut self, pin_high: bool) {
+        self.edge_at = Instant::now();
+        if pin_high == self.level {
+            self.glitches = self.glitches.wrapping_add(1);
+        }
+        self.pin = pin_high;
+    }
+
+    /// When a new level will have held long enough, if one is pending
+    pub fn deadline(&self) -> Option<Instant> {
+        (self.pin != self.level).then(|| self.edge_at + self.debounce)
+    }
+
+    /// Accept a pending level that has held long enough; returns it
+    pub fn poll(&mut self) -> Option<bool> {
+        let due = self.deadline()?;
+        if Instant::now() < due {
+            return None;
+        }
+        self.level = self.pin;
+        Some(self.level)
+    }
+}
+
+/// Turns pin level changes into triggers according to [`InputSettings`]
+pub struct TriggerDetector {
+    settings: InputSettings,
+    /// Pin level after inversion
+    active: bool,
+    /// Next trigger of a held level
+    repeat_at: Option<Instant>,
+}
+
+impl TriggerDetector {
+    /// Start from the pin's current level; a level already held triggers
+    /// on the first [`Self::repeat`]
+    pub fn new(settings: InputSettings, pin_high: bool) -> Self {
+        let mut detector = Self {
+            settings,
+            active: pin_high != settings.inverted,
+            repeat_at: None,
+        };
+        if detector.holding() {
+            detector.repeat_at = Some(Instant::now());
+        }
+        detector
+    }
+
+    /// Level after inversion, as shown on status displays
+    pub const fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// When a held level triggers next
+    pub const fn repeat_at(&self) -> Option<Instant> {
+        self.repeat_at
+    }
+
+    /// The pin is now at `pin_high`; whether that triggers
+    pub fn update(&mut self, pin_high: bool) -> bool {
+        let active = pin_high != self.settings.inverted;
+        if active == self.active {
+            return false;
+        }
+        self.active = active;
+        match self.settings.mode {
+            TriggerMode::Rising => active,
+            TriggerMode::Falling => !active,
+            TriggerMode::BothEdges => true,
+            TriggerMode::LevelHigh | TriggerMode::LevelLow => {
+                let holding = self.holding();
+                self.repeat_at = holding.then(|| Instant::now() + repeat_interval());
+                holding
+            }
+        }
+    }
+
+    /// Whether a held level is due to trigger again
+    pub fn repeat(&mut self) -> bool {
+        match self.repeat_at {
+            Some(at) if Instant::now() >= at => {
+                self.repeat_at = Some(Instant::now() + repeat_interval());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn holding(&self) -> bool {
+        match self.settings.mode {
+            TriggerMode::LevelHigh => self.active,
+            TriggerMode::LevelLow => !self.active,
+            _ => false,
+        }
+    }
+}
+
+const fn repeat_interval() -> Duration {
+    Duration::from_millis(LEVEL_REPEAT_MS)
+}
+
 /// Monitor a digital input with interrupt-based detection and debouncing
+///
+/// Levels pass a [`Debouncer`] of `debounce_ms`, then trigger as
+/// `settings` say; `status` follows the filtered level and glitch count.
+#[cfg(feature = "esp32s3")]
 pub async fn input_monitor_task<const PIN: u8>(
     mut pin: Input<'static>,
     input_id: DigitalInput,
+    settings: InputSettings,
     debounce_ms: u32,
     channel: &'static InputEventChannel,
+    status: &'static InputStatus,
 ) -> ! {
-    let debounce_duration = Duration::from_millis(debounce_ms as u64);
-    let mut last_trigger = Instant::MIN;
+    use embassy_futures::select::{select, Either};
+
+    let mut filter = Debouncer::new(debounce_ms, pin.is_high());
+    let mut detector = TriggerDetector::new(settings, filter.level());
 
-    defmt::info!("Input monitor started: {:?} (GPIO{})", input_id, PIN);
+    defmt::info!(
+        "Input monitor started: {:?} (GPIO{}, {:?})",
+        input_id,
+        PIN,
+        settings
+    );
 
     loop {
-        // Wait for rising edge (sensor activation)
-        pin.wait_for_rising_edge().await;
+        status.set_level(input_id, detector.is_active());
+        status.set_glitches(input_id, filter.glitches());
 
-        let now = Instant::now();
+        // Wait for an edge, for a new level to settle, or for a held level
+        // to trigger again
+        let wake_at = match (filter.deadline(), detector.repeat_at()) {
+            (Some(settled), Some(repeat)) => Some(settled.min(repeat)),
+            (settled, repeat) => settled.or(repeat),
+        };
+        let edge = match wake_at {
+            Some(at) => matches!(
+                select(pin.wait_for_any_edge(), Timer::at(at)).await,
+                Either::First(())
+            ),
+            None => {
+                pin.wait_for_any_edge().await;
+                true
+            }
+        };
+        if edge {
+            filter.edge(pin.is_high());
+            continue;
+        }
 
-        // Debounce check
-        if now.duration_since(last_trigger) >= debounce_duration {
-            last_trigger = now;
-            let timestamp_ms = now.as_millis();
+        let was_active = detector.is_active();
+        let triggered = match filter.poll() {
+            Some(level) => detector.update(level),
+            None => detector.repeat(),
+        };
 
+        // Level changes first, so gestures see a press before its trigger
+        let timestamp_ms = Instant::now().as_millis();
+        if detector.is_active() != was_active {
+            let kind = if was_active {
+                InputEventKind::Released
+            } else {
+                InputEventKind::Activated
+            };
             let event = InputEvent {
                 input: input_id,
                 timestamp_ms,
+                kind,
             };
-
             if channel.try_send(event).is_err() {
-                defmt::warn!("Event channel full, dropping {:?}", input_id);
-            } else {
-                defmt::info!("Input triggered: {:?}", input_id);
+                defmt::warn!("Event channel full, dropping {:?} {:?}", input_id, kind);
             }
+        }
+        if !triggered {
+            continue;
+        }
 
-            Timer::after(debounce_duration).await;
+        let event = InputEvent {
+            input: input_id,
+            timestamp_ms,
+            kind: InputEventKind::Triggered,
+        };
+        status.record_trigger(input_id);
+        if channel.try_send(event).is_err() {
+            defmt::warn!("Event channel full, dropping {:?}", input_id);
+        } else {
+            defmt::info!("Input triggered: {:?}", input_id);
         }
     }
 }
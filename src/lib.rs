@@ -1,8 +1,30 @@
 #![no_std]
 
+#[cfg(feature = "mock")]
+extern crate std;
+
+pub mod api;
+pub mod ble;
+pub mod bus;
+pub mod captive;
+pub mod dmx;
+pub mod gesture;
 pub mod hardware;
+pub mod http;
 pub mod input;
+pub mod modbus;
+pub mod mqtt;
+pub mod network;
+pub mod osc;
+pub mod random;
 pub mod relay;
+pub mod runner;
+pub mod storage;
 pub mod tca9554;
+pub mod wifi;
 
 pub mod sequence;
+pub mod show;
+
+#[cfg(feature = "mock")]
+pub mod mock;
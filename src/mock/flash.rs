@@ -0,0 +1,134 @@
+//! RAM-backed NOR flash emulator
+//!
+//! Behaves like the ESP32-S3's SPI flash as far as [`crate::storage`]
+//! can tell: word-aligned access, writes that only clear bits and
+//! sector erases back to `0xFF`. Erases are counted per sector to check
+//! wear levelling, and a simulated power loss can cut a save short.
+
+use core::cell::RefCell;
+use std::rc::Rc;
+use std::vec;
+use std::vec::Vec;
+
+use crate::storage::{Flash, FlashError, FLASH_WORD};
+
+struct State {
+    data: Vec<u8>,
+    erase_counts: Vec<u32>,
+    /// Writes and erases left before power is lost
+    budget: Option<usize>,
+}
+
+/// Mock flash partition
+///
+/// Clones share the same memory, so a test can keep one handle while the
+/// other is moved into a [`crate::storage::ConfigStore`].
+#[derive(Clone)]
+pub struct MockFlash {
+    state: Rc<RefCell<State>>,
+}
+
+impl MockFlash {
+    pub const SECTOR_SIZE: u32 = 4096;
+
+    /// Blank (erased) partition of `capacity` bytes
+    pub fn new(capacity: u32) -> Self {
+        assert_eq!(
+            capacity % Self::SECTOR_SIZE,
+            0,
+            "capacity must be whole sectors"
+        );
+        Self {
+            state: Rc::new(RefCell::new(State {
+                data: vec![0xFF; capacity as usize],
+                erase_counts: vec![0; (capacity / Self::SECTOR_SIZE) as usize],
+                budget: None,
+            })),
+        }
+    }
+
+    /// Erase cycles per sector so far
+    pub fn erase_counts(&self) -> Vec<u32> {
+        self.state.borrow().erase_counts.clone()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.state.borrow().data.clone()
+    }
+
+    /// Invert one bit, as a worn or disturbed cell would
+    pub fn flip_bit(&self, offset: u32, bit: u8) {
+        self.state.borrow_mut().data[offset as usize] ^= 1 << bit;
+    }
+
+    /// Lose power after `operations` more writes or erases; every later
+    /// one fails with [`FlashError::Io`] and changes nothing
+    pub fn power_loss_after(&self, operations: usize) {
+        self.state.borrow_mut().budget = Some(operations);
+    }
+
+    /// Power back on
+    pub fn restore_power(&self) {
+        self.state.borrow_mut().budget = None;
+    }
+
+    fn check(state: &State, offset: u32, len: usize, align: u32) -> Result<usize, FlashError> {
+        if offset % align != 0 || len as u32 % align != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        let start = offset as usize;
+        if start + len > state.data.len() {
+            return Err(FlashError::OutOfBounds);
+        }
+        Ok(start)
+    }
+
+    fn spend(state: &mut State) -> Result<(), FlashError> {
+        match &mut state.budget {
+            Some(0) => Err(FlashError::Io),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Flash for MockFlash {
+    const SECTOR_SIZE: u32 = Self::SECTOR_SIZE;
+
+    fn capacity(&self) -> u32 {
+        self.state.borrow().data.len() as u32
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        let state = self.state.borrow();
+        let start = Self::check(&state, offset, bytes.len(), FLASH_WORD)?;
+        bytes.copy_from_slice(&state.data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let mut state = self.state.borrow_mut();
+        let start = Self::check(&state, offset, bytes.len(), FLASH_WORD)?;
+        Self::spend(&mut state)?;
+        for (cell, byte) in state.data[start..].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        let mut state = self.state.borrow_mut();
+        let len = to.checked_sub(from).ok_or(FlashError::OutOfBounds)? as usize;
+        Self::check(&state, from, len, Self::SECTOR_SIZE)?;
+        for sector in from / Self::SECTOR_SIZE..to / Self::SECTOR_SIZE {
+            Self::spend(&mut state)?;
+            let sector_start = (sector * Self::SECTOR_SIZE) as usize;
+            state.data[sector_start..sector_start + Self::SECTOR_SIZE as usize].fill(0xFF);
+            state.erase_counts[sector as usize] += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! In-memory I2C bus that records every write
+
+use core::cell::RefCell;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use embassy_time::Instant;
+use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, Operation};
+
+use crate::bus::BusRecovery;
+
+/// A single write seen on the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct I2cTransaction {
+    pub address: u8,
+    pub bytes: Vec<u8>,
+    /// Virtual time of the write (see [`crate::mock::Clock`])
+    pub timestamp_ms: u64,
+}
+
+/// Mock I2C bus
+///
+/// Clones share the same transaction log, so a test can keep one handle
+/// while the other is moved into a driver.
+#[derive(Debug, Clone, Default)]
+pub struct MockI2c {
+    log: Rc<RefCell<Vec<I2cTransaction>>>,
+}
+
+impl MockI2c {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All writes recorded so far
+    pub fn transactions(&self) -> Vec<I2cTransaction> {
+        self.log.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.log.borrow_mut().clear();
+    }
+}
+
+impl ErrorType for MockI2c {
+    type Error = ErrorKind;
+}
+
+impl I2c for MockI2c {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let timestamp_ms = Instant::now().as_millis();
+        for op in operations {
+            match op {
+                Operation::Write(bytes) => self.log.borrow_mut().push(I2cTransaction {
+                    address,
+                    bytes: bytes.to_vec(),
+                    timestamp_ms,
+                }),
+                Operation::Read(buf) => buf.fill(0),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BusRecovery for MockI2c {
+    async fn recover_bus(&mut self) {}
+}
@@ -0,0 +1,39 @@
+//! Test doubles for running the hardware-independent modules on a host
+//!
+//! Enabled by the `mock` feature. Provides an in-memory I2C bus, a
+//! simulated TCA9554, a RAM-backed flash partition, an MQTT broker, a
+//! virtual clock on top of the embassy-time mock driver and a defmt
+//! logger that discards all output.
+
+pub mod flash;
+pub mod i2c;
+pub mod mqtt;
+pub mod tca9554;
+pub mod time;
+
+pub use flash::MockFlash;
+pub use i2c::{I2cTransaction, MockI2c};
+pub use mqtt::{MockBroker, MockMqttConnection};
+pub use tca9554::{MockOperation, MockTca9554, PinSnapshot, Tca9554Transaction};
+pub use time::Clock;
+
+defmt::timestamp!("{=u64:ms}", embassy_time::Instant::now().as_millis());
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+/// defmt sink for host builds (there is no RTT channel to write to)
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
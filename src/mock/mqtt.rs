@@ -0,0 +1,347 @@
+//! In-memory MQTT broker
+//!
+//! Stands in for a local mosquitto: [`MockBroker::connect`] hands out a
+//! transport for [`crate::mqtt::MqttClient::run`], and the broker answers
+//! CONNECT, SUBSCRIBE, PUBLISH and PINGREQ the way a real one would while
+//! recording everything the client sends.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::string::{String, ToString};
+use std::vec;
+use std::vec::Vec;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+use crate::mqtt::{decode, encode_publish, fixed_header, Packet, Publish, QoS};
+
+/// Message published by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+}
+
+impl Message {
+    pub fn payload_str(&self) -> &str {
+        core::str::from_utf8(&self.payload).unwrap()
+    }
+}
+
+/// CONNECT received from the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRecord {
+    pub client_id: String,
+    pub keep_alive_s: u16,
+    pub clean_session: bool,
+    /// Topic, payload and retain flag of the last will
+    pub will: Option<(String, Vec<u8>, bool)>,
+}
+
+struct State {
+    /// Bumped by every connect; older transports read as closed
+    generation: u32,
+    open: bool,
+    to_client: VecDeque<u8>,
+    from_client: Vec<u8>,
+    reader: Option<Waker>,
+    published: Vec<Message>,
+    subscriptions: Vec<String>,
+    connects: Vec<ConnectRecord>,
+    /// Packet ids the client acknowledged
+    acked: Vec<u16>,
+    pings: usize,
+    ack_publishes: bool,
+    answer_pings: bool,
+    next_packet_id: u16,
+}
+
+/// Mock broker; clones share the same state
+#[derive(Clone)]
+pub struct MockBroker {
+    state: Rc<RefCell<State>>,
+}
+
+impl Default for MockBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBroker {
+    pub fn new() -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                generation: 0,
+                open: false,
+                to_client: VecDeque::new(),
+                from_client: Vec::new(),
+                reader: None,
+                published: Vec::new(),
+                subscriptions: Vec::new(),
+                connects: Vec::new(),
+                acked: Vec::new(),
+                pings: 0,
+                ack_publishes: true,
+                answer_pings: true,
+                next_packet_id: 1,
+            })),
+        }
+    }
+
+    /// Accept a new connection, dropping the previous one
+    pub fn connect(&self) -> MockMqttConnection {
+        let mut state = self.state.borrow_mut();
+        state.generation += 1;
+        state.open = true;
+        state.to_client.clear();
+        state.from_client.clear();
+        if let Some(waker) = state.reader.take() {
+            waker.wake();
+        }
+        MockMqttConnection {
+            broker: self.clone(),
+            generation: state.generation,
+        }
+    }
+
+    /// Close the connection as if the network went away
+    pub fn disconnect(&self) {
+        let mut state = self.state.borrow_mut();
+        state.open = false;
+        if let Some(waker) = state.reader.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether PUBLISH with QoS 1 gets a PUBACK (default on)
+    pub fn set_ack_publishes(&self, ack: bool) {
+        self.state.borrow_mut().ack_publishes = ack;
+    }
+
+    /// Whether PINGREQ gets a PINGRESP (default on)
+    pub fn set_answer_pings(&self, answer: bool) {
+        self.state.borrow_mut().answer_pings = answer;
+    }
+
+    /// Deliver a message to the client, as if another client published it
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: QoS) {
+        let mut state = self.state.borrow_mut();
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => {
+                state.next_packet_id += 1;
+                Some(state.next_packet_id)
+            }
+        };
+        let mut buf = [0; 1024];
+        let len = encode_publish(
+            &mut buf,
+            &Publish {
+                topic,
+                payload,
+                qos,
+                retain: false,
+                dup: false,
+                packet_id,
+            },
+        )
+        .unwrap();
+        state.send(&buf[..len]);
+    }
+
+    /// Everything the client published, oldest first
+    pub fn published(&self) -> Vec<Message> {
+        self.state.borrow().published.clone()
+    }
+
+    /// Messages the client published on `topic`
+    pub fn published_on(&self, topic: &str) -> Vec<Message> {
+        self.state
+            .borrow()
+            .published
+            .iter()
+            .filter(|message| message.topic == topic)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear_published(&self) {
+        self.state.borrow_mut().published.clear();
+    }
+
+    /// Topic filters the client subscribed to
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.state.borrow().subscriptions.clone()
+    }
+
+    pub fn connects(&self) -> Vec<ConnectRecord> {
+        self.state.borrow().connects.clone()
+    }
+
+    /// Packet ids of broker messages the client acknowledged
+    pub fn acked(&self) -> Vec<u16> {
+        self.state.borrow().acked.clone()
+    }
+
+    pub fn pings(&self) -> usize {
+        self.state.borrow().pings
+    }
+}
+
+impl State {
+    fn send(&mut self, bytes: &[u8]) {
+        self.to_client.extend(bytes);
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    /// Handle every complete packet from the client
+    fn receive(&mut self) {
+        while let Some((first, header_len, remaining)) = fixed_header(&self.from_client).unwrap() {
+            let total = header_len + remaining;
+            if self.from_client.len() < total {
+                break;
+            }
+            let packet: Vec<u8> = self.from_client.drain(..total).collect();
+            self.handle(first, &packet, &packet[header_len..]);
+        }
+    }
+
+    fn handle(&mut self, first: u8, packet: &[u8], body: &[u8]) {
+        let mut reader = Reader(body);
+        match first >> 4 {
+            1 => {
+                assert_eq!(reader.string(), "MQTT");
+                assert_eq!(reader.bytes(1), [4], "protocol level");
+                let flags = reader.bytes(1)[0];
+                let keep_alive_s = reader.u16();
+                let client_id = reader.string();
+                let will = (flags & 0x04 != 0).then(|| {
+                    let topic = reader.string();
+                    let len = reader.u16() as usize;
+                    (topic, reader.bytes(len).to_vec(), flags & 0x20 != 0)
+                });
+                self.connects.push(ConnectRecord {
+                    client_id,
+                    keep_alive_s,
+                    clean_session: flags & 0x02 != 0,
+                    will,
+                });
+                self.send(&[0x20, 0x02, 0x00, 0x00]);
+            }
+            3 => {
+                let Ok(Some((Packet::Publish(publish), _))) = decode(packet) else {
+                    panic!("bad PUBLISH {packet:02x?}");
+                };
+                self.published.push(Message {
+                    topic: publish.topic.to_string(),
+                    payload: publish.payload.to_vec(),
+                    qos: publish.qos,
+                    retain: publish.retain,
+                    dup: publish.dup,
+                });
+                if let (Some(packet_id), true) = (publish.packet_id, self.ack_publishes) {
+                    let [high, low] = packet_id.to_be_bytes();
+                    self.send(&[0x40, 0x02, high, low]);
+                }
+            }
+            4 => self.acked.push(reader.u16()),
+            8 => {
+                let [high, low] = reader.u16().to_be_bytes();
+                let mut filters = 0;
+                while !reader.0.is_empty() {
+                    self.subscriptions.push(reader.string());
+                    reader.bytes(1);
+                    filters += 1;
+                }
+                let mut suback = vec![0x90, 2 + filters, high, low];
+                suback.extend(core::iter::repeat_n(1, filters as usize));
+                self.send(&suback);
+            }
+            12 => {
+                self.pings += 1;
+                if self.answer_pings {
+                    self.send(&[0xD0, 0x00]);
+                }
+            }
+            14 => self.open = false,
+            other => panic!("unexpected packet type {other}"),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        bytes
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn string(&mut self) -> String {
+        let len = self.u16() as usize;
+        String::from_utf8(self.bytes(len).to_vec()).unwrap()
+    }
+}
+
+/// Client end of a [`MockBroker`] connection
+pub struct MockMqttConnection {
+    broker: MockBroker,
+    generation: u32,
+}
+
+impl MockMqttConnection {
+    fn is_open(&self, state: &State) -> bool {
+        state.open && state.generation == self.generation
+    }
+}
+
+impl ErrorType for MockMqttConnection {
+    type Error = ErrorKind;
+}
+
+impl Read for MockMqttConnection {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        poll_fn(|cx| {
+            let mut state = self.broker.state.borrow_mut();
+            if !self.is_open(&state) {
+                return Poll::Ready(Ok(0));
+            }
+            if state.to_client.is_empty() {
+                state.reader = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let len = buf.len().min(state.to_client.len());
+            for (slot, byte) in buf.iter_mut().zip(state.to_client.drain(..len)) {
+                *slot = byte;
+            }
+            Poll::Ready(Ok(len))
+        })
+        .await
+    }
+}
+
+impl Write for MockMqttConnection {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        let mut state = self.broker.state.borrow_mut();
+        if !self.is_open(&state) {
+            return Err(ErrorKind::BrokenPipe);
+        }
+        state.from_client.extend_from_slice(buf);
+        state.receive();
+        Ok(buf.len())
+    }
+}
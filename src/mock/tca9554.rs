@@ -0,0 +1,328 @@
+//! Simulated TCA9554 I/O expander
+//!
+//! Models the four-register file (input, output, polarity inversion,
+//! configuration) behind the I2C command-byte protocol, so the real
+//! driver in [`crate::tca9554`] can be exercised without hardware.
+
+use core::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec;
+use std::vec::Vec;
+
+use embassy_time::Instant;
+use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+use crate::bus::BusRecovery;
+
+const INPUT_PORT: usize = 0x00;
+const OUTPUT_PORT: usize = 0x01;
+const POLARITY: usize = 0x02;
+const CONFIGURATION: usize = 0x03;
+
+/// Register contents after power-on reset (datasheet table 7.6)
+const POWER_ON_REGISTERS: [u8; 4] = [0x00, 0xFF, 0x00, 0xFF];
+
+/// Fault applied to the next transaction
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    Error(ErrorKind),
+    /// Never complete (clock stretched forever)
+    Stall,
+    /// Corrupt the data bytes written to a register
+    BitFlip(u8),
+}
+
+/// One operation within a recorded transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockOperation {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+}
+
+/// A transaction seen by the simulated device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tca9554Transaction {
+    pub timestamp_ms: u64,
+    pub address: u8,
+    pub operations: Vec<MockOperation>,
+    /// Error returned to the driver, if any
+    pub error: Option<ErrorKind>,
+}
+
+/// Level change on the output pins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinSnapshot {
+    pub timestamp_ms: u64,
+    /// Driven level of every pin configured as output (inputs read as 0)
+    pub outputs: u8,
+}
+
+struct State {
+    address: u8,
+    registers: [u8; 4],
+    pointer: usize,
+    /// Externally applied levels on the pins
+    pin_levels: u8,
+    injected: VecDeque<Fault>,
+    stuck: Option<ErrorKind>,
+    /// SDA held low by the device until a bus recovery
+    bus_hung: bool,
+    recoveries: u32,
+    log: Vec<Tca9554Transaction>,
+    waveform: Vec<PinSnapshot>,
+}
+
+impl State {
+    fn outputs(&self) -> u8 {
+        self.registers[OUTPUT_PORT] & !self.registers[CONFIGURATION]
+    }
+
+    fn read_register(&self, register: usize) -> u8 {
+        match register {
+            INPUT_PORT => {
+                let config = self.registers[CONFIGURATION];
+                let levels = (self.pin_levels & config) | self.outputs();
+                levels ^ (self.registers[POLARITY] & config)
+            }
+            _ => self.registers[register],
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u8) {
+        // The input port is read-only; writes are acknowledged and ignored
+        if register != INPUT_PORT {
+            self.registers[register] = value;
+        }
+    }
+
+    fn record_outputs(&mut self, timestamp_ms: u64) {
+        let outputs = self.outputs();
+        if self.waveform.last().map(|s| s.outputs) != Some(outputs) {
+            self.waveform.push(PinSnapshot {
+                timestamp_ms,
+                outputs,
+            });
+        }
+    }
+
+    fn execute(&mut self, operations: &mut [Operation<'_>], flip: u8) -> Result<(), ErrorKind> {
+        for op in operations.iter_mut() {
+            match op {
+                Operation::Write(bytes) => {
+                    let Some((&command, data)) = bytes.split_first() else {
+                        continue;
+                    };
+                    if command > CONFIGURATION as u8 {
+                        return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data));
+                    }
+                    self.pointer = command as usize;
+                    // No auto-increment: every data byte lands in the same register
+                    for &value in data {
+                        self.write_register(self.pointer, value ^ flip);
+                    }
+                }
+                Operation::Read(buf) => buf.fill(self.read_register(self.pointer)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Simulated TCA9554 on a private I2C bus
+///
+/// Clones share the same device, so a test can keep one handle for
+/// assertions while the other is moved into the driver.
+#[derive(Clone)]
+pub struct MockTca9554 {
+    state: Rc<RefCell<State>>,
+}
+
+impl MockTca9554 {
+    pub fn new(address: u8) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                address,
+                registers: POWER_ON_REGISTERS,
+                pointer: 0,
+                pin_levels: 0x00,
+                injected: VecDeque::new(),
+                stuck: None,
+                bus_hung: false,
+                recoveries: 0,
+                log: Vec::new(),
+                waveform: vec![PinSnapshot {
+                    timestamp_ms: Instant::now().as_millis(),
+                    outputs: 0x00,
+                }],
+            })),
+        }
+    }
+
+    /// Raw register value (0x00-0x03)
+    pub fn register(&self, register: u8) -> u8 {
+        self.state.borrow().registers[register as usize & 0x03]
+    }
+
+    /// Levels currently driven on the output pins
+    pub fn outputs(&self) -> u8 {
+        self.state.borrow().outputs()
+    }
+
+    /// Set the externally applied levels seen by pins configured as inputs
+    pub fn set_pin_levels(&self, levels: u8) {
+        self.state.borrow_mut().pin_levels = levels;
+    }
+
+    /// Return `error` for the next transaction instead of executing it
+    pub fn inject_error(&self, error: ErrorKind) {
+        self.state
+            .borrow_mut()
+            .injected
+            .push_back(Fault::Error(error));
+    }
+
+    /// Let the next transaction hang forever
+    pub fn inject_stall(&self) {
+        self.state.borrow_mut().injected.push_back(Fault::Stall);
+    }
+
+    /// XOR the register data of the next transaction with `mask`
+    pub fn inject_bit_flip(&self, mask: u8) {
+        self.state
+            .borrow_mut()
+            .injected
+            .push_back(Fault::BitFlip(mask));
+    }
+
+    /// Hold SDA low: every transaction fails with a bus error until the
+    /// driver performs a bus recovery
+    pub fn hang_bus(&self) {
+        self.state.borrow_mut().bus_hung = true;
+    }
+
+    /// Number of bus recoveries performed by the driver
+    pub fn recoveries(&self) -> u32 {
+        self.state.borrow().recoveries
+    }
+
+    /// Fail every transaction with `error` until cleared with `None`
+    pub fn set_stuck_error(&self, error: Option<ErrorKind>) {
+        self.state.borrow_mut().stuck = error;
+    }
+
+    /// Reset the register file to power-on defaults, as after a brown-out
+    pub fn power_cycle(&self) {
+        let mut state = self.state.borrow_mut();
+        state.registers = POWER_ON_REGISTERS;
+        state.pointer = 0;
+        let now = Instant::now().as_millis();
+        state.record_outputs(now);
+    }
+
+    /// Every transaction seen on the bus, including failed ones
+    pub fn transactions(&self) -> Vec<Tca9554Transaction> {
+        self.state.borrow().log.clone()
+    }
+
+    /// Output level history, starting with the power-on state and adding
+    /// one entry per change
+    pub fn waveform(&self) -> Vec<PinSnapshot> {
+        self.state.borrow().waveform.clone()
+    }
+
+    /// Level history of a single pin as `(timestamp_ms, high)` edges
+    pub fn pin_waveform(&self, pin: u8) -> Vec<(u64, bool)> {
+        let mut edges: Vec<(u64, bool)> = Vec::new();
+        for snapshot in self.state.borrow().waveform.iter() {
+            let high = snapshot.outputs & (1 << pin) != 0;
+            if edges.last().map(|&(_, h)| h) != Some(high) {
+                edges.push((snapshot.timestamp_ms, high));
+            }
+        }
+        edges
+    }
+
+    /// Drop recorded transactions and restart the waveform from the
+    /// current output levels
+    pub fn clear_log(&self) {
+        let mut state = self.state.borrow_mut();
+        let snapshot = PinSnapshot {
+            timestamp_ms: Instant::now().as_millis(),
+            outputs: state.outputs(),
+        };
+        state.log.clear();
+        state.waveform = vec![snapshot];
+    }
+}
+
+impl MockTca9554 {
+    /// Consume a pending stall fault if the next transaction would hit it
+    fn take_stall(&self, address: u8) -> bool {
+        let mut state = self.state.borrow_mut();
+        let reachable = address == state.address && !state.bus_hung && state.stuck.is_none();
+        if reachable && matches!(state.injected.front(), Some(Fault::Stall)) {
+            state.injected.pop_front();
+            return true;
+        }
+        false
+    }
+}
+
+impl ErrorType for MockTca9554 {
+    type Error = ErrorKind;
+}
+
+impl I2c for MockTca9554 {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if self.take_stall(address) {
+            core::future::pending::<()>().await
+        }
+
+        let timestamp_ms = Instant::now().as_millis();
+        let mut state = self.state.borrow_mut();
+
+        let result = if address != state.address {
+            Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))
+        } else if state.bus_hung {
+            Err(ErrorKind::Bus)
+        } else if let Some(error) = state.stuck {
+            Err(error)
+        } else {
+            match state.injected.pop_front() {
+                Some(Fault::Error(error)) => Err(error),
+                Some(Fault::BitFlip(mask)) => state.execute(operations, mask),
+                Some(Fault::Stall) | None => state.execute(operations, 0x00),
+            }
+        };
+
+        let operations = operations
+            .iter()
+            .map(|op| match op {
+                Operation::Write(bytes) => MockOperation::Write(bytes.to_vec()),
+                Operation::Read(buf) => MockOperation::Read(buf.to_vec()),
+            })
+            .collect();
+        state.log.push(Tca9554Transaction {
+            timestamp_ms,
+            address,
+            operations,
+            error: result.err(),
+        });
+        state.record_outputs(timestamp_ms);
+
+        result
+    }
+}
+
+impl BusRecovery for MockTca9554 {
+    async fn recover_bus(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.bus_hung = false;
+        state.recoveries += 1;
+    }
+}
@@ -0,0 +1,72 @@
+//! Virtual clock for host tests
+
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Wake;
+
+use embassy_time::{Duration, Instant, MockDriver};
+
+/// Upper bound on virtual time for [`Clock::block_on`], to catch hangs
+const BLOCK_ON_LIMIT_MS: u64 = 60 * 60 * 1000;
+
+/// Serializes access to the global mock driver between test threads
+static CLOCK_LOCK: Mutex<()> = Mutex::new(());
+
+/// Exclusive handle to the global mock time driver
+///
+/// The embassy-time mock driver is a process-wide singleton, so tests
+/// that depend on time must hold a `Clock` for their whole duration.
+/// Taking the clock resets virtual time to zero.
+pub struct Clock {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Clock {
+    pub fn take() -> Self {
+        let guard = CLOCK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        MockDriver::get().reset();
+        Self { _guard: guard }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        Instant::now().as_millis()
+    }
+
+    pub fn advance_ms(&self, ms: u64) {
+        MockDriver::get().advance(Duration::from_millis(ms));
+    }
+
+    /// Run a future to completion, advancing virtual time in 1ms steps
+    /// whenever it is blocked on a timer
+    pub fn block_on<F: Future>(&self, fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let deadline = self.now_ms() + BLOCK_ON_LIMIT_MS;
+
+        loop {
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+            // Woken without time passing (channel, signal, ...): poll again
+            if flag.0.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+            assert!(self.now_ms() < deadline, "future did not complete");
+            self.advance_ms(1);
+            flag.0.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
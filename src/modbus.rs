@@ -0,0 +1,826 @@
+//! Modbus server for PLC and SCADA integration
+//!
+//! Addresses are 0-based as sent on the wire.
+//!
+//! | Table             | Address      | Access | Meaning                                     |
+//! | ----------------- | ------------ | ------ | ------------------------------------------- |
+//! | Coils             | 0..          | rw     | Relays, R1 first, 8 per bank                |
+//! | Discrete inputs   | 0-7          | r      | Levels of DI1-DI8                           |
+//! | Holding registers | 0            | r      | Relay outputs of bank 0, bit 0 = R1         |
+//! |                   | 1            | r      | Input levels, bit 0 = DI1                   |
+//! |                   | 2            | r      | Sequences running                           |
+//! |                   | 3            | r      | Sequences queued                            |
+//! |                   | 4            | r      | Sequences in the show                       |
+//! |                   | 5            | w      | Non-zero stops everything, all relays off   |
+//! |                   | 100 + n      | rw     | Sequence n (show order): 1 while running,   |
+//! |                   |              |        | write non-zero to start it                  |
+//! |                   | 200 + 2i     | rw     | Cooldown of DI(i+1) in ms, high word first  |
+//! |                   | 300 + 2i     | r      | Cooldown left on DI(i+1) in ms, high first  |
+//!
+//! The same map is served over Modbus TCP and, as a slave, over Modbus
+//! RTU on the RS485 port.
+//!
+//! Supported function codes are 1, 2, 3, 5, 6, 15 and 16. Starting a
+//! sequence ignores its input and cooldown. Cooldowns written here last
+//! until the next restart. Register 5 and the trigger registers act on any
+//! non-zero write, so writing them is not idempotent.
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::bus::BusRecovery;
+use crate::hardware::{DigitalInput, RelayId, RelayMask};
+use crate::input::InputStatus;
+use crate::relay::RelayController;
+use crate::runner::{SequenceRunner, StartOutcome};
+use crate::sequence::{SequenceConfig, SharedDispatcher};
+
+/// Port of Modbus TCP
+pub const TCP_PORT: u16 = 502;
+/// Largest protocol data unit: function code and data
+pub const MAX_PDU: usize = 253;
+/// MBAP header in front of every Modbus TCP PDU
+pub const MBAP_LEN: usize = 7;
+/// Largest Modbus RTU frame: address, PDU and CRC
+pub const MAX_RTU_FRAME: usize = 256;
+/// RTU address every slave carries out, without answering
+pub const BROADCAST_ADDRESS: u8 = 0;
+
+pub const ALL_OFF_REGISTER: u16 = 5;
+pub const TRIGGER_REGISTERS: u16 = 100;
+pub const COOLDOWN_REGISTERS: u16 = 200;
+pub const REMAINING_REGISTERS: u16 = 300;
+
+const INPUTS: u16 = 8;
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// Exception returned instead of a normal response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    /// Relay bus failure
+    ServerDeviceFailure = 4,
+    /// Relays needed by the sequence are in use
+    ServerDeviceBusy = 6,
+}
+
+/// Parsed request PDU, borrowing packed data from the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    ReadCoils {
+        address: u16,
+        count: u16,
+    },
+    ReadDiscreteInputs {
+        address: u16,
+        count: u16,
+    },
+    ReadHoldingRegisters {
+        address: u16,
+        count: u16,
+    },
+    WriteSingleCoil {
+        address: u16,
+        on: bool,
+    },
+    WriteSingleRegister {
+        address: u16,
+        value: u16,
+    },
+    /// `bits` holds `count` coils, LSB of the first byte first
+    WriteMultipleCoils {
+        address: u16,
+        count: u16,
+        bits: &'a [u8],
+    },
+    /// `values` holds big-endian registers
+    WriteMultipleRegisters {
+        address: u16,
+        values: &'a [u8],
+    },
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(pdu: &'a [u8]) -> Result<Self, Exception> {
+        let (&function, data) = pdu.split_first().ok_or(Exception::IllegalFunction)?;
+        if !matches!(function, 1..=3 | 5 | 6 | 15 | 16) {
+            return Err(Exception::IllegalFunction);
+        }
+        let (fields, payload) = data
+            .split_at_checked(4)
+            .ok_or(Exception::IllegalDataValue)?;
+        let address = u16::from_be_bytes([fields[0], fields[1]]);
+        let count = u16::from_be_bytes([fields[2], fields[3]]);
+        let limit = |max: u16| {
+            if (1..=max).contains(&count) && address.checked_add(count - 1).is_some() {
+                Ok(())
+            } else {
+                Err(Exception::IllegalDataValue)
+            }
+        };
+        // Byte count followed by exactly that many bytes
+        let values = |expected: u16| match payload.split_first() {
+            Some((&len, rest)) if len as u16 == expected && rest.len() == len as usize => Ok(rest),
+            _ => Err(Exception::IllegalDataValue),
+        };
+        Ok(match function {
+            1..=3 | 5 | 6 if !payload.is_empty() => return Err(Exception::IllegalDataValue),
+            1 => {
+                limit(MAX_READ_BITS)?;
+                Self::ReadCoils { address, count }
+            }
+            2 => {
+                limit(MAX_READ_BITS)?;
+                Self::ReadDiscreteInputs { address, count }
+            }
+            3 => {
+                limit(MAX_READ_REGISTERS)?;
+                Self::ReadHoldingRegisters { address, count }
+            }
+            5 => Self::WriteSingleCoil {
+                address,
+                on: match count {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                },
+            },
+            6 => Self::WriteSingleRegister {
+                address,
+                value: count,
+            },
+            15 => {
+                limit(MAX_WRITE_BITS)?;
+                Self::WriteMultipleCoils {
+                    address,
+                    count,
+                    bits: values(count.div_ceil(8))?,
+                }
+            }
+            // 16
+            _ => {
+                limit(MAX_WRITE_REGISTERS)?;
+                Self::WriteMultipleRegisters {
+                    address,
+                    values: values(count * 2)?,
+                }
+            }
+        })
+    }
+}
+
+/// Write the exception response for `function` to `response`; returns
+/// its length
+pub fn exception_response(function: u8, exception: Exception, response: &mut [u8]) -> usize {
+    response[0] = function | 0x80;
+    response[1] = exception as u8;
+    2
+}
+
+/// MBAP header of a Modbus TCP frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Mbap {
+    pub transaction: u16,
+    pub unit: u8,
+}
+
+/// Modbus TCP frame split off a receive buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpFrame<'a> {
+    pub mbap: Mbap,
+    pub pdu: &'a [u8],
+    /// Bytes of the buffer taken up by the frame
+    pub len: usize,
+}
+
+/// Frame that cannot be a Modbus request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameError {
+    /// Other protocol id, or a length no PDU can have
+    BadHeader,
+    /// RTU frame whose CRC does not match
+    BadCrc,
+}
+
+/// Split the first Modbus TCP frame off `buf`; `Ok(None)` until it is
+/// complete
+///
+/// The connection cannot be resynchronised after an error.
+pub fn parse_tcp(buf: &[u8]) -> Result<Option<TcpFrame<'_>>, FrameError> {
+    let Some(header) = buf.get(..MBAP_LEN) else {
+        return Ok(None);
+    };
+    let protocol = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if protocol != 0 || !(2..=MAX_PDU + 1).contains(&len) {
+        return Err(FrameError::BadHeader);
+    }
+    let end = MBAP_LEN - 1 + len;
+    let Some(pdu) = buf.get(MBAP_LEN..end) else {
+        return Ok(None);
+    };
+    let mbap = Mbap {
+        transaction: u16::from_be_bytes([header[0], header[1]]),
+        unit: header[6],
+    };
+    Ok(Some(TcpFrame {
+        mbap,
+        pdu,
+        len: end,
+    }))
+}
+
+/// Write the MBAP header for a response PDU of `pdu_len` bytes
+pub fn write_mbap(mbap: Mbap, pdu_len: usize, out: &mut [u8; MBAP_LEN]) {
+    out[..2].copy_from_slice(&mbap.transaction.to_be_bytes());
+    out[2..4].copy_from_slice(&[0, 0]);
+    out[4..6].copy_from_slice(&(pdu_len as u16 + 1).to_be_bytes());
+    out[6] = mbap.unit;
+}
+
+/// CRC-16/MODBUS of `data`, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Modbus RTU frame with its CRC checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtuFrame<'a> {
+    /// Slave address, [`BROADCAST_ADDRESS`] for every slave
+    pub address: u8,
+    pub pdu: &'a [u8],
+}
+
+/// Check a whole Modbus RTU frame, as delimited by [`RtuReceiver`]
+pub fn parse_rtu(frame: &[u8]) -> Result<RtuFrame<'_>, FrameError> {
+    if !(4..=MAX_RTU_FRAME).contains(&frame.len()) {
+        return Err(FrameError::BadHeader);
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(FrameError::BadCrc);
+    }
+    Ok(RtuFrame {
+        address: body[0],
+        pdu: &body[1..],
+    })
+}
+
+/// Write the RTU frame carrying `pdu` from slave `address`; returns its
+/// length
+pub fn write_rtu(address: u8, pdu: &[u8], out: &mut [u8; MAX_RTU_FRAME]) -> usize {
+    let end = 1 + pdu.len();
+    out[0] = address;
+    out[1..end].copy_from_slice(pdu);
+    let crc = crc16(&out[..end]);
+    out[end..end + 2].copy_from_slice(&crc.to_le_bytes());
+    end + 2
+}
+
+/// Character time and silent intervals of Modbus RTU at one baud rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RtuTiming {
+    /// One character: start, 8 data, parity or second stop, stop bit
+    pub char_us: u32,
+    /// Longest silence allowed inside a frame, t1.5
+    pub t15_us: u32,
+    /// Silence that ends a frame, t3.5
+    pub t35_us: u32,
+}
+
+impl RtuTiming {
+    /// Above 19200 baud the intervals are fixed at 750 µs and 1.75 ms
+    pub const fn new(baud: u32) -> Self {
+        let char_us = 11_000_000u32.div_ceil(baud);
+        if baud > 19_200 {
+            Self {
+                char_us,
+                t15_us: 750,
+                t35_us: 1750,
+            }
+        } else {
+            Self {
+                char_us,
+                t15_us: char_us * 3 / 2,
+                t35_us: char_us * 7 / 2,
+            }
+        }
+    }
+}
+
+/// Splits received bytes into RTU frames by the silence between them
+///
+/// A frame with a gap over t1.5, a line error or more than
+/// [`MAX_RTU_FRAME`] bytes is dropped whole.
+pub struct RtuReceiver {
+    timing: RtuTiming,
+    buf: [u8; MAX_RTU_FRAME],
+    len: usize,
+    /// A frame is being received
+    active: bool,
+    broken: bool,
+    /// End of the last character received
+    last_us: u64,
+}
+
+impl RtuReceiver {
+    pub const fn new(timing: RtuTiming) -> Self {
+        Self {
+            timing,
+            buf: [0; MAX_RTU_FRAME],
+            len: 0,
+            active: false,
+            broken: false,
+            last_us: 0,
+        }
+    }
+
+    /// Add `bytes`, the last of which was complete at `now_us`
+    pub fn receive(&mut self, bytes: &[u8], now_us: u64) {
+        if bytes.is_empty() {
+            return;
+        }
+        let char_us = self.timing.char_us as u64;
+        if self.active {
+            // Silence between the last character and the first of these
+            let first_start = now_us.saturating_sub(bytes.len() as u64 * char_us);
+            let gap = first_start.saturating_sub(self.last_us);
+            if gap > self.timing.t35_us as u64 {
+                self.clear();
+            } else if gap > self.timing.t15_us as u64 {
+                self.broken = true;
+            }
+        }
+        self.active = true;
+        self.last_us = now_us;
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(slots) if !self.broken => {
+                slots.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            _ => self.broken = true,
+        }
+    }
+
+    /// Parity, framing or overrun error on the line at `now_us`
+    pub fn fault(&mut self, now_us: u64) {
+        self.active = true;
+        self.broken = true;
+        self.last_us = now_us;
+    }
+
+    /// When the frame being received ends if the line stays silent
+    pub fn deadline_us(&self) -> Option<u64> {
+        self.active
+            .then_some(self.last_us + self.timing.t35_us as u64)
+    }
+
+    /// The frame received, once t3.5 of silence has followed it
+    ///
+    /// A dropped frame is consumed without being returned.
+    pub fn take(&mut self, now_us: u64) -> Option<&[u8]> {
+        if self.deadline_us().is_none_or(|deadline| now_us < deadline) {
+            return None;
+        }
+        let (len, broken) = (self.len, self.broken);
+        self.clear();
+        (!broken).then_some(&self.buf[..len])
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.active = false;
+        self.broken = false;
+    }
+}
+
+/// Serves the register map over any Modbus transport
+pub struct ModbusServer<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    inputs: &'a InputStatus,
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    dispatcher: &'a SharedDispatcher,
+    configs: &'a [SequenceConfig],
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> ModbusServer<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub const fn new(
+        inputs: &'a InputStatus,
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        dispatcher: &'a SharedDispatcher,
+        configs: &'a [SequenceConfig],
+    ) -> Self {
+        Self {
+            inputs,
+            relays,
+            runner,
+            dispatcher,
+            configs,
+        }
+    }
+
+    /// Answer the request PDU `pdu`; returns the length of the response
+    /// PDU written to `response`
+    pub async fn handle(&self, pdu: &[u8], response: &mut [u8; MAX_PDU]) -> usize {
+        let function = pdu.first().copied().unwrap_or(0);
+        let result = match Request::parse(pdu) {
+            Ok(request) => self.execute(request, pdu, response).await,
+            Err(exception) => Err(exception),
+        };
+        match result {
+            Ok(len) => len,
+            Err(exception) => {
+                defmt::info!("Modbus function {} refused: {}", function, exception);
+                exception_response(function, exception, response)
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        request: Request<'_>,
+        pdu: &[u8],
+        response: &mut [u8; MAX_PDU],
+    ) -> Result<usize, Exception> {
+        response[0] = pdu[0];
+        match request {
+            Request::ReadCoils { address, count } => {
+                let outputs = self.outputs().await;
+                let relays = self.relays.relay_count() as u16;
+                self.read_bits(address, count, relays, response, |coil| {
+                    RelayId::from_index(coil).is_some_and(|relay| {
+                        outputs[relay.bank as usize] & relay.mask().bits() != 0
+                    })
+                })
+            }
+            Request::ReadDiscreteInputs { address, count } => {
+                let levels = self.inputs.snapshot().levels;
+                self.read_bits(address, count, INPUTS, response, |input| {
+                    levels & 1 << input != 0
+                })
+            }
+            Request::ReadHoldingRegisters { address, count } => {
+                let outputs = self.outputs().await;
+                response[1] = (count * 2) as u8;
+                for (i, register) in (address..=address + (count - 1)).enumerate() {
+                    let value = self
+                        .register(register, &outputs)
+                        .ok_or(Exception::IllegalDataAddress)?;
+                    response[2 + 2 * i..4 + 2 * i].copy_from_slice(&value.to_be_bytes());
+                }
+                Ok(2 + 2 * count as usize)
+            }
+            Request::WriteSingleCoil { address, on } => {
+                self.write_coils(address, 1, &[on as u8]).await?;
+                response[1..5].copy_from_slice(&pdu[1..5]);
+                Ok(5)
+            }
+            Request::WriteSingleRegister { address, value } => {
+                self.write_registers(address, &value.to_be_bytes()).await?;
+                response[1..5].copy_from_slice(&pdu[1..5]);
+                Ok(5)
+            }
+            Request::WriteMultipleCoils {
+                address,
+                count,
+                bits,
+            } => {
+                self.write_coils(address, count, bits).await?;
+                response[1..5].copy_from_slice(&pdu[1..5]);
+                Ok(5)
+            }
+            Request::WriteMultipleRegisters { address, values } => {
+                self.write_registers(address, values).await?;
+                response[1..5].copy_from_slice(&pdu[1..5]);
+                Ok(5)
+            }
+        }
+    }
+
+    async fn outputs(&self) -> [u8; BANKS] {
+        let mut outputs = [0; BANKS];
+        for (bank, state) in outputs.iter_mut().enumerate() {
+            *state = self.relays.bank_state(bank as u8).await.unwrap_or(0);
+        }
+        outputs
+    }
+
+    /// Pack `count` bits from `address` on, of a table of `size` bits
+    fn read_bits(
+        &self,
+        address: u16,
+        count: u16,
+        size: u16,
+        response: &mut [u8; MAX_PDU],
+        bit: impl Fn(u16) -> bool,
+    ) -> Result<usize, Exception> {
+        if address as u32 + count as u32 > size as u32 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        let bytes = count.div_ceil(8) as usize;
+        response[1] = bytes as u8;
+        response[2..2 + bytes].fill(0);
+        for i in 0..count {
+            if bit(address + i) {
+                response[2 + i as usize / 8] |= 1 << (i % 8);
+            }
+        }
+        Ok(2 + bytes)
+    }
+
+    /// Value of a holding register, `None` if it does not exist or cannot
+    /// be read
+    fn register(&self, register: u16, outputs: &[u8; BANKS]) -> Option<u16> {
+        let sequences = self.configs.len() as u16;
+        Some(match register {
+            0 => outputs.first().copied().unwrap_or(0) as u16,
+            1 => self.inputs.snapshot().levels as u16,
+            2 => self.runner.running().iter().flatten().count() as u16,
+            3 => self.runner.queued_count() as u16,
+            4 => sequences,
+            ALL_OFF_REGISTER => 0,
+            r if (TRIGGER_REGISTERS..TRIGGER_REGISTERS + sequences).contains(&r) => {
+                let name = self.configs[(r - TRIGGER_REGISTERS) as usize].name;
+                self.runner.running().contains(&Some(name)) as u16
+            }
+            r if (COOLDOWN_REGISTERS..COOLDOWN_REGISTERS + 2 * INPUTS).contains(&r) => {
+                let input = input((r - COOLDOWN_REGISTERS) / 2);
+                let ms = self
+                    .dispatcher
+                    .lock(|dispatcher| dispatcher.borrow().cooldown_ms(input));
+                half(ms, r - COOLDOWN_REGISTERS)
+            }
+            r if (REMAINING_REGISTERS..REMAINING_REGISTERS + 2 * INPUTS).contains(&r) => {
+                let input = input((r - REMAINING_REGISTERS) / 2);
+                let ms = self
+                    .dispatcher
+                    .lock(|dispatcher| dispatcher.borrow().remaining_ms(input));
+                half(ms.min(u32::MAX as u64) as u32, r - REMAINING_REGISTERS)
+            }
+            _ => return None,
+        })
+    }
+
+    async fn write_coils(&self, address: u16, count: u16, bits: &[u8]) -> Result<(), Exception> {
+        if address as u32 + count as u32 > self.relays.relay_count() as u32 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        // One write per bank touched
+        for bank in 0..BANKS as u16 {
+            let mut relays = 0;
+            let mut levels = 0;
+            for i in 0..count {
+                let Some(relay) = RelayId::from_index(address + i) else {
+                    continue;
+                };
+                if relay.bank as u16 != bank {
+                    continue;
+                }
+                relays |= relay.mask().bits();
+                if bits[i as usize / 8] & 1 << (i % 8) != 0 {
+                    levels |= relay.mask().bits();
+                }
+            }
+            if relays != 0 {
+                self.relays
+                    .update_bank(bank as u8, RelayMask(relays), RelayMask(levels))
+                    .await
+                    .map_err(|_| Exception::ServerDeviceFailure)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_registers(&self, address: u16, values: &[u8]) -> Result<(), Exception> {
+        let sequences = self.configs.len() as u16;
+        // Past the last register is not writable either
+        if values.len() / 2 > (u16::MAX - address) as usize + 1 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        let registers = (address..=u16::MAX).zip(values.chunks_exact(2));
+        let writable = |r: u16| {
+            r == ALL_OFF_REGISTER
+                || (TRIGGER_REGISTERS..TRIGGER_REGISTERS + sequences).contains(&r)
+                || (COOLDOWN_REGISTERS..COOLDOWN_REGISTERS + 2 * INPUTS).contains(&r)
+        };
+        if !registers.clone().all(|(r, _)| writable(r)) {
+            return Err(Exception::IllegalDataAddress);
+        }
+        for (register, value) in registers {
+            let value = u16::from_be_bytes([value[0], value[1]]);
+            match register {
+                ALL_OFF_REGISTER if value != 0 => self
+                    .runner
+                    .abort(self.relays)
+                    .await
+                    .map_err(|_| Exception::ServerDeviceFailure)?,
+                ALL_OFF_REGISTER => {}
+                r if r < COOLDOWN_REGISTERS => {
+                    if value == 0 {
+                        continue;
+                    }
+                    let config = &self.configs[(r - TRIGGER_REGISTERS) as usize];
+                    match self.runner.start(config) {
+                        StartOutcome::Rejected => return Err(Exception::ServerDeviceBusy),
+                        outcome => {
+                            defmt::info!("Sequence '{}' {:?} over Modbus", config.name, outcome)
+                        }
+                    }
+                }
+                r => {
+                    let offset = r - COOLDOWN_REGISTERS;
+                    let input = input(offset / 2);
+                    self.dispatcher.lock(|dispatcher| {
+                        let mut dispatcher = dispatcher.borrow_mut();
+                        let ms = dispatcher.cooldown_ms(input);
+                        let ms = if offset % 2 == 0 {
+                            ms & 0xFFFF | (value as u32) << 16
+                        } else {
+                            ms & 0xFFFF_0000 | value as u32
+                        };
+                        dispatcher.set_cooldown_ms(input, ms);
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn input(index: u16) -> DigitalInput {
+    const INPUTS: [DigitalInput; 8] = [
+        DigitalInput::DI1,
+        DigitalInput::DI2,
+        DigitalInput::DI3,
+        DigitalInput::DI4,
+        DigitalInput::DI5,
+        DigitalInput::DI6,
+        DigitalInput::DI7,
+        DigitalInput::DI8,
+    ];
+    INPUTS[index as usize]
+}
+
+/// High word of `value` for an even register offset, low word for odd
+fn half(value: u32, offset: u16) -> u16 {
+    if offset % 2 == 0 {
+        (value >> 16) as u16
+    } else {
+        value as u16
+    }
+}
+
+/// Serve Modbus TCP on [`TCP_PORT`], one connection at a time
+///
+/// Any unit id is answered; it is echoed in the response.
+#[cfg(feature = "esp32s3")]
+pub async fn serve<I2C, const BANKS: usize, const SLOTS: usize>(
+    stack: embassy_net::Stack<'_>,
+    server: &ModbusServer<'_, I2C, BANKS, SLOTS>,
+) -> !
+where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_net::tcp::TcpSocket;
+    use embassy_time::Duration;
+    use embedded_io_async::Write as _;
+
+    /// Idle time before a connection is dropped
+    const IDLE_TIMEOUT_S: u64 = 60;
+
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 512];
+    let mut frame = [0; MBAP_LEN + MAX_PDU];
+    let mut response = [0; MAX_PDU];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(IDLE_TIMEOUT_S)));
+        if let Err(e) = socket.accept(TCP_PORT).await {
+            defmt::warn!("Modbus accept failed: {:?}", e);
+            continue;
+        }
+        defmt::info!("Modbus client connected: {:?}", socket.remote_endpoint());
+
+        let mut len = 0;
+        'connection: loop {
+            match socket.read(&mut frame[len..]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => len += n,
+            }
+            // Several requests may arrive in one segment
+            loop {
+                let request = match parse_tcp(&frame[..len]) {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(e) => {
+                        defmt::warn!("Modbus TCP frame rejected: {}, closing", e);
+                        break 'connection;
+                    }
+                };
+                let pdu_len = server.handle(request.pdu, &mut response).await;
+                let used = request.len;
+                let mut header = [0; MBAP_LEN];
+                write_mbap(request.mbap, pdu_len, &mut header);
+                let sent = async {
+                    socket.write_all(&header).await?;
+                    socket.write_all(&response[..pdu_len]).await?;
+                    socket.flush().await
+                };
+                if sent.await.is_err() {
+                    break 'connection;
+                }
+                frame.copy_within(used..len, 0);
+                len -= used;
+            }
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
+
+/// Serve Modbus RTU as slave `address` on a half-duplex serial line
+///
+/// `uart` must hand over every byte as it arrives, so the silence between
+/// characters can be timed. Broadcast requests are carried out without an
+/// answer.
+#[cfg(feature = "esp32s3")]
+pub async fn serve_rtu<U, I2C, const BANKS: usize, const SLOTS: usize>(
+    uart: &mut U,
+    server: &ModbusServer<'_, I2C, BANKS, SLOTS>,
+    address: u8,
+    timing: RtuTiming,
+) -> !
+where
+    U: embedded_io_async::Read + embedded_io_async::Write,
+    I2C: I2c + BusRecovery,
+{
+    use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+
+    let mut receiver = RtuReceiver::new(timing);
+    let mut chunk = [0; 32];
+    let mut response = [0; MAX_PDU];
+    let mut frame = [0; MAX_RTU_FRAME];
+
+    loop {
+        let read = uart.read(&mut chunk);
+        let result = match receiver.deadline_us() {
+            Some(deadline) => with_deadline(Instant::from_micros(deadline), read)
+                .await
+                .ok(),
+            None => Some(read.await),
+        };
+        let now_us = Instant::now().as_micros();
+        let request = match result {
+            Some(Ok(n)) => {
+                receiver.receive(&chunk[..n], now_us);
+                continue;
+            }
+            Some(Err(_)) => {
+                receiver.fault(now_us);
+                continue;
+            }
+            None => match receiver.take(now_us).map(parse_rtu) {
+                Some(Ok(request)) => request,
+                Some(Err(e)) => {
+                    defmt::warn!("Modbus RTU frame dropped: {}", e);
+                    continue;
+                }
+                None => continue,
+            },
+        };
+        if request.address != address && request.address != BROADCAST_ADDRESS {
+            continue;
+        }
+        let pdu_len = server.handle(request.pdu, &mut response).await;
+        if request.address == BROADCAST_ADDRESS {
+            continue;
+        }
+        let len = write_rtu(address, &response[..pdu_len], &mut frame);
+        if uart.write_all(&frame[..len]).await.is_err() || uart.flush().await.is_err() {
+            defmt::warn!("Modbus RTU response failed");
+        }
+        // Anything heard while transmitting is our own echo
+        let silence = Duration::from_micros(timing.t35_us as u64);
+        while let Ok(Ok(_)) = with_timeout(silence, uart.read(&mut chunk)).await {}
+    }
+}
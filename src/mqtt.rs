@@ -0,0 +1,1057 @@
+//! MQTT 3.1.1 client for home automation and monitoring
+//!
+//! Topics live under `prop/{client_id}`:
+//!
+//! | Topic                  | Direction     | Payload                                      |
+//! | ---------------------- | ------------- | -------------------------------------------- |
+//! | `status`               | out, retained | `online`; `offline` as the last will         |
+//! | `input/{n}`            | out           | `{"input":"DI3","timestamp_ms":...}`         |
+//! | `relay/{n}`            | out, retained | `ON` / `OFF`                                 |
+//! | `relay/{n}/set`        | in            | `ON` / `OFF`                                 |
+//! | `sequence/{slug}`      | out           | `started`, `finished`, `cancelled`, `failed` |
+//! | `sequence/{slug}/fire` | in            | anything; ignores the cooldown               |
+//! | `all-off/set`          | in            | anything                                     |
+//!
+//! Relays are numbered from 1 across all banks, like `R1` in show files;
+//! `{slug}` is the sequence name in lower case with everything but letters
+//! and digits replaced by `_`. Everything is published with QoS 1 and
+//! sent again after a reconnect until the broker acknowledges it. Home
+//! Assistant discovery configs are published, retained, on every connect.
+
+use core::fmt::{self, Write as _};
+
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::i2c::I2c;
+use embedded_io_async::{Read, Write};
+
+use crate::api::JsonStr;
+use crate::bus::{BusError, BusRecovery};
+use crate::hardware::{RelayId, RelayState};
+use crate::input::{InputEvent, InputEventChannel};
+use crate::relay::RelayController;
+use crate::runner::{SequenceEvent, SequenceEventKind, SequenceRunner, StartOutcome};
+use crate::sequence::SequenceConfig;
+
+/// Largest packet sent or received; bigger incoming ones are skipped
+pub const MAX_PACKET: usize = 1024;
+pub const KEEP_ALIVE_S: u16 = 30;
+/// Time the broker has to answer CONNECT
+pub const CONNECT_TIMEOUT_MS: u64 = 10_000;
+/// QoS 1 messages sent but not yet acknowledged
+pub const MAX_INFLIGHT: usize = 16;
+/// Prefix of Home Assistant discovery topics
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Longest topic the client builds
+const MAX_TOPIC: usize = 96;
+/// Longest payload the client builds
+const MAX_PAYLOAD: usize = 640;
+
+/// Connection failure; the client reconnects after any of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MqttError {
+    /// Broker sent something that is not MQTT 3.1.1
+    Malformed,
+    /// Packet does not fit the buffer
+    TooLarge,
+    /// CONNACK return code other than 0
+    Refused(u8),
+    /// No CONNACK or PINGRESP in time
+    Timeout,
+    /// Broker closed the connection
+    Closed,
+    /// Transport failure
+    Io,
+}
+
+/// Delivery guarantee of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+/// PUBLISH packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+    /// Set when sending a message again
+    pub dup: bool,
+    /// Present for QoS 1
+    pub packet_id: Option<u16>,
+}
+
+/// Packet received from the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish<'a>),
+    PubAck(u16),
+    SubAck { packet_id: u16, failed: bool },
+    PingResp,
+}
+
+/// Message the broker publishes when the client drops off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+/// CONNECT packet; sessions are always clean
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive_s: u16,
+    /// Sent with QoS 1
+    pub will: Option<Will<'a>>,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+pub const PINGREQ: [u8; 2] = [0xC0, 0x00];
+pub const DISCONNECT: [u8; 2] = [0xE0, 0x00];
+
+/// Fixed header: first byte, header length and remaining length; `None`
+/// until the remaining length is complete
+pub fn fixed_header(buf: &[u8]) -> Result<Option<(u8, usize, usize)>, MqttError> {
+    let Some(&first) = buf.first() else {
+        return Ok(None);
+    };
+    let mut remaining = 0;
+    for i in 0..4 {
+        let Some(&byte) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+        remaining |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((first, 2 + i, remaining)));
+        }
+    }
+    Err(MqttError::Malformed)
+}
+
+/// Decode the packet at the start of `buf` and its length; `None` until
+/// it is complete
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>, MqttError> {
+    let Some((first, header_len, remaining)) = fixed_header(buf)? else {
+        return Ok(None);
+    };
+    let total = header_len + remaining;
+    let Some(body) = buf.get(header_len..total) else {
+        return Ok(None);
+    };
+    let id = |body: &[u8]| match body {
+        [high, low, ..] => Ok(u16::from_be_bytes([*high, *low])),
+        _ => Err(MqttError::Malformed),
+    };
+    let packet = match (first >> 4, first & 0x0F, body) {
+        (2, 0, [flags, code]) => Packet::ConnAck {
+            session_present: flags & 1 != 0,
+            code: *code,
+        },
+        (3, flags, _) => {
+            let qos = match (flags >> 1) & 3 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => return Err(MqttError::Malformed),
+            };
+            let topic_len = id(body)? as usize;
+            let topic = body
+                .get(2..2 + topic_len)
+                .and_then(|topic| core::str::from_utf8(topic).ok())
+                .ok_or(MqttError::Malformed)?;
+            let mut rest = &body[2 + topic_len..];
+            let packet_id = match qos {
+                QoS::AtMostOnce => None,
+                QoS::AtLeastOnce => {
+                    let packet_id = id(rest)?;
+                    rest = &rest[2..];
+                    Some(packet_id)
+                }
+            };
+            Packet::Publish(Publish {
+                topic,
+                payload: rest,
+                qos,
+                retain: flags & 1 != 0,
+                dup: flags & 8 != 0,
+                packet_id,
+            })
+        }
+        (4, 0, [_, _]) => Packet::PubAck(id(body)?),
+        (9, 0, [_, _, codes @ ..]) if !codes.is_empty() => Packet::SubAck {
+            packet_id: id(body)?,
+            failed: codes.contains(&0x80),
+        },
+        (13, 0, []) => Packet::PingResp,
+        _ => return Err(MqttError::Malformed),
+    };
+    Ok(Some((packet, total)))
+}
+
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), MqttError> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(MqttError::TooLarge)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn put_u16(&mut self, value: u16) -> Result<(), MqttError> {
+        self.put(&value.to_be_bytes())
+    }
+
+    /// Length-prefixed string or binary data
+    fn put_data(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        let len = u16::try_from(data.len()).map_err(|_| MqttError::TooLarge)?;
+        self.put_u16(len)?;
+        self.put(data)
+    }
+}
+
+/// Write a packet with fixed header byte `first`, the body coming from
+/// `body`; returns the packet length
+fn packet(
+    buf: &mut [u8],
+    first: u8,
+    body: impl FnOnce(&mut Encoder<'_>) -> Result<(), MqttError>,
+) -> Result<usize, MqttError> {
+    const MAX_HEADER: usize = 5;
+    let mut encoder = Encoder {
+        buf: buf.get_mut(MAX_HEADER..).ok_or(MqttError::TooLarge)?,
+        len: 0,
+    };
+    body(&mut encoder)?;
+    let len = encoder.len;
+
+    let mut header = [first, 0, 0, 0, 0];
+    let mut header_len = 1;
+    let mut remaining = len;
+    loop {
+        header[header_len] = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            header[header_len] |= 0x80;
+        }
+        header_len += 1;
+        if remaining == 0 {
+            break;
+        }
+    }
+    buf.copy_within(MAX_HEADER..MAX_HEADER + len, header_len);
+    buf[..header_len].copy_from_slice(&header[..header_len]);
+    Ok(header_len + len)
+}
+
+pub fn encode_connect(buf: &mut [u8], connect: &Connect<'_>) -> Result<usize, MqttError> {
+    packet(buf, 0x10, |out| {
+        let mut flags = 0x02; // clean session
+        if let Some(will) = &connect.will {
+            flags |= 0x04 | (QoS::AtLeastOnce as u8) << 3;
+            if will.retain {
+                flags |= 0x20;
+            }
+        }
+        if connect.username.is_some() {
+            flags |= 0x80;
+        }
+        if connect.password.is_some() {
+            flags |= 0x40;
+        }
+        out.put_data(b"MQTT")?;
+        out.put(&[4, flags])?;
+        out.put_u16(connect.keep_alive_s)?;
+        out.put_data(connect.client_id.as_bytes())?;
+        if let Some(will) = &connect.will {
+            out.put_data(will.topic.as_bytes())?;
+            out.put_data(will.payload)?;
+        }
+        if let Some(username) = connect.username {
+            out.put_data(username.as_bytes())?;
+        }
+        if let Some(password) = connect.password {
+            out.put_data(password.as_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+pub fn encode_publish(buf: &mut [u8], publish: &Publish<'_>) -> Result<usize, MqttError> {
+    let flags = (publish.dup as u8) << 3 | (publish.qos as u8) << 1 | publish.retain as u8;
+    packet(buf, 0x30 | flags, |out| {
+        out.put_data(publish.topic.as_bytes())?;
+        if let Some(packet_id) = publish.packet_id {
+            out.put_u16(packet_id)?;
+        }
+        out.put(publish.payload)
+    })
+}
+
+/// SUBSCRIBE to `filters`, all with QoS 1
+pub fn encode_subscribe(
+    buf: &mut [u8],
+    packet_id: u16,
+    filters: &[&str],
+) -> Result<usize, MqttError> {
+    packet(buf, 0x82, |out| {
+        out.put_u16(packet_id)?;
+        for filter in filters {
+            out.put_data(filter.as_bytes())?;
+            out.put(&[QoS::AtLeastOnce as u8])?;
+        }
+        Ok(())
+    })
+}
+
+pub fn encode_puback(buf: &mut [u8], packet_id: u16) -> Result<usize, MqttError> {
+    packet(buf, 0x40, |out| out.put_u16(packet_id))
+}
+
+/// Sequence name as used in topics and ids
+pub struct Slug<'a>(pub &'a str);
+
+impl Slug<'_> {
+    fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.0.chars().map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+    }
+
+    pub fn matches(&self, slug: &str) -> bool {
+        self.chars().eq(slug.chars())
+    }
+}
+
+impl fmt::Display for Slug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|c| f.write_char(c))
+    }
+}
+
+/// Command received on a subscribed topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttCommand<'a> {
+    SetRelay(RelayId, RelayState),
+    /// Start the sequence with this slug
+    Fire(&'a str),
+    AllOff,
+}
+
+/// Why a command was not carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CommandError {
+    UnknownTopic,
+    /// Relay payload other than `ON`/`OFF`
+    BadPayload,
+    NoSuchRelay,
+    NoSuchSequence,
+    /// Relays needed by the sequence are in use
+    Busy,
+    /// Relay bus failure
+    Bus,
+}
+
+impl<'a> MqttCommand<'a> {
+    /// Command for a message on `topic` under the `base` topic
+    pub fn parse(base: &str, topic: &'a str, payload: &[u8]) -> Result<Self, CommandError> {
+        let rest = topic
+            .strip_prefix(base)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or(CommandError::UnknownTopic)?;
+        let mut parts = rest.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("relay"), Some(number), Some("set"), None) => {
+                let relay = match number.parse::<u16>() {
+                    Ok(number @ 1..) => RelayId::from_index(number - 1),
+                    _ => None,
+                }
+                .ok_or(CommandError::NoSuchRelay)?;
+                let state = match payload.trim_ascii() {
+                    b"ON" | b"on" | b"1" | b"true" => RelayState::High,
+                    b"OFF" | b"off" | b"0" | b"false" => RelayState::Low,
+                    _ => return Err(CommandError::BadPayload),
+                };
+                Ok(Self::SetRelay(relay, state))
+            }
+            (Some("sequence"), Some(slug), Some("fire"), None) if !slug.is_empty() => {
+                Ok(Self::Fire(slug))
+            }
+            (Some("all-off"), Some("set"), None, None) => Ok(Self::AllOff),
+            _ => Err(CommandError::UnknownTopic),
+        }
+    }
+}
+
+/// Connection options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MqttConfig<'a> {
+    /// Client id; also names the device in topics and in Home Assistant,
+    /// so keep it to letters, digits, `-` and `_`
+    pub client_id: &'a str,
+    pub keep_alive_s: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+impl<'a> MqttConfig<'a> {
+    pub const fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            keep_alive_s: KEEP_ALIVE_S,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub const fn with_keep_alive(mut self, keep_alive_s: u16) -> Self {
+        self.keep_alive_s = keep_alive_s;
+        self
+    }
+
+    pub const fn with_credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.username = Some(username);
+        self.password = Some(password);
+        self
+    }
+}
+
+/// Message published with QoS 1, kept until acknowledged
+#[derive(Debug, Clone, Copy)]
+enum Outgoing {
+    Online,
+    Input(InputEvent),
+    Relay(RelayId, bool),
+    Sequence(SequenceEvent),
+}
+
+/// Home Assistant entity announced by discovery
+#[derive(Clone, Copy)]
+enum Entity<'c> {
+    Relay(RelayId),
+    SequenceButton(&'c SequenceConfig),
+    SequenceSensor(&'c SequenceConfig),
+    Input(u8),
+    AllOff,
+}
+
+/// What woke the session loop
+enum Wake<const BANKS: usize> {
+    Read(Result<usize, ()>),
+    Input(InputEvent),
+    Relays([u8; BANKS]),
+    Sequence(SequenceEvent),
+    Tick,
+}
+
+/// MQTT bridge to the relays and the sequence runner
+///
+/// [`Self::run`] drives one broker connection; call it again to
+/// reconnect. Unacknowledged messages carry over to the next connection.
+pub struct MqttClient<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    config: MqttConfig<'a>,
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    configs: &'a [SequenceConfig],
+    /// `prop/{client_id}`
+    base: heapless::String<48>,
+    next_packet_id: u16,
+    inflight: heapless::Vec<(u16, Outgoing), MAX_INFLIGHT>,
+    established: bool,
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> MqttClient<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub fn new(
+        config: MqttConfig<'a>,
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        configs: &'a [SequenceConfig],
+    ) -> Self {
+        let mut base = heapless::String::new();
+        if write!(base, "prop/{}", config.client_id).is_err() {
+            defmt::warn!("MQTT client id too long for topics");
+        }
+        Self {
+            config,
+            relays,
+            runner,
+            configs,
+            base,
+            next_packet_id: 1,
+            inflight: heapless::Vec::new(),
+            established: false,
+        }
+    }
+
+    /// Root of this controller's topics
+    pub fn base_topic(&self) -> &str {
+        &self.base
+    }
+
+    /// Whether the last [`Self::run`] got past CONNECT
+    pub const fn established(&self) -> bool {
+        self.established
+    }
+
+    /// Messages waiting for an acknowledgement
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Talk to the broker over `io` until the connection fails
+    ///
+    /// `inputs` carries the input events to publish.
+    pub async fn run<T: Read + Write>(
+        &mut self,
+        io: &mut T,
+        inputs: &InputEventChannel,
+    ) -> MqttError {
+        self.established = false;
+        match self.session(io, inputs).await {
+            Ok(never) => match never {},
+            Err(error) => error,
+        }
+    }
+
+    async fn session<T: Read + Write>(
+        &mut self,
+        io: &mut T,
+        inputs: &InputEventChannel,
+    ) -> Result<core::convert::Infallible, MqttError> {
+        let mut rx = [0; MAX_PACKET];
+        let mut rx_len = 0;
+        let mut skip = 0;
+        let mut tx = [0; MAX_PACKET];
+
+        let mut status = heapless::String::<MAX_TOPIC>::new();
+        write!(status, "{}/status", self.base).map_err(|_| MqttError::TooLarge)?;
+        let connect = Connect {
+            client_id: self.config.client_id,
+            keep_alive_s: self.config.keep_alive_s,
+            will: Some(Will {
+                topic: &status,
+                payload: b"offline",
+                retain: true,
+            }),
+            username: self.config.username,
+            password: self.config.password,
+        };
+        let len = encode_connect(&mut tx, &connect)?;
+        send(io, &tx[..len]).await?;
+
+        // Wait for CONNACK
+        let deadline = Instant::now() + Duration::from_millis(CONNECT_TIMEOUT_MS);
+        loop {
+            if let Some((packet, used)) = decode(&rx[..rx_len])? {
+                match packet {
+                    Packet::ConnAck { code: 0, .. } => {}
+                    Packet::ConnAck { code, .. } => return Err(MqttError::Refused(code)),
+                    _ => return Err(MqttError::Malformed),
+                }
+                rx.copy_within(used..rx_len, 0);
+                rx_len -= used;
+                break;
+            }
+            let read = io.read(&mut rx[rx_len..]);
+            match select(read, Timer::at(deadline)).await {
+                Either::First(Ok(0)) => return Err(MqttError::Closed),
+                Either::First(Ok(n)) => rx_len += n,
+                Either::First(Err(_)) => return Err(MqttError::Io),
+                Either::Second(()) => return Err(MqttError::Timeout),
+            }
+        }
+        self.established = true;
+        defmt::info!("MQTT connected as {}", self.config.client_id);
+
+        let mut filters: [heapless::String<MAX_TOPIC>; 3] = Default::default();
+        for (filter, suffix) in
+            filters
+                .iter_mut()
+                .zip(["relay/+/set", "sequence/+/fire", "all-off/set"])
+        {
+            write!(filter, "{}/{}", self.base, suffix).map_err(|_| MqttError::TooLarge)?;
+        }
+        let filters = filters.each_ref().map(|filter| filter.as_str());
+        let packet_id = self.packet_id();
+        let len = encode_subscribe(&mut tx, packet_id, &filters)?;
+        send(io, &tx[..len]).await?;
+
+        self.announce(io, &mut tx).await?;
+
+        // Relay states are published afresh below; resend the rest
+        self.inflight
+            .retain(|(_, outgoing)| !matches!(outgoing, Outgoing::Relay(..)));
+        for i in 0..self.inflight.len() {
+            let (packet_id, outgoing) = self.inflight[i];
+            self.publish(io, &mut tx, outgoing, packet_id, true).await?;
+        }
+        self.track(io, &mut tx, Outgoing::Online).await?;
+
+        let mut relay_changes = self.relays.watch();
+        let mut sequence_events = self.runner.subscribe();
+        if relay_changes.is_none() || sequence_events.is_none() {
+            defmt::warn!("MQTT cannot follow relays or sequences, no watcher free");
+        }
+        let mut current = [0; BANKS];
+        for (bank, outputs) in current.iter_mut().enumerate() {
+            *outputs = self.relays.bank_state(bank as u8).await.unwrap_or(0);
+        }
+        let mut published: [Option<u8>; BANKS] = [None; BANKS];
+
+        let keep_alive = Duration::from_secs(self.config.keep_alive_s as u64);
+        let mut last_sent = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+
+        loop {
+            // Relay states not yet published, while there is room
+            while !self.inflight.is_full() {
+                let Some((relay, on)) = next_relay_change(&current, &mut published) else {
+                    break;
+                };
+                self.track(io, &mut tx, Outgoing::Relay(relay, on)).await?;
+                last_sent = Instant::now();
+            }
+
+            let tick_at = match ping_sent {
+                _ if keep_alive == Duration::from_secs(0) => Instant::MAX,
+                Some(sent) => sent + keep_alive,
+                None => last_sent + keep_alive / 2,
+            };
+            let room = !self.inflight.is_full();
+            if skip == 0 && rx_len == rx.len() {
+                return Err(MqttError::TooLarge);
+            }
+            let wake = {
+                let read = async { Wake::Read(io.read(&mut rx[rx_len..]).await.map_err(|_| ())) };
+                let input = async {
+                    if !room {
+                        core::future::pending::<()>().await;
+                    }
+                    Wake::Input(inputs.receive().await)
+                };
+                let relays = async {
+                    match relay_changes.as_mut() {
+                        Some(changes) => Wake::Relays(changes.changed().await),
+                        None => core::future::pending().await,
+                    }
+                };
+                let sequence = async {
+                    match sequence_events.as_mut() {
+                        Some(events) if room => Wake::Sequence(events.next_message_pure().await),
+                        _ => core::future::pending().await,
+                    }
+                };
+                let tick = async {
+                    Timer::at(tick_at).await;
+                    Wake::Tick
+                };
+                match select(select3(read, input, relays), select(sequence, tick)).await {
+                    Either::First(Either3::First(wake))
+                    | Either::First(Either3::Second(wake))
+                    | Either::First(Either3::Third(wake))
+                    | Either::Second(Either::First(wake))
+                    | Either::Second(Either::Second(wake)) => wake,
+                }
+            };
+
+            match wake {
+                Wake::Read(Ok(0)) => return Err(MqttError::Closed),
+                Wake::Read(Ok(n)) => rx_len += n,
+                Wake::Read(Err(())) => return Err(MqttError::Io),
+                Wake::Input(event) => {
+                    self.track(io, &mut tx, Outgoing::Input(event)).await?;
+                    last_sent = Instant::now();
+                }
+                Wake::Relays(outputs) => current = outputs,
+                Wake::Sequence(event) => {
+                    self.track(io, &mut tx, Outgoing::Sequence(event)).await?;
+                    last_sent = Instant::now();
+                }
+                Wake::Tick => {
+                    if ping_sent.is_some() {
+                        return Err(MqttError::Timeout);
+                    }
+                    send(io, &PINGREQ).await?;
+                    ping_sent = Some(Instant::now());
+                    last_sent = Instant::now();
+                }
+            }
+
+            // Handle every complete packet received
+            loop {
+                if skip > 0 {
+                    let dropped = skip.min(rx_len);
+                    rx.copy_within(dropped..rx_len, 0);
+                    rx_len -= dropped;
+                    skip -= dropped;
+                }
+                if let Some((_, header_len, remaining)) = fixed_header(&rx[..rx_len])? {
+                    if header_len + remaining > rx.len() {
+                        defmt::warn!("MQTT packet of {} bytes skipped", header_len + remaining);
+                        skip = header_len + remaining;
+                        continue;
+                    }
+                }
+                let Some((packet, used)) = decode(&rx[..rx_len])? else {
+                    break;
+                };
+                match packet {
+                    Packet::PubAck(packet_id) => {
+                        self.inflight.retain(|(id, _)| *id != packet_id);
+                    }
+                    Packet::SubAck { failed: true, .. } => {
+                        defmt::warn!("MQTT broker refused a subscription");
+                    }
+                    Packet::SubAck { .. } => {}
+                    Packet::PingResp => ping_sent = None,
+                    Packet::Publish(publish) => {
+                        if let Some(packet_id) = publish.packet_id {
+                            let len = encode_puback(&mut tx, packet_id)?;
+                            send(io, &tx[..len]).await?;
+                        }
+                        if let Err(error) = self.command(publish.topic, publish.payload).await {
+                            defmt::info!("MQTT command on {} ignored: {}", publish.topic, error);
+                        }
+                    }
+                    Packet::ConnAck { .. } => return Err(MqttError::Malformed),
+                }
+                rx.copy_within(used..rx_len, 0);
+                rx_len -= used;
+            }
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        // Packet id 0 is not allowed
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    /// Publish with QoS 1 and remember it until acknowledged
+    ///
+    /// The caller makes sure there is room in the in-flight list.
+    async fn track<T: Write>(
+        &mut self,
+        io: &mut T,
+        tx: &mut [u8],
+        outgoing: Outgoing,
+    ) -> Result<(), MqttError> {
+        let packet_id = self.packet_id();
+        if self.inflight.push((packet_id, outgoing)).is_err() {
+            defmt::warn!("MQTT in-flight list full, message not tracked");
+        }
+        self.publish(io, tx, outgoing, packet_id, false).await
+    }
+
+    async fn publish<T: Write>(
+        &self,
+        io: &mut T,
+        tx: &mut [u8],
+        outgoing: Outgoing,
+        packet_id: u16,
+        dup: bool,
+    ) -> Result<(), MqttError> {
+        let mut topic = heapless::String::<MAX_TOPIC>::new();
+        let mut payload = heapless::String::<MAX_PAYLOAD>::new();
+        let base = &self.base;
+        let retain = match outgoing {
+            Outgoing::Online => {
+                write!(topic, "{base}/status")?;
+                payload.push_str("online")?;
+                true
+            }
+            Outgoing::Input(event) => {
+                write!(topic, "{base}/input/{}", event.input as u8 + 1)?;
+                write!(
+                    payload,
+                    "{{\"input\":\"DI{}\",\"timestamp_ms\":{}}}",
+                    event.input as u8 + 1,
+                    event.timestamp_ms
+                )?;
+                false
+            }
+            Outgoing::Relay(relay, on) => {
+                write!(topic, "{base}/relay/{}", relay.index() + 1)?;
+                payload.push_str(if on { "ON" } else { "OFF" })?;
+                true
+            }
+            Outgoing::Sequence(event) => {
+                write!(topic, "{base}/sequence/{}", Slug(event.name))?;
+                payload.push_str(match event.kind {
+                    SequenceEventKind::Started => "started",
+                    SequenceEventKind::Finished => "finished",
+                    SequenceEventKind::Cancelled => "cancelled",
+                    SequenceEventKind::Failed => "failed",
+                })?;
+                false
+            }
+        };
+        let len = encode_publish(
+            tx,
+            &Publish {
+                topic: &topic,
+                payload: payload.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain,
+                dup,
+                packet_id: Some(packet_id),
+            },
+        )?;
+        send(io, &tx[..len]).await
+    }
+
+    /// Publish Home Assistant discovery configs, retained
+    async fn announce<T: Write>(&self, io: &mut T, tx: &mut [u8]) -> Result<(), MqttError> {
+        let relays = (0..self.relays.relay_count() as u16)
+            .filter_map(RelayId::from_index)
+            .map(Entity::Relay);
+        let sequences = self.configs.iter().flat_map(|config| {
+            [
+                Entity::SequenceButton(config),
+                Entity::SequenceSensor(config),
+            ]
+        });
+        let inputs = (0..8).map(Entity::Input);
+        let entities = relays
+            .chain(sequences)
+            .chain(inputs)
+            .chain([Entity::AllOff]);
+
+        let mut topic = heapless::String::<MAX_TOPIC>::new();
+        let mut payload = heapless::String::<MAX_PAYLOAD>::new();
+        for entity in entities {
+            topic.clear();
+            payload.clear();
+            self.discovery(entity, &mut topic, &mut payload)?;
+            let len = encode_publish(
+                tx,
+                &Publish {
+                    topic: &topic,
+                    payload: payload.as_bytes(),
+                    qos: QoS::AtMostOnce,
+                    retain: true,
+                    dup: false,
+                    packet_id: None,
+                },
+            )?;
+            send(io, &tx[..len]).await?;
+        }
+        Ok(())
+    }
+
+    fn discovery(
+        &self,
+        entity: Entity<'_>,
+        topic: &mut impl fmt::Write,
+        payload: &mut impl fmt::Write,
+    ) -> fmt::Result {
+        let id = self.config.client_id;
+        let base = &self.base;
+        match entity {
+            Entity::Relay(relay) => {
+                let n = relay.index() + 1;
+                write!(topic, "{DISCOVERY_PREFIX}/switch/{id}/relay_{n}/config")?;
+                write!(
+                    payload,
+                    "{{\"name\":\"Relay {n}\",\"unique_id\":\"{id}_relay_{n}\",\
+                     \"state_topic\":\"{base}/relay/{n}\",\
+                     \"command_topic\":\"{base}/relay/{n}/set\",\"qos\":1,"
+                )?;
+            }
+            Entity::SequenceButton(config) => {
+                let slug = Slug(config.name);
+                write!(
+                    topic,
+                    "{DISCOVERY_PREFIX}/button/{id}/sequence_{slug}/config"
+                )?;
+                write!(
+                    payload,
+                    "{{\"name\":{},\"unique_id\":\"{id}_sequence_{slug}\",\
+                     \"command_topic\":\"{base}/sequence/{slug}/fire\",\"qos\":1,",
+                    JsonStr(config.name)
+                )?;
+            }
+            Entity::SequenceSensor(config) => {
+                let slug = Slug(config.name);
+                write!(
+                    topic,
+                    "{DISCOVERY_PREFIX}/sensor/{id}/sequence_{slug}/config"
+                )?;
+                write!(
+                    payload,
+                    "{{\"name\":{},\"unique_id\":\"{id}_sequence_{slug}_state\",\
+                     \"state_topic\":\"{base}/sequence/{slug}\",\"qos\":1,",
+                    JsonStr(config.name)
+                )?;
+            }
+            Entity::Input(input) => {
+                let n = input + 1;
+                write!(
+                    topic,
+                    "{DISCOVERY_PREFIX}/device_automation/{id}/input_{n}/config"
+                )?;
+                write!(
+                    payload,
+                    "{{\"automation_type\":\"trigger\",\"topic\":\"{base}/input/{n}\",\
+                     \"type\":\"button_short_press\",\"subtype\":\"button_{n}\",\"qos\":1,"
+                )?;
+            }
+            Entity::AllOff => {
+                write!(topic, "{DISCOVERY_PREFIX}/button/{id}/all_off/config")?;
+                write!(
+                    payload,
+                    "{{\"name\":\"All off\",\"unique_id\":\"{id}_all_off\",\
+                     \"command_topic\":\"{base}/all-off/set\",\"qos\":1,"
+                )?;
+            }
+        }
+        write!(
+            payload,
+            "\"availability_topic\":\"{base}/status\",\
+             \"device\":{{\"identifiers\":[\"{id}\"],\"name\":\"Prop controller {id}\",\
+             \"model\":\"ESP32-S3-ETH-8DI-8RO\"}}}}"
+        )
+    }
+
+    async fn command(&self, topic: &str, payload: &[u8]) -> Result<(), CommandError> {
+        match MqttCommand::parse(&self.base, topic, payload)? {
+            MqttCommand::SetRelay(relay, state) => self
+                .relays
+                .set_relay(relay, state)
+                .await
+                .map_err(|error| match error {
+                    BusError::NoSuchBank => CommandError::NoSuchRelay,
+                    _ => CommandError::Bus,
+                }),
+            MqttCommand::Fire(slug) => {
+                let config = self
+                    .configs
+                    .iter()
+                    .find(|config| Slug(config.name).matches(slug))
+                    .ok_or(CommandError::NoSuchSequence)?;
+                match self.runner.start(config) {
+                    StartOutcome::Rejected => Err(CommandError::Busy),
+                    outcome => {
+                        defmt::info!("Sequence '{}' {:?} over MQTT", config.name, outcome);
+                        Ok(())
+                    }
+                }
+            }
+            MqttCommand::AllOff => self
+                .runner
+                .abort(self.relays)
+                .await
+                .map_err(|_| CommandError::Bus),
+        }
+    }
+}
+
+impl From<fmt::Error> for MqttError {
+    fn from(_: fmt::Error) -> Self {
+        MqttError::TooLarge
+    }
+}
+
+impl From<()> for MqttError {
+    fn from(_: ()) -> Self {
+        MqttError::TooLarge
+    }
+}
+
+async fn send<T: Write>(io: &mut T, bytes: &[u8]) -> Result<(), MqttError> {
+    io.write_all(bytes).await.map_err(|_| MqttError::Io)?;
+    io.flush().await.map_err(|_| MqttError::Io)
+}
+
+/// First relay whose state differs from what was last published; marks
+/// it published
+fn next_relay_change<const BANKS: usize>(
+    current: &[u8; BANKS],
+    published: &mut [Option<u8>; BANKS],
+) -> Option<(RelayId, bool)> {
+    for (bank, (&outputs, published)) in current.iter().zip(published.iter_mut()).enumerate() {
+        let differs = match *published {
+            Some(previous) => previous ^ outputs,
+            None => 0xFF,
+        };
+        if differs != 0 {
+            let channel = differs.trailing_zeros() as u8;
+            let bit = 1 << channel;
+            let previous = published.unwrap_or(!outputs);
+            *published = Some(previous & !bit | outputs & bit);
+            return RelayId::new(bank as u8, channel).map(|relay| (relay, outputs & bit != 0));
+        }
+    }
+    None
+}
+
+/// Connect to the broker in `settings` and keep reconnecting, with
+/// backoff, whenever the connection drops
+#[cfg(feature = "esp32s3")]
+pub async fn serve<I2C, const BANKS: usize, const SLOTS: usize>(
+    stack: embassy_net::Stack<'_>,
+    client: &mut MqttClient<'_, I2C, BANKS, SLOTS>,
+    settings: crate::storage::MqttSettings,
+    inputs: &InputEventChannel,
+) -> !
+where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_net::tcp::TcpSocket;
+    use embassy_net::{IpAddress, IpEndpoint};
+
+    const RECONNECT_MIN_MS: u64 = 1_000;
+    const RECONNECT_MAX_MS: u64 = 60_000;
+
+    if settings.broker == [0; 4] {
+        defmt::info!("MQTT disabled");
+        loop {
+            inputs.receive().await;
+        }
+    }
+    let [a, b, c, d] = settings.broker;
+    let broker = IpEndpoint::new(IpAddress::v4(a, b, c, d), settings.port);
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    let mut backoff = RECONNECT_MIN_MS;
+    loop {
+        stack.wait_config_up().await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        let keep_alive = client.config.keep_alive_s.max(1) as u64;
+        socket.set_timeout(Some(embassy_time::Duration::from_secs(2 * keep_alive)));
+        match socket.connect(broker).await {
+            Ok(()) => {
+                let error = client.run(&mut socket, inputs).await;
+                defmt::warn!("MQTT connection lost: {}", error);
+                if client.established() {
+                    backoff = RECONNECT_MIN_MS;
+                }
+            }
+            Err(e) => defmt::warn!("MQTT connect to {} failed: {:?}", settings.broker, e),
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+        Timer::after_millis(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_MS);
+    }
+}
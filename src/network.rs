@@ -0,0 +1,205 @@
+//! Link supervision: DHCP, static fallback and reconnect
+//!
+//! [`LinkSupervisor`] decides which IPv4 setup to apply as the link comes
+//! and goes; [`link_task`] applies it to the `embassy-net` stack running
+//! on the W5500 or, without Ethernet, on the Wi-Fi station.
+
+#[cfg(feature = "esp32s3")]
+use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
+
+use crate::storage::NetworkSettings;
+
+/// Time to wait for a DHCP lease before falling back to a static address
+pub const DHCP_TIMEOUT_MS: u64 = 10_000;
+/// Time on the fallback address before DHCP is tried again
+pub const DHCP_RETRY_MS: u64 = 60_000;
+/// Link and lease polling interval of [`link_task`]
+pub const LINK_POLL_MS: u64 = 250;
+
+/// Fixed IPv4 address configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct StaticIpv4 {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns: Option<[u8; 4]>,
+}
+
+impl StaticIpv4 {
+    /// Configured address from the settings, `None` if left blank
+    pub fn from_settings(settings: &NetworkSettings) -> Option<Self> {
+        let set = |ip: [u8; 4]| (ip != [0; 4]).then_some(ip);
+        Some(Self {
+            address: set(settings.address)?,
+            prefix_len: settings.prefix_len,
+            gateway: set(settings.gateway),
+            dns: set(settings.dns),
+        })
+    }
+
+    /// IPv4 link-local address (169.254.1.0-169.254.254.255) derived from
+    /// the MAC, so the controller stays reachable without a DHCP server
+    pub const fn link_local(mac: [u8; 6]) -> Self {
+        Self {
+            address: [169, 254, 1 + mac[4] % 254, mac[5]],
+            prefix_len: 16,
+            gateway: None,
+            dns: None,
+        }
+    }
+}
+
+/// IPv4 setup to apply to the network stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Ipv4Setup {
+    Dhcp,
+    Static(StaticIpv4),
+}
+
+/// Where the supervisor is in bringing the interface up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LinkState {
+    /// No cable or no link partner
+    Down,
+    /// Link up, waiting for a lease since the given time
+    Requesting { since: Instant },
+    /// DHCP lease held
+    Bound,
+    /// No lease within [`DHCP_TIMEOUT_MS`], using the fallback address
+    /// since the given time
+    Fallback { since: Instant },
+    /// DHCP disabled in the settings
+    Static,
+}
+
+/// Link-state machine for one interface
+///
+/// Call [`Self::update`] periodically with the current link and address
+/// state; whenever it returns a setup, apply it to the stack. A link that
+/// drops and comes back starts over with a fresh DHCP request, and so does
+/// a link that stays up on the fallback address for [`DHCP_RETRY_MS`], so a
+/// DHCP server that comes up late is still picked up.
+pub struct LinkSupervisor {
+    settings: NetworkSettings,
+    mac: [u8; 6],
+    state: LinkState,
+    dhcp_timeout: Duration,
+    dhcp_retry: Duration,
+}
+
+impl LinkSupervisor {
+    pub const fn new(settings: NetworkSettings, mac: [u8; 6]) -> Self {
+        Self {
+            settings,
+            mac,
+            state: LinkState::Down,
+            dhcp_timeout: Duration::from_millis(DHCP_TIMEOUT_MS),
+            dhcp_retry: Duration::from_millis(DHCP_RETRY_MS),
+        }
+    }
+
+    pub const fn with_dhcp_timeout(mut self, timeout_ms: u64) -> Self {
+        self.dhcp_timeout = Duration::from_millis(timeout_ms);
+        self
+    }
+
+    pub const fn with_dhcp_retry(mut self, retry_ms: u64) -> Self {
+        self.dhcp_retry = Duration::from_millis(retry_ms);
+        self
+    }
+
+    pub const fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Address used when DHCP is off or times out: the configured static
+    /// address, else a link-local one
+    pub fn fallback(&self) -> StaticIpv4 {
+        StaticIpv4::from_settings(&self.settings)
+            .unwrap_or_else(|| StaticIpv4::link_local(self.mac))
+    }
+
+    /// Advance on the current link state and whether the stack has an
+    /// address; returns the setup to apply, if it changed
+    pub fn update(&mut self, link_up: bool, configured: bool) -> Option<Ipv4Setup> {
+        let now = Instant::now();
+        match self.state {
+            _ if !link_up => {
+                if self.state != LinkState::Down {
+                    defmt::warn!("Network link down");
+                    self.state = LinkState::Down;
+                }
+                None
+            }
+            LinkState::Down if self.settings.dhcp => {
+                defmt::info!("Network link up, requesting DHCP lease");
+                self.state = LinkState::Requesting { since: now };
+                Some(Ipv4Setup::Dhcp)
+            }
+            LinkState::Down => {
+                let fixed = self.fallback();
+                defmt::info!("Network link up, static address {}", fixed.address);
+                self.state = LinkState::Static;
+                Some(Ipv4Setup::Static(fixed))
+            }
+            LinkState::Requesting { .. } if configured => {
+                defmt::info!("DHCP lease acquired");
+                self.state = LinkState::Bound;
+                None
+            }
+            LinkState::Requesting { since } if now.duration_since(since) >= self.dhcp_timeout => {
+                let fixed = self.fallback();
+                defmt::warn!("No DHCP lease, falling back to {}", fixed.address);
+                self.state = LinkState::Fallback { since: now };
+                Some(Ipv4Setup::Static(fixed))
+            }
+            LinkState::Fallback { since } if now.duration_since(since) >= self.dhcp_retry => {
+                defmt::info!("Retrying DHCP");
+                self.state = LinkState::Requesting { since: now };
+                Some(Ipv4Setup::Dhcp)
+            }
+            LinkState::Bound if !configured => {
+                defmt::warn!("DHCP lease lost, requesting a new one");
+                self.state = LinkState::Requesting { since: now };
+                None
+            }
+            LinkState::Requesting { .. }
+            | LinkState::Bound
+            | LinkState::Fallback { .. }
+            | LinkState::Static => None,
+        }
+    }
+}
+
+/// Supervise the link of `stack` forever, applying DHCP or static setups
+#[cfg(feature = "esp32s3")]
+pub async fn link_task(stack: embassy_net::Stack<'static>, mut supervisor: LinkSupervisor) -> ! {
+    use embassy_net::{ConfigV4, Ipv4Address, Ipv4Cidr, StaticConfigV4};
+
+    loop {
+        let before = supervisor.state();
+        match supervisor.update(stack.is_link_up(), stack.is_config_up()) {
+            Some(Ipv4Setup::Dhcp) => stack.set_config_v4(ConfigV4::Dhcp(Default::default())),
+            Some(Ipv4Setup::Static(fixed)) => {
+                let ip = |[a, b, c, d]: [u8; 4]| Ipv4Address::new(a, b, c, d);
+                let mut dns_servers = heapless::Vec::new();
+                if let Some(dns) = fixed.dns {
+                    let _ = dns_servers.push(ip(dns));
+                }
+                stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+                    address: Ipv4Cidr::new(ip(fixed.address), fixed.prefix_len),
+                    gateway: fixed.gateway.map(ip),
+                    dns_servers,
+                }));
+            }
+            None => {}
+        }
+        if supervisor.state() == LinkState::Bound && before != LinkState::Bound {
+            if let Some(config) = stack.config_v4() {
+                defmt::info!("IP address: {}", defmt::Display2Format(&config.address));
+            }
+        }
+        Timer::after_millis(LINK_POLL_MS).await;
+    }
+}
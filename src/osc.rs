@@ -0,0 +1,531 @@
+//! Open Sound Control (OSC 1.0) over UDP, for cueing from and to other
+//! show-control systems such as QLab or TouchDesigner
+//!
+//! Incoming messages, plain or in bundles (run on arrival, time tags are
+//! ignored):
+//!
+//! | Address               | Arguments      | Action                                      |
+//! | --------------------- | -------------- | ------------------------------------------- |
+//! | `/relay/{n}`          | value          | Switch relay `n` (from 1, like `R1`)        |
+//! | `/sequence/{name}/go` | optional value | Start the sequence, ignoring its cooldown   |
+//! | `/allOff`             | optional value | Stop every sequence, switch all relays off  |
+//!
+//! A value is an int (non-zero is on), a float (0.5 and up is on), `T`/`F`
+//! or the string `on`/`off`. An optional value that reads as off is
+//! ignored, so buttons sending 1 on press and 0 on release fire once.
+//!
+//! Every input trigger is sent out as `/input/{n} 1`, `n` from 1.
+
+use core::fmt::Write as _;
+use core::str;
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::bus::{BusError, BusRecovery};
+use crate::hardware::{DigitalInput, RelayId, RelayState};
+use crate::relay::RelayController;
+use crate::runner::{SequenceRunner, StartOutcome};
+use crate::sequence::SequenceConfig;
+
+/// Largest packet received or sent
+pub const MAX_PACKET: usize = 512;
+/// Deepest bundle nesting unpacked
+pub const MAX_BUNDLE_DEPTH: usize = 4;
+
+const BUNDLE: &[u8; 8] = b"#bundle\0";
+
+/// Why a packet or message was not acted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OscError {
+    /// Not OSC: bad padding or string, truncated argument or bundle
+    Malformed,
+    /// Argument type other than `i f s S b T F N I`
+    UnsupportedType(u8),
+    UnknownAddress,
+    /// Missing or unusable argument
+    BadArgument,
+    NoSuchRelay,
+    NoSuchSequence,
+    /// Relays needed by the sequence are in use
+    Busy,
+    /// Relay bus failure
+    Bus,
+    /// Message does not fit the output buffer
+    TooLarge,
+}
+
+/// Message argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OscArg<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+    Blob(&'a [u8]),
+    Bool(bool),
+    /// `N` (nil) or `I` (impulse)
+    Nil,
+}
+
+impl OscArg<'_> {
+    /// On/off reading of the argument, `None` if it has none
+    pub fn as_switch(&self) -> Option<bool> {
+        match *self {
+            Self::Int(value) => Some(value != 0),
+            Self::Float(value) => Some(value >= 0.5),
+            Self::Bool(value) => Some(value),
+            Self::Str("on" | "true" | "1") => Some(true),
+            Self::Str("off" | "false" | "0") => Some(false),
+            _ => None,
+        }
+    }
+
+    const fn tag(&self) -> u8 {
+        match self {
+            Self::Int(_) => b'i',
+            Self::Float(_) => b'f',
+            Self::Str(_) => b's',
+            Self::Blob(_) => b'b',
+            Self::Bool(true) => b'T',
+            Self::Bool(false) => b'F',
+            Self::Nil => b'N',
+        }
+    }
+}
+
+/// Decoded message, borrowing from the packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OscMessage<'a> {
+    pub address: &'a str,
+    /// Type tags without the leading comma
+    tags: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> OscMessage<'a> {
+    /// Decode one message, checking all its arguments
+    pub fn parse(packet: &'a [u8]) -> Result<Self, OscError> {
+        let (address, rest) = read_str(packet)?;
+        if !address.starts_with('/') {
+            return Err(OscError::Malformed);
+        }
+        // Very old senders leave out the type tag string
+        let (tags, data) = if rest.is_empty() {
+            ("", rest)
+        } else {
+            let (tags, data) = read_str(rest)?;
+            (tags.strip_prefix(',').ok_or(OscError::Malformed)?, data)
+        };
+        let message = Self {
+            address,
+            tags: tags.as_bytes(),
+            data,
+        };
+        let (mut tags, mut data) = (message.tags, message.data);
+        while let Some((&tag, rest)) = tags.split_first() {
+            (_, data) = read_arg(tag, data)?;
+            tags = rest;
+        }
+        Ok(message)
+    }
+
+    pub fn args(&self) -> impl Iterator<Item = OscArg<'a>> {
+        let mut data = self.data;
+        // Arguments were checked in `parse`
+        self.tags.iter().map_while(move |&tag| {
+            let (arg, rest) = read_arg(tag, data).ok()?;
+            data = rest;
+            Some(arg)
+        })
+    }
+
+    /// First argument, if any
+    pub fn arg(&self) -> Option<OscArg<'a>> {
+        self.args().next()
+    }
+}
+
+/// Null-terminated string padded to four bytes, and what follows it
+fn read_str(buf: &[u8]) -> Result<(&str, &[u8]), OscError> {
+    let len = buf
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(OscError::Malformed)?;
+    let padded = (len + 4) & !3;
+    if buf.len() < padded {
+        return Err(OscError::Malformed);
+    }
+    let text = str::from_utf8(&buf[..len]).map_err(|_| OscError::Malformed)?;
+    Ok((text, &buf[padded..]))
+}
+
+fn read_arg(tag: u8, data: &[u8]) -> Result<(OscArg<'_>, &[u8]), OscError> {
+    let word = |data: &[u8]| -> Result<[u8; 4], OscError> {
+        match data {
+            [a, b, c, d, ..] => Ok([*a, *b, *c, *d]),
+            _ => Err(OscError::Malformed),
+        }
+    };
+    Ok(match tag {
+        b'i' => (OscArg::Int(i32::from_be_bytes(word(data)?)), &data[4..]),
+        b'f' => (OscArg::Float(f32::from_be_bytes(word(data)?)), &data[4..]),
+        b's' | b'S' => {
+            let (text, rest) = read_str(data)?;
+            (OscArg::Str(text), rest)
+        }
+        b'b' => {
+            let len = u32::from_be_bytes(word(data)?) as usize;
+            let body = &data[4..];
+            // Bounded by the packet before padding, so no offset can overflow
+            if len > body.len() {
+                return Err(OscError::Malformed);
+            }
+            let rest = body.get((len + 3) & !3..).ok_or(OscError::Malformed)?;
+            (OscArg::Blob(&body[..len]), rest)
+        }
+        b'T' => (OscArg::Bool(true), data),
+        b'F' => (OscArg::Bool(false), data),
+        b'N' | b'I' => (OscArg::Nil, data),
+        other => return Err(OscError::UnsupportedType(other)),
+    })
+}
+
+/// Messages of a packet, unpacking bundles
+///
+/// Stops after the first error.
+pub struct Messages<'a> {
+    single: Option<&'a [u8]>,
+    /// Remaining elements of each open bundle, innermost last
+    bundles: heapless::Vec<&'a [u8], MAX_BUNDLE_DEPTH>,
+}
+
+impl<'a> Messages<'a> {
+    pub fn new(packet: &'a [u8]) -> Self {
+        let mut messages = Self {
+            single: None,
+            bundles: heapless::Vec::new(),
+        };
+        match bundle_elements(packet) {
+            Some(elements) => {
+                let _ = messages.bundles.push(elements);
+            }
+            None => messages.single = Some(packet),
+        }
+        messages
+    }
+
+    fn fail(&mut self) -> Option<Result<OscMessage<'a>, OscError>> {
+        self.bundles.clear();
+        Some(Err(OscError::Malformed))
+    }
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Result<OscMessage<'a>, OscError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(packet) = self.single.take() {
+            return Some(OscMessage::parse(packet));
+        }
+        loop {
+            let elements = self.bundles.last_mut()?;
+            let Some((size, rest)) = elements.split_first_chunk::<4>() else {
+                if !elements.is_empty() {
+                    return self.fail();
+                }
+                self.bundles.pop();
+                continue;
+            };
+            let size = u32::from_be_bytes(*size) as usize;
+            if size > rest.len() {
+                return self.fail();
+            }
+            let (element, rest) = rest.split_at(size);
+            *elements = rest;
+            match bundle_elements(element) {
+                Some(inner) => {
+                    if self.bundles.push(inner).is_err() {
+                        return self.fail();
+                    }
+                }
+                None => {
+                    let message = OscMessage::parse(element);
+                    if message.is_err() {
+                        self.bundles.clear();
+                    }
+                    return Some(message);
+                }
+            }
+        }
+    }
+}
+
+/// Elements of a bundle, after its time tag; `None` if not a bundle
+fn bundle_elements(packet: &[u8]) -> Option<&[u8]> {
+    packet.strip_prefix(BUNDLE)?.get(8..)
+}
+
+/// Encode a message into `buf`, returning its length
+pub fn encode(address: &str, args: &[OscArg<'_>], buf: &mut [u8]) -> Result<usize, OscError> {
+    let mut out = Encoder { buf, len: 0 };
+    out.put_str(address.as_bytes())?;
+    out.put(b",")?;
+    for arg in args {
+        out.put(&[arg.tag()])?;
+    }
+    out.put(&[0])?;
+    out.pad()?;
+    for arg in args {
+        match *arg {
+            OscArg::Int(value) => out.put(&value.to_be_bytes())?,
+            OscArg::Float(value) => out.put(&value.to_be_bytes())?,
+            OscArg::Str(text) => out.put_str(text.as_bytes())?,
+            OscArg::Blob(blob) => {
+                let len = u32::try_from(blob.len()).map_err(|_| OscError::TooLarge)?;
+                out.put(&len.to_be_bytes())?;
+                out.put(blob)?;
+                out.pad()?;
+            }
+            OscArg::Bool(_) | OscArg::Nil => {}
+        }
+    }
+    Ok(out.len)
+}
+
+/// `/input/{n} 1` for a trigger of `input`
+pub fn encode_trigger(input: DigitalInput, buf: &mut [u8]) -> Result<usize, OscError> {
+    let mut address = heapless::String::<16>::new();
+    let _ = write!(address, "/input/{}", input as u8 + 1);
+    encode(&address, &[OscArg::Int(1)], buf)
+}
+
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), OscError> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(OscError::TooLarge)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Null-terminated, padded string
+    fn put_str(&mut self, text: &[u8]) -> Result<(), OscError> {
+        self.put(text)?;
+        self.put(&[0])?;
+        self.pad()
+    }
+
+    fn pad(&mut self) -> Result<(), OscError> {
+        while self.len % 4 != 0 {
+            self.put(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+/// What an incoming message asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OscCommand<'a> {
+    SetRelay(RelayId, RelayState),
+    Trigger(&'a str),
+    AllOff,
+    /// Off value on a one-shot address, e.g. a button release
+    Ignore,
+}
+
+impl<'a> OscCommand<'a> {
+    pub fn parse(message: &OscMessage<'a>) -> Result<Self, OscError> {
+        let mut parts = message.address[1..].split('/');
+        let path = (parts.next(), parts.next(), parts.next(), parts.next());
+        let fire = || match message.arg() {
+            None | Some(OscArg::Nil) => Ok(true),
+            Some(arg) => arg.as_switch().ok_or(OscError::BadArgument),
+        };
+        match path {
+            (Some("relay"), Some(number), None, None) => {
+                let relay = match number.parse::<u16>() {
+                    Ok(number @ 1..) => RelayId::from_index(number - 1),
+                    _ => None,
+                }
+                .ok_or(OscError::NoSuchRelay)?;
+                let on = message
+                    .arg()
+                    .and_then(|arg| arg.as_switch())
+                    .ok_or(OscError::BadArgument)?;
+                let state = if on {
+                    RelayState::High
+                } else {
+                    RelayState::Low
+                };
+                Ok(Self::SetRelay(relay, state))
+            }
+            (Some("sequence"), Some(name), Some("go"), None) if !name.is_empty() => {
+                Ok(if fire()? {
+                    Self::Trigger(name)
+                } else {
+                    Self::Ignore
+                })
+            }
+            (Some("allOff"), None, None, None) => {
+                Ok(if fire()? { Self::AllOff } else { Self::Ignore })
+            }
+            _ => Err(OscError::UnknownAddress),
+        }
+    }
+}
+
+/// Applies incoming OSC to the relays and the sequence runner
+pub struct OscControl<'a, I2C, const BANKS: usize, const SLOTS: usize> {
+    relays: &'a RelayController<I2C, BANKS>,
+    runner: &'a SequenceRunner<SLOTS>,
+    configs: &'a [SequenceConfig],
+}
+
+impl<'a, I2C, const BANKS: usize, const SLOTS: usize> OscControl<'a, I2C, BANKS, SLOTS>
+where
+    I2C: I2c + BusRecovery,
+{
+    pub const fn new(
+        relays: &'a RelayController<I2C, BANKS>,
+        runner: &'a SequenceRunner<SLOTS>,
+        configs: &'a [SequenceConfig],
+    ) -> Self {
+        Self {
+            relays,
+            runner,
+            configs,
+        }
+    }
+
+    /// Act on every message of `packet`; returns how many were applied
+    ///
+    /// Failed messages are logged and skipped.
+    pub async fn handle(&self, packet: &[u8]) -> usize {
+        let mut applied = 0;
+        for message in Messages::new(packet) {
+            let result = match message {
+                Ok(message) => match OscCommand::parse(&message) {
+                    Ok(command) => self.apply(command).await,
+                    Err(error) => Err(error),
+                },
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(()) => applied += 1,
+                Err(error) => defmt::info!("OSC message ignored: {}", error),
+            }
+        }
+        applied
+    }
+
+    pub async fn apply(&self, command: OscCommand<'_>) -> Result<(), OscError> {
+        match command {
+            OscCommand::SetRelay(relay, state) => self
+                .relays
+                .set_relay(relay, state)
+                .await
+                .map_err(|error| match error {
+                    BusError::NoSuchBank => OscError::NoSuchRelay,
+                    _ => OscError::Bus,
+                }),
+            OscCommand::Trigger(name) => {
+                let config = self
+                    .configs
+                    .iter()
+                    .find(|config| config.name == name)
+                    .ok_or(OscError::NoSuchSequence)?;
+                match self.runner.start(config) {
+                    StartOutcome::Rejected => Err(OscError::Busy),
+                    outcome => {
+                        defmt::info!("Sequence '{}' {:?} over OSC", config.name, outcome);
+                        Ok(())
+                    }
+                }
+            }
+            OscCommand::AllOff => self
+                .runner
+                .abort(self.relays)
+                .await
+                .map_err(|_| OscError::Bus),
+            OscCommand::Ignore => Ok(()),
+        }
+    }
+}
+
+/// Listen for OSC on the configured port and send input triggers out
+///
+/// `triggers` carries the input events to report; with OSC turned off
+/// they are drained and dropped.
+#[cfg(feature = "esp32s3")]
+pub async fn serve<I2C, const BANKS: usize, const SLOTS: usize>(
+    stack: embassy_net::Stack<'_>,
+    control: &OscControl<'_, I2C, BANKS, SLOTS>,
+    settings: crate::storage::OscSettings,
+    triggers: &crate::input::InputEventChannel,
+) -> !
+where
+    I2C: I2c + BusRecovery,
+{
+    use embassy_futures::select::{select, Either};
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use embassy_net::{IpAddress, IpEndpoint};
+
+    if settings.port == 0 {
+        defmt::info!("OSC disabled");
+        loop {
+            triggers.receive().await;
+        }
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 2 * MAX_PACKET];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2 * MAX_PACKET];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(settings.port) {
+        defmt::error!("OSC bind to port {} failed: {:?}", settings.port, e);
+        loop {
+            triggers.receive().await;
+        }
+    }
+    defmt::info!("OSC listening on UDP port {}", settings.port);
+
+    let [a, b, c, d] = settings.target;
+    let mut target = (settings.target != [0; 4]).then(|| IpAddress::v4(a, b, c, d));
+    let mut packet = [0; MAX_PACKET];
+    loop {
+        match select(socket.recv_from(&mut packet), triggers.receive()).await {
+            Either::First(Ok((len, meta))) => {
+                if settings.target == [0; 4] {
+                    target = Some(meta.endpoint.addr);
+                }
+                control.handle(&packet[..len]).await;
+            }
+            Either::First(Err(e)) => defmt::warn!("OSC receive failed: {:?}", e),
+            Either::Second(event) => {
+                let Some(addr) = target.filter(|_| settings.target_port != 0) else {
+                    continue;
+                };
+                let Ok(len) = encode_trigger(event.input, &mut packet) else {
+                    continue;
+                };
+                let endpoint = IpEndpoint::new(addr, settings.target_port);
+                if let Err(e) = socket.send_to(&packet[..len], endpoint).await {
+                    defmt::warn!("OSC send failed: {:?}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Random numbers for sequence timing and branches
+//!
+//! Programs draw from a [`RandomSource`]: a seedable [`Prng`] so host tests
+//! replay the same "random" show, or the ESP32-S3 hardware RNG on target.
+
+/// Source of random numbers for sequence programs
+pub trait RandomSource {
+    fn next_u32(&mut self) -> u32;
+
+    /// Uniform value in `min..=max` (bounds may be given in either order)
+    fn range(&mut self, min: u32, max: u32) -> u32 {
+        let (low, high) = if min <= max { (min, max) } else { (max, min) };
+        let span = (high - low) as u64 + 1;
+        low + ((self.next_u32() as u64 * span) >> 32) as u32
+    }
+
+    /// `true` with a probability of `percent` in 100
+    fn chance(&mut self, percent: u8) -> bool {
+        self.range(0, 99) < percent as u32
+    }
+
+    /// Index picked with probability proportional to its weight, `None`
+    /// when all weights are zero
+    fn weighted(&mut self, weights: impl Iterator<Item = u16> + Clone) -> Option<usize> {
+        let total: u32 = weights.clone().map(u32::from).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = self.range(0, total - 1);
+        for (index, weight) in weights.enumerate() {
+            let weight = u32::from(weight);
+            if pick < weight {
+                return Some(index);
+            }
+            pick -= weight;
+        }
+        None
+    }
+}
+
+/// Small seedable generator (SplitMix64); every seed, including 0, gives a
+/// full-period sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prng {
+    state: u64,
+}
+
+impl Prng {
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for Prng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+impl RandomSource for esp_hal::rng::Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.random()
+    }
+}
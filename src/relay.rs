@@ -1,57 +1,256 @@
-/// Relay sequence execution using TCA9554 I2C expander
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+/// Relay sequence execution using TCA9554 I2C expanders
+use core::future::{pending, Future};
+use core::pin::{pin, Pin};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::Mutex,
+    watch::{self, Watch},
+};
 use embassy_time::{Duration, Timer};
 use embedded_hal_async::i2c::I2c;
 
-use crate::hardware::{RelayOutput, RelayState};
-use crate::sequence::SequenceStep;
-use crate::tca9554::Tca9554;
+use crate::bus::{BusError, BusRecovery, RetryPolicy};
+use crate::hardware::{RelayId, RelayMask, RelayState};
+use crate::input::InputWait;
+use crate::random::RandomSource;
+use crate::sequence::{
+    find_program, skip_instruction, Completion, Instruction, Program, ProgramError, SequenceStep,
+    LOOP_FOREVER, MAX_CALL_DEPTH, MAX_LOOP_DEPTH,
+};
+use crate::tca9554::{merge_outputs, Tca9554};
+
+/// Open `Loop` block of a running program
+struct LoopFrame {
+    /// First instruction of the loop body
+    start: usize,
+    /// Passes left including the current one, [`LOOP_FOREVER`] for endless
+    remaining: u32,
+}
+
+/// Program being interpreted; one per nested `Call`
+struct Frame<'a> {
+    code: &'a [Instruction],
+    pc: usize,
+    loops: heapless::Vec<LoopFrame, MAX_LOOP_DEPTH>,
+}
+
+impl<'a> Frame<'a> {
+    fn new(code: &'a [Instruction]) -> Self {
+        Self {
+            code,
+            pc: 0,
+            loops: heapless::Vec::new(),
+        }
+    }
+}
+
+/// Push a called program onto the interpreter's frame stack
+fn call<'a>(
+    frames: &mut heapless::Vec<Frame<'a>, MAX_CALL_DEPTH>,
+    library: &[Program],
+    name: &str,
+) -> Result<(), ProgramError> {
+    let program = find_program(library, name).ok_or(ProgramError::UnknownProgram)?;
+    defmt::debug!("  Call: {}", program.name);
+    frames
+        .push(Frame::new(program.code))
+        .map_err(|_| ProgramError::CallTooDeep)
+}
+
+/// Wait `ms`, returning `false` if `stop` completed first
+async fn hold<S: Future>(ms: u32, stop: Pin<&mut S>) -> bool {
+    let timer = Timer::after(Duration::from_millis(ms as u64));
+    matches!(select(timer, stop).await, Either::First(()))
+}
+
+/// Most tasks following relay changes through [`RelayController::watch`]
+pub const RELAY_WATCHERS: usize = 2;
 
-/// Relay controller managing 8 relay outputs via I2C
-pub struct RelayController<I2C> {
-    expander: Mutex<CriticalSectionRawMutex, Tca9554<I2C>>,
+/// Relay controller managing banks of 8 relay outputs via I2C
+///
+/// Bank 0 is the on-board expander; `BANKS > 1` adds expansion boards,
+/// one TCA9554/TCA9554A per bank, usually sharing the bus through
+/// [`crate::bus::SharedI2c`].
+pub struct RelayController<I2C, const BANKS: usize = 1> {
+    banks: Mutex<CriticalSectionRawMutex, [Tca9554<I2C>; BANKS]>,
+    /// Read back and retry every output write when set
+    verify: Option<RetryPolicy>,
+    /// Output state of every bank, updated after each write
+    outputs: Watch<CriticalSectionRawMutex, [u8; BANKS], RELAY_WATCHERS>,
 }
 
-impl<I2C, E> RelayController<I2C>
+impl<I2C> RelayController<I2C>
 where
-    I2C: I2c<Error = E>,
+    I2C: I2c + BusRecovery,
 {
     pub fn new(expander: Tca9554<I2C>) -> Self {
+        Self::with_banks([expander])
+    }
+}
+
+impl<I2C, const BANKS: usize> RelayController<I2C, BANKS>
+where
+    I2C: I2c + BusRecovery,
+{
+    /// Create a controller over several expanders, in bank order
+    pub fn with_banks(expanders: [Tca9554<I2C>; BANKS]) -> Self {
         Self {
-            expander: Mutex::new(expander),
+            banks: Mutex::new(expanders),
+            verify: None,
+            outputs: Watch::new(),
         }
     }
 
-    pub async fn init(&self) -> Result<(), E> {
-        let mut expander = self.expander.lock().await;
-        expander.init().await?;
-        defmt::info!("Relay controller initialized - all relays OFF");
+    /// Enable verified writes: every relay change is read back from the
+    /// expander and retried according to `policy`
+    pub fn with_verify(mut self, policy: RetryPolicy) -> Self {
+        self.verify = Some(policy);
+        self
+    }
+
+    /// Total number of relays across all banks
+    pub const fn relay_count(&self) -> usize {
+        BANKS * 8
+    }
+
+    /// Follow relay changes: every bank's outputs after each write that
+    /// changed them; `None` when [`RELAY_WATCHERS`] are already watching
+    pub fn watch(
+        &self,
+    ) -> Option<watch::Receiver<'_, CriticalSectionRawMutex, [u8; BANKS], RELAY_WATCHERS>> {
+        self.outputs.receiver()
+    }
+
+    fn publish(&self, banks: &[Tca9554<I2C>; BANKS]) {
+        let outputs = core::array::from_fn(|bank| banks[bank].get_output_state());
+        self.outputs.sender().send_if_modified(|current| {
+            let changed = *current != Some(outputs);
+            *current = Some(outputs);
+            changed
+        });
+    }
+
+    pub async fn init(&self) -> Result<(), BusError> {
+        let mut banks = self.banks.lock().await;
+        for expander in banks.iter_mut() {
+            expander.init().await.map_err(BusError::from_i2c)?;
+        }
+        self.publish(&banks);
+        defmt::info!(
+            "Relay controller initialized ({} bank(s)) - all relays OFF",
+            BANKS
+        );
         Ok(())
     }
 
-    pub async fn set_relay(&self, relay: RelayOutput, state: RelayState) -> Result<(), E> {
-        let mut expander = self.expander.lock().await;
-        let pin = relay as u8;
-        match state {
-            RelayState::High => expander.set_pin_high(pin).await?,
-            RelayState::Low => expander.set_pin_low(pin).await?,
+    async fn write_outputs(&self, expander: &mut Tca9554<I2C>, value: u8) -> Result<(), BusError> {
+        match &self.verify {
+            Some(policy) => expander.write_outputs_verified(value, policy).await,
+            None => expander
+                .write_outputs(value)
+                .await
+                .map_err(BusError::from_i2c),
         }
-        defmt::debug!("Relay {} -> {:?}", pin + 1, state);
+    }
+
+    pub async fn set_relay(
+        &self,
+        relay: impl Into<RelayId>,
+        state: RelayState,
+    ) -> Result<(), BusError> {
+        let relay = relay.into();
+        let levels = match state {
+            RelayState::High => relay.mask(),
+            RelayState::Low => RelayMask::NONE,
+        };
+        self.update_bank(relay.bank, relay.mask(), levels).await?;
+        defmt::debug!("Relay {} -> {:?}", relay.index() + 1, state);
         Ok(())
     }
 
-    pub async fn execute_sequence(&self, sequence: &[SequenceStep]) -> Result<(), E> {
+    /// Switch every relay in `relays` on bank 0 to `state` in a single I2C
+    /// write
+    pub async fn set_relays(&self, relays: RelayMask, state: RelayState) -> Result<(), BusError> {
+        let levels = match state {
+            RelayState::High => relays,
+            RelayState::Low => RelayMask::NONE,
+        };
+        self.update_bank(0, relays, levels).await
+    }
+
+    /// Invert every relay in `relays` on bank 0 in a single I2C write
+    pub async fn toggle_relays(&self, relays: RelayMask) -> Result<(), BusError> {
+        self.toggle_bank(0, relays).await
+    }
+
+    /// Set each relay in `relays` on bank 0 to its bit in `levels`
+    pub async fn update_relays(
+        &self,
+        relays: RelayMask,
+        levels: RelayMask,
+    ) -> Result<(), BusError> {
+        self.update_bank(0, relays, levels).await
+    }
+
+    /// Replace the state of all eight relays on bank 0 with `pattern`
+    pub async fn apply_pattern(&self, pattern: RelayMask) -> Result<(), BusError> {
+        self.update_bank(0, RelayMask::ALL, pattern).await
+    }
+
+    /// Set each relay in `relays` to its bit in `levels`, leaving the others
+    /// untouched, in a single I2C write to the bank's expander
+    ///
+    /// Fails with [`BusError::NoSuchBank`] if no expander is configured for
+    /// `bank`.
+    pub async fn update_bank(
+        &self,
+        bank: u8,
+        relays: RelayMask,
+        levels: RelayMask,
+    ) -> Result<(), BusError> {
+        let mut banks = self.banks.lock().await;
+        let expander = banks.get_mut(bank as usize).ok_or(BusError::NoSuchBank)?;
+        let value = merge_outputs(expander.get_output_state(), relays.bits(), levels.bits());
+        let result = self.write_outputs(expander, value).await;
+        self.publish(&banks);
+        result
+    }
+
+    /// Invert every relay in `relays` on one bank in a single I2C write
+    pub async fn toggle_bank(&self, bank: u8, relays: RelayMask) -> Result<(), BusError> {
+        let mut banks = self.banks.lock().await;
+        let expander = banks.get_mut(bank as usize).ok_or(BusError::NoSuchBank)?;
+        let value = expander.get_output_state() ^ relays.bits();
+        let result = self.write_outputs(expander, value).await;
+        self.publish(&banks);
+        result
+    }
+
+    /// Run a sequence to completion
+    ///
+    /// If a step fails the relays are switched off (best effort) before the
+    /// error is returned, so a bus fault never leaves an effect energized.
+    pub async fn execute_sequence(&self, sequence: &[SequenceStep]) -> Result<(), BusError> {
         defmt::info!("Executing sequence ({} steps)", sequence.len());
 
         for step in sequence {
             defmt::debug!(
-                "  Step: {:?} -> {:?} for {}ms",
-                step.relay,
-                step.state,
+                "  Step: bank {} {=u8:08b} -> {=u8:08b} for {}ms",
+                step.bank,
+                step.relays.bits(),
+                step.levels.bits(),
                 step.duration_ms
             );
 
-            self.set_relay(step.relay, step.state).await?;
+            if let Err(error) = self.update_bank(step.bank, step.relays, step.levels).await {
+                defmt::error!("Sequence step failed: {:?}", error);
+                if self.all_off().await.is_err() {
+                    defmt::error!("Failed to switch relays off after sequence error");
+                }
+                return Err(error);
+            }
             Timer::after(Duration::from_millis(step.duration_ms as u64)).await;
         }
 
@@ -59,9 +258,178 @@ where
         Ok(())
     }
 
-    pub async fn all_off(&self) -> Result<(), E> {
+    /// Run a program to completion
+    ///
+    /// `Call`s are resolved in `library`; `WaitInput` blocks on `inputs`;
+    /// random waits, choices and skips draw from `rng`. An endless `Loop`
+    /// only ends through [`Self::run_program_until`].
+    pub async fn run_program<W: InputWait, R: RandomSource>(
+        &self,
+        code: &[Instruction],
+        library: &[Program],
+        inputs: &W,
+        rng: &mut R,
+    ) -> Result<(), ProgramError> {
+        self.run_program_until(code, library, inputs, rng, pending::<()>())
+            .await
+            .map(|_| ())
+    }
+
+    /// Run a program until it ends or `stop` completes
+    ///
+    /// `stop` is only checked while the program waits (`Wait`,
+    /// `WaitRandom`, `WaitInput`), so a relay write is never interrupted
+    /// half way. Relays are left as they are when stopped; a failed relay
+    /// write switches them all off, as in [`Self::execute_sequence`].
+    pub async fn run_program_until<W: InputWait, R: RandomSource>(
+        &self,
+        code: &[Instruction],
+        library: &[Program],
+        inputs: &W,
+        rng: &mut R,
+        stop: impl Future,
+    ) -> Result<Completion, ProgramError> {
+        let mut stop = pin!(stop);
+        let mut frames: heapless::Vec<Frame, MAX_CALL_DEPTH> = heapless::Vec::new();
+        frames
+            .push(Frame::new(code))
+            .map_err(|_| ProgramError::CallTooDeep)?;
+
+        while let Some(frame) = frames.last_mut() {
+            let Some(&instruction) = frame.code.get(frame.pc) else {
+                if !frame.loops.is_empty() {
+                    return Err(ProgramError::UnterminatedLoop);
+                }
+                frames.pop();
+                continue;
+            };
+            frame.pc += 1;
+
+            match instruction {
+                Instruction::Set {
+                    bank,
+                    relays,
+                    levels,
+                } => {
+                    defmt::debug!(
+                        "  Set: bank {} {=u8:08b} -> {=u8:08b}",
+                        bank,
+                        relays.bits(),
+                        levels.bits()
+                    );
+                    if let Err(error) = self.update_bank(bank, relays, levels).await {
+                        defmt::error!("Program step failed: {:?}", error);
+                        if self.all_off().await.is_err() {
+                            defmt::error!("Failed to switch relays off after program error");
+                        }
+                        return Err(error.into());
+                    }
+                }
+                Instruction::Wait(ms) => {
+                    if !hold(ms, stop.as_mut()).await {
+                        return Ok(Completion::Cancelled);
+                    }
+                }
+                Instruction::WaitRandom { min_ms, max_ms } => {
+                    let ms = rng.range(min_ms, max_ms);
+                    defmt::debug!("  Random wait: {}ms", ms);
+                    if !hold(ms, stop.as_mut()).await {
+                        return Ok(Completion::Cancelled);
+                    }
+                }
+                Instruction::WaitInput(input) => {
+                    defmt::debug!("  Waiting for {:?}", input);
+                    match select(inputs.wait_for(input), stop.as_mut()).await {
+                        Either::First(result) => result?,
+                        Either::Second(_) => return Ok(Completion::Cancelled),
+                    }
+                }
+                Instruction::Loop(count) => {
+                    let start = frame.pc;
+                    frame
+                        .loops
+                        .push(LoopFrame {
+                            start,
+                            remaining: count,
+                        })
+                        .map_err(|_| ProgramError::LoopTooDeep)?;
+                }
+                Instruction::End => match frame.loops.last_mut() {
+                    Some(block) if block.remaining == LOOP_FOREVER => frame.pc = block.start,
+                    Some(block) if block.remaining > 1 => {
+                        block.remaining -= 1;
+                        frame.pc = block.start;
+                    }
+                    Some(_) => {
+                        frame.loops.pop();
+                    }
+                    None => {
+                        frames.pop();
+                    }
+                },
+                Instruction::Call(name) => call(&mut frames, library, name)?,
+                Instruction::Choose(choices) => {
+                    let index = rng
+                        .weighted(choices.iter().map(|choice| choice.weight))
+                        .ok_or(ProgramError::EmptyChoice)?;
+                    call(&mut frames, library, choices[index].program)?;
+                }
+                Instruction::Skip(percent) => {
+                    if rng.chance(percent) {
+                        frame.pc = skip_instruction(frame.code, frame.pc);
+                        defmt::debug!("  Skipped to {}", frame.pc);
+                    }
+                }
+            }
+        }
+
+        Ok(Completion::Finished)
+    }
+
+    /// Current bank 0 relay bitmask as last written (bit 0 = Relay1)
+    pub async fn output_state(&self) -> u8 {
+        self.bank_state(0).await.unwrap_or(0)
+    }
+
+    /// Relay bitmask of one bank as last written, `None` if not configured
+    pub async fn bank_state(&self, bank: u8) -> Option<u8> {
+        let banks = self.banks.lock().await;
+        banks.get(bank as usize).map(|e| e.get_output_state())
+    }
+
+    /// Reload the cached relay state from every expander after a brown-out
+    /// or bus glitch
+    pub async fn sync_from_device(&self) -> Result<(), BusError> {
+        let mut banks = self.banks.lock().await;
+        for expander in banks.iter_mut() {
+            expander
+                .sync_from_device()
+                .await
+                .map_err(BusError::from_i2c)?;
+            defmt::info!(
+                "Relay state reloaded from {=u8:#04x}: outputs={=u8:#04x} config={=u8:#04x}",
+                expander.address(),
+                expander.get_output_state(),
+                expander.get_configuration()
+            );
+        }
+        self.publish(&banks);
+        Ok(())
+    }
+
+    /// Switch every relay on every bank off
+    ///
+    /// All banks are attempted even if one fails; the first error is
+    /// returned.
+    pub async fn all_off(&self) -> Result<(), BusError> {
         defmt::info!("Turning all relays OFF");
-        let mut expander = self.expander.lock().await;
-        expander.all_off().await
+        let mut banks = self.banks.lock().await;
+        let mut result = Ok(());
+        for expander in banks.iter_mut() {
+            let written = self.write_outputs(expander, 0x00).await;
+            result = result.and(written);
+        }
+        self.publish(&banks);
+        result
     }
 }
@@ -0,0 +1,691 @@
+//! Concurrent sequence playback
+//!
+//! Sequences run in a fixed number of slots, each driven by its own task
+//! (see [`SequenceRunner::run_slot`]), so a long ambient loop no longer
+//! delays a jump scare on other relays. Starting a sequence never blocks:
+//! conflicts on shared relays are resolved by the sequence's
+//! [`ConflictPolicy`]. Every started sequence gets a [`SequenceHandle`]
+//! that can cancel it; [`SequenceRunner::abort`] stops everything.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use crate::bus::{BusError, BusRecovery};
+use crate::hardware::{RelayMask, MAX_BANKS};
+use crate::input::InputWait;
+use crate::random::Prng;
+use crate::relay::RelayController;
+use crate::sequence::{
+    find_program, Cleanup, Completion, ConflictPolicy, Instruction, Program, ProgramError,
+    SequenceConfig, SequenceStep, MAX_CALL_DEPTH,
+};
+
+/// Sequences waiting for a slot or for conflicting relays to free up
+pub const QUEUE_DEPTH: usize = 8;
+
+/// Longest [`SequenceRunner::abort`] waits for the slots to stop before
+/// switching the relays off anyway
+pub const ABORT_TIMEOUT_MS: u64 = 100;
+/// Longest [`SequenceRunner::abort`] then waits for the relays to switch
+/// off, bus lock and verify retries included
+pub const ALL_OFF_TIMEOUT_MS: u64 = 500;
+
+/// Most tasks following [`SequenceEvent`]s at the same time
+pub const EVENT_SUBSCRIBERS: usize = 2;
+/// Events kept for a subscriber that falls behind
+pub const EVENT_DEPTH: usize = 8;
+
+/// What happened to a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SequenceEventKind {
+    /// A slot began playing it
+    Started,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+/// Sequence lifecycle notification, see [`SequenceRunner::subscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SequenceEvent {
+    pub name: &'static str,
+    pub kind: SequenceEventKind,
+}
+
+/// Receiving end of [`SequenceRunner::subscribe`]
+pub type SequenceEvents<'a> =
+    Subscriber<'a, CriticalSectionRawMutex, SequenceEvent, EVENT_DEPTH, EVENT_SUBSCRIBERS, 0>;
+
+/// Relays used by a sequence, one mask per bank
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Footprint([u8; MAX_BANKS]);
+
+impl Footprint {
+    pub const EMPTY: Self = Self([0; MAX_BANKS]);
+
+    pub fn of(sequence: &[SequenceStep]) -> Self {
+        let mut masks = [0u8; MAX_BANKS];
+        for step in sequence {
+            if let Some(mask) = masks.get_mut(step.bank as usize) {
+                *mask |= step.relays.bits();
+            }
+        }
+        Self(masks)
+    }
+
+    /// Relays set anywhere in a program, including the programs it calls
+    pub fn of_program(code: &[Instruction], library: &[Program]) -> Self {
+        let mut footprint = Self::EMPTY;
+        footprint.add_program(code, library, 1);
+        footprint
+    }
+
+    fn add_program(&mut self, code: &[Instruction], library: &[Program], depth: usize) {
+        if depth > MAX_CALL_DEPTH {
+            return;
+        }
+        for instruction in code {
+            match instruction {
+                Instruction::Set { bank, relays, .. } => {
+                    if let Some(mask) = self.0.get_mut(*bank as usize) {
+                        *mask |= relays.bits();
+                    }
+                }
+                Instruction::Call(name) => {
+                    if let Some(program) = find_program(library, name) {
+                        self.add_program(program.code, library, depth + 1);
+                    }
+                }
+                Instruction::Choose(choices) => {
+                    for choice in choices.iter() {
+                        if let Some(program) = find_program(library, choice.program) {
+                            self.add_program(program.code, library, depth + 1);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn overlaps(&self, other: &Footprint) -> bool {
+        self.0.iter().zip(other.0.iter()).any(|(a, b)| a & b != 0)
+    }
+
+    pub fn union(&self, other: &Footprint) -> Self {
+        let mut masks = self.0;
+        for (mask, other) in masks.iter_mut().zip(other.0.iter()) {
+            *mask |= other;
+        }
+        Self(masks)
+    }
+
+    /// Banks with at least one relay in use, with their masks
+    pub fn banks(&self) -> impl Iterator<Item = (u8, RelayMask)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, mask)| **mask != 0)
+            .map(|(bank, mask)| (bank as u8, RelayMask(*mask)))
+    }
+}
+
+/// Identifies one started sequence, for [`SequenceRunner::cancel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SequenceHandle(u32);
+
+impl SequenceHandle {
+    pub const fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Result of [`SequenceRunner::start`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StartOutcome {
+    /// Playing in a free slot
+    Started(SequenceHandle),
+    /// Waiting for a slot or for conflicting sequences to finish
+    Queued(SequenceHandle),
+    /// Conflicting sequences were cancelled; starts once they stopped
+    Preempting(SequenceHandle),
+    /// Dropped by [`ConflictPolicy::Reject`], a full queue or an abort in
+    /// progress
+    Rejected,
+}
+
+impl StartOutcome {
+    pub const fn handle(&self) -> Option<SequenceHandle> {
+        match self {
+            Self::Started(handle) | Self::Queued(handle) | Self::Preempting(handle) => {
+                Some(*handle)
+            }
+            Self::Rejected => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Job {
+    id: u32,
+    sequence: &'static [SequenceStep],
+    program: Option<&'static [Instruction]>,
+    name: &'static str,
+    policy: ConflictPolicy,
+    cleanup: Cleanup,
+    footprint: Footprint,
+    /// Seeds the program's random choices
+    seed: u64,
+    library: &'static [Program],
+}
+
+struct Slot {
+    start: Signal<CriticalSectionRawMutex, Job>,
+    cancel: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            start: Signal::new(),
+            cancel: Signal::new(),
+        }
+    }
+}
+
+struct RunnerState<const SLOTS: usize> {
+    running: [Option<Job>; SLOTS],
+    queue: heapless::Vec<Job, QUEUE_DEPTH>,
+    /// Set per slot by an abort: stop without running cleanup steps
+    skip_cleanup: [bool; SLOTS],
+    aborting: bool,
+    next_id: u32,
+    /// Hands out a seed per started sequence
+    rng: Prng,
+    /// Programs that `Call` instructions can refer to
+    library: &'static [Program],
+}
+
+impl<const SLOTS: usize> RunnerState<SLOTS> {
+    fn running_overlaps(&self, footprint: &Footprint) -> bool {
+        self.running
+            .iter()
+            .flatten()
+            .any(|job| job.footprint.overlaps(footprint))
+    }
+
+    fn queued_overlaps(&self, footprint: &Footprint) -> bool {
+        self.queue
+            .iter()
+            .any(|job| job.footprint.overlaps(footprint))
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.running.iter().position(|job| job.is_none())
+    }
+}
+
+/// Plays up to `SLOTS` sequences at the same time
+pub struct SequenceRunner<const SLOTS: usize> {
+    state: BlockingMutex<CriticalSectionRawMutex, RefCell<RunnerState<SLOTS>>>,
+    slots: [Slot; SLOTS],
+    /// Signalled whenever a slot finishes
+    finished: Signal<CriticalSectionRawMutex, ()>,
+    /// Same as `finished`, reserved for [`Self::abort`]
+    stopped: Signal<CriticalSectionRawMutex, ()>,
+    events:
+        PubSubChannel<CriticalSectionRawMutex, SequenceEvent, EVENT_DEPTH, EVENT_SUBSCRIBERS, 0>,
+}
+
+impl<const SLOTS: usize> Default for SequenceRunner<SLOTS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SLOTS: usize> SequenceRunner<SLOTS> {
+    pub const fn new() -> Self {
+        Self {
+            state: BlockingMutex::new(RefCell::new(RunnerState {
+                running: [None; SLOTS],
+                queue: heapless::Vec::new(),
+                skip_cleanup: [false; SLOTS],
+                aborting: false,
+                next_id: 0,
+                rng: Prng::new(0),
+                library: &[],
+            })),
+            slots: [const { Slot::new() }; SLOTS],
+            finished: Signal::new(),
+            stopped: Signal::new(),
+            events: PubSubChannel::new(),
+        }
+    }
+
+    /// Resolve `Call`s in programs started from now on against `library`
+    pub fn set_library(&self, library: &'static [Program]) {
+        self.state
+            .lock(|state| state.borrow_mut().library = library);
+    }
+
+    /// Seed the random timing and branches of the sequences started from
+    /// now on; the same seed replays the same show
+    pub fn reseed(&self, seed: u64) {
+        self.state
+            .lock(|state| state.borrow_mut().rng = Prng::new(seed));
+    }
+
+    /// Request playback of a sequence
+    ///
+    /// Returns immediately; queued and preempting sequences are started by
+    /// the slot tasks as soon as their relays are free.
+    pub fn start(&self, config: &SequenceConfig) -> StartOutcome {
+        let policy = config.conflict;
+        let outcome = self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            if state.aborting {
+                return StartOutcome::Rejected;
+            }
+            let job = Job {
+                id: state.next_id,
+                sequence: config.sequence,
+                program: config.program,
+                name: config.name,
+                policy,
+                cleanup: config.cleanup,
+                footprint: match config.program {
+                    Some(code) => Footprint::of_program(code, state.library),
+                    None => Footprint::of(config.sequence),
+                },
+                seed: state.rng.next_u64(),
+                library: state.library,
+            };
+            let handle = SequenceHandle(job.id);
+            let conflict = policy != ConflictPolicy::Merge
+                && (state.running_overlaps(&job.footprint)
+                    || state.queued_overlaps(&job.footprint));
+
+            let outcome = match policy {
+                ConflictPolicy::Reject if conflict || state.free_slot().is_none() => {
+                    StartOutcome::Rejected
+                }
+                ConflictPolicy::Preempt if conflict => {
+                    // Conflicting queued sequences are dropped, running ones
+                    // cancelled; the new one starts at once if none was
+                    // running, else it goes first in line
+                    state.queue.retain(|queued| {
+                        let keep = !queued.footprint.overlaps(&job.footprint);
+                        if !keep {
+                            defmt::info!("Dropping queued sequence: {}", queued.name);
+                        }
+                        keep
+                    });
+                    for (slot, running) in state.running.iter().enumerate() {
+                        if let Some(running) = running {
+                            if running.footprint.overlaps(&job.footprint) {
+                                defmt::info!("Preempting sequence: {}", running.name);
+                                self.slots[slot].cancel.signal(());
+                            }
+                        }
+                    }
+                    let outcome = match state.free_slot() {
+                        Some(slot) if !state.running_overlaps(&job.footprint) => {
+                            self.assign(&mut state, slot, job);
+                            StartOutcome::Started(handle)
+                        }
+                        _ => match state.queue.insert(0, job) {
+                            Ok(()) => StartOutcome::Preempting(handle),
+                            Err(_) => StartOutcome::Rejected,
+                        },
+                    };
+                    // The dropped sequences may have been holding back others
+                    self.dispatch(&mut state);
+                    outcome
+                }
+                _ => match state.free_slot() {
+                    Some(slot) if !conflict => {
+                        self.assign(&mut state, slot, job);
+                        StartOutcome::Started(handle)
+                    }
+                    _ => match state.queue.push(job) {
+                        Ok(()) => StartOutcome::Queued(handle),
+                        Err(_) => StartOutcome::Rejected,
+                    },
+                },
+            };
+            if outcome != StartOutcome::Rejected {
+                state.next_id = state.next_id.wrapping_add(1);
+            }
+            outcome
+        });
+
+        match outcome {
+            StartOutcome::Rejected => defmt::warn!("Sequence rejected: {}", config.name),
+            _ => defmt::info!("Sequence {}: {:?}", config.name, outcome),
+        }
+        outcome
+    }
+
+    /// Stop a sequence: a queued one is dropped, a running one stops at
+    /// once and runs its [`Cleanup`]
+    ///
+    /// Returns `false` if the sequence already finished.
+    pub fn cancel(&self, handle: SequenceHandle) -> bool {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            if let Some(index) = state.queue.iter().position(|job| job.id == handle.0) {
+                let job = state.queue.remove(index);
+                defmt::info!("Cancelled queued sequence: {}", job.name);
+                // It may have been holding back later sequences
+                self.dispatch(&mut state);
+                return true;
+            }
+            let slot = state
+                .running
+                .iter()
+                .position(|job| job.is_some_and(|job| job.id == handle.0));
+            match slot {
+                Some(slot) => {
+                    self.slots[slot].cancel.signal(());
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Whether a sequence is still playing or queued
+    pub fn is_active(&self, handle: SequenceHandle) -> bool {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            state
+                .running
+                .iter()
+                .flatten()
+                .chain(state.queue.iter())
+                .any(|job| job.id == handle.0)
+        })
+    }
+
+    /// Stop every sequence and switch all relays off
+    ///
+    /// Queued sequences are dropped and running ones stopped without their
+    /// cleanup steps. Waiting for the slots is bounded by
+    /// [`ABORT_TIMEOUT_MS`]; the relays are switched off even if a slot is
+    /// still stuck in a bus transaction by then, and switching them off is
+    /// bounded by [`ALL_OFF_TIMEOUT_MS`] (a [`BusError::Timeout`] past it).
+    /// New sequences are rejected until the abort is done.
+    pub async fn abort<I2C, const BANKS: usize>(
+        &self,
+        relays: &RelayController<I2C, BANKS>,
+    ) -> Result<(), BusError>
+    where
+        I2C: I2c + BusRecovery,
+    {
+        defmt::warn!("Aborting all sequences");
+        self.stopped.reset();
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.aborting = true;
+            state.queue.clear();
+            for slot in 0..SLOTS {
+                if state.running[slot].is_some() {
+                    state.skip_cleanup[slot] = true;
+                    self.slots[slot].cancel.signal(());
+                }
+            }
+        });
+
+        let stopped = with_timeout(Duration::from_millis(ABORT_TIMEOUT_MS), async {
+            while !self.is_idle() {
+                self.stopped.wait().await;
+            }
+        })
+        .await;
+        if stopped.is_err() {
+            defmt::error!("Sequence slots did not stop within {}ms", ABORT_TIMEOUT_MS);
+        }
+
+        let result = with_timeout(Duration::from_millis(ALL_OFF_TIMEOUT_MS), relays.all_off())
+            .await
+            .unwrap_or_else(|_| {
+                defmt::error!("Relays did not switch off within {}ms", ALL_OFF_TIMEOUT_MS);
+                Err(BusError::Timeout)
+            });
+        self.state.lock(|state| state.borrow_mut().aborting = false);
+        result
+    }
+
+    fn assign(&self, state: &mut RunnerState<SLOTS>, slot: usize, job: Job) {
+        state.running[slot] = Some(job);
+        state.skip_cleanup[slot] = false;
+        // Drop a cancel aimed at the slot's previous sequence
+        self.slots[slot].cancel.reset();
+        self.slots[slot].start.signal(job);
+    }
+
+    /// Start queued sequences whose relays are free, in queue order
+    ///
+    /// A sequence that cannot start yet blocks later ones that share its
+    /// relays, so triggers on the same relays keep their order.
+    fn dispatch(&self, state: &mut RunnerState<SLOTS>) {
+        let mut blocked = Footprint::EMPTY;
+        let mut i = 0;
+        while i < state.queue.len() {
+            let job = state.queue[i];
+            let busy = job.footprint.overlaps(&blocked)
+                || (job.policy != ConflictPolicy::Merge && state.running_overlaps(&job.footprint));
+            match state.free_slot() {
+                Some(slot) if !busy => {
+                    state.queue.remove(i);
+                    defmt::info!("Starting queued sequence: {}", job.name);
+                    self.assign(state, slot, job);
+                }
+                _ => {
+                    blocked = blocked.union(&job.footprint);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    fn finish(&self, slot: usize) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.running[slot] = None;
+            self.dispatch(&mut state);
+        });
+        self.finished.signal(());
+        self.stopped.signal(());
+    }
+
+    /// Names of the sequences currently playing, by slot
+    pub fn running(&self) -> [Option<&'static str>; SLOTS] {
+        self.state
+            .lock(|state| state.borrow().running.map(|job| job.map(|j| j.name)))
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.state.lock(|state| state.borrow().queue.len())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            state.queue.is_empty() && state.running.iter().all(Option::is_none)
+        })
+    }
+
+    /// Follow sequences starting and ending in the slots; `None` when
+    /// [`EVENT_SUBSCRIBERS`] are already subscribed
+    ///
+    /// A subscriber that falls more than [`EVENT_DEPTH`] events behind
+    /// loses the oldest ones.
+    pub fn subscribe(&self) -> Option<SequenceEvents<'_>> {
+        self.events.subscriber().ok()
+    }
+
+    fn notify(&self, job: &Job, kind: SequenceEventKind) {
+        self.events
+            .immediate_publisher()
+            .publish_immediate(SequenceEvent {
+                name: job.name,
+                kind,
+            });
+    }
+
+    /// Wait until nothing is playing or queued (single waiter)
+    pub async fn wait_idle(&self) {
+        while !self.is_idle() {
+            self.finished.wait().await;
+        }
+    }
+
+    /// Drive one slot forever; spawn one task per slot
+    ///
+    /// `inputs` serves the `WaitInput` instructions of programs.
+    pub async fn run_slot<I2C, W, const BANKS: usize>(
+        &self,
+        slot: usize,
+        relays: &RelayController<I2C, BANKS>,
+        inputs: &W,
+    ) -> !
+    where
+        I2C: I2c + BusRecovery,
+        W: InputWait,
+    {
+        defmt::info!("Sequence slot {} ready", slot);
+        loop {
+            let job = self.slots[slot].start.wait().await;
+            defmt::info!("[slot {}] Playing sequence: {}", slot, job.name);
+            self.notify(&job, SequenceEventKind::Started);
+
+            let result = match job.program {
+                Some(code) => {
+                    let mut rng = Prng::new(job.seed);
+                    let cancel = self.slots[slot].cancel.wait();
+                    relays
+                        .run_program_until(code, job.library, inputs, &mut rng, cancel)
+                        .await
+                }
+                None => self
+                    .play(slot, job.sequence, relays)
+                    .await
+                    .map_err(ProgramError::from),
+            };
+
+            let kind = match result {
+                Ok(Completion::Finished) => {
+                    defmt::info!("[slot {}] Sequence '{}' complete", slot, job.name);
+                    SequenceEventKind::Finished
+                }
+                Ok(Completion::Cancelled) => {
+                    defmt::info!("[slot {}] Sequence '{}' cancelled", slot, job.name);
+                    if !self.skips_cleanup(slot) {
+                        self.clean_up(slot, &job, relays).await;
+                    }
+                    SequenceEventKind::Cancelled
+                }
+                Err(error) => {
+                    defmt::error!(
+                        "[slot {}] Sequence '{}' failed: {:?}",
+                        slot,
+                        job.name,
+                        error
+                    );
+                    Self::release(&job, relays).await;
+                    SequenceEventKind::Failed
+                }
+            };
+
+            self.finish(slot);
+            self.notify(&job, kind);
+        }
+    }
+
+    fn skips_cleanup(&self, slot: usize) -> bool {
+        self.state.lock(|state| state.borrow().skip_cleanup[slot])
+    }
+
+    /// Apply a cancelled sequence's [`Cleanup`]
+    ///
+    /// Cleanup steps can themselves be interrupted by another cancel or an
+    /// abort.
+    async fn clean_up<I2C, const BANKS: usize>(
+        &self,
+        slot: usize,
+        job: &Job,
+        relays: &RelayController<I2C, BANKS>,
+    ) where
+        I2C: I2c + BusRecovery,
+    {
+        match job.cleanup {
+            Cleanup::ReleaseTouched => Self::release(job, relays).await,
+            Cleanup::Steps(steps) => match self.play(slot, steps, relays).await {
+                Ok(Completion::Finished) => {}
+                Ok(Completion::Cancelled) => {
+                    defmt::warn!("[slot {}] Cleanup of '{}' interrupted", slot, job.name)
+                }
+                Err(error) => {
+                    defmt::error!(
+                        "[slot {}] Cleanup of '{}' failed: {:?}",
+                        slot,
+                        job.name,
+                        error
+                    );
+                    Self::release(job, relays).await;
+                }
+            },
+            Cleanup::None => {}
+        }
+    }
+
+    /// Switch off the relays a stopped sequence used, leaving other slots'
+    /// relays alone
+    async fn release<I2C, const BANKS: usize>(job: &Job, relays: &RelayController<I2C, BANKS>)
+    where
+        I2C: I2c + BusRecovery,
+    {
+        for (bank, mask) in job.footprint.banks() {
+            if relays
+                .update_bank(bank, mask, RelayMask::NONE)
+                .await
+                .is_err()
+            {
+                defmt::error!("Failed to switch bank {} off after '{}'", bank, job.name);
+            }
+        }
+    }
+
+    async fn play<I2C, const BANKS: usize>(
+        &self,
+        slot: usize,
+        steps: &[SequenceStep],
+        relays: &RelayController<I2C, BANKS>,
+    ) -> Result<Completion, BusError>
+    where
+        I2C: I2c + BusRecovery,
+    {
+        let cancel = &self.slots[slot].cancel;
+        for step in steps {
+            if cancel.try_take().is_some() {
+                return Ok(Completion::Cancelled);
+            }
+            relays
+                .update_bank(step.bank, step.relays, step.levels)
+                .await?;
+
+            let hold = Timer::after(Duration::from_millis(step.duration_ms as u64));
+            if let Either::Second(()) = select(hold, cancel.wait()).await {
+                return Ok(Completion::Cancelled);
+            }
+        }
+        Ok(Completion::Finished)
+    }
+}
@@ -1,36 +1,330 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 use embassy_time::{Duration, Instant};
 
-use crate::hardware::{DigitalInput, RelayOutput, RelayState};
+use crate::bus::BusError;
+use crate::gesture::{Gesture, GestureEvent, MAX_GESTURES};
+use crate::hardware::{DigitalInput, RelayId, RelayMask, RelayOutput, RelayState};
 
 /// Single step in a relay sequence
+///
+/// All relays in a step belong to one bank and switch in the same I2C
+/// write, so effects that must fire together are truly simultaneous.
 #[derive(Debug, Clone, Copy)]
 pub struct SequenceStep {
-    pub relay: RelayOutput,
-    pub state: RelayState,
+    /// Expander bank the relays belong to (0 = on-board relays)
+    pub bank: u8,
+    /// Relays changed by this step
+    pub relays: RelayMask,
+    /// New level of each relay in `relays` (bit set = High)
+    pub levels: RelayMask,
+    /// Time to hold before the next step
     pub duration_ms: u32,
 }
 
 impl SequenceStep {
     pub const fn new(relay: RelayOutput, state: RelayState, duration_ms: u32) -> Self {
+        Self::group(RelayMask::of(&[relay]), state, duration_ms)
+    }
+
+    /// Switch several relays to the same state at once
+    pub const fn group(relays: RelayMask, state: RelayState, duration_ms: u32) -> Self {
+        let levels = match state {
+            RelayState::High => relays,
+            RelayState::Low => RelayMask::NONE,
+        };
+        Self::pattern(relays, levels, duration_ms)
+    }
+
+    /// Set each relay in `relays` to its bit in `levels` at once, e.g. one
+    /// relay on and another off in the same instant
+    pub const fn pattern(relays: RelayMask, levels: RelayMask, duration_ms: u32) -> Self {
+        Self::on_bank(0, relays, levels, duration_ms)
+    }
+
+    /// Switch a relay on any bank
+    pub const fn relay(relay: RelayId, state: RelayState, duration_ms: u32) -> Self {
+        let levels = match state {
+            RelayState::High => relay.mask(),
+            RelayState::Low => RelayMask::NONE,
+        };
+        Self::on_bank(relay.bank, relay.mask(), levels, duration_ms)
+    }
+
+    /// [`Self::pattern`] on an expansion bank
+    pub const fn on_bank(bank: u8, relays: RelayMask, levels: RelayMask, duration_ms: u32) -> Self {
         Self {
-            relay,
-            state,
+            bank,
+            relays,
+            levels: RelayMask(levels.0 & relays.0),
             duration_ms,
         }
     }
 }
 
+/// `Loop` count that repeats until the sequence is cancelled
+pub const LOOP_FOREVER: u32 = 0;
+/// Deepest nesting of `Call`s, counting the top-level program
+pub const MAX_CALL_DEPTH: usize = 4;
+/// Deepest nesting of `Loop` blocks within one program
+pub const MAX_LOOP_DEPTH: usize = 4;
+
+/// Weighted option of a `Choose` instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Choice {
+    /// Relative likelihood; 0 never picks this option
+    pub weight: u16,
+    /// Program to call when picked
+    pub program: &'static str,
+}
+
+impl Choice {
+    pub const fn new(weight: u16, program: &'static str) -> Self {
+        Self { weight, program }
+    }
+}
+
+/// Sequence program instruction
+///
+/// Unlike a flat [`SequenceStep`] list, programs can repeat blocks, call
+/// other named programs, wait for inputs and vary from run to run:
+///
+/// ```ignore
+/// const FLICKER: &[Instruction] = &[
+///     Instruction::Loop(LOOP_FOREVER),
+///     Instruction::set(RelayOutput::Relay4, RelayState::High),
+///     Instruction::Wait(80),
+///     Instruction::set(RelayOutput::Relay4, RelayState::Low),
+///     Instruction::WaitRandom { min_ms: 200, max_ms: 1500 },
+///     Instruction::Skip(70),
+///     Instruction::Choose(&[Choice::new(3, "growl"), Choice::new(1, "scream")]),
+///     Instruction::End,
+/// ];
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub enum Instruction {
+    /// Switch relays on one bank in a single write, without waiting
+    Set {
+        bank: u8,
+        relays: RelayMask,
+        levels: RelayMask,
+    },
+    /// Hold for this many milliseconds
+    Wait(u32),
+    /// Repeat the block up to the matching `End` this many times
+    /// ([`LOOP_FOREVER`] until cancelled)
+    Loop(u32),
+    /// Run another program from the library, by name
+    Call(&'static str),
+    /// Hold until the input is triggered
+    WaitInput(DigitalInput),
+    /// Close the innermost `Loop`; outside a loop, stop the program
+    End,
+    /// Hold for a random time in `min_ms..=max_ms`
+    WaitRandom { min_ms: u32, max_ms: u32 },
+    /// Call one of the programs, picked at random by weight
+    Choose(&'static [Choice]),
+    /// Skip the next instruction (a whole block if it is a `Loop`) with a
+    /// probability of this many percent; an `End` is never skipped
+    Skip(u8),
+}
+
+impl Instruction {
+    pub const fn set(relay: RelayOutput, state: RelayState) -> Self {
+        Self::group(RelayMask::of(&[relay]), state)
+    }
+
+    /// Switch several bank 0 relays to the same state at once
+    pub const fn group(relays: RelayMask, state: RelayState) -> Self {
+        let levels = match state {
+            RelayState::High => relays,
+            RelayState::Low => RelayMask::NONE,
+        };
+        Self::on_bank(0, relays, levels)
+    }
+
+    /// Set each relay in `relays` on `bank` to its bit in `levels`
+    pub const fn on_bank(bank: u8, relays: RelayMask, levels: RelayMask) -> Self {
+        Self::Set {
+            bank,
+            relays,
+            levels: RelayMask(levels.0 & relays.0),
+        }
+    }
+}
+
+/// Named program that `Call` instructions can refer to
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    pub name: &'static str,
+    pub code: &'static [Instruction],
+}
+
+impl Program {
+    pub const fn new(name: &'static str, code: &'static [Instruction]) -> Self {
+        Self { name, code }
+    }
+}
+
+/// Look up a program by name
+pub fn find_program<'a>(library: &'a [Program], name: &str) -> Option<&'a Program> {
+    library.iter().find(|program| program.name == name)
+}
+
+/// Why a program could not run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ProgramError {
+    /// Relay write failed
+    Bus(BusError),
+    /// `Call` names a program that is not in the library
+    UnknownProgram,
+    /// `Call`s nested deeper than [`MAX_CALL_DEPTH`] (or recursive)
+    CallTooDeep,
+    /// `Loop`s nested deeper than [`MAX_LOOP_DEPTH`]
+    LoopTooDeep,
+    /// `Loop` without a matching `End`
+    UnterminatedLoop,
+    /// No input listener left for a `WaitInput`
+    InputUnavailable,
+    /// `Choose` without options, or with all weights zero
+    EmptyChoice,
+}
+
+impl From<BusError> for ProgramError {
+    fn from(error: BusError) -> Self {
+        Self::Bus(error)
+    }
+}
+
+/// How a sequence or program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Completion {
+    Finished,
+    Cancelled,
+}
+
+/// Check a program and everything it calls before running it
+///
+/// Catches the errors the interpreter would otherwise only hit part way
+/// through a show.
+pub fn validate(code: &[Instruction], library: &[Program]) -> Result<(), ProgramError> {
+    validate_at(code, library, 1)
+}
+
+fn validate_at(
+    code: &[Instruction],
+    library: &[Program],
+    depth: usize,
+) -> Result<(), ProgramError> {
+    if depth > MAX_CALL_DEPTH {
+        return Err(ProgramError::CallTooDeep);
+    }
+    let mut loops = 0;
+    for instruction in code {
+        match instruction {
+            Instruction::Loop(_) => {
+                loops += 1;
+                if loops > MAX_LOOP_DEPTH {
+                    return Err(ProgramError::LoopTooDeep);
+                }
+            }
+            Instruction::End if loops > 0 => loops -= 1,
+            Instruction::Call(name) => {
+                let program = find_program(library, name).ok_or(ProgramError::UnknownProgram)?;
+                validate_at(program.code, library, depth + 1)?;
+            }
+            Instruction::Choose(choices) => {
+                if choices.iter().all(|choice| choice.weight == 0) {
+                    return Err(ProgramError::EmptyChoice);
+                }
+                for choice in choices.iter() {
+                    let program = find_program(library, choice.program)
+                        .ok_or(ProgramError::UnknownProgram)?;
+                    validate_at(program.code, library, depth + 1)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    if loops > 0 {
+        return Err(ProgramError::UnterminatedLoop);
+    }
+    Ok(())
+}
+
+/// Index of the instruction after the one at `pc`, treating a `Loop` and
+/// its body up to the matching `End` as one instruction
+///
+/// An `End` stays where it is: skipping it would leave its loop open.
+pub(crate) fn skip_instruction(code: &[Instruction], pc: usize) -> usize {
+    match code.get(pc) {
+        Some(Instruction::Loop(_)) => {}
+        Some(Instruction::End) => return pc,
+        _ => return pc + 1,
+    }
+    let mut depth = 0;
+    for (index, instruction) in code.iter().enumerate().skip(pc) {
+        match instruction {
+            Instruction::Loop(_) => depth += 1,
+            Instruction::End => {
+                depth -= 1;
+                if depth == 0 {
+                    return index + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    code.len()
+}
+
+/// What to do when a sequence is started while another running sequence
+/// uses some of the same relays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub enum ConflictPolicy {
+    /// Wait until the conflicting sequences have finished
+    #[default]
+    Queue,
+    /// Cancel the conflicting sequences and start as soon as they stopped
+    Preempt,
+    /// Run alongside them; the most recent step wins on shared relays
+    Merge,
+    /// Drop the new trigger
+    Reject,
+}
+
+/// What a sequence leaves behind when it is cancelled or preempted
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Cleanup {
+    /// Switch off every relay the sequence uses
+    #[default]
+    ReleaseTouched,
+    /// Play these steps (e.g. a controlled wind-down)
+    Steps(&'static [SequenceStep]),
+    /// Leave the relays as they are
+    None,
+}
+
 /// Configuration for a trigger-to-sequence mapping
 #[derive(Debug, Clone, Copy)]
 pub struct SequenceConfig {
     /// Which digital input triggers this sequence
     pub trigger: DigitalInput,
+    /// What the input has to do to start this sequence
+    pub gesture: Gesture,
     /// Cooldown duration in milliseconds
     pub cooldown_ms: u32,
     /// The sequence steps to execute
     pub sequence: &'static [SequenceStep],
     /// Optional name for logging
     pub name: &'static str,
+    /// Handling of relays shared with an already running sequence
+    pub conflict: ConflictPolicy,
+    /// Run when the sequence is cancelled before its last step
+    pub cleanup: Cleanup,
+    /// Program played instead of `sequence` when set
+    pub program: Option<&'static [Instruction]>,
 }
 
 impl SequenceConfig {
@@ -42,44 +336,143 @@ impl SequenceConfig {
     ) -> Self {
         Self {
             trigger,
+            gesture: Gesture::Trigger,
             cooldown_ms,
             sequence,
             name,
+            conflict: ConflictPolicy::Queue,
+            cleanup: Cleanup::ReleaseTouched,
+            program: None,
         }
     }
+
+    /// Start on a hold, double trigger or pattern instead of a trigger
+    pub const fn with_gesture(mut self, gesture: Gesture) -> Self {
+        self.gesture = gesture;
+        self
+    }
+
+    pub const fn with_conflict(mut self, conflict: ConflictPolicy) -> Self {
+        self.conflict = conflict;
+        self
+    }
+
+    pub const fn with_cleanup(mut self, cleanup: Cleanup) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Play a program (loops, calls, input waits) instead of `sequence`
+    pub const fn with_program(mut self, code: &'static [Instruction]) -> Self {
+        self.program = Some(code);
+        self
+    }
 }
 
 /// Manages sequence dispatch and per-sequence cooldown tracking
+///
+/// Plain-trigger mappings use the cooldown of their input; every other
+/// gesture mapping has a cooldown of its own.
 pub struct SequenceDispatcher {
     cooldowns: [Option<Instant>; 8],
     cooldown_durations: [Duration; 8],
+    gestures: heapless::Vec<GestureCooldown, MAX_GESTURES>,
+}
+
+/// Cooldown of a mapping with a gesture other than [`Gesture::Trigger`]
+struct GestureCooldown {
+    input: DigitalInput,
+    gesture: Gesture,
+    duration: Duration,
+    last: Option<Instant>,
 }
 
 impl SequenceDispatcher {
     /// Create a new dispatcher from a sequence configuration array
     pub fn new(configs: &[SequenceConfig]) -> Self {
         let mut cooldown_durations = [Duration::from_millis(0); 8];
+        let mut gestures = heapless::Vec::new();
 
-        // Set cooldown duration for each configured input
+        // Set cooldown duration for each configured input and gesture
         for config in configs {
-            let idx = config.trigger as usize;
-            cooldown_durations[idx] = Duration::from_millis(config.cooldown_ms as u64);
+            let duration = Duration::from_millis(config.cooldown_ms as u64);
+            if config.gesture == Gesture::Trigger {
+                cooldown_durations[config.trigger as usize] = duration;
+            } else {
+                // Mappings past the recognizer's capacity never fire
+                let _ = gestures.push(GestureCooldown {
+                    input: config.trigger,
+                    gesture: config.gesture,
+                    duration,
+                    last: None,
+                });
+            }
         }
 
         Self {
             cooldowns: [None; 8],
             cooldown_durations,
+            gestures,
+        }
+    }
+
+    /// Check if the mapping `config` is currently in cooldown
+    pub fn mapping_cooling_down(&self, config: &SequenceConfig) -> bool {
+        match self.gesture(config) {
+            Some(cooldown) => remaining(cooldown.last, cooldown.duration).is_some(),
+            None => self.is_cooling_down(config.trigger),
         }
     }
 
+    /// Cooldown of the mapping `config` in milliseconds
+    pub fn mapping_cooldown_ms(&self, config: &SequenceConfig) -> u32 {
+        match self.gesture(config) {
+            Some(cooldown) => cooldown.duration.as_millis() as u32,
+            None => self.cooldown_ms(config.trigger),
+        }
+    }
+
+    /// Remaining cooldown of the mapping `config` in milliseconds
+    pub fn mapping_remaining_ms(&self, config: &SequenceConfig) -> u64 {
+        match self.gesture(config) {
+            Some(cooldown) => {
+                remaining(cooldown.last, cooldown.duration).map_or(0, |left| left.as_millis())
+            }
+            None => self.remaining_ms(config.trigger),
+        }
+    }
+
+    /// Mark the mapping `config` as started (starts its cooldown)
+    pub fn mark_mapping(&mut self, config: &SequenceConfig) {
+        let gesture = self.gestures.iter_mut().find(|cooldown| {
+            cooldown.input == config.trigger && cooldown.gesture == config.gesture
+        });
+        match gesture {
+            Some(cooldown) => cooldown.last = Some(Instant::now()),
+            None => self.mark_triggered(config.trigger),
+        }
+    }
+
+    fn gesture(&self, config: &SequenceConfig) -> Option<&GestureCooldown> {
+        self.gestures
+            .iter()
+            .find(|cooldown| cooldown.input == config.trigger && cooldown.gesture == config.gesture)
+    }
+
     /// Check if an input is currently in cooldown
     pub fn is_cooling_down(&self, input: DigitalInput) -> bool {
         let idx = input as usize;
-        if let Some(last) = self.cooldowns[idx] {
-            Instant::now().duration_since(last) < self.cooldown_durations[idx]
-        } else {
-            false
-        }
+        remaining(self.cooldowns[idx], self.cooldown_durations[idx]).is_some()
+    }
+
+    /// Cooldown of an input in milliseconds
+    pub fn cooldown_ms(&self, input: DigitalInput) -> u32 {
+        self.cooldown_durations[input as usize].as_millis() as u32
+    }
+
+    /// Change the cooldown of an input until the next restart
+    pub fn set_cooldown_ms(&mut self, input: DigitalInput, cooldown_ms: u32) {
+        self.cooldown_durations[input as usize] = Duration::from_millis(cooldown_ms as u64);
     }
 
     /// Mark an input as triggered (starts cooldown)
@@ -90,28 +483,42 @@ impl SequenceDispatcher {
     /// Get remaining cooldown time in milliseconds
     pub fn remaining_ms(&self, input: DigitalInput) -> u64 {
         let idx = input as usize;
-        if let Some(last) = self.cooldowns[idx] {
-            let elapsed = Instant::now().duration_since(last);
-            if elapsed < self.cooldown_durations[idx] {
-                (self.cooldown_durations[idx] - elapsed).as_millis()
-            } else {
-                0
-            }
-        } else {
-            0
-        }
+        remaining(self.cooldowns[idx], self.cooldown_durations[idx])
+            .map_or(0, |left| left.as_millis())
     }
 
-    /// Find a matching sequence configuration for the given input
+    /// Find the sequence configuration started by a plain trigger of `input`
     pub fn find_config<'a>(
         &self,
         configs: &'a [SequenceConfig],
         input: DigitalInput,
     ) -> Option<&'a SequenceConfig> {
-        configs.iter().find(|cfg| cfg.trigger == input)
+        configs
+            .iter()
+            .find(|cfg| cfg.trigger == input && cfg.gesture == Gesture::Trigger)
+    }
+
+    /// Find the sequence configuration waiting for a recognized gesture
+    pub fn find_gesture<'a>(
+        &self,
+        configs: &'a [SequenceConfig],
+        event: &GestureEvent,
+    ) -> Option<&'a SequenceConfig> {
+        configs
+            .iter()
+            .find(|cfg| cfg.trigger == event.input && cfg.gesture == event.gesture)
     }
 }
 
+/// Cooldown left after starting at `last`, `None` once over
+fn remaining(last: Option<Instant>, duration: Duration) -> Option<Duration> {
+    let elapsed = Instant::now().duration_since(last?);
+    (elapsed < duration).then(|| duration - elapsed)
+}
+
+/// Dispatcher shared by the control task and remote control interfaces
+pub type SharedDispatcher = Mutex<CriticalSectionRawMutex, RefCell<SequenceDispatcher>>;
+
 // Pre-defined sequences
 pub const JUMP_SCARE: &[SequenceStep] = &[
     SequenceStep::new(RelayOutput::Relay1, RelayState::High, 1000),
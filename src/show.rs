@@ -0,0 +1,804 @@
+//! Show files: sequence programs and trigger mappings in a text format
+//!
+//! A show file replaces the `const` sequences and `SEQUENCE_CONFIGS`, so
+//! timings can change without reflashing:
+//!
+//! ```text
+//! # Lines starting with '#' are comments
+//! program flicker
+//!     loop 3
+//!         set R4 on
+//!         wait 80ms
+//!         set R4 off
+//!         wait 200..1500      # random wait
+//!     end
+//! end
+//!
+//! program scare
+//!     set R1 R2 on R3 off     # one write, same bank
+//!     wait 1s
+//!     skip 50%                # 50% chance to skip the next line
+//!     call flicker
+//!     choose flicker:3 calm   # weighted (default weight 1)
+//!     wait DI3                # until input DI3 triggers
+//!     set R1 R2 off
+//! end
+//!
+//! trigger DI1 scare cooldown 5s conflict preempt cleanup release
+//! trigger DI2 calm hold 3s            # held for 3s, fires while held
+//! trigger DI2 flicker release 3s      # let go after at least 3s
+//! trigger DI4 scare double 500ms      # second trigger within 500ms
+//! trigger DI1 calm after DI3,DI5 within 10s   # DI3, DI5, then DI1
+//! ```
+//!
+//! Relays are numbered across banks (R1-R8 on bank 0, R9-R16 on bank 1,
+//! ...). Programs must be defined before they are called or triggered,
+//! which also rules out recursion. `loop` takes a count or `forever`; a
+//! `skip` right before `end` never skips the `end`. Trigger options
+//! default to `cooldown 0 conflict queue cleanup release`.
+//! A trigger line takes at most one gesture (`hold`, `release`, `double` or
+//! `after`); an input may have several trigger lines with different
+//! gestures, each with its own cooldown. `after` patterns have no time
+//! limit without `within`.
+use core::fmt;
+use core::ops::Range;
+
+use crate::gesture::Gesture;
+use crate::hardware::{DigitalInput, RelayId, RelayMask};
+use crate::sequence::{
+    Choice, Cleanup, ConflictPolicy, Instruction, Program, SequenceConfig, LOOP_FOREVER,
+    MAX_CALL_DEPTH, MAX_LOOP_DEPTH,
+};
+
+pub const MAX_SHOW_INSTRUCTIONS: usize = 512;
+pub const MAX_SHOW_PROGRAMS: usize = 32;
+pub const MAX_SHOW_CHOICES: usize = 64;
+pub const MAX_SHOW_TRIGGERS: usize = 16;
+/// Longest program name, in bytes, so every name fits an API status reply
+pub const MAX_SHOW_NAME_LEN: usize = 32;
+/// Inputs listed by all `after` patterns together
+pub const MAX_SHOW_PATTERN_INPUTS: usize = 32;
+
+/// What is wrong at a [`ParseError`] position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ParseErrorKind {
+    UnknownCommand,
+    /// Instruction outside a program, or `program`/`trigger` inside one
+    MisplacedCommand,
+    MissingArgument,
+    UnexpectedArgument,
+    InvalidNumber,
+    InvalidDuration,
+    InvalidRelay,
+    InvalidInput,
+    /// Expected `on` or `off`
+    InvalidState,
+    /// Relays of one `set` on different banks
+    MixedBanks,
+    InvalidOption,
+    UnknownProgram,
+    DuplicateProgram,
+    DuplicateTrigger,
+    /// `program` or `loop` without `end`
+    UnclosedBlock,
+    LoopTooDeep,
+    CallTooDeep,
+    EmptyChoice,
+    /// Show exceeds one of the `MAX_SHOW_*` capacities
+    TooLarge,
+}
+
+impl ParseErrorKind {
+    pub const fn message(&self) -> &'static str {
+        match self {
+            Self::UnknownCommand => "unknown command",
+            Self::MisplacedCommand => "command not allowed here",
+            Self::MissingArgument => "missing argument",
+            Self::UnexpectedArgument => "unexpected argument",
+            Self::InvalidNumber => "invalid number",
+            Self::InvalidDuration => "invalid duration (e.g. 250, 250ms, 2s)",
+            Self::InvalidRelay => "invalid relay (R1, R2, ...)",
+            Self::InvalidInput => "invalid input (DI1-DI8)",
+            Self::InvalidState => "expected 'on' or 'off'",
+            Self::MixedBanks => "relays of one 'set' must be on the same bank",
+            Self::InvalidOption => "invalid trigger option",
+            Self::UnknownProgram => "unknown program (define it before use)",
+            Self::DuplicateProgram => "program already defined",
+            Self::DuplicateTrigger => "input already has this trigger",
+            Self::UnclosedBlock => "block is missing its 'end'",
+            Self::LoopTooDeep => "loops nested too deep",
+            Self::CallTooDeep => "calls nested too deep",
+            Self::EmptyChoice => "choose needs at least one non-zero weight",
+            Self::TooLarge => "show too large",
+        }
+    }
+}
+
+/// Show file error with its 1-based position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ParseError {
+    pub line: u32,
+    pub column: u32,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind.message())
+    }
+}
+
+/// Backing memory of a parsed show
+///
+/// Parsed programs refer into this storage and into the source text, so
+/// both must live as long as the show (usually in a `StaticCell`).
+pub struct ShowStorage {
+    instructions: heapless::Vec<Instruction, MAX_SHOW_INSTRUCTIONS>,
+    choices: heapless::Vec<Choice, MAX_SHOW_CHOICES>,
+    programs: heapless::Vec<Program, MAX_SHOW_PROGRAMS>,
+    configs: heapless::Vec<SequenceConfig, MAX_SHOW_TRIGGERS>,
+    patterns: heapless::Vec<DigitalInput, MAX_SHOW_PATTERN_INPUTS>,
+}
+
+impl Default for ShowStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShowStorage {
+    pub const fn new() -> Self {
+        Self {
+            instructions: heapless::Vec::new(),
+            choices: heapless::Vec::new(),
+            programs: heapless::Vec::new(),
+            configs: heapless::Vec::new(),
+            patterns: heapless::Vec::new(),
+        }
+    }
+}
+
+/// Parsed show: a program library plus trigger mappings
+#[derive(Debug, Clone, Copy)]
+pub struct Show {
+    /// Every program, for the runner's library and `Call`s
+    pub programs: &'static [Program],
+    /// One entry per `trigger` line, playing its program
+    pub configs: &'static [SequenceConfig],
+}
+
+/// Parse a show file
+///
+/// Stops at the first error. Instructions, choices and programs are placed
+/// in `storage`; names point into `source`.
+pub fn parse_show(
+    source: &'static str,
+    storage: &'static mut ShowStorage,
+) -> Result<Show, ParseError> {
+    let ShowStorage {
+        instructions,
+        choices,
+        programs,
+        configs,
+        patterns,
+    } = storage;
+    instructions.clear();
+    choices.clear();
+    programs.clear();
+    configs.clear();
+    patterns.clear();
+
+    let mut parser = Parser {
+        instructions,
+        choices,
+        patterns,
+        defs: heapless::Vec::new(),
+        patches: heapless::Vec::new(),
+        triggers: heapless::Vec::new(),
+        open: None,
+    };
+    let mut line_number = 0;
+    for line in source.lines() {
+        line_number += 1;
+        parser.parse_line(line, line_number)?;
+    }
+    if let Some(open) = &parser.open {
+        return Err(open.error(ParseErrorKind::UnclosedBlock));
+    }
+
+    // Every choice is known: point `Choose` instructions at them
+    let Parser {
+        instructions,
+        choices,
+        defs,
+        patches,
+        triggers,
+        patterns,
+        ..
+    } = parser;
+    let choices: &'static [Choice] = choices;
+    for patch in &patches {
+        instructions[patch.instruction] =
+            Instruction::Choose(&choices[patch.start..patch.start + patch.len]);
+    }
+
+    let instructions: &'static [Instruction] = instructions;
+    for def in &defs {
+        // Capacity matches `defs`, so this cannot fail
+        let _ = programs.push(Program::new(def.name, &instructions[def.start..def.end]));
+    }
+    let programs: &'static [Program] = programs;
+
+    let patterns: &'static [DigitalInput] = patterns;
+    for trigger in &triggers {
+        let program = &programs[trigger.program];
+        let gesture = match trigger.gesture {
+            Gesture::Pattern { within_ms, .. } => Gesture::Pattern {
+                after: &patterns[trigger.after.clone()],
+                within_ms,
+            },
+            gesture => gesture,
+        };
+        let config = SequenceConfig::new(trigger.input, trigger.cooldown_ms, &[], program.name)
+            .with_gesture(gesture)
+            .with_program(program.code)
+            .with_conflict(trigger.conflict)
+            .with_cleanup(trigger.cleanup);
+        let _ = configs.push(config);
+    }
+
+    Ok(Show { programs, configs })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    text: &'static str,
+    line: u32,
+    column: u32,
+}
+
+impl Token {
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            kind,
+        }
+    }
+}
+
+/// Whitespace-separated words of one line, comments stripped
+struct Tokens {
+    rest: &'static str,
+    line: u32,
+    column: u32,
+}
+
+impl Tokens {
+    fn new(text: &'static str, line: u32) -> Self {
+        let text = match text.find('#') {
+            Some(comment) => &text[..comment],
+            None => text,
+        };
+        Self {
+            rest: text,
+            line,
+            column: 1,
+        }
+    }
+
+    /// Next word, or a `MissingArgument` error just past the line's end
+    fn expect(&mut self) -> Result<Token, ParseError> {
+        self.next().ok_or(ParseError {
+            line: self.line,
+            column: self.column,
+            kind: ParseErrorKind::MissingArgument,
+        })
+    }
+
+    fn finish(&mut self) -> Result<(), ParseError> {
+        match self.next() {
+            Some(extra) => Err(extra.error(ParseErrorKind::UnexpectedArgument)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Iterator for Tokens {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let start = self.rest.len() - self.rest.trim_start().len();
+        self.column += start as u32;
+        self.rest = &self.rest[start..];
+        if self.rest.is_empty() {
+            return None;
+        }
+        let len = self
+            .rest
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest.len());
+        let token = Token {
+            text: &self.rest[..len],
+            line: self.line,
+            column: self.column,
+        };
+        self.rest = &self.rest[len..];
+        self.column += len as u32;
+        Some(token)
+    }
+}
+
+/// Program seen so far
+struct ProgramDef {
+    name: &'static str,
+    start: usize,
+    end: usize,
+    /// Call depth including itself
+    depth: usize,
+}
+
+/// Program currently being parsed
+struct OpenProgram {
+    def: ProgramDef,
+    /// `program` keyword position, for an unclosed block
+    token: Token,
+    /// Open `loop` keywords
+    loops: heapless::Vec<Token, MAX_LOOP_DEPTH>,
+}
+
+impl OpenProgram {
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        match self.loops.last() {
+            Some(token) => token.error(kind),
+            None => self.token.error(kind),
+        }
+    }
+}
+
+/// `Choose` instruction waiting for the frozen choice list
+struct ChoosePatch {
+    instruction: usize,
+    start: usize,
+    len: usize,
+}
+
+struct Trigger {
+    input: DigitalInput,
+    /// `Pattern` inputs are in `after` until the pattern list is final
+    gesture: Gesture,
+    after: Range<usize>,
+    program: usize,
+    cooldown_ms: u32,
+    conflict: ConflictPolicy,
+    cleanup: Cleanup,
+}
+
+struct Parser {
+    instructions: &'static mut heapless::Vec<Instruction, MAX_SHOW_INSTRUCTIONS>,
+    choices: &'static mut heapless::Vec<Choice, MAX_SHOW_CHOICES>,
+    patterns: &'static mut heapless::Vec<DigitalInput, MAX_SHOW_PATTERN_INPUTS>,
+    defs: heapless::Vec<ProgramDef, MAX_SHOW_PROGRAMS>,
+    patches: heapless::Vec<ChoosePatch, MAX_SHOW_CHOICES>,
+    triggers: heapless::Vec<Trigger, MAX_SHOW_TRIGGERS>,
+    open: Option<OpenProgram>,
+}
+
+impl Parser {
+    fn parse_line(&mut self, line: &'static str, number: u32) -> Result<(), ParseError> {
+        let mut tokens = Tokens::new(line, number);
+        let Some(command) = tokens.next() else {
+            return Ok(());
+        };
+
+        match (command.text, self.open.is_some()) {
+            ("program", false) => self.begin_program(command, &mut tokens),
+            ("trigger", false) => self.trigger(&mut tokens),
+            ("program" | "trigger", true) => Err(command.error(ParseErrorKind::MisplacedCommand)),
+            ("end", true) => self.end(&mut tokens),
+            ("set" | "wait" | "loop" | "call" | "choose" | "skip" | "end", false) => {
+                Err(command.error(ParseErrorKind::MisplacedCommand))
+            }
+            ("set", true) => self.set(command, &mut tokens),
+            ("wait", true) => self.wait(command, &mut tokens),
+            ("loop", true) => self.begin_loop(command, &mut tokens),
+            ("call", true) => self.call(command, &mut tokens),
+            ("choose", true) => self.choose(command, &mut tokens),
+            ("skip", true) => self.skip(command, &mut tokens),
+            _ => Err(command.error(ParseErrorKind::UnknownCommand)),
+        }
+    }
+
+    fn find(&self, name: &Token) -> Result<usize, ParseError> {
+        self.defs
+            .iter()
+            .position(|def| def.name == name.text)
+            .ok_or(name.error(ParseErrorKind::UnknownProgram))
+    }
+
+    fn push(&mut self, token: Token, instruction: Instruction) -> Result<(), ParseError> {
+        self.instructions
+            .push(instruction)
+            .map_err(|_| token.error(ParseErrorKind::TooLarge))
+    }
+
+    fn open(&mut self) -> &mut OpenProgram {
+        self.open.as_mut().expect("instruction outside a program")
+    }
+
+    fn begin_program(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let name = tokens.expect()?;
+        tokens.finish()?;
+        if self.find(&name).is_ok() {
+            return Err(name.error(ParseErrorKind::DuplicateProgram));
+        }
+        if self.defs.is_full() || name.text.len() > MAX_SHOW_NAME_LEN {
+            return Err(name.error(ParseErrorKind::TooLarge));
+        }
+        self.open = Some(OpenProgram {
+            def: ProgramDef {
+                name: name.text,
+                start: self.instructions.len(),
+                end: 0,
+                depth: 1,
+            },
+            token: command,
+            loops: heapless::Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn end(&mut self, tokens: &mut Tokens) -> Result<(), ParseError> {
+        tokens.finish()?;
+        let end = self.instructions.len();
+        let open = self.open();
+        if open.loops.pop().is_some() {
+            return self.push_end();
+        }
+        let mut def = self.open.take().expect("checked by caller").def;
+        def.end = end;
+        // Room was checked when the program was opened
+        let _ = self.defs.push(def);
+        Ok(())
+    }
+
+    fn push_end(&mut self) -> Result<(), ParseError> {
+        self.instructions.push(Instruction::End).map_err(|_| {
+            let open = self.open.as_ref().expect("checked by caller");
+            open.token.error(ParseErrorKind::TooLarge)
+        })
+    }
+
+    fn set(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let mut bank = None;
+        let mut pending = 0u8;
+        let mut relays = 0u8;
+        let mut levels = 0u8;
+        let mut last = command;
+
+        for token in tokens.by_ref() {
+            last = token;
+            if let Some(state) = parse_state(token.text) {
+                if pending == 0 {
+                    return Err(token.error(ParseErrorKind::InvalidRelay));
+                }
+                relays |= pending;
+                if state {
+                    levels |= pending;
+                }
+                pending = 0;
+                continue;
+            }
+            let relay = parse_relay(&token)?;
+            match bank {
+                None => bank = Some(relay.bank),
+                Some(bank) if bank != relay.bank => {
+                    return Err(token.error(ParseErrorKind::MixedBanks))
+                }
+                Some(_) => {}
+            }
+            pending |= relay.mask().bits();
+        }
+
+        if pending != 0 {
+            return Err(after(&last, ParseErrorKind::MissingArgument));
+        }
+        let Some(bank) = bank else {
+            return Err(after(&command, ParseErrorKind::MissingArgument));
+        };
+        self.push(
+            command,
+            Instruction::on_bank(bank, RelayMask(relays), RelayMask(levels)),
+        )
+    }
+
+    fn wait(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let argument = tokens.expect()?;
+        tokens.finish()?;
+        let instruction = if starts_with_ignore_case(argument.text, "DI") {
+            Instruction::WaitInput(parse_input(&argument)?)
+        } else if let Some((min, max)) = argument.text.split_once("..") {
+            Instruction::WaitRandom {
+                min_ms: parse_duration(min, &argument)?,
+                max_ms: parse_duration(max, &argument)?,
+            }
+        } else {
+            Instruction::Wait(parse_duration(argument.text, &argument)?)
+        };
+        self.push(command, instruction)
+    }
+
+    fn begin_loop(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let count = tokens.expect()?;
+        tokens.finish()?;
+        let count = if count.text == "forever" {
+            LOOP_FOREVER
+        } else {
+            match parse_number(&count)? {
+                0 => return Err(count.error(ParseErrorKind::InvalidNumber)),
+                n => n,
+            }
+        };
+        self.open()
+            .loops
+            .push(command)
+            .map_err(|_| command.error(ParseErrorKind::LoopTooDeep))?;
+        self.push(command, Instruction::Loop(count))
+    }
+
+    /// Account for a call to `name` in the open program's call depth
+    fn add_call(&mut self, name: &Token) -> Result<&'static str, ParseError> {
+        let index = self.find(name)?;
+        let (callee, depth) = (self.defs[index].name, self.defs[index].depth + 1);
+        if depth > MAX_CALL_DEPTH {
+            return Err(name.error(ParseErrorKind::CallTooDeep));
+        }
+        let open = self.open();
+        open.def.depth = open.def.depth.max(depth);
+        Ok(callee)
+    }
+
+    fn call(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let name = tokens.expect()?;
+        tokens.finish()?;
+        let callee = self.add_call(&name)?;
+        self.push(command, Instruction::Call(callee))
+    }
+
+    fn choose(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let start = self.choices.len();
+        let mut total = 0u32;
+        for option in tokens.by_ref() {
+            let (name, weight) = match option.text.split_once(':') {
+                Some((name, weight)) => {
+                    let weight = weight
+                        .parse::<u16>()
+                        .map_err(|_| option.error(ParseErrorKind::InvalidNumber))?;
+                    (name, weight)
+                }
+                None => (option.text, 1),
+            };
+            let name = Token {
+                text: name,
+                ..option
+            };
+            let program = self.add_call(&name)?;
+            self.choices
+                .push(Choice::new(weight, program))
+                .map_err(|_| option.error(ParseErrorKind::TooLarge))?;
+            total += weight as u32;
+        }
+
+        let len = self.choices.len() - start;
+        if len == 0 {
+            return Err(after(&command, ParseErrorKind::MissingArgument));
+        }
+        if total == 0 {
+            return Err(command.error(ParseErrorKind::EmptyChoice));
+        }
+        self.patches
+            .push(ChoosePatch {
+                instruction: self.instructions.len(),
+                start,
+                len,
+            })
+            .map_err(|_| command.error(ParseErrorKind::TooLarge))?;
+        // Placeholder until the choice list is final
+        self.push(command, Instruction::Choose(&[]))
+    }
+
+    fn skip(&mut self, command: Token, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let percent = tokens.expect()?;
+        tokens.finish()?;
+        let digits = percent.text.strip_suffix('%').unwrap_or(percent.text);
+        let value = digits
+            .parse::<u8>()
+            .ok()
+            .filter(|value| *value <= 100)
+            .ok_or(percent.error(ParseErrorKind::InvalidNumber))?;
+        self.push(command, Instruction::Skip(value))
+    }
+
+    fn trigger(&mut self, tokens: &mut Tokens) -> Result<(), ParseError> {
+        let input_token = tokens.expect()?;
+        let input = parse_input(&input_token)?;
+        let name = tokens.expect()?;
+        let program = self.find(&name)?;
+
+        let mut trigger = Trigger {
+            input,
+            gesture: Gesture::Trigger,
+            after: 0..0,
+            program,
+            cooldown_ms: 0,
+            conflict: ConflictPolicy::Queue,
+            cleanup: Cleanup::ReleaseTouched,
+        };
+        let mut within = None;
+        while let Some(key) = tokens.next() {
+            let value = tokens.expect()?;
+            let gesture = match key.text {
+                "cooldown" => {
+                    trigger.cooldown_ms = parse_duration(value.text, &value)?;
+                    None
+                }
+                "conflict" => {
+                    trigger.conflict = match value.text {
+                        "queue" => ConflictPolicy::Queue,
+                        "preempt" => ConflictPolicy::Preempt,
+                        "merge" => ConflictPolicy::Merge,
+                        "reject" => ConflictPolicy::Reject,
+                        _ => return Err(value.error(ParseErrorKind::InvalidOption)),
+                    };
+                    None
+                }
+                "cleanup" => {
+                    trigger.cleanup = match value.text {
+                        "release" => Cleanup::ReleaseTouched,
+                        "none" => Cleanup::None,
+                        _ => return Err(value.error(ParseErrorKind::InvalidOption)),
+                    };
+                    None
+                }
+                "hold" => Some(Gesture::Hold(parse_duration(value.text, &value)?)),
+                "release" => Some(Gesture::Release(parse_duration(value.text, &value)?)),
+                "double" => Some(Gesture::Double(parse_duration(value.text, &value)?)),
+                "after" => {
+                    trigger.after = self.pattern(&value)?;
+                    Some(Gesture::Pattern {
+                        after: &[],
+                        within_ms: 0,
+                    })
+                }
+                "within" => {
+                    within = Some((key, parse_duration(value.text, &value)?));
+                    None
+                }
+                _ => return Err(key.error(ParseErrorKind::InvalidOption)),
+            };
+            if let Some(gesture) = gesture {
+                // One gesture per line
+                if trigger.gesture != Gesture::Trigger {
+                    return Err(key.error(ParseErrorKind::InvalidOption));
+                }
+                trigger.gesture = gesture;
+            }
+        }
+        if let Some((key, within_ms)) = within {
+            let Gesture::Pattern { after, .. } = trigger.gesture else {
+                return Err(key.error(ParseErrorKind::InvalidOption));
+            };
+            trigger.gesture = Gesture::Pattern { after, within_ms };
+        }
+
+        if self.triggers.iter().any(|other| self.same(other, &trigger)) {
+            return Err(input_token.error(ParseErrorKind::DuplicateTrigger));
+        }
+        self.triggers
+            .push(trigger)
+            .map_err(|_| input_token.error(ParseErrorKind::TooLarge))
+    }
+
+    /// Comma-separated `after` inputs, appended to the pattern list
+    fn pattern(&mut self, token: &Token) -> Result<Range<usize>, ParseError> {
+        let start = self.patterns.len();
+        let mut column = token.column;
+        for text in token.text.split(',') {
+            let part = Token {
+                text,
+                line: token.line,
+                column,
+            };
+            column += text.len() as u32 + 1;
+            let input = parse_input(&part)?;
+            self.patterns
+                .push(input)
+                .map_err(|_| part.error(ParseErrorKind::TooLarge))?;
+        }
+        Ok(start..self.patterns.len())
+    }
+
+    /// Whether two trigger lines wait for the same thing
+    fn same(&self, a: &Trigger, b: &Trigger) -> bool {
+        a.input == b.input
+            && a.gesture == b.gesture
+            && self.patterns[a.after.clone()] == self.patterns[b.after.clone()]
+    }
+}
+
+/// Error positioned just after `token`
+fn after(token: &Token, kind: ParseErrorKind) -> ParseError {
+    ParseError {
+        line: token.line,
+        column: token.column + token.text.len() as u32,
+        kind,
+    }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.get(..prefix.len())
+        .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+}
+
+fn parse_number(token: &Token) -> Result<u32, ParseError> {
+    token
+        .text
+        .parse()
+        .map_err(|_| token.error(ParseErrorKind::InvalidNumber))
+}
+
+/// `250`, `250ms` or `2s`, in milliseconds
+fn parse_duration(text: &str, token: &Token) -> Result<u32, ParseError> {
+    let invalid = || token.error(ParseErrorKind::InvalidDuration);
+    let (digits, scale) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = text.strip_suffix('s') {
+        (s, 1000)
+    } else {
+        (text, 1)
+    };
+    digits
+        .parse::<u32>()
+        .ok()
+        .and_then(|value| value.checked_mul(scale))
+        .ok_or_else(invalid)
+}
+
+fn parse_relay(token: &Token) -> Result<RelayId, ParseError> {
+    let invalid = || token.error(ParseErrorKind::InvalidRelay);
+    if !starts_with_ignore_case(token.text, "R") {
+        return Err(invalid());
+    }
+    match token.text[1..].parse::<u16>() {
+        Ok(number @ 1..) => RelayId::from_index(number - 1).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_input(token: &Token) -> Result<DigitalInput, ParseError> {
+    let invalid = || token.error(ParseErrorKind::InvalidInput);
+    if !starts_with_ignore_case(token.text, "DI") {
+        return Err(invalid());
+    }
+    Ok(
+        match token.text[2..].parse::<u8>().map_err(|_| invalid())? {
+            1 => DigitalInput::DI1,
+            2 => DigitalInput::DI2,
+            3 => DigitalInput::DI3,
+            4 => DigitalInput::DI4,
+            5 => DigitalInput::DI5,
+            6 => DigitalInput::DI6,
+            7 => DigitalInput::DI7,
+            8 => DigitalInput::DI8,
+            _ => return Err(invalid()),
+        },
+    )
+}
+
+fn parse_state(text: &str) -> Option<bool> {
+    match text {
+        "on" | "high" => Some(true),
+        "off" | "low" => Some(false),
+        _ => None,
+    }
+}
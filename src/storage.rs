@@ -0,0 +1,939 @@
+//! Persistent configuration in a dedicated flash partition
+//!
+//! The partition is split into two slots, A and B. Each save erases and
+//! writes the slot that does *not* hold the newest record, so the previous
+//! configuration survives a power loss mid-write and both halves wear at
+//! the same rate. A slot holds one record:
+//!
+//! ```text
+//! offset 0   header   magic "PRCF", schema version, settings length,
+//!                     sequence number, show length, CRC-32
+//! offset 24  settings (see `Settings`), padded to a flash word
+//!            show     show file text (sequences, triggers, cooldowns)
+//! ```
+//!
+//! The header is written last and the CRC covers header and payload, so a
+//! torn write is simply an invalid slot. On load the valid record with the
+//! highest sequence number wins. Records from an older schema load with
+//! defaults for the fields their version predates; records from a newer
+//! schema are ignored.
+use core::fmt;
+use core::str;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+
+use crate::hardware::DigitalInput;
+
+/// Reads and writes are done in whole words of this many bytes
+pub const FLASH_WORD: u32 = 4;
+/// Largest show file the store accepts
+pub const MAX_SHOW_BYTES: usize = 16 * 1024;
+/// Record layout written by this firmware
+pub const SCHEMA_VERSION: u16 = 8;
+pub const DEFAULT_DEBOUNCE_MS: u16 = 100;
+
+const MAGIC: [u8; 4] = *b"PRCF";
+const HEADER_LEN: u32 = 24;
+/// Encoded size of [`Settings`] in each schema version, version 1 first:
+/// debounce and network (1), then OSC (2), MQTT (3), DMX (4), Modbus RTU
+/// (5), Wi-Fi (6), input modes (7) and the Bluetooth switch (8) appended
+/// in turn
+const SETTINGS_LENS: [usize; SCHEMA_VERSION as usize] = [30, 38, 44, 52, 58, 154, 162, 163];
+const SETTINGS_LEN: usize = SETTINGS_LENS[SCHEMA_VERSION as usize - 1];
+const SLOTS: u32 = 2;
+
+/// Flash driver failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlashError {
+    OutOfBounds,
+    /// Offset or length not a multiple of [`FLASH_WORD`] or of the sector
+    NotAligned,
+    /// Device reported an error
+    Io,
+}
+
+/// Raw access to the configuration partition
+///
+/// Offsets are relative to the start of the partition. Like NOR flash,
+/// `write` can only clear bits; `erase` sets whole sectors back to `0xFF`.
+pub trait Flash {
+    /// Erase granularity in bytes
+    const SECTOR_SIZE: u32;
+
+    /// Partition size in bytes
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError>;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError>;
+
+    /// Erase `from..to`; both must be sector aligned
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError>;
+}
+
+/// Configuration store failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StorageError {
+    Flash(FlashError),
+    /// Show does not fit in a slot or in the load buffer
+    TooLarge,
+}
+
+impl From<FlashError> for StorageError {
+    fn from(error: FlashError) -> Self {
+        StorageError::Flash(error)
+    }
+}
+
+/// Wired network address configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct NetworkSettings {
+    /// Ask a DHCP server; the static fields are ignored when set
+    pub dhcp: bool,
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: [u8; 4],
+    pub dns: [u8; 4],
+}
+
+impl NetworkSettings {
+    pub const DHCP: Self = Self {
+        dhcp: true,
+        address: [0; 4],
+        prefix_len: 24,
+        gateway: [0; 4],
+        dns: [0; 4],
+    };
+
+    pub const fn fixed(address: [u8; 4], prefix_len: u8, gateway: [u8; 4]) -> Self {
+        Self {
+            dhcp: false,
+            address,
+            prefix_len,
+            gateway,
+            dns: gateway,
+        }
+    }
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self::DHCP
+    }
+}
+
+/// OSC listener and trigger feedback, see [`crate::osc`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct OscSettings {
+    /// UDP port to listen on, 0 turns OSC off
+    pub port: u16,
+    /// Where input triggers are sent; `0.0.0.0` sends them to whoever
+    /// last sent a message
+    pub target: [u8; 4],
+    /// Port input triggers are sent to, 0 sends none
+    pub target_port: u16,
+}
+
+impl OscSettings {
+    pub const DEFAULT: Self = Self {
+        port: 8000,
+        target: [0; 4],
+        target_port: 9000,
+    };
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// MQTT broker to connect to, see [`crate::mqtt`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MqttSettings {
+    /// Broker address, `0.0.0.0` turns MQTT off
+    pub broker: [u8; 4],
+    pub port: u16,
+}
+
+impl MqttSettings {
+    pub const DEFAULT: Self = Self {
+        broker: [0; 4],
+        port: 1883,
+    };
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What DMX-driven relays do when every source has gone quiet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SignalLoss {
+    /// Leave the relays as the last frame set them
+    HoldLastLook,
+    /// Stop all sequences and switch every relay off
+    AllOff,
+}
+
+/// Art-Net and sACN receiver, see [`crate::dmx`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DmxSettings {
+    pub artnet: bool,
+    pub sacn: bool,
+    /// Art-Net port-address or sACN universe to follow
+    pub universe: u16,
+    /// DMX channel of relay R1, 1 to 512
+    pub start_address: u16,
+    /// Level at or above which a relay channel switches its relay on
+    pub threshold: u8,
+    /// Level a sequence channel must rise to, from below, to fire
+    pub trigger_level: u8,
+    pub on_loss: SignalLoss,
+}
+
+impl DmxSettings {
+    pub const DEFAULT: Self = Self {
+        artnet: false,
+        sacn: false,
+        universe: 1,
+        start_address: 1,
+        threshold: 128,
+        trigger_level: 128,
+        on_loss: SignalLoss::HoldLastLook,
+    };
+}
+
+impl Default for DmxSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Parity bit of a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Parity {
+    /// No parity bit; two stop bits keep the character at 11 bits
+    None,
+    Even,
+    Odd,
+}
+
+/// Modbus RTU slave on the RS485 port, see [`crate::modbus`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ModbusRtuSettings {
+    /// Slave address 1 to 247, 0 turns the RS485 port off
+    pub address: u8,
+    pub baud: u32,
+    pub parity: Parity,
+}
+
+impl ModbusRtuSettings {
+    /// Modbus over serial line default: 19200 baud, even parity
+    pub const DEFAULT: Self = Self {
+        address: 1,
+        baud: 19_200,
+        parity: Parity::Even,
+    };
+}
+
+impl Default for ModbusRtuSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What makes a digital input trigger, see [`crate::input::TriggerDetector`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TriggerMode {
+    /// Going active, e.g. a beam broken or a button pressed
+    Rising,
+    /// Going inactive, e.g. a button released
+    Falling,
+    BothEdges,
+    /// Going active, then again every
+    /// [`LEVEL_REPEAT_MS`](crate::input::LEVEL_REPEAT_MS) while it stays
+    /// active
+    LevelHigh,
+    /// Going inactive, then again every
+    /// [`LEVEL_REPEAT_MS`](crate::input::LEVEL_REPEAT_MS) while it stays
+    /// inactive
+    LevelLow,
+}
+
+/// Bias resistor of a digital input pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InputPull {
+    Up,
+    Down,
+    /// For sensors that drive the line both ways
+    None,
+}
+
+/// Trigger mode and wiring of one digital input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct InputSettings {
+    pub mode: TriggerMode,
+    pub pull: InputPull,
+    /// Active low: the pin level is inverted before anything else looks at
+    /// it, including the level shown on status displays
+    pub inverted: bool,
+}
+
+impl InputSettings {
+    /// Rising edges with the pull-up on, as the inputs always worked
+    pub const DEFAULT: Self = Self {
+        mode: TriggerMode::Rising,
+        pull: InputPull::Up,
+        inverted: false,
+    };
+
+    /// Bits 0-2 mode, bits 3-4 pull, bit 5 inversion; 0 is the default
+    fn encode(&self) -> u8 {
+        let mode = match self.mode {
+            TriggerMode::Rising => 0,
+            TriggerMode::Falling => 1,
+            TriggerMode::BothEdges => 2,
+            TriggerMode::LevelHigh => 3,
+            TriggerMode::LevelLow => 4,
+        };
+        let pull = match self.pull {
+            InputPull::Up => 0,
+            InputPull::Down => 1,
+            InputPull::None => 2,
+        };
+        mode | pull << 3 | (self.inverted as u8) << 5
+    }
+
+    fn decode(byte: u8) -> Self {
+        Self {
+            mode: match byte & 0x07 {
+                1 => TriggerMode::Falling,
+                2 => TriggerMode::BothEdges,
+                3 => TriggerMode::LevelHigh,
+                4 => TriggerMode::LevelLow,
+                _ => TriggerMode::Rising,
+            },
+            pull: match byte >> 3 & 0x03 {
+                1 => InputPull::Down,
+                2 => InputPull::None,
+                _ => InputPull::Up,
+            },
+            inverted: byte & 0x20 != 0,
+        }
+    }
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Network the Wi-Fi station joins, see [`crate::wifi`]
+///
+/// Both fields are UTF-8, zero-padded. The password is stored in plain
+/// text in the configuration partition, so anyone who can read the flash
+/// can read it; it is left out of logs and `Debug` output, and no API or
+/// BLE readback returns it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct WifiSettings {
+    ssid: [u8; 32],
+    password: [u8; 64],
+}
+
+impl WifiSettings {
+    /// No network: Wi-Fi comes up as the setup access point
+    pub const UNCONFIGURED: Self = Self {
+        ssid: [0; 32],
+        password: [0; 64],
+    };
+
+    /// `None` if either does not fit or the SSID is empty
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        let mut settings = Self::UNCONFIGURED;
+        if ssid.is_empty() {
+            return None;
+        }
+        settings
+            .ssid
+            .get_mut(..ssid.len())?
+            .copy_from_slice(ssid.as_bytes());
+        settings
+            .password
+            .get_mut(..password.len())?
+            .copy_from_slice(password.as_bytes());
+        Some(settings)
+    }
+
+    pub fn ssid(&self) -> &str {
+        padded_str(&self.ssid)
+    }
+
+    /// Empty for an open network
+    pub fn password(&self) -> &str {
+        padded_str(&self.password)
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.ssid().is_empty()
+    }
+}
+
+impl Default for WifiSettings {
+    fn default() -> Self {
+        Self::UNCONFIGURED
+    }
+}
+
+impl fmt::Debug for WifiSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiSettings")
+            .field("ssid", &self.ssid())
+            .finish_non_exhaustive()
+    }
+}
+
+impl defmt::Format for WifiSettings {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "WifiSettings {{ ssid: {=str} }}", self.ssid())
+    }
+}
+
+/// Device settings stored next to the show file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    /// Time a digital input must hold a new level before it counts, DI1
+    /// first
+    pub debounce_ms: [u16; 8],
+    pub network: NetworkSettings,
+    pub osc: OscSettings,
+    pub mqtt: MqttSettings,
+    pub dmx: DmxSettings,
+    pub modbus_rtu: ModbusRtuSettings,
+    pub wifi: WifiSettings,
+    /// Trigger mode and wiring per digital input, DI1 first
+    pub inputs: [InputSettings; 8],
+    /// Whether the Bluetooth service runs, see [`crate::ble`]; off unless
+    /// switched on for commissioning
+    pub ble_enabled: bool,
+}
+
+impl Settings {
+    pub const DEFAULT: Self = Self {
+        debounce_ms: [DEFAULT_DEBOUNCE_MS; 8],
+        network: NetworkSettings::DHCP,
+        osc: OscSettings::DEFAULT,
+        mqtt: MqttSettings::DEFAULT,
+        dmx: DmxSettings::DEFAULT,
+        modbus_rtu: ModbusRtuSettings::DEFAULT,
+        wifi: WifiSettings::UNCONFIGURED,
+        inputs: [InputSettings::DEFAULT; 8],
+        ble_enabled: false,
+    };
+
+    pub const fn debounce_ms(&self, input: DigitalInput) -> u32 {
+        self.debounce_ms[input as usize] as u32
+    }
+
+    pub const fn input(&self, input: DigitalInput) -> InputSettings {
+        self.inputs[input as usize]
+    }
+
+    fn encode(&self) -> [u8; SETTINGS_LEN] {
+        let mut bytes = [0; SETTINGS_LEN];
+        for (chunk, ms) in bytes[..16].chunks_exact_mut(2).zip(self.debounce_ms) {
+            chunk.copy_from_slice(&ms.to_le_bytes());
+        }
+        let network = &self.network;
+        bytes[16] = network.dhcp as u8;
+        bytes[17] = network.prefix_len;
+        bytes[18..22].copy_from_slice(&network.address);
+        bytes[22..26].copy_from_slice(&network.gateway);
+        bytes[26..30].copy_from_slice(&network.dns);
+        let osc = &self.osc;
+        bytes[30..32].copy_from_slice(&osc.port.to_le_bytes());
+        bytes[32..36].copy_from_slice(&osc.target);
+        bytes[36..38].copy_from_slice(&osc.target_port.to_le_bytes());
+        bytes[38..42].copy_from_slice(&self.mqtt.broker);
+        bytes[42..44].copy_from_slice(&self.mqtt.port.to_le_bytes());
+        let dmx = &self.dmx;
+        bytes[44] = dmx.artnet as u8 | (dmx.sacn as u8) << 1;
+        bytes[45] = match dmx.on_loss {
+            SignalLoss::HoldLastLook => 0,
+            SignalLoss::AllOff => 1,
+        };
+        bytes[46..48].copy_from_slice(&dmx.universe.to_le_bytes());
+        bytes[48..50].copy_from_slice(&dmx.start_address.to_le_bytes());
+        bytes[50] = dmx.threshold;
+        bytes[51] = dmx.trigger_level;
+        let rtu = &self.modbus_rtu;
+        bytes[52] = rtu.address;
+        bytes[53] = match rtu.parity {
+            Parity::None => 0,
+            Parity::Even => 1,
+            Parity::Odd => 2,
+        };
+        bytes[54..58].copy_from_slice(&rtu.baud.to_le_bytes());
+        bytes[58..90].copy_from_slice(&self.wifi.ssid);
+        bytes[90..154].copy_from_slice(&self.wifi.password);
+        for (byte, input) in bytes[154..162].iter_mut().zip(&self.inputs) {
+            *byte = input.encode();
+        }
+        bytes[162] = self.ble_enabled as u8;
+        bytes
+    }
+
+    /// Settings from an encoding of schema `version`, at least as long as
+    /// that version's layout; fields added in later versions keep their
+    /// defaults
+    fn decode(bytes: &[u8], version: u16) -> Self {
+        let mut settings = Self::DEFAULT;
+        for (ms, chunk) in settings.debounce_ms.iter_mut().zip(bytes.chunks_exact(2)) {
+            *ms = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        settings.network = NetworkSettings {
+            dhcp: bytes[16] != 0,
+            prefix_len: bytes[17],
+            address: word(&bytes[18..]),
+            gateway: word(&bytes[22..]),
+            dns: word(&bytes[26..]),
+        };
+        if version >= 2 {
+            settings.osc = OscSettings {
+                port: u16::from_le_bytes([bytes[30], bytes[31]]),
+                target: word(&bytes[32..]),
+                target_port: u16::from_le_bytes([bytes[36], bytes[37]]),
+            };
+        }
+        if version >= 3 {
+            settings.mqtt = MqttSettings {
+                broker: word(&bytes[38..]),
+                port: u16::from_le_bytes([bytes[42], bytes[43]]),
+            };
+        }
+        if version >= 4 {
+            settings.dmx = DmxSettings {
+                artnet: bytes[44] & 1 != 0,
+                sacn: bytes[44] & 2 != 0,
+                universe: u16::from_le_bytes([bytes[46], bytes[47]]),
+                start_address: u16::from_le_bytes([bytes[48], bytes[49]]),
+                threshold: bytes[50],
+                trigger_level: bytes[51],
+                on_loss: match bytes[45] {
+                    1 => SignalLoss::AllOff,
+                    _ => SignalLoss::HoldLastLook,
+                },
+            };
+        }
+        if version >= 5 {
+            settings.modbus_rtu = ModbusRtuSettings {
+                address: bytes[52],
+                baud: u32::from_le_bytes(word(&bytes[54..])),
+                parity: match bytes[53] {
+                    0 => Parity::None,
+                    2 => Parity::Odd,
+                    _ => Parity::Even,
+                },
+            };
+        }
+        if version >= 6 {
+            settings.wifi.ssid.copy_from_slice(&bytes[58..90]);
+            settings.wifi.password.copy_from_slice(&bytes[90..154]);
+        }
+        if version >= 7 {
+            for (input, &byte) in settings.inputs.iter_mut().zip(&bytes[154..162]) {
+                *input = InputSettings::decode(byte);
+            }
+        }
+        if version >= 8 {
+            settings.ble_enabled = bytes[162] != 0;
+        }
+        settings
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Configuration read back from flash
+#[derive(Debug, Clone, Copy)]
+pub struct StoredConfig<'a> {
+    pub settings: Settings,
+    /// Show file text, see [`crate::show`]
+    pub show: &'a str,
+    /// Incremented on every save
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    version: u16,
+    settings_len: u16,
+    sequence: u32,
+    show_len: u32,
+    crc: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0; HEADER_LEN as usize];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.settings_len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.show_len.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN as usize]) -> Option<Self> {
+        if bytes[0..4] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            settings_len: u16::from_le_bytes([bytes[6], bytes[7]]),
+            sequence: u32::from_le_bytes(word(&bytes[8..])),
+            show_len: u32::from_le_bytes(word(&bytes[12..])),
+            crc: u32::from_le_bytes(word(&bytes[16..])),
+        })
+    }
+
+    /// Offset of the show text within the slot
+    fn show_offset(&self) -> u32 {
+        HEADER_LEN + align(self.settings_len as u32)
+    }
+
+    /// CRC of the header fields, to be continued over the payload
+    fn crc_start(&self) -> Crc32 {
+        let mut crc = Crc32::new();
+        crc.update(&self.encode()[4..16]);
+        crc
+    }
+}
+
+/// A/B slot configuration store on top of a [`Flash`] partition
+pub struct ConfigStore<F> {
+    flash: F,
+}
+
+/// Store shared by the tasks that save configuration changes
+pub type SharedConfigStore<F> = Mutex<CriticalSectionRawMutex, ConfigStore<F>>;
+
+impl<F: Flash> ConfigStore<F> {
+    pub const fn new(flash: F) -> Self {
+        Self { flash }
+    }
+
+    /// Largest show text [`Self::save`] accepts
+    pub fn show_capacity(&self) -> usize {
+        let space = self
+            .slot_size()
+            .saturating_sub(HEADER_LEN + align(SETTINGS_LEN as u32));
+        (space as usize).min(MAX_SHOW_BYTES)
+    }
+
+    /// Newest valid configuration, `None` on a blank or reset partition
+    ///
+    /// The show text is copied into `show`.
+    pub fn load<'a>(
+        &mut self,
+        show: &'a mut [u8],
+    ) -> Result<Option<StoredConfig<'a>>, StorageError> {
+        let Some((slot, header)) = self.newest()? else {
+            return Ok(None);
+        };
+        let base = self.slot_base(slot);
+
+        let mut settings = [0; align(SETTINGS_LEN as u32) as usize];
+        let settings_len = (header.settings_len as usize).min(SETTINGS_LEN);
+        self.flash.read(base + HEADER_LEN, &mut settings)?;
+
+        let show_len = header.show_len as usize;
+        let show = show.get_mut(..show_len).ok_or(StorageError::TooLarge)?;
+        self.read_unaligned(base + header.show_offset(), show)?;
+        let Ok(show) = str::from_utf8(show) else {
+            defmt::warn!("Stored show in slot {} is not UTF-8", slot);
+            return Ok(None);
+        };
+
+        Ok(Some(StoredConfig {
+            settings: Settings::decode(&settings[..settings_len], header.version),
+            show,
+            sequence: header.sequence,
+        }))
+    }
+
+    /// Write a new record into the older slot and return its sequence number
+    pub fn save(&mut self, settings: &Settings, show: &str) -> Result<u32, StorageError> {
+        let show = show.as_bytes();
+        if show.len() > self.show_capacity() {
+            return Err(StorageError::TooLarge);
+        }
+        let (slot, sequence) = match self.newest()? {
+            Some((slot, header)) => ((slot + 1) % SLOTS, header.sequence.wrapping_add(1)),
+            None => (0, 1),
+        };
+        let base = self.slot_base(slot);
+        self.flash.erase(base, base + self.slot_size())?;
+
+        let mut header = Header {
+            version: SCHEMA_VERSION,
+            settings_len: SETTINGS_LEN as u16,
+            sequence,
+            show_len: show.len() as u32,
+            crc: 0,
+        };
+        let encoded = settings.encode();
+        let mut crc = header.crc_start();
+        crc.update(&encoded);
+        crc.update(show);
+        header.crc = crc.finish();
+
+        let mut padded = [0xFF; align(SETTINGS_LEN as u32) as usize];
+        padded[..SETTINGS_LEN].copy_from_slice(&encoded);
+        self.flash.write(base + HEADER_LEN, &padded)?;
+        self.write_unaligned(base + header.show_offset(), show)?;
+        // Header last: until it is complete the slot reads as invalid
+        self.flash.write(base, &header.encode())?;
+
+        defmt::info!("Configuration saved to slot {} (#{})", slot, sequence);
+        Ok(sequence)
+    }
+
+    /// Erase both slots; the next load returns `None` so built-in defaults apply
+    pub fn factory_reset(&mut self) -> Result<(), StorageError> {
+        let end = self.slot_base(SLOTS);
+        self.flash.erase(0, end)?;
+        defmt::warn!("Configuration erased (factory reset)");
+        Ok(())
+    }
+
+    fn slot_size(&self) -> u32 {
+        self.flash.capacity() / SLOTS / F::SECTOR_SIZE * F::SECTOR_SIZE
+    }
+
+    fn slot_base(&self, slot: u32) -> u32 {
+        slot * self.slot_size()
+    }
+
+    /// Slot holding the valid record with the highest sequence number
+    fn newest(&mut self) -> Result<Option<(u32, Header)>, FlashError> {
+        let mut newest: Option<(u32, Header)> = None;
+        for slot in 0..SLOTS {
+            let Some(header) = self.verify(slot)? else {
+                continue;
+            };
+            match newest {
+                Some((_, best)) if !is_newer(header.sequence, best.sequence) => {}
+                _ => newest = Some((slot, header)),
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Header of `slot` if its record is complete, intact and readable
+    fn verify(&mut self, slot: u32) -> Result<Option<Header>, FlashError> {
+        let base = self.slot_base(slot);
+        let mut bytes = [0; HEADER_LEN as usize];
+        self.flash.read(base, &mut bytes)?;
+        let Some(header) = Header::decode(&bytes) else {
+            return Ok(None);
+        };
+        if header.version == 0 || header.version > SCHEMA_VERSION {
+            defmt::warn!(
+                "Slot {} has unsupported schema version {}",
+                slot,
+                header.version
+            );
+            return Ok(None);
+        }
+        if (header.settings_len as usize) < SETTINGS_LENS[header.version as usize - 1] {
+            defmt::warn!("Slot {} settings too short for their schema", slot);
+            return Ok(None);
+        }
+        let end = header.show_offset() as u64 + header.show_len as u64;
+        if end > self.slot_size() as u64 {
+            return Ok(None);
+        }
+
+        let mut crc = header.crc_start();
+        self.crc_region(&mut crc, base + HEADER_LEN, header.settings_len as u32)?;
+        self.crc_region(&mut crc, base + header.show_offset(), header.show_len)?;
+        if crc.finish() != header.crc {
+            defmt::warn!("Slot {} failed its CRC check", slot);
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    fn crc_region(&mut self, crc: &mut Crc32, offset: u32, len: u32) -> Result<(), FlashError> {
+        let mut chunk = [0; 64];
+        let mut done = 0;
+        while done < len {
+            let part = (len - done).min(chunk.len() as u32);
+            let read = &mut chunk[..align(part) as usize];
+            self.flash.read(offset + done, read)?;
+            crc.update(&read[..part as usize]);
+            done += part;
+        }
+        Ok(())
+    }
+
+    fn read_unaligned(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        let (whole, tail) = split_words(bytes.len());
+        self.flash.read(offset, &mut bytes[..whole])?;
+        if tail > 0 {
+            let mut last = [0; FLASH_WORD as usize];
+            self.flash.read(offset + whole as u32, &mut last)?;
+            bytes[whole..].copy_from_slice(&last[..tail]);
+        }
+        Ok(())
+    }
+
+    fn write_unaligned(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let (whole, tail) = split_words(bytes.len());
+        self.flash.write(offset, &bytes[..whole])?;
+        if tail > 0 {
+            let mut last = [0xFF; FLASH_WORD as usize];
+            last[..tail].copy_from_slice(&bytes[whole..]);
+            self.flash.write(offset + whole as u32, &last)?;
+        }
+        Ok(())
+    }
+}
+
+/// `a` was saved after `b`, allowing for the counter wrapping around
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+const fn align(len: u32) -> u32 {
+    len.div_ceil(FLASH_WORD) * FLASH_WORD
+}
+
+/// Length in whole words and the remaining bytes
+fn split_words(len: usize) -> (usize, usize) {
+    let tail = len % FLASH_WORD as usize;
+    (len - tail, tail)
+}
+
+/// Text up to the first zero byte; empty if it is not UTF-8
+fn padded_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+fn word(bytes: &[u8]) -> [u8; 4] {
+    [bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+/// CRC-32 (IEEE 802.3), as used by zip and Ethernet
+struct Crc32(u32);
+
+impl Crc32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    const fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = Self::TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Region of the ESP32-S3's SPI flash (see `partitions.csv`)
+#[cfg(feature = "esp32s3")]
+pub struct FlashPartition {
+    flash: esp_storage::FlashStorage,
+    offset: u32,
+    size: u32,
+}
+
+#[cfg(feature = "esp32s3")]
+impl FlashPartition {
+    pub fn new(flash: esp_storage::FlashStorage, offset: u32, size: u32) -> Self {
+        Self {
+            flash,
+            offset,
+            size,
+        }
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<u32, FlashError> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.size => Ok(self.offset + offset),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+impl Flash for FlashPartition {
+    const SECTOR_SIZE: u32 = 4096;
+
+    fn capacity(&self) -> u32 {
+        self.size
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        use embedded_storage::nor_flash::ReadNorFlash;
+        let offset = self.check(offset, bytes.len())?;
+        self.flash.read(offset, bytes).map_err(nor_error)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        use embedded_storage::nor_flash::NorFlash;
+        let offset = self.check(offset, bytes.len())?;
+        NorFlash::write(&mut self.flash, offset, bytes).map_err(nor_error)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        use embedded_storage::nor_flash::NorFlash;
+        let from = self.check(from, 0)?;
+        let to = self.check(to, 0)?;
+        self.flash.erase(from, to).map_err(nor_error)
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+fn nor_error(error: impl embedded_storage::nor_flash::NorFlashError) -> FlashError {
+    use embedded_storage::nor_flash::NorFlashErrorKind;
+    match error.kind() {
+        NorFlashErrorKind::NotAligned => FlashError::NotAligned,
+        NorFlashErrorKind::OutOfBounds => FlashError::OutOfBounds,
+        _ => FlashError::Io,
+    }
+}
@@ -1,8 +1,19 @@
 /// TCA9554 I2C I/O Expander driver for 8-channel relay control
 /// Address: 0x20
-use embedded_hal_async::i2c::I2c;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal_async::i2c::{Error, I2c};
+
+use crate::bus::{BusError, BusRecovery, RetryPolicy};
 
 pub const TCA9554_ADDRESS: u8 = 0x20;
+/// Base address of the TCA9554A variant (0x38-0x3F)
+pub const TCA9554A_ADDRESS: u8 = 0x38;
+
+/// Address of an expander from its base address and A2-A0 strap pins,
+/// e.g. `expander_address(TCA9554_ADDRESS, 3)` = 0x23
+pub const fn expander_address(base: u8, straps: u8) -> u8 {
+    base | (straps & 0x07)
+}
 
 #[repr(u8)]
 enum Register {
@@ -12,10 +23,21 @@ enum Register {
     Configuration = 0x03,
 }
 
+/// Direction of a single expander pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PinDirection {
+    Output,
+    Input,
+}
+
 pub struct Tca9554<I2C> {
     i2c: I2C,
     address: u8,
     output_state: u8,
+    /// Configuration register shadow (1 = input)
+    config_state: u8,
+    /// Polarity inversion register shadow (1 = inverted)
+    polarity_state: u8,
 }
 
 impl<I2C, E> Tca9554<I2C>
@@ -27,15 +49,19 @@ where
             i2c,
             address,
             output_state: 0x00,
+            config_state: 0x00,
+            polarity_state: 0x00,
         }
     }
 
     pub async fn init(&mut self) -> Result<(), E> {
+        // Set all outputs low first: the output latch powers up as 0xFF,
+        // so switching direction first would pulse every relay
+        self.write_register(Register::OutputPort, 0x00).await?;
         // Configure all pins as outputs
         self.write_register(Register::Configuration, 0x00).await?;
-        // Set all outputs low
-        self.write_register(Register::OutputPort, 0x00).await?;
         self.output_state = 0x00;
+        self.config_state = 0x00;
         Ok(())
     }
 
@@ -43,6 +69,14 @@ where
         self.i2c.write(self.address, &[register as u8, value]).await
     }
 
+    async fn read_register(&mut self, register: Register) -> Result<u8, E> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(self.address, &[register as u8], &mut value)
+            .await?;
+        Ok(value[0])
+    }
+
     pub async fn set_pin_high(&mut self, pin: u8) -> Result<(), E> {
         if pin >= 8 {
             return Ok(());
@@ -61,12 +95,189 @@ where
             .await
     }
 
+    /// Drive every pin in `mask` high in a single write
+    pub async fn set_pins_high(&mut self, mask: u8) -> Result<(), E> {
+        self.write_outputs(self.output_state | mask).await
+    }
+
+    /// Drive every pin in `mask` low in a single write
+    pub async fn set_pins_low(&mut self, mask: u8) -> Result<(), E> {
+        self.write_outputs(self.output_state & !mask).await
+    }
+
+    /// Invert every pin in `mask` in a single write
+    pub async fn toggle_pins(&mut self, mask: u8) -> Result<(), E> {
+        self.write_outputs(self.output_state ^ mask).await
+    }
+
+    /// Set the pins in `mask` to the matching bits of `levels`, leaving the
+    /// other pins untouched, in a single write
+    pub async fn update_pins(&mut self, mask: u8, levels: u8) -> Result<(), E> {
+        self.write_outputs(merge_outputs(self.output_state, mask, levels))
+            .await
+    }
+
     pub async fn all_off(&mut self) -> Result<(), E> {
         self.output_state = 0x00;
         self.write_register(Register::OutputPort, 0x00).await
     }
 
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
     pub fn get_output_state(&self) -> u8 {
         self.output_state
     }
+
+    /// Write all eight outputs at once (bit 0 = pin 0)
+    pub async fn write_outputs(&mut self, value: u8) -> Result<(), E> {
+        self.output_state = value;
+        self.write_register(Register::OutputPort, value).await
+    }
+
+    /// Write all eight outputs and confirm the value by reading the output
+    /// port back
+    ///
+    /// Failed attempts (NAK, timeout, read-back mismatch) are retried after
+    /// a bus recovery and an exponential backoff, as set by `policy`. The
+    /// shadow state is only updated once the device confirmed the value.
+    pub async fn write_outputs_verified(
+        &mut self,
+        value: u8,
+        policy: &RetryPolicy,
+    ) -> Result<(), BusError>
+    where
+        I2C: BusRecovery,
+        E: Error,
+    {
+        let timeout = Duration::from_millis(policy.timeout_ms as u64);
+        let mut last_error = BusError::Bus;
+
+        for attempt in 0..policy.attempts.max(1) {
+            if attempt > 0 {
+                defmt::warn!(
+                    "TCA9554 write {=u8:#04x} failed ({:?}), retry {}/{}",
+                    value,
+                    last_error,
+                    attempt,
+                    policy.attempts - 1
+                );
+                self.i2c.recover_bus().await;
+                Timer::after_millis(policy.backoff_ms(attempt) as u64).await;
+            }
+
+            match self.try_write_verified(value, timeout).await {
+                Ok(()) => {
+                    self.output_state = value;
+                    return Ok(());
+                }
+                Err(error) => last_error = error,
+            }
+        }
+
+        defmt::error!(
+            "TCA9554 write {=u8:#04x} failed after {} attempts: {:?}",
+            value,
+            policy.attempts,
+            last_error
+        );
+        Err(last_error)
+    }
+
+    async fn try_write_verified(&mut self, value: u8, timeout: Duration) -> Result<(), BusError>
+    where
+        E: Error,
+    {
+        with_timeout(timeout, self.write_register(Register::OutputPort, value))
+            .await
+            .map_err(|_| BusError::Timeout)?
+            .map_err(BusError::from_i2c)?;
+        let actual = with_timeout(timeout, self.read_register(Register::OutputPort))
+            .await
+            .map_err(|_| BusError::Timeout)?
+            .map_err(BusError::from_i2c)?;
+
+        if actual != value {
+            return Err(BusError::Mismatch {
+                expected: value,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the logic levels on all pins (after polarity inversion)
+    pub async fn read_input_port(&mut self) -> Result<u8, E> {
+        self.read_register(Register::InputPort).await
+    }
+
+    /// Read the level of a single pin, `false` for pins >= 8
+    pub async fn is_pin_high(&mut self, pin: u8) -> Result<bool, E> {
+        if pin >= 8 {
+            return Ok(false);
+        }
+        Ok(self.read_input_port().await? & (1 << pin) != 0)
+    }
+
+    /// Read the output port register from the device
+    ///
+    /// Unlike [`Self::get_output_state`] this reflects what the expander
+    /// actually latched, not the driver's shadow copy.
+    pub async fn read_output_port(&mut self) -> Result<u8, E> {
+        self.read_register(Register::OutputPort).await
+    }
+
+    /// Set the polarity inversion mask (1 = input port bit is inverted)
+    pub async fn set_polarity(&mut self, mask: u8) -> Result<(), E> {
+        self.write_register(Register::Polarity, mask).await?;
+        self.polarity_state = mask;
+        Ok(())
+    }
+
+    pub fn get_polarity(&self) -> u8 {
+        self.polarity_state
+    }
+
+    /// Set the direction of every pin at once (1 = input, 0 = output)
+    pub async fn set_configuration(&mut self, mask: u8) -> Result<(), E> {
+        self.write_register(Register::Configuration, mask).await?;
+        self.config_state = mask;
+        Ok(())
+    }
+
+    pub fn get_configuration(&self) -> u8 {
+        self.config_state
+    }
+
+    /// Change the direction of a single pin, leaving the others untouched
+    pub async fn set_pin_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), E> {
+        if pin >= 8 {
+            return Ok(());
+        }
+        let mask = match direction {
+            PinDirection::Input => self.config_state | (1 << pin),
+            PinDirection::Output => self.config_state & !(1 << pin),
+        };
+        self.set_configuration(mask).await
+    }
+
+    /// Reload the output, polarity and configuration shadows from the device
+    ///
+    /// Use after a brown-out or bus glitch, when the expander may have
+    /// reset to its power-on defaults (all inputs, output latch 0xFF).
+    pub async fn sync_from_device(&mut self) -> Result<(), E> {
+        let output = self.read_register(Register::OutputPort).await?;
+        let polarity = self.read_register(Register::Polarity).await?;
+        let config = self.read_register(Register::Configuration).await?;
+        self.output_state = output;
+        self.polarity_state = polarity;
+        self.config_state = config;
+        Ok(())
+    }
+}
+
+/// Replace the bits of `current` selected by `mask` with those of `levels`
+pub const fn merge_outputs(current: u8, mask: u8, levels: u8) -> u8 {
+    (current & !mask) | (levels & mask)
 }
@@ -0,0 +1,204 @@
+//! Wi-Fi station with a setup access point as fallback
+//!
+//! The station joins the network stored in [`WifiSettings`], retrying
+//! with exponential backoff. With no network stored, or after
+//! [`FAILURES_BEFORE_SETUP`] attempts in a row fail, the controller also
+//! opens an open access point serving the [`crate::captive`] setup page.
+//! The access point closes again once the station connects.
+
+use embassy_time::{Duration, Instant};
+
+use crate::storage::WifiSettings;
+
+/// First retry delay; it doubles with each failure
+pub const BACKOFF_MIN_MS: u64 = 1000;
+pub const BACKOFF_MAX_MS: u64 = 60_000;
+/// Failed attempts before the setup access point opens
+pub const FAILURES_BEFORE_SETUP: u32 = 3;
+
+/// What the station is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum WifiState {
+    /// No network stored; only the setup access point runs
+    Unconfigured,
+    /// Attempt due now
+    Connecting,
+    Connected,
+    /// Backing off until the next attempt
+    Waiting {
+        until: Instant,
+    },
+}
+
+/// Connection state machine of the Wi-Fi station
+///
+/// Report every attempt with [`Self::connected`] or [`Self::failed`], and
+/// a dropped connection with [`Self::failed`]; keep the access point open
+/// while [`Self::access_point`] says so.
+pub struct WifiSupervisor {
+    state: WifiState,
+    /// Failures since the last successful connection
+    failures: u32,
+    access_point: bool,
+}
+
+impl WifiSupervisor {
+    pub fn new(settings: &WifiSettings) -> Self {
+        let configured = settings.is_configured();
+        Self {
+            state: if configured {
+                WifiState::Connecting
+            } else {
+                WifiState::Unconfigured
+            },
+            failures: 0,
+            access_point: !configured,
+        }
+    }
+
+    pub const fn state(&self) -> WifiState {
+        self.state
+    }
+
+    pub const fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether the setup access point should be up
+    pub const fn access_point(&self) -> bool {
+        self.access_point
+    }
+
+    pub fn connected(&mut self) {
+        if self.state == WifiState::Unconfigured {
+            return;
+        }
+        self.state = WifiState::Connected;
+        self.failures = 0;
+        self.access_point = false;
+    }
+
+    /// An attempt failed or the connection dropped
+    pub fn failed(&mut self) {
+        if self.state == WifiState::Unconfigured {
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= FAILURES_BEFORE_SETUP {
+            self.access_point = true;
+        }
+        let until = Instant::now() + Duration::from_millis(backoff_ms(self.failures));
+        self.state = WifiState::Waiting { until };
+    }
+
+    /// Backoff over: make the next attempt
+    pub fn retry(&mut self) {
+        if let WifiState::Waiting { .. } = self.state {
+            self.state = WifiState::Connecting;
+        }
+    }
+}
+
+/// Delay after `failures` failures in a row
+pub fn backoff_ms(failures: u32) -> u64 {
+    let doublings = failures.saturating_sub(1).min(6);
+    (BACKOFF_MIN_MS << doublings).min(BACKOFF_MAX_MS)
+}
+
+/// Keep the station connected and the access point up as needed, forever
+///
+/// `ap_ssid` names the setup network.
+#[cfg(feature = "esp32s3")]
+pub async fn run(
+    controller: &mut esp_wifi::wifi::WifiController<'_>,
+    settings: WifiSettings,
+    ap_ssid: &str,
+) -> ! {
+    use embassy_time::Timer;
+    use esp_wifi::wifi::{
+        AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiEvent,
+    };
+
+    use crate::captive::PORTAL_URL;
+
+    let client = ClientConfiguration {
+        ssid: settings.ssid().into(),
+        password: settings.password().into(),
+        auth_method: if settings.password().is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    };
+    let access_point = AccessPointConfiguration {
+        ssid: ap_ssid.into(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+
+    let mut supervisor = WifiSupervisor::new(&settings);
+    let mut running = None;
+    loop {
+        let wanted = supervisor.access_point();
+        if running != Some(wanted) {
+            if running.is_some() {
+                let _ = controller.stop_async().await;
+            }
+            let config = match (wanted, settings.is_configured()) {
+                (false, _) => Configuration::Client(client.clone()),
+                (true, true) => Configuration::Mixed(client.clone(), access_point.clone()),
+                (true, false) => Configuration::AccessPoint(access_point.clone()),
+            };
+            if let Err(e) = controller.set_configuration(&config) {
+                defmt::error!("Wi-Fi configuration rejected: {:?}", e);
+            }
+            if let Err(e) = controller.start_async().await {
+                defmt::error!("Wi-Fi failed to start: {:?}", e);
+            }
+            if wanted {
+                defmt::info!(
+                    "Wi-Fi setup network '{}' open, visit {}",
+                    ap_ssid,
+                    PORTAL_URL
+                );
+            }
+            // Closing the access point restarted the station too
+            if running.is_some() && supervisor.state() == WifiState::Connected {
+                if let Err(e) = controller.connect_async().await {
+                    defmt::warn!("Wi-Fi reconnect failed: {:?}", e);
+                    supervisor.failed();
+                }
+            }
+            running = Some(wanted);
+        }
+
+        match supervisor.state() {
+            WifiState::Unconfigured => core::future::pending().await,
+            WifiState::Connecting => match controller.connect_async().await {
+                Ok(()) => {
+                    defmt::info!("Wi-Fi connected to '{}'", settings.ssid());
+                    supervisor.connected();
+                }
+                Err(e) => {
+                    supervisor.failed();
+                    defmt::warn!(
+                        "Wi-Fi connection to '{}' failed ({} in a row): {:?}",
+                        settings.ssid(),
+                        supervisor.failures(),
+                        e
+                    );
+                }
+            },
+            WifiState::Connected => {
+                controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                defmt::warn!("Wi-Fi connection lost");
+                supervisor.failed();
+            }
+            WifiState::Waiting { until } => {
+                Timer::at(until).await;
+                supervisor.retry();
+            }
+        }
+    }
+}
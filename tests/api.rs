@@ -0,0 +1,360 @@
+//! Host tests for the REST API endpoints
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use prop_relay_control::api::{Api, Reply, DASHBOARD_HTML, MAX_RESPONSE};
+use prop_relay_control::ble::BluetoothSwitch;
+use prop_relay_control::hardware::DigitalInput;
+use prop_relay_control::http::Status;
+use prop_relay_control::input::InputStatus;
+use prop_relay_control::mock::{Clock, MockI2c};
+use prop_relay_control::sequence::SequenceConfig;
+use prop_relay_control::show::{parse_show, ShowStorage, MAX_SHOW_NAME_LEN, MAX_SHOW_TRIGGERS};
+
+use common::{Fixture, FLASH};
+
+const CONFIGS: &[SequenceConfig] = &[
+    SequenceConfig::new(DigitalInput::DI1, 5000, FLASH, "flash"),
+    SequenceConfig::new(DigitalInput::DI3, 0, FLASH, "say \"boo\""),
+];
+
+impl Fixture {
+    fn api(&self) -> Api<'_, MockI2c, 1, 1> {
+        Api::new(
+            &self.inputs,
+            &self.relays,
+            &self.runner,
+            &self.dispatcher,
+            self.configs,
+        )
+    }
+
+    fn reply(&self, clock: &Clock, raw: &str) -> (Reply, String) {
+        let mut body = heapless::String::<MAX_RESPONSE>::new();
+        let reply = clock.block_on(self.api().respond(raw.as_bytes(), &mut body));
+        (reply, body.as_str().to_owned())
+    }
+
+    fn request(&self, clock: &Clock, raw: &str) -> (Status, String) {
+        match self.reply(clock, raw) {
+            (Reply::Json(status), body) => (status, body),
+            (reply, _) => panic!("expected JSON, got {reply:?}"),
+        }
+    }
+}
+
+fn get(path: &str) -> String {
+    format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n")
+}
+
+fn with_body(method: &str, path: &str, body: &str) -> String {
+    format!(
+        "{method} {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[test]
+fn lists_and_sets_relays() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+
+    let (status, body) = api.request(
+        &clock,
+        &with_body("PUT", "/api/relays/2", "{\"state\": \"on\"}"),
+    );
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body, r#"{"relay":2,"bank":0,"state":"on"}"#);
+    assert_eq!(
+        api.bus.transactions().last().unwrap().bytes,
+        vec![0x01, 0b10]
+    );
+
+    let (_, body) = api.request(&clock, "POST /api/relays/3?state=on HTTP/1.1\r\n\r\n");
+    assert_eq!(body, r#"{"relay":3,"bank":0,"state":"on"}"#);
+
+    let (status, body) = api.request(&clock, &get("/api/relays"));
+    assert_eq!(status, Status::Ok);
+    assert!(body.starts_with(r#"{"relays":[{"relay":1,"bank":0,"state":"off"},{"relay":2,"bank":0,"state":"on"},{"relay":3,"bank":0,"state":"on"}"#));
+    assert!(body.ends_with(r#"{"relay":8,"bank":0,"state":"off"}]}"#));
+
+    assert_eq!(
+        api.request(&clock, &get("/api/relays/2")).1,
+        r#"{"relay":2,"bank":0,"state":"on"}"#
+    );
+}
+
+#[test]
+fn rejects_bad_relay_requests() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+
+    let (status, body) = api.request(&clock, &get("/api/relays/9"));
+    assert_eq!(
+        (status, body.as_str()),
+        (Status::NotFound, r#"{"error":"no such relay"}"#)
+    );
+    assert_eq!(
+        api.request(&clock, &get("/api/relays/0")).0,
+        Status::NotFound
+    );
+    assert_eq!(
+        api.request(
+            &clock,
+            &with_body("PUT", "/api/relays/9", "{\"state\":\"on\"}")
+        )
+        .0,
+        Status::NotFound
+    );
+    assert_eq!(
+        api.request(
+            &clock,
+            &with_body("PUT", "/api/relays/1", "{\"state\":\"dim\"}")
+        )
+        .0,
+        Status::BadRequest
+    );
+    assert_eq!(
+        api.request(&clock, &with_body("PUT", "/api/relays/1", "{\"level\":1}"))
+            .0,
+        Status::BadRequest
+    );
+    assert_eq!(
+        api.request(&clock, "DELETE /api/relays/1 HTTP/1.1\r\n\r\n")
+            .0,
+        Status::MethodNotAllowed
+    );
+    assert!(api.bus.transactions().is_empty());
+}
+
+#[test]
+fn triggers_sequence_by_name_and_reports_cooldown() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+    api.dispatcher
+        .lock(|dispatcher| dispatcher.borrow_mut().mark_triggered(DigitalInput::DI1));
+    clock.advance_ms(1500);
+
+    let (status, body) = api.request(&clock, &get("/api/sequences/flash"));
+    assert_eq!(status, Status::Ok);
+    assert_eq!(
+        body,
+        r#"{"name":"flash","trigger":"DI1","cooldown_ms":5000,"remaining_ms":3500}"#
+    );
+
+    // Names are escaped in the listing
+    let (_, body) = api.request(&clock, &get("/api/sequences"));
+    assert!(body
+        .ends_with(r#"{"name":"say \"boo\"","trigger":"DI3","cooldown_ms":0,"remaining_ms":0}]}"#));
+
+    // Manual trigger ignores the cooldown
+    let (status, body) = api.request(&clock, "POST /api/sequences/flash/trigger HTTP/1.1\r\n\r\n");
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body, r#"{"name":"flash","outcome":"started","handle":0}"#);
+    assert_eq!(api.runner.running(), [Some("flash")]);
+
+    let (status, _) = api.request(&clock, "POST /api/sequences/nope/trigger HTTP/1.1\r\n\r\n");
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(
+        api.request(&clock, &get("/api/sequences/flash/trigger")).0,
+        Status::MethodNotAllowed
+    );
+}
+
+#[test]
+fn all_off_switches_everything_off() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+    api.request(
+        &clock,
+        &with_body("PUT", "/api/relays/5", "{\"state\":true}"),
+    );
+
+    let (status, body) = api.request(&clock, "POST /api/all-off HTTP/1.1\r\n\r\n");
+    assert_eq!((status, body.as_str()), (Status::Ok, r#"{"all_off":true}"#));
+    assert_eq!(
+        api.bus.transactions().last().unwrap().bytes,
+        vec![0x01, 0x00]
+    );
+    assert_eq!(
+        api.request(&clock, &get("/api/all-off")).0,
+        Status::MethodNotAllowed
+    );
+}
+
+#[test]
+fn unknown_paths_and_bad_requests() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+
+    assert_eq!(api.request(&clock, &get("/fog")).0, Status::NotFound);
+    assert_eq!(api.request(&clock, &get("/api/fog")).0, Status::NotFound);
+    let (status, body) = api.request(&clock, "GARBAGE\r\n\r\n");
+    assert_eq!(
+        (status, body.as_str()),
+        (Status::BadRequest, r#"{"error":"malformed request"}"#)
+    );
+    assert_eq!(
+        api.request(&clock, "GET /api/relays HTTP/1.1\r\n").0,
+        Status::PayloadTooLarge
+    );
+}
+
+#[test]
+fn triggers_sequence_by_encoded_name() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+
+    let (status, body) = api.request(
+        &clock,
+        "POST /api/sequences/say%20%22boo%22/trigger HTTP/1.1\r\n\r\n",
+    );
+    assert_eq!(status, Status::Ok, "{body}");
+    assert_eq!(api.runner.running(), [Some("say \"boo\"")]);
+
+    for bad in ["say%20%22boo%2", "say%20%22boo%22%22", "say%zz%22boo%22"] {
+        let (status, _) = api.request(&clock, &get(&format!("/api/sequences/{bad}")));
+        assert_eq!(status, Status::NotFound, "{bad}");
+    }
+}
+
+#[test]
+fn serves_dashboard_and_event_stream() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+
+    assert_eq!(api.reply(&clock, &get("/")).0, Reply::Dashboard);
+    assert_eq!(api.reply(&clock, &get("/index.html")).0, Reply::Dashboard);
+    assert_eq!(api.reply(&clock, &get("/api/events")).0, Reply::Events);
+    assert_eq!(
+        api.request(&clock, "POST / HTTP/1.1\r\n\r\n").0,
+        Status::MethodNotAllowed
+    );
+    assert_eq!(
+        api.request(&clock, "POST /api/status HTTP/1.1\r\n\r\n").0,
+        Status::MethodNotAllowed
+    );
+
+    // The page drives the API endpoints above
+    for path in ["/api/events", "/api/relays/", "/api/all-off", "/trigger"] {
+        assert!(DASHBOARD_HTML.contains(path), "{path}");
+    }
+}
+
+#[test]
+fn status_reports_inputs_relays_and_sequences() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+    api.inputs.set_level(DigitalInput::DI2, true);
+    api.inputs.record_trigger(DigitalInput::DI2);
+    api.inputs.record_trigger(DigitalInput::DI2);
+    api.inputs.set_glitches(DigitalInput::DI2, 5);
+    api.request(
+        &clock,
+        &with_body("PUT", "/api/relays/1", "{\"state\":\"on\"}"),
+    );
+    api.request(&clock, "POST /api/sequences/flash/trigger HTTP/1.1\r\n\r\n");
+    api.dispatcher
+        .lock(|dispatcher| dispatcher.borrow_mut().mark_triggered(DigitalInput::DI1));
+
+    let (status, body) = api.request(&clock, &get("/api/status"));
+    assert_eq!(status, Status::Ok);
+    assert!(
+        body.starts_with(
+            r#"{"inputs":[{"input":"DI1","high":false,"triggers":0,"glitches":0},{"input":"DI2","high":true,"triggers":2,"glitches":5},"#
+        ),
+        "{body}"
+    );
+    assert!(body.contains(r#"],"relays":[{"relay":1,"bank":0,"state":"on"},"#));
+    assert!(body.contains(r#"}],"running":["flash"],"queued":0,"sequences":[{"name":"flash","trigger":"DI1","cooldown_ms":5000,"remaining_ms":5000},"#));
+    assert!(body.ends_with("}]}"));
+}
+
+#[test]
+fn status_of_a_full_show_fits() {
+    // Longest names, every byte escaped in JSON
+    let mut source = String::new();
+    for i in 0..MAX_SHOW_TRIGGERS {
+        let name = format!("{i:02}{}", "\x01".repeat(MAX_SHOW_NAME_LEN - 2));
+        source += &format!(
+            "program {name}\n  set R1 on\nend\ntrigger DI{} {name} cooldown 1000000s{}\n",
+            i % 8 + 1,
+            if i < 8 { "" } else { " hold 1s" }
+        );
+    }
+    let show = parse_show(source.leak(), Box::leak(Box::new(ShowStorage::new()))).unwrap();
+    assert_eq!(show.configs.len(), MAX_SHOW_TRIGGERS);
+
+    let clock = Clock::take();
+    let api = Fixture::new(show.configs);
+    use DigitalInput::*;
+    for input in [DI1, DI2, DI3, DI4, DI5, DI6, DI7, DI8] {
+        api.inputs.set_glitches(input, u32::MAX);
+    }
+    api.dispatcher.lock(|dispatcher| {
+        for config in show.configs {
+            dispatcher.borrow_mut().mark_mapping(config);
+        }
+    });
+
+    let (status, body) = api.request(&clock, &get("/api/status"));
+    assert_eq!(status, Status::Ok);
+    assert!(body.len() > 4096, "{}", body.len());
+    assert!(body.ends_with(r#""remaining_ms":1000000000}]}"#), "{body}");
+}
+
+#[test]
+fn bluetooth_is_switched_over_the_api() {
+    let clock = Clock::take();
+    let api = Fixture::new(CONFIGS);
+    // Without a switch there is nothing to serve
+    assert_eq!(
+        api.request(&clock, &get("/api/bluetooth")).0,
+        Status::NotFound
+    );
+
+    let switch = BluetoothSwitch::new(false);
+    let with_switch = api.api().with_bluetooth(&switch);
+    let request = |raw: &str| {
+        let mut body = heapless::String::<MAX_RESPONSE>::new();
+        let reply = clock.block_on(with_switch.respond(raw.as_bytes(), &mut body));
+        assert_eq!(reply, Reply::Json(Status::Ok));
+        body.as_str().to_owned()
+    };
+    assert_eq!(request(&get("/api/bluetooth")), r#"{"state":"off"}"#);
+    assert_eq!(
+        request(&with_body("PUT", "/api/bluetooth", r#"{"state":"off"}"#)),
+        r#"{"state":"off","restarting":false}"#
+    );
+    assert_eq!(
+        request(&with_body("PUT", "/api/bluetooth", r#"{"state":"on"}"#)),
+        r#"{"state":"on","restarting":true}"#
+    );
+    // The setting task stores the change and restarts
+    assert!(clock.block_on(switch.changed()));
+}
+
+#[test]
+fn input_status_notifies_watchers_of_changes() {
+    let clock = Clock::take();
+    let inputs = InputStatus::new();
+    let mut first = inputs.watch().unwrap();
+    let second = inputs.watch().unwrap();
+    assert!(inputs.watch().is_none());
+    drop(second);
+    assert!(inputs.watch().is_some());
+
+    inputs.set_level(DigitalInput::DI8, true);
+    let snapshot = clock.block_on(first.changed());
+    assert!(snapshot.is_high(DigitalInput::DI8));
+    assert_eq!(snapshot.levels, 0x80);
+
+    // Same level again is not a change; a trigger is
+    inputs.set_level(DigitalInput::DI8, true);
+    assert!(first.try_changed().is_none());
+    inputs.record_trigger(DigitalInput::DI8);
+    assert_eq!(first.try_changed().unwrap().triggers[7], 1);
+    assert_eq!(inputs.snapshot().triggers[7], 1);
+}
@@ -0,0 +1,246 @@
+//! Host tests for the Bluetooth GATT characteristics and show upload
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use prop_relay_control::ble::{
+    save_show, BleControl, BleError, RelayWrite, ShowUpload, UploadCommand, UploadError,
+    UploadStatus,
+};
+use prop_relay_control::hardware::{DigitalInput, RelayId, RelayState};
+use prop_relay_control::input::InputStatus;
+use prop_relay_control::mock::{Clock, MockFlash};
+use prop_relay_control::runner::{SequenceRunner, StartOutcome};
+use prop_relay_control::sequence::SequenceConfig;
+use prop_relay_control::show::{ParseErrorKind, ShowStorage};
+use prop_relay_control::storage::{ConfigStore, Settings};
+
+use common::SNAKE;
+
+const CONFIGS: &[SequenceConfig] = &[
+    SequenceConfig::new(DigitalInput::DI1, 5000, SNAKE, "jump scare"),
+    SequenceConfig::new(DigitalInput::DI2, 3000, SNAKE, "snake"),
+];
+
+const SHOW: &str =
+    "program blink\n    set R1 on\n    wait 1s\n    set R1 off\nend\n\ntrigger DI1 blink\n";
+
+fn begin(len: usize) -> Vec<u8> {
+    let mut data = vec![1];
+    data.extend_from_slice(&(len as u32).to_le_bytes());
+    data
+}
+
+fn chunk(offset: usize, text: &[u8]) -> Vec<u8> {
+    let mut data = vec![2];
+    data.extend_from_slice(&(offset as u32).to_le_bytes());
+    data.extend_from_slice(text);
+    data
+}
+
+/// Upload `text` in writes of `size` bytes, up to the commit
+fn upload(upload: &mut ShowUpload<'_>, text: &[u8], size: usize) -> Result<bool, UploadError> {
+    upload.write(&begin(text.len()))?;
+    for (i, piece) in text.chunks(size).enumerate() {
+        upload.write(&chunk(i * size, piece))?;
+    }
+    upload.write(&[3])
+}
+
+#[test]
+fn parses_relay_writes() {
+    assert_eq!(
+        RelayWrite::parse(&[3, 1]),
+        Ok(RelayWrite::Set(
+            RelayId::new(0, 2).unwrap(),
+            RelayState::High
+        ))
+    );
+    assert_eq!(
+        RelayWrite::parse(&[10, 0]),
+        Ok(RelayWrite::Set(
+            RelayId::new(1, 1).unwrap(),
+            RelayState::Low
+        ))
+    );
+    assert_eq!(
+        RelayWrite::parse(&[1, 0xff]),
+        Ok(RelayWrite::Set(
+            RelayId::new(0, 0).unwrap(),
+            RelayState::High
+        ))
+    );
+    assert_eq!(RelayWrite::parse(&[0, 0]), Ok(RelayWrite::AllOff));
+    assert_eq!(RelayWrite::parse(&[0, 1]), Err(BleError::NoSuchRelay));
+    assert_eq!(RelayWrite::parse(&[200, 1]), Err(BleError::NoSuchRelay));
+    assert_eq!(RelayWrite::parse(&[3]), Err(BleError::Malformed));
+    assert_eq!(RelayWrite::parse(&[3, 1, 0]), Err(BleError::Malformed));
+}
+
+#[test]
+fn controls_relays_and_sequences() {
+    let clock = Clock::take();
+    let (bus, relays) = common::relay_board();
+    let runner = SequenceRunner::<1>::new();
+    let inputs = InputStatus::new();
+    let control = BleControl::new(&inputs, &relays, &runner, CONFIGS);
+
+    clock.block_on(control.write_relay(&[3, 1])).unwrap();
+    assert_eq!(bus.transactions().last().unwrap().bytes, vec![0x01, 0b100]);
+    assert_eq!(clock.block_on(control.relay_value()).as_slice(), &[0b100]);
+    assert_eq!(
+        clock.block_on(control.write_relay(&[9, 1])),
+        Err(BleError::NoSuchRelay)
+    );
+
+    assert_eq!(control.trigger(b"nope"), Err(BleError::NoSuchSequence));
+    assert_eq!(control.trigger(&[0xff]), Err(BleError::Malformed));
+    assert!(matches!(
+        control.trigger(b"snake"),
+        Ok(StartOutcome::Started(_))
+    ));
+    assert_eq!(runner.running(), [Some("snake")]);
+
+    clock.block_on(control.write_relay(&[0, 0])).unwrap();
+    assert_eq!(bus.transactions().last().unwrap().bytes, vec![0x01, 0x00]);
+
+    inputs.set_level(DigitalInput::DI3, true);
+    assert_eq!(control.input_value(), 0b100);
+    assert_eq!(control.sequence_list().as_slice(), b"jump scare\nsnake");
+}
+
+#[test]
+fn decodes_upload_commands() {
+    assert_eq!(
+        UploadCommand::parse(&[1, 0x00, 0x01, 0, 0]),
+        Ok(UploadCommand::Begin(256))
+    );
+    assert_eq!(
+        UploadCommand::parse(&chunk(4, b"set")),
+        Ok(UploadCommand::Data {
+            offset: 4,
+            text: b"set"
+        })
+    );
+    assert_eq!(UploadCommand::parse(&[3]), Ok(UploadCommand::Commit));
+    assert_eq!(UploadCommand::parse(&[4]), Ok(UploadCommand::Cancel));
+    assert_eq!(UploadCommand::parse(&[1, 0]), Err(UploadError::Malformed));
+    assert_eq!(UploadCommand::parse(&[3, 0]), Err(UploadError::Malformed));
+    assert_eq!(UploadCommand::parse(&[9]), Err(UploadError::Malformed));
+    assert_eq!(UploadCommand::parse(&[]), Err(UploadError::Malformed));
+}
+
+#[test]
+fn assembles_uploads_in_order() {
+    let mut buffer = [0; 256];
+    let mut show = ShowUpload::new(&mut buffer);
+    assert_eq!(show.status(), UploadStatus::Idle);
+    assert_eq!(show.write(&[3]), Err(UploadError::NotStarted));
+
+    show.write(&begin(SHOW.len())).unwrap();
+    show.write(&chunk(0, &SHOW.as_bytes()[..20])).unwrap();
+    assert_eq!(
+        show.status(),
+        UploadStatus::Receiving {
+            received: 20,
+            len: SHOW.len() as u32
+        }
+    );
+    assert_eq!(show.write(&[3]), Err(UploadError::Incomplete));
+    assert_eq!(
+        show.status(),
+        UploadStatus::Rejected(UploadError::Incomplete)
+    );
+
+    // A rejection drops the upload; a repeated piece is a gap too
+    assert_eq!(show.write(&chunk(20, b"x")), Err(UploadError::NotStarted));
+    show.write(&begin(SHOW.len())).unwrap();
+    show.write(&chunk(0, b"program")).unwrap();
+    assert_eq!(
+        show.write(&chunk(0, b"program")),
+        Err(UploadError::OutOfOrder)
+    );
+
+    assert_eq!(upload(&mut show, SHOW.as_bytes(), 7), Ok(true));
+    assert_eq!(show.take_text(), Some(SHOW));
+    show.finish(Ok(()));
+    assert_eq!(
+        show.status(),
+        UploadStatus::Saved {
+            len: SHOW.len() as u32
+        }
+    );
+    // The buffer went with the text
+    assert_eq!(show.write(&begin(1)), Err(UploadError::TooLarge));
+}
+
+#[test]
+fn rejects_unusable_uploads() {
+    let mut buffer = [0; 16];
+    let mut show = ShowUpload::new(&mut buffer);
+    assert_eq!(show.write(&begin(17)), Err(UploadError::TooLarge));
+    show.write(&begin(4)).unwrap();
+    assert_eq!(show.write(&chunk(0, b"12345")), Err(UploadError::TooLarge));
+    assert_eq!(
+        upload(&mut show, &[b'a', 0xc3, b'b'], 2),
+        Err(UploadError::NotUtf8)
+    );
+    assert_eq!(show.take_text(), None);
+
+    show.write(&begin(4)).unwrap();
+    show.write(&[4]).unwrap();
+    assert_eq!(show.status(), UploadStatus::Idle);
+    assert_eq!(show.write(&[5]), Err(UploadError::Malformed));
+}
+
+#[test]
+fn encodes_upload_status() {
+    assert_eq!(UploadStatus::Idle.encode(), [0; 12]);
+    assert_eq!(
+        UploadStatus::Receiving {
+            received: 300,
+            len: 1000
+        }
+        .encode(),
+        [1, 0, 0, 0, 0x2c, 0x01, 0, 0, 0xe8, 0x03, 0, 0]
+    );
+    assert_eq!(
+        UploadStatus::Saved { len: 5 }.encode(),
+        [2, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0]
+    );
+    assert_eq!(
+        UploadStatus::Rejected(UploadError::OutOfOrder).encode(),
+        [3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+}
+
+#[test]
+fn saves_only_valid_shows() {
+    let mut store = ConfigStore::new(MockFlash::new(64 * 1024));
+    let storage = || Box::leak(Box::new(ShowStorage::new()));
+
+    let error = save_show(
+        "program blink\n    flash R1\nend\n",
+        storage(),
+        &mut store,
+        &Settings::DEFAULT,
+    )
+    .unwrap_err();
+    let UploadError::Show(parse_error) = error else {
+        panic!("expected a show error, got {error:?}");
+    };
+    assert_eq!(parse_error.line, 2);
+    assert_eq!(parse_error.kind, ParseErrorKind::UnknownCommand);
+    assert_eq!(
+        UploadStatus::Rejected(error).encode()[..8],
+        [3, 7, ParseErrorKind::UnknownCommand as u8, 0, 2, 0, 0, 0]
+    );
+    let mut text = [0; 1024];
+    assert!(store.load(&mut text).unwrap().is_none());
+
+    save_show(SHOW, storage(), &mut store, &Settings::DEFAULT).unwrap();
+    let saved = store.load(&mut text).unwrap().unwrap();
+    assert_eq!(saved.show, SHOW);
+    assert_eq!(saved.settings, Settings::DEFAULT);
+}
@@ -0,0 +1,277 @@
+//! Host tests for the setup portal's DHCP server, DNS responder and form
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::captive::{
+    dns_response, parse_setup_form, portal_route, write_portal_page, DhcpServer, PortalReply,
+    SetupError, AP_ADDRESS, DHCP_REPLY_LEN, LEASES, LEASE_TIME_S, MAX_DNS_REPLY, PORTAL_URL,
+};
+use prop_relay_control::http::{Method, Request, Status};
+use prop_relay_control::storage::WifiSettings;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+/// Client message of type `kind` from the client with MAC ending in `id`
+fn dhcp(kind: u8, id: u8, options: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 240];
+    packet[..4].copy_from_slice(&[1, 1, 6, 0]);
+    packet[4..8].copy_from_slice(&[0xDE, 0xAD, 0xBE, id]);
+    packet[10] = 0x80;
+    packet[28..34].copy_from_slice(&[0x02, 0, 0, 0, 0, id]);
+    packet[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    packet.extend_from_slice(&[53, 1, kind]);
+    packet.extend_from_slice(options);
+    packet.push(255);
+    packet
+}
+
+fn requesting(address: [u8; 4]) -> Vec<u8> {
+    let mut options = vec![50, 4];
+    options.extend_from_slice(&address);
+    options
+}
+
+/// Offered address and the options of a server reply
+struct Reply {
+    yiaddr: [u8; 4],
+    options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Reply {
+    fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    fn kind(&self) -> u8 {
+        self.option(53).unwrap()[0]
+    }
+}
+
+fn exchange(server: &mut DhcpServer, request: &[u8]) -> Option<Reply> {
+    let mut reply = [0; DHCP_REPLY_LEN];
+    let len = server.handle(request, &mut reply)?;
+    assert_eq!(len, DHCP_REPLY_LEN);
+    assert_eq!(reply[..4], [2, 1, 6, 0]);
+    assert_eq!(reply[4..8], request[4..8]);
+    assert_eq!(reply[10..12], request[10..12]);
+    assert_eq!(reply[28..34], request[28..34]);
+    let mut options = Vec::new();
+    let mut at = 240;
+    while reply[at] != 255 {
+        let len = reply[at + 1] as usize;
+        options.push((reply[at], reply[at + 2..at + 2 + len].to_vec()));
+        at += 2 + len;
+    }
+    Some(Reply {
+        yiaddr: reply[16..20].try_into().unwrap(),
+        options,
+    })
+}
+
+#[test]
+fn leases_addresses_on_the_setup_network() {
+    let mut server = DhcpServer::new();
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 1, &[])).unwrap();
+    assert_eq!(offer.kind(), OFFER);
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+    assert_eq!(offer.option(54), Some(&AP_ADDRESS[..]));
+    assert_eq!(offer.option(1), Some(&[255, 255, 255, 0][..]));
+    assert_eq!(offer.option(3), Some(&AP_ADDRESS[..]));
+    assert_eq!(offer.option(6), Some(&AP_ADDRESS[..]));
+    assert_eq!(offer.option(51), Some(&LEASE_TIME_S.to_be_bytes()[..]));
+    assert_eq!(offer.option(114), Some(PORTAL_URL.as_bytes()));
+
+    let mut options = requesting([192, 168, 4, 2]);
+    options.extend_from_slice(&[54, 4, 192, 168, 4, 1]);
+    let ack = exchange(&mut server, &dhcp(REQUEST, 1, &options)).unwrap();
+    assert_eq!(ack.kind(), ACK);
+    assert_eq!(ack.yiaddr, [192, 168, 4, 2]);
+
+    // Same client again keeps its address, the next one gets another
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 1, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 2, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 3]);
+
+    // Released addresses are handed out again
+    assert!(exchange(&mut server, &dhcp(RELEASE, 1, &[])).is_none());
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 3, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+}
+
+#[test]
+fn refuses_foreign_addresses_and_other_servers() {
+    let mut server = DhcpServer::new();
+    // Back from another network, asking for its address there
+    let nak = exchange(&mut server, &dhcp(REQUEST, 1, &requesting([10, 0, 0, 7]))).unwrap();
+    assert_eq!(nak.kind(), NAK);
+    assert_eq!(nak.yiaddr, [0; 4]);
+    assert_eq!(nak.option(51), None);
+
+    // Took another server's offer: its lease here is dropped
+    let mut options = requesting([10, 0, 0, 7]);
+    options.extend_from_slice(&[54, 4, 10, 0, 0, 1]);
+    assert!(exchange(&mut server, &dhcp(REQUEST, 1, &options)).is_none());
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 2, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+
+    // Not a client message
+    let mut reply = dhcp(DISCOVER, 3, &[]);
+    reply[0] = 2;
+    assert!(exchange(&mut server, &reply).is_none());
+    assert!(exchange(&mut server, &dhcp(DISCOVER, 3, &[])[..239]).is_none());
+}
+
+#[test]
+fn offers_every_address_once() {
+    let mut server = DhcpServer::new();
+    for id in 0..LEASES as u8 {
+        let offer = exchange(&mut server, &dhcp(DISCOVER, id, &[])).unwrap();
+        assert_eq!(offer.yiaddr, [192, 168, 4, 2 + id]);
+    }
+}
+
+#[test]
+fn reuses_the_oldest_lease_when_full() {
+    let mut server = DhcpServer::new();
+    for id in 0..LEASES as u8 {
+        exchange(&mut server, &dhcp(DISCOVER, id, &[])).unwrap();
+    }
+    // A released address is taken before any lease is reused
+    assert!(exchange(&mut server, &dhcp(RELEASE, 3, &[])).is_none());
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 98, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 5]);
+
+    // Full again: the first client's address goes to the new one, then
+    // the second's to the first client coming back
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 99, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 0, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 3]);
+
+    // Clients still holding a lease keep their address
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 99, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 2]);
+    let offer = exchange(&mut server, &dhcp(DISCOVER, 98, &[])).unwrap();
+    assert_eq!(offer.yiaddr, [192, 168, 4, 5]);
+}
+
+fn dns_query(flags: u16, name: &str, kind: u16) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34];
+    query.extend_from_slice(&flags.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    query
+}
+
+#[test]
+fn resolves_every_name_to_the_portal() {
+    let mut reply = [0; MAX_DNS_REPLY];
+    let query = dns_query(0x0100, "connectivitycheck.gstatic.com", 1);
+    let len = dns_response(&query, AP_ADDRESS, &mut reply).unwrap();
+    assert_eq!(len, query.len() + 16);
+    assert_eq!(reply[..4], [0x12, 0x34, 0x85, 0x00]);
+    assert_eq!(reply[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(reply[12..query.len()], query[12..]);
+    assert_eq!(
+        reply[query.len()..len],
+        [0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 4, 1]
+    );
+
+    // No IPv6 address: empty answer
+    let query = dns_query(0, "captive.apple.com", 28);
+    let len = dns_response(&query, AP_ADDRESS, &mut reply).unwrap();
+    assert_eq!(len, query.len());
+    assert_eq!(reply[2..8], [0x84, 0x00, 0, 1, 0, 0]);
+
+    let response = dns_query(0x8000, "example.com", 1);
+    assert_eq!(dns_response(&response, AP_ADDRESS, &mut reply), None);
+    let mut two = dns_query(0, "example.com", 1);
+    two[5] = 2;
+    assert_eq!(dns_response(&two, AP_ADDRESS, &mut reply), None);
+    let query = dns_query(0, "example.com", 1);
+    assert_eq!(
+        dns_response(&query[..query.len() - 1], AP_ADDRESS, &mut reply),
+        None
+    );
+    assert_eq!(dns_response(&query, AP_ADDRESS, &mut reply[..20]), None);
+}
+
+#[test]
+fn reads_the_setup_form() {
+    let settings = parse_setup_form(b"ssid=Haunted+House&password=b00%21b00%21b00").unwrap();
+    assert_eq!(settings.ssid(), "Haunted House");
+    assert_eq!(settings.password(), "b00!b00!b00");
+    let open = parse_setup_form(b"password=&ssid=Crypt%20Wi-Fi").unwrap();
+    assert_eq!(open.ssid(), "Crypt Wi-Fi");
+    assert_eq!(open.password(), "");
+
+    for (body, error) in [
+        (&b"ssid=&password=12345678"[..], SetupError::MissingSsid),
+        (b"password=12345678", SetupError::MissingSsid),
+        (b"ssid=Crypt&password=1234567", SetupError::BadPassword),
+        (
+            b"ssid=0123456789abcdef0123456789abcdefX",
+            SetupError::SsidTooLong,
+        ),
+        (b"ssid=Crypt%2", SetupError::BadEncoding),
+        (b"ssid=Crypt%zz", SetupError::BadEncoding),
+        (b"ssid=Crypt%FF", SetupError::BadEncoding),
+        (b"ssid=Crypt%00", SetupError::BadEncoding),
+    ] {
+        assert_eq!(parse_setup_form(body), Err(error), "{body:?}");
+    }
+    let long = format!("ssid=Crypt&password={}", "p".repeat(64));
+    assert_eq!(
+        parse_setup_form(long.as_bytes()),
+        Err(SetupError::BadPassword)
+    );
+}
+
+fn request(method: Method, path: &str, body: &'static [u8]) -> PortalReply {
+    let path = path.to_string().leak();
+    portal_route(&Request {
+        method,
+        path,
+        query: None,
+        body,
+    })
+}
+
+#[test]
+fn answers_every_page_with_the_form() {
+    for path in ["/", "/generate_204", "/hotspot-detect.html"] {
+        assert_eq!(request(Method::Get, path, b""), PortalReply::Form);
+    }
+    assert_eq!(
+        request(Method::Post, "/wifi", b"ssid=%3Cb%3E&password="),
+        PortalReply::Saved(WifiSettings::new("<b>", "").unwrap())
+    );
+    let refused = request(Method::Post, "/wifi", b"ssid=");
+    assert_eq!(refused, PortalReply::Refused(SetupError::MissingSsid));
+
+    let mut page = String::new();
+    let saved = request(Method::Post, "/wifi", b"ssid=%3Cb%3E&password=");
+    assert_eq!(write_portal_page(&saved, &mut page), Ok(Status::Ok));
+    assert!(page.contains("joins <b>&lt;b&gt;</b>"));
+    page.clear();
+    assert_eq!(
+        write_portal_page(&refused, &mut page),
+        Ok(Status::BadRequest)
+    );
+    assert!(page.contains(SetupError::MissingSsid.message()));
+}
@@ -0,0 +1,62 @@
+//! Setup shared by the protocol host tests: a relay board on a mock bus
+//! and the sequences they start
+//!
+//! Each test file uses only part of this.
+
+#![allow(dead_code)]
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use prop_relay_control::hardware::{RelayOutput, RelayState};
+use prop_relay_control::input::InputStatus;
+use prop_relay_control::mock::MockI2c;
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::runner::SequenceRunner;
+use prop_relay_control::sequence::{
+    SequenceConfig, SequenceDispatcher, SequenceStep, SharedDispatcher,
+};
+use prop_relay_control::tca9554::{Tca9554, TCA9554_ADDRESS};
+
+/// Relay 4 on for half a second
+pub const FLASH: &[SequenceStep] = &[
+    SequenceStep::new(RelayOutput::Relay4, RelayState::High, 500),
+    SequenceStep::new(RelayOutput::Relay4, RelayState::Low, 0),
+];
+
+/// Relay 6 on for half a second
+pub const SNAKE: &[SequenceStep] = &[
+    SequenceStep::new(RelayOutput::Relay6, RelayState::High, 500),
+    SequenceStep::new(RelayOutput::Relay6, RelayState::Low, 0),
+];
+
+/// Relay controller on a fresh mock bus, and the bus to check its writes
+pub fn relay_board() -> (MockI2c, RelayController<MockI2c>) {
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    (bus, relays)
+}
+
+/// Everything a protocol server reads and drives, with `configs` mapped
+pub struct Fixture {
+    pub bus: MockI2c,
+    pub inputs: InputStatus,
+    pub relays: RelayController<MockI2c>,
+    pub runner: SequenceRunner<1>,
+    pub dispatcher: SharedDispatcher,
+    pub configs: &'static [SequenceConfig],
+}
+
+impl Fixture {
+    pub fn new(configs: &'static [SequenceConfig]) -> Self {
+        let (bus, relays) = relay_board();
+        Self {
+            bus,
+            inputs: InputStatus::new(),
+            relays,
+            runner: SequenceRunner::new(),
+            dispatcher: Mutex::new(RefCell::new(SequenceDispatcher::new(configs))),
+            configs,
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Host tests for the per-input cooldown tracker
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::hardware::DigitalInput;
+use prop_relay_control::input::CooldownTracker;
+use prop_relay_control::mock::Clock;
+
+#[test]
+fn untriggered_input_is_not_cooling_down() {
+    let _clock = Clock::take();
+    let tracker = CooldownTracker::new(500);
+
+    assert!(!tracker.is_cooling_down(DigitalInput::DI1));
+    assert_eq!(tracker.remaining_ms(DigitalInput::DI1), 0);
+}
+
+#[test]
+fn cooldown_expires_after_duration() {
+    let clock = Clock::take();
+    let mut tracker = CooldownTracker::new(500);
+
+    tracker.mark_triggered(DigitalInput::DI3);
+    assert!(tracker.is_cooling_down(DigitalInput::DI3));
+    assert!(!tracker.is_cooling_down(DigitalInput::DI4));
+
+    clock.advance_ms(200);
+    assert_eq!(tracker.remaining_ms(DigitalInput::DI3), 300);
+
+    clock.advance_ms(300);
+    assert!(!tracker.is_cooling_down(DigitalInput::DI3));
+    assert_eq!(tracker.remaining_ms(DigitalInput::DI3), 0);
+}
@@ -0,0 +1,264 @@
+//! Host tests for Art-Net/sACN decoding, source merging and relay mapping
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use prop_relay_control::dmx::{
+    parse_artnet, parse_sacn, sacn_group, DmxControl, DmxError, DmxFrame, DmxMerger, SourceId,
+    MAX_SOURCES, SOURCE_TIMEOUT_MS,
+};
+use prop_relay_control::hardware::{DigitalInput, RelayOutput, RelayState};
+use prop_relay_control::mock::Clock;
+use prop_relay_control::runner::SequenceRunner;
+use prop_relay_control::sequence::SequenceConfig;
+use prop_relay_control::storage::{DmxSettings, SignalLoss};
+
+use common::SNAKE;
+
+const CONFIGS: &[SequenceConfig] = &[SequenceConfig::new(DigitalInput::DI2, 3000, SNAKE, "snake")];
+
+const CID: [u8; 16] = *b"0123456789abcdef";
+
+fn artnet(universe: u16, sequence: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = b"Art-Net\0".to_vec();
+    packet.extend_from_slice(&0x5000u16.to_le_bytes());
+    packet.extend_from_slice(&14u16.to_be_bytes());
+    packet.extend_from_slice(&[sequence, 0]);
+    packet.extend_from_slice(&universe.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn sacn(universe: u16, priority: u8, options: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x00, 0x10, 0x00, 0x00];
+    packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+    packet.extend_from_slice(&[0x70, 0x00, 0, 0, 0, 4]);
+    packet.extend_from_slice(&CID);
+    packet.extend_from_slice(&[0x70, 0x00, 0, 0, 0, 2]);
+    packet.extend_from_slice(&[b'x'; 64]);
+    packet.extend_from_slice(&[priority, 0, 0, 0, options]);
+    packet.extend_from_slice(&universe.to_be_bytes());
+    packet.extend_from_slice(&[0x70, 0x00, 0x02, 0xA1, 0, 0, 0, 1]);
+    packet.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn frame(universe: u16, priority: u8, sequence: u8, data: &[u8]) -> DmxFrame<'_> {
+    DmxFrame {
+        universe,
+        priority,
+        sequence,
+        terminated: false,
+        data,
+    }
+}
+
+const CONSOLE: SourceId = SourceId::ArtNet([10, 0, 0, 1]);
+const BACKUP: SourceId = SourceId::ArtNet([10, 0, 0, 2]);
+
+#[test]
+fn parses_artnet_dmx() {
+    let packet = artnet(0x0123, 9, &[0, 255, 128, 7]);
+    let frame = parse_artnet(&packet).unwrap();
+    assert_eq!(frame.universe, 0x0123);
+    assert_eq!(frame.sequence, 9);
+    assert_eq!(frame.priority, 100);
+    assert_eq!(frame.data, [0, 255, 128, 7]);
+
+    let mut poll = packet.clone();
+    poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+    assert_eq!(parse_artnet(&poll), Err(DmxError::NotDmx));
+    let mut old = packet.clone();
+    old[11] = 13;
+    assert_eq!(parse_artnet(&old), Err(DmxError::UnsupportedVersion));
+    assert_eq!(
+        parse_artnet(&packet[..packet.len() - 1]),
+        Err(DmxError::Malformed)
+    );
+    assert_eq!(
+        parse_artnet(&artnet(1, 0, &[0; 514])),
+        Err(DmxError::Malformed)
+    );
+    assert_eq!(parse_artnet(b"Art-Net"), Err(DmxError::Malformed));
+}
+
+#[test]
+fn parses_sacn_data() {
+    let packet = sacn(7, 150, 0, &[1, 2, 3]);
+    let (cid, frame) = parse_sacn(&packet).unwrap();
+    assert_eq!(cid, CID);
+    assert_eq!(frame.universe, 7);
+    assert_eq!(frame.priority, 150);
+    assert!(!frame.terminated);
+    assert_eq!(frame.data, [1, 2, 3]);
+
+    let packet = sacn(7, 100, 0x40, &[]);
+    let (_, frame) = parse_sacn(&packet).unwrap();
+    assert!(frame.terminated);
+    assert_eq!(parse_sacn(&sacn(7, 100, 0x80, &[1])), Err(DmxError::NotDmx));
+    let mut text = sacn(7, 100, 0, &[1]);
+    text[125] = 0x17;
+    assert_eq!(parse_sacn(&text), Err(DmxError::NotDmx));
+    let mut sync = sacn(7, 100, 0, &[1]);
+    sync[21] = 8;
+    assert_eq!(parse_sacn(&sync), Err(DmxError::NotDmx));
+    let mut foreign = sacn(7, 100, 0, &[1]);
+    foreign[4] = b'B';
+    assert_eq!(parse_sacn(&foreign), Err(DmxError::Malformed));
+    let short = sacn(7, 100, 0, &[1, 2]);
+    assert_eq!(
+        parse_sacn(&short[..short.len() - 1]),
+        Err(DmxError::Malformed)
+    );
+
+    assert_eq!(sacn_group(0x0102), [239, 255, 1, 2]);
+}
+
+#[test]
+fn merges_highest_priority_then_highest_level() {
+    let mut merger = DmxMerger::new(1, 3, 4);
+    assert_eq!(merger.levels(), None);
+    assert!(!merger.receive(CONSOLE, &frame(2, 100, 0, &[255; 8]), 0));
+
+    assert!(merger.receive(CONSOLE, &frame(1, 100, 0, &[9, 9, 10, 0, 200, 0]), 0));
+    assert!(merger.receive(BACKUP, &frame(1, 100, 0, &[9, 9, 0, 50, 100]), 0));
+    assert_eq!(merger.levels().unwrap()[..4], [10, 50, 200, 0]);
+
+    let override_ = SourceId::Sacn(CID);
+    assert!(merger.receive(override_, &frame(1, 150, 0, &[0, 0, 1]), 0));
+    // Short frame: the missing slots read as 0
+    assert_eq!(merger.levels().unwrap()[..4], [1, 0, 0, 0]);
+
+    let mut end = frame(1, 150, 0, &[]);
+    end.terminated = true;
+    assert!(merger.receive(override_, &end, 0));
+    assert_eq!(merger.levels().unwrap()[..4], [10, 50, 200, 0]);
+    assert_eq!(merger.source_count(), 2);
+}
+
+#[test]
+fn drops_stale_packets_and_silent_sources() {
+    let mut merger = DmxMerger::new(1, 1, 1);
+    assert!(merger.receive(CONSOLE, &frame(1, 100, 200, &[10]), 0));
+    assert!(!merger.receive(CONSOLE, &frame(1, 100, 190, &[20]), 10));
+    assert!(!merger.receive(CONSOLE, &frame(1, 100, 200, &[20]), 10));
+    assert!(merger.receive(CONSOLE, &frame(1, 100, 201, &[30]), 20));
+    // Wrapped around, and far enough behind to be a restart
+    assert!(merger.receive(CONSOLE, &frame(1, 100, 5, &[40]), 30));
+    assert!(merger.receive(CONSOLE, &frame(1, 100, 100, &[50]), 40));
+    assert_eq!(merger.levels().unwrap()[0], 50);
+
+    for n in 2..=MAX_SOURCES as u8 {
+        assert!(merger.receive(
+            SourceId::ArtNet([10, 0, 0, n]),
+            &frame(1, 100, 0, &[0]),
+            500
+        ));
+    }
+    assert!(!merger.receive(
+        SourceId::ArtNet([10, 0, 0, 99]),
+        &frame(1, 100, 0, &[0]),
+        500
+    ));
+
+    assert_eq!(merger.next_expiry_ms(), Some(40 + SOURCE_TIMEOUT_MS));
+    assert!(!merger.expire(40 + SOURCE_TIMEOUT_MS - 1));
+    assert!(merger.expire(40 + SOURCE_TIMEOUT_MS));
+    assert_eq!(merger.source_count(), MAX_SOURCES - 1);
+    assert!(merger.expire(500 + SOURCE_TIMEOUT_MS));
+    assert_eq!(merger.levels(), None);
+    assert_eq!(merger.next_expiry_ms(), None);
+
+    // Window cut short at slot 512
+    assert_eq!(DmxMerger::new(1, 510, 9).width(), 3);
+}
+
+fn settings(on_loss: SignalLoss) -> DmxSettings {
+    DmxSettings {
+        artnet: true,
+        start_address: 11,
+        threshold: 100,
+        trigger_level: 200,
+        on_loss,
+        ..DmxSettings::DEFAULT
+    }
+}
+
+/// Universe with relay levels at address 11 and the sequence at 19
+fn universe(relays: [u8; 8], sequence: u8) -> Vec<u8> {
+    let mut data = vec![0; 20];
+    data[10..18].copy_from_slice(&relays);
+    data[18] = sequence;
+    data
+}
+
+#[test]
+fn drives_relays_and_sequences() {
+    let clock = Clock::take();
+    let (bus, relays) = common::relay_board();
+    let runner = SequenceRunner::<1>::new();
+    let mut control = DmxControl::new(&relays, &runner, CONFIGS, settings(SignalLoss::AllOff));
+    let writes = || bus.transactions().len();
+
+    let data = universe([255, 0, 99, 100, 0, 0, 0, 0], 255);
+    clock.block_on(control.receive(CONSOLE, &frame(1, 100, 0, &data), 0));
+    assert!(control.has_signal());
+    assert_eq!(bus.transactions().last().unwrap().bytes, [0x01, 0b1001]);
+    // Level already up when the signal appeared: no trigger
+    assert_eq!(runner.running(), [None]);
+
+    // No threshold crossed, no write
+    let before = writes();
+    let data = universe([120, 0, 50, 255, 0, 0, 0, 0], 0);
+    clock.block_on(control.receive(CONSOLE, &frame(1, 100, 0, &data), 10));
+    assert_eq!(writes(), before);
+
+    // Another surface switches R2 on; DMX leaves it alone
+    clock
+        .block_on(relays.set_relay(RelayOutput::Relay2, RelayState::High))
+        .unwrap();
+    let data = universe([120, 0, 50, 255, 0, 0, 0, 200], 200);
+    clock.block_on(control.receive(CONSOLE, &frame(1, 100, 0, &data), 20));
+    assert_eq!(
+        bus.transactions().last().unwrap().bytes,
+        [0x01, 0b1000_1011]
+    );
+    assert_eq!(runner.running(), [Some("snake")]);
+
+    clock.block_on(control.expire(20 + SOURCE_TIMEOUT_MS));
+    assert!(!control.has_signal());
+    assert_eq!(bus.transactions().last().unwrap().bytes, [0x01, 0x00]);
+
+    // Signal back: every mapped relay written again
+    let data = universe([0, 0, 0, 0, 0, 0, 0, 255], 0);
+    clock.block_on(control.receive(CONSOLE, &frame(1, 100, 0, &data), 5000));
+    assert_eq!(
+        bus.transactions().last().unwrap().bytes,
+        [0x01, 0b1000_0000]
+    );
+}
+
+#[test]
+fn holds_last_look_on_signal_loss() {
+    let clock = Clock::take();
+    let (bus, relays) = common::relay_board();
+    let runner = SequenceRunner::<1>::new();
+    let mut control = DmxControl::new(
+        &relays,
+        &runner,
+        CONFIGS,
+        settings(SignalLoss::HoldLastLook),
+    );
+
+    let data = universe([255; 8], 0);
+    clock.block_on(control.receive(CONSOLE, &frame(1, 100, 0, &data), 0));
+    let before = bus.transactions().len();
+    clock.block_on(control.expire(SOURCE_TIMEOUT_MS));
+    assert!(!control.has_signal());
+    assert_eq!(bus.transactions().len(), before);
+    assert_eq!(clock.block_on(relays.output_state()), 0xFF);
+}
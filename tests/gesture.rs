@@ -0,0 +1,221 @@
+//! Host tests for hold, release, double-trigger and pattern gestures
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::gesture::{Gesture, GestureEvent, GestureRecognizer};
+use prop_relay_control::hardware::DigitalInput;
+use prop_relay_control::input::{InputEvent, InputEventKind};
+use prop_relay_control::mock::Clock;
+use prop_relay_control::sequence::{SequenceConfig, SequenceDispatcher, JUMP_SCARE};
+
+use DigitalInput::{DI1, DI2, DI3, DI5};
+use InputEventKind::{Activated, Released, Triggered};
+
+const CONFIGS: &[SequenceConfig] = &[
+    SequenceConfig::new(DI1, 0, JUMP_SCARE, "scare"),
+    SequenceConfig::new(DI1, 5000, JUMP_SCARE, "hold").with_gesture(Gesture::Hold(3000)),
+    SequenceConfig::new(DI1, 0, JUMP_SCARE, "long press").with_gesture(Gesture::Release(2000)),
+    SequenceConfig::new(DI2, 0, JUMP_SCARE, "double").with_gesture(Gesture::Double(500)),
+    SequenceConfig::new(DI1, 0, JUMP_SCARE, "pattern").with_gesture(Gesture::Pattern {
+        after: &[DI3, DI5],
+        within_ms: 10_000,
+    }),
+];
+
+fn event(input: DigitalInput, timestamp_ms: u64, kind: InputEventKind) -> InputEvent {
+    InputEvent {
+        input,
+        timestamp_ms,
+        kind,
+    }
+}
+
+/// Gestures other than plain triggers completed by `event`
+fn feed(recognizer: &mut GestureRecognizer, event: InputEvent) -> Vec<GestureEvent> {
+    recognizer
+        .feed(&event)
+        .into_iter()
+        .filter(|gesture| gesture.gesture != Gesture::Trigger)
+        .collect()
+}
+
+/// Trigger `input` at `timestamp_ms`; number of gestures it completes
+fn trigger(recognizer: &mut GestureRecognizer, input: DigitalInput, timestamp_ms: u64) -> usize {
+    feed(recognizer, event(input, timestamp_ms, Triggered)).len()
+}
+
+#[test]
+fn triggers_are_reported_as_plain_gestures() {
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    let events = recognizer.feed(&event(DI1, 10, Triggered));
+    assert_eq!(
+        events.as_slice(),
+        &[GestureEvent {
+            input: DI1,
+            gesture: Gesture::Trigger,
+            timestamp_ms: 10
+        }]
+    );
+    assert!(recognizer.feed(&event(DI1, 20, Activated)).is_empty());
+}
+
+#[test]
+fn hold_fires_once_while_held() {
+    let clock = Clock::take();
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    assert_eq!(recognizer.deadline(), None);
+
+    clock.advance_ms(1000);
+    assert!(feed(&mut recognizer, event(DI1, 1000, Activated)).is_empty());
+    assert_eq!(recognizer.deadline().unwrap().as_millis(), 4000);
+    clock.advance_ms(2999);
+    assert!(recognizer.poll().is_empty());
+
+    clock.advance_ms(1);
+    let events = recognizer.poll();
+    assert_eq!(
+        events.as_slice(),
+        &[GestureEvent {
+            input: DI1,
+            gesture: Gesture::Hold(3000),
+            timestamp_ms: 4000
+        }]
+    );
+    assert_eq!(recognizer.deadline(), None);
+    clock.advance_ms(5000);
+    assert!(recognizer.poll().is_empty());
+
+    // Released after the hold: the long press completes too, not the hold
+    let events = feed(&mut recognizer, event(DI1, 9000, Released));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].gesture, Gesture::Release(2000));
+}
+
+#[test]
+fn late_hold_is_reported_before_the_release() {
+    let _clock = Clock::take();
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    feed(&mut recognizer, event(DI1, 0, Activated));
+
+    // The release arrived before the hold was polled
+    let events = feed(&mut recognizer, event(DI1, 3500, Released));
+    let gestures: Vec<_> = events.iter().map(|event| event.gesture).collect();
+    assert_eq!(gestures, [Gesture::Hold(3000), Gesture::Release(2000)]);
+    assert_eq!(events[0].timestamp_ms, 3000);
+}
+
+#[test]
+fn short_press_is_no_long_press() {
+    let _clock = Clock::take();
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    feed(&mut recognizer, event(DI1, 0, Activated));
+    assert!(feed(&mut recognizer, event(DI1, 1999, Released)).is_empty());
+
+    // A new press starts over
+    feed(&mut recognizer, event(DI1, 5000, Activated));
+    assert_eq!(recognizer.deadline().unwrap().as_millis(), 8000);
+    assert_eq!(feed(&mut recognizer, event(DI1, 7000, Released)).len(), 1);
+    // Release without a press seen, e.g. held since power-up
+    assert!(feed(&mut recognizer, event(DI1, 9000, Released)).is_empty());
+}
+
+#[test]
+fn double_needs_two_triggers_within_the_window() {
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    assert_eq!(trigger(&mut recognizer, DI2, 0), 0);
+    assert_eq!(trigger(&mut recognizer, DI2, 501), 0);
+    assert_eq!(trigger(&mut recognizer, DI2, 1000), 1);
+
+    // A third trigger starts a new pair
+    assert_eq!(trigger(&mut recognizer, DI2, 1100), 0);
+    assert_eq!(trigger(&mut recognizer, DI2, 1200), 1);
+    // Other inputs do not count
+    assert_eq!(trigger(&mut recognizer, DI1, 1300), 0);
+    assert_eq!(trigger(&mut recognizer, DI2, 1400), 0);
+}
+
+#[test]
+fn pattern_needs_its_inputs_in_order() {
+    let mut recognizer = GestureRecognizer::new(CONFIGS);
+    assert_eq!(trigger(&mut recognizer, DI3, 0), 0);
+    // Inputs outside the pattern are ignored
+    assert_eq!(trigger(&mut recognizer, DI2, 100), 0);
+    assert_eq!(trigger(&mut recognizer, DI5, 200), 0);
+    let events = feed(&mut recognizer, event(DI1, 300, Triggered));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].input, DI1);
+    assert!(matches!(events[0].gesture, Gesture::Pattern { .. }));
+
+    // Wrong order starts over; DI3 starts the pattern again
+    assert_eq!(trigger(&mut recognizer, DI3, 1000), 0);
+    assert_eq!(trigger(&mut recognizer, DI1, 1100), 0);
+    assert_eq!(trigger(&mut recognizer, DI5, 1200), 0);
+    assert_eq!(trigger(&mut recognizer, DI3, 1300), 0);
+    assert_eq!(trigger(&mut recognizer, DI3, 1400), 0);
+    assert_eq!(trigger(&mut recognizer, DI5, 1500), 0);
+    assert_eq!(trigger(&mut recognizer, DI1, 1600), 1);
+
+    // Too slow
+    assert_eq!(trigger(&mut recognizer, DI3, 20_000), 0);
+    assert_eq!(trigger(&mut recognizer, DI5, 25_000), 0);
+    assert_eq!(trigger(&mut recognizer, DI1, 30_001), 0);
+}
+
+#[test]
+fn dispatcher_finds_gesture_mappings() {
+    let _clock = Clock::take();
+    let dispatcher = SequenceDispatcher::new(CONFIGS);
+    let find = |gesture| {
+        dispatcher
+            .find_gesture(
+                CONFIGS,
+                &GestureEvent {
+                    input: DI1,
+                    gesture,
+                    timestamp_ms: 0,
+                },
+            )
+            .map(|config| config.name)
+    };
+    assert_eq!(find(Gesture::Trigger), Some("scare"));
+    assert_eq!(find(Gesture::Hold(3000)), Some("hold"));
+    assert_eq!(find(Gesture::Hold(1000)), None);
+    assert_eq!(find(Gesture::Double(500)), None);
+    assert_eq!(
+        dispatcher
+            .find_config(CONFIGS, DI1)
+            .map(|config| config.name),
+        Some("scare")
+    );
+    // Each mapping keeps its own cooldown
+    assert_eq!(dispatcher.cooldown_ms(DI1), 0);
+    assert_eq!(dispatcher.mapping_cooldown_ms(&CONFIGS[1]), 5000);
+    assert_eq!(dispatcher.mapping_cooldown_ms(&CONFIGS[2]), 0);
+}
+
+#[test]
+fn trigger_cooldown_does_not_block_gestures_on_the_input() {
+    const TAPS: &[SequenceConfig] = &[
+        SequenceConfig::new(DI2, 5000, JUMP_SCARE, "tap"),
+        SequenceConfig::new(DI2, 5000, JUMP_SCARE, "double tap").with_gesture(Gesture::Double(500)),
+    ];
+    let clock = Clock::take();
+    let mut recognizer = GestureRecognizer::new(TAPS);
+    let mut dispatcher = SequenceDispatcher::new(TAPS);
+
+    // As the control task does: start each mapped gesture off cooldown
+    let mut started = Vec::new();
+    for at in [0, 200, 400] {
+        clock.advance_ms(at - clock.now_ms());
+        for gesture in &recognizer.feed(&event(DI2, at, Triggered)) {
+            let config = dispatcher.find_gesture(TAPS, gesture).unwrap();
+            if !dispatcher.mapping_cooling_down(config) {
+                dispatcher.mark_mapping(config);
+                started.push((at, config.name));
+            }
+        }
+    }
+    assert_eq!(started, [(0, "tap"), (200, "double tap")]);
+    assert_eq!(dispatcher.remaining_ms(DI2), 4600);
+    assert_eq!(dispatcher.mapping_remaining_ms(&TAPS[1]), 4800);
+}
@@ -0,0 +1,84 @@
+//! Host tests for the HTTP request parser
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::http::{
+    parse_request, response_head, HttpError, Method, Status, CONTENT_HTML, CONTENT_JSON,
+    EVENT_STREAM_HEAD,
+};
+
+#[test]
+fn parses_request_line_query_and_body() {
+    let raw = b"PUT /api/relays/3?verbose=1&x HTTP/1.1\r\n\
+                Host: controller\r\n\
+                content-length: 15\r\n\
+                \r\n\
+                {\"state\":\"on\"}\n";
+    let request = parse_request(raw).unwrap();
+
+    assert_eq!(request.method, Method::Put);
+    assert_eq!(request.path, "/api/relays/3");
+    assert_eq!(request.query_param("verbose"), Some("1"));
+    assert_eq!(request.query_param("x"), Some(""));
+    assert_eq!(request.query_param("y"), None);
+    assert_eq!(request.body, b"{\"state\":\"on\"}\n");
+    assert_eq!(
+        request.segments().collect::<Vec<_>>(),
+        vec!["api", "relays", "3"]
+    );
+}
+
+#[test]
+fn waits_for_headers_and_body() {
+    let raw = b"POST /api/all-off HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd";
+
+    for len in 0..raw.len() {
+        assert_eq!(parse_request(&raw[..len]), Err(HttpError::Incomplete));
+    }
+    assert_eq!(parse_request(raw).unwrap().body, b"abcd");
+}
+
+#[test]
+fn rejects_malformed_requests() {
+    let cases: &[(&[u8], HttpError)] = &[
+        (b"GET /\r\n\r\n", HttpError::BadRequest),
+        (b"GET / HTTP/1.1 extra\r\n\r\n", HttpError::BadRequest),
+        (b"GET relays HTTP/1.1\r\n\r\n", HttpError::BadRequest),
+        (b"get / HTTP/1.1\r\n\r\n", HttpError::BadRequest),
+        (b"GET / HTTP/1.1\r\nNoColon\r\n\r\n", HttpError::BadRequest),
+        (
+            b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+            HttpError::BadRequest,
+        ),
+        (b"PATCH / HTTP/1.1\r\n\r\n", HttpError::NotImplemented),
+        (
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            HttpError::NotImplemented,
+        ),
+        (b"GET / HTTP/2.0\r\n\r\n", HttpError::VersionNotSupported),
+    ];
+    for (raw, error) in cases {
+        assert_eq!(
+            parse_request(raw),
+            Err(*error),
+            "{}",
+            String::from_utf8_lossy(raw)
+        );
+    }
+    assert_eq!(HttpError::Incomplete.status(), Status::PayloadTooLarge);
+}
+
+#[test]
+fn response_head_has_status_and_length() {
+    assert_eq!(
+        response_head(Status::NotFound, CONTENT_JSON, 27).as_str(),
+        "HTTP/1.1 404 Not Found\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: 27\r\n\
+         Connection: close\r\n\r\n"
+    );
+    let longest = response_head(Status::VersionNotSupported, CONTENT_HTML, usize::MAX);
+    assert!(longest.ends_with("\r\n\r\n"));
+    assert!(EVENT_STREAM_HEAD.contains("\r\nContent-Type: text/event-stream\r\n"));
+    assert!(EVENT_STREAM_HEAD.ends_with("\r\n\r\n"));
+}
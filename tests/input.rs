@@ -0,0 +1,207 @@
+//! Host tests for input debouncing, trigger modes and inversion
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::input::{Debouncer, TriggerDetector, LEVEL_REPEAT_MS};
+use prop_relay_control::mock::Clock;
+use prop_relay_control::storage::{InputPull, InputSettings, TriggerMode};
+
+fn detector(mode: TriggerMode, inverted: bool, pin_high: bool) -> TriggerDetector {
+    let settings = InputSettings {
+        mode,
+        pull: InputPull::Up,
+        inverted,
+    };
+    TriggerDetector::new(settings, pin_high)
+}
+
+/// Feed `edges` (time in ms, pin level after the edge) through `filter`,
+/// polling whenever a level is due as the input task does; returns the
+/// accepted levels with the time they were accepted
+fn filter_edges(
+    clock: &Clock,
+    filter: &mut Debouncer,
+    edges: &[(u64, bool)],
+    until_ms: u64,
+) -> Vec<(u64, bool)> {
+    let mut accepted = Vec::new();
+    let mut edges = edges.iter().peekable();
+    loop {
+        let settled = filter.deadline().map(|at| at.as_millis());
+        let next_edge = edges.peek().map(|&&(at, _)| at);
+        let at = match (settled, next_edge) {
+            (Some(settled), Some(edge)) => settled.min(edge),
+            (settled, edge) => match settled.or(edge) {
+                Some(at) => at,
+                None => break,
+            },
+        };
+        if at > until_ms {
+            break;
+        }
+        clock.advance_ms(at - clock.now_ms());
+        if next_edge == Some(at) {
+            let &(_, level) = edges.next().unwrap();
+            filter.edge(level);
+        } else if let Some(level) = filter.poll() {
+            accepted.push((at, level));
+        }
+    }
+    clock.advance_ms(until_ms.saturating_sub(clock.now_ms()));
+    accepted
+}
+
+#[test]
+fn spike_shorter_than_debounce_is_a_glitch() {
+    let clock = Clock::take();
+    let mut filter = Debouncer::new(50, false);
+
+    // A 10ms noise spike on a long cable
+    let accepted = filter_edges(&clock, &mut filter, &[(100, true), (110, false)], 500);
+    assert_eq!(accepted, []);
+    assert!(!filter.level());
+    assert_eq!(filter.glitches(), 1);
+    assert_eq!(filter.deadline(), None);
+}
+
+#[test]
+fn level_counts_once_stable() {
+    let clock = Clock::take();
+    let mut filter = Debouncer::new(50, false);
+
+    // Contact bounce on press and release; the level counts 50ms after
+    // the last bounce
+    let edges = [
+        (100, true),
+        (102, false),
+        (105, true),
+        (107, false),
+        (110, true),
+        (400, false),
+        (403, true),
+        (406, false),
+    ];
+    let accepted = filter_edges(&clock, &mut filter, &edges, 1000);
+    assert_eq!(accepted, [(160, true), (456, false)]);
+    assert_eq!(filter.glitches(), 3);
+}
+
+#[test]
+fn missed_pulse_still_counts_as_glitch() {
+    let clock = Clock::take();
+    let mut filter = Debouncer::new(20, true);
+
+    // The pin went low and back before it was read
+    filter_edges(&clock, &mut filter, &[(30, true)], 100);
+    assert!(filter.level());
+    assert_eq!(filter.glitches(), 1);
+}
+
+#[test]
+fn zero_debounce_accepts_at_once() {
+    let clock = Clock::take();
+    let mut filter = Debouncer::new(0, false);
+    let accepted = filter_edges(&clock, &mut filter, &[(10, true), (11, false)], 50);
+    assert_eq!(accepted, [(10, true), (11, false)]);
+    assert_eq!(filter.glitches(), 0);
+}
+
+#[test]
+fn filtered_edges_drive_triggers() {
+    let clock = Clock::take();
+    let mut filter = Debouncer::new(30, false);
+    let mut rising = detector(TriggerMode::Rising, false, filter.level());
+
+    // Spike, then a real press with bounce, then release
+    let edges = [
+        (10, true),
+        (12, false),
+        (100, true),
+        (101, false),
+        (102, true),
+        (300, false),
+    ];
+    let triggers: Vec<u64> = filter_edges(&clock, &mut filter, &edges, 500)
+        .into_iter()
+        .filter(|&(_, level)| rising.update(level))
+        .map(|(at, _)| at)
+        .collect();
+    assert_eq!(triggers, [132]);
+    assert_eq!(filter.glitches(), 2);
+}
+
+/// Triggers from driving the pin through `levels`
+fn triggers(detector: &mut TriggerDetector, levels: &[bool]) -> Vec<bool> {
+    levels.iter().map(|&high| detector.update(high)).collect()
+}
+
+#[test]
+fn edge_modes_pick_their_edges() {
+    let _clock = Clock::take();
+    let levels = [true, true, false, true, false];
+
+    let mut rising = detector(TriggerMode::Rising, false, false);
+    assert_eq!(
+        triggers(&mut rising, &levels),
+        [true, false, false, true, false]
+    );
+    let mut falling = detector(TriggerMode::Falling, false, false);
+    assert_eq!(
+        triggers(&mut falling, &levels),
+        [false, false, true, false, true]
+    );
+    let mut both = detector(TriggerMode::BothEdges, false, false);
+    assert_eq!(
+        triggers(&mut both, &levels),
+        [true, false, true, true, true]
+    );
+    assert_eq!(both.repeat_at(), None);
+}
+
+#[test]
+fn inversion_applies_before_the_mode() {
+    let _clock = Clock::take();
+    // Active-low PIR: idles high, pulls low on motion
+    let mut pir = detector(TriggerMode::Rising, true, true);
+    assert!(!pir.is_active());
+    assert!(pir.update(false));
+    assert!(pir.is_active());
+    assert!(!pir.update(true));
+    assert!(!pir.is_active());
+}
+
+#[test]
+fn held_levels_repeat_until_released() {
+    let clock = Clock::take();
+    let mut mat = detector(TriggerMode::LevelHigh, false, false);
+    assert_eq!(mat.repeat_at(), None);
+    assert!(!mat.repeat());
+
+    assert!(mat.update(true));
+    clock.advance_ms(LEVEL_REPEAT_MS - 1);
+    assert!(!mat.repeat());
+    clock.advance_ms(1);
+    assert!(mat.repeat());
+    assert!(!mat.repeat());
+    clock.advance_ms(LEVEL_REPEAT_MS);
+    assert!(mat.repeat());
+
+    assert!(!mat.update(false));
+    assert_eq!(mat.repeat_at(), None);
+    clock.advance_ms(LEVEL_REPEAT_MS);
+    assert!(!mat.repeat());
+}
+
+#[test]
+fn level_held_at_power_up_triggers() {
+    let _clock = Clock::take();
+    let mut low = detector(TriggerMode::LevelLow, false, false);
+    assert!(low.repeat());
+    assert!(!low.update(true));
+    assert!(low.update(false));
+
+    // Inverted: a high pin is inactive, so level-low is held
+    let mut inverted = detector(TriggerMode::LevelLow, true, true);
+    assert!(inverted.repeat());
+    assert!(!detector(TriggerMode::LevelHigh, true, true).repeat());
+}
@@ -0,0 +1,345 @@
+//! Host tests for the Modbus TCP and RTU framing and the register map
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use prop_relay_control::hardware::{DigitalInput, RelayOutput, RelayState};
+use prop_relay_control::mock::Clock;
+use prop_relay_control::modbus::{
+    crc16, parse_rtu, parse_tcp, write_mbap, write_rtu, Exception, FrameError, Mbap, ModbusServer,
+    Request, RtuFrame, RtuReceiver, RtuTiming, MAX_PDU, MAX_RTU_FRAME,
+};
+use prop_relay_control::sequence::SequenceConfig;
+
+use common::{Fixture, FLASH};
+
+const CONFIGS: &[SequenceConfig] = &[
+    SequenceConfig::new(DigitalInput::DI1, 70_000, FLASH, "flash"),
+    SequenceConfig::new(DigitalInput::DI3, 0, FLASH, "strobe"),
+];
+
+impl Fixture {
+    /// Response PDU to the request PDU `pdu`
+    fn call(&self, clock: &Clock, pdu: &[u8]) -> Vec<u8> {
+        let server = ModbusServer::new(
+            &self.inputs,
+            &self.relays,
+            &self.runner,
+            &self.dispatcher,
+            self.configs,
+        );
+        let mut response = [0; MAX_PDU];
+        let len = clock.block_on(server.handle(pdu, &mut response));
+        response[..len].to_vec()
+    }
+
+    fn last_write(&self) -> Vec<u8> {
+        self.bus.transactions().last().unwrap().bytes.clone()
+    }
+}
+
+#[test]
+fn parses_request_pdus() {
+    assert_eq!(
+        Request::parse(&[1, 0, 2, 0, 3]),
+        Ok(Request::ReadCoils {
+            address: 2,
+            count: 3
+        })
+    );
+    assert_eq!(
+        Request::parse(&[3, 0, 100, 0, 125]),
+        Ok(Request::ReadHoldingRegisters {
+            address: 100,
+            count: 125
+        })
+    );
+    assert_eq!(
+        Request::parse(&[5, 0, 3, 0xFF, 0x00]),
+        Ok(Request::WriteSingleCoil {
+            address: 3,
+            on: true
+        })
+    );
+    assert_eq!(
+        Request::parse(&[15, 0, 1, 0, 10, 2, 0xFF, 0x03]),
+        Ok(Request::WriteMultipleCoils {
+            address: 1,
+            count: 10,
+            bits: &[0xFF, 0x03]
+        })
+    );
+    assert_eq!(
+        Request::parse(&[16, 0, 200, 0, 2, 4, 0, 1, 0, 2]),
+        Ok(Request::WriteMultipleRegisters {
+            address: 200,
+            values: &[0, 1, 0, 2]
+        })
+    );
+
+    for (pdu, exception) in [
+        (&[][..], Exception::IllegalFunction),
+        (&[4, 0, 0, 0, 1], Exception::IllegalFunction),
+        (&[0x2B, 0x0E], Exception::IllegalFunction),
+        (&[1, 0, 0, 0, 0], Exception::IllegalDataValue),
+        (&[3, 0, 0, 0, 126], Exception::IllegalDataValue),
+        (&[2, 0xFF, 0xFF, 0, 2], Exception::IllegalDataValue),
+        (&[5, 0, 0, 0x12, 0x34], Exception::IllegalDataValue),
+        (&[6, 0, 0, 0, 0, 0], Exception::IllegalDataValue),
+        (&[15, 0, 0, 0, 9, 1, 0xFF], Exception::IllegalDataValue),
+        (&[16, 0, 0, 0, 1, 2, 0], Exception::IllegalDataValue),
+    ] {
+        assert_eq!(Request::parse(pdu), Err(exception), "{pdu:?}");
+    }
+}
+
+#[test]
+fn splits_tcp_frames() {
+    let requests = [
+        0x12, 0x34, 0, 0, 0, 6, 1, 3, 0, 0, 0, 2, // read 2 registers
+        0x12, 0x35, 0, 0, 0, 6, 1, 1, 0, 0, 0, // first half of the next
+    ];
+    let first = parse_tcp(&requests).unwrap().unwrap();
+    assert_eq!(
+        first.mbap,
+        Mbap {
+            transaction: 0x1234,
+            unit: 1
+        }
+    );
+    assert_eq!(first.pdu, [3, 0, 0, 0, 2]);
+    assert_eq!(first.len, 12);
+    assert_eq!(parse_tcp(&requests[first.len..]), Ok(None));
+    assert_eq!(parse_tcp(&requests[..5]), Ok(None));
+
+    let mut other = requests;
+    other[3] = 1;
+    assert_eq!(parse_tcp(&other), Err(FrameError::BadHeader));
+    let mut empty = requests;
+    empty[5] = 1;
+    assert_eq!(parse_tcp(&empty), Err(FrameError::BadHeader));
+
+    let mut header = [0; 7];
+    write_mbap(first.mbap, 5, &mut header);
+    assert_eq!(header, [0x12, 0x34, 0, 0, 0, 6, 1]);
+}
+
+#[test]
+fn maps_coils_to_relays() {
+    let clock = Clock::take();
+    let fixture = Fixture::new(CONFIGS);
+    assert_eq!(
+        fixture.call(&clock, &[5, 0, 2, 0xFF, 0]),
+        [5, 0, 2, 0xFF, 0]
+    );
+    assert_eq!(fixture.last_write(), [0x01, 0b100]);
+
+    assert_eq!(
+        fixture.call(&clock, &[15, 0, 4, 0, 4, 1, 0b1010]),
+        [15, 0, 4, 0, 4]
+    );
+    assert_eq!(fixture.last_write(), [0x01, 0b1010_0100]);
+
+    assert_eq!(fixture.call(&clock, &[1, 0, 0, 0, 8]), [1, 1, 0b1010_0100]);
+    assert_eq!(fixture.call(&clock, &[1, 0, 5, 0, 3]), [1, 1, 0b101]);
+
+    // Only 8 relays on one bank
+    let writes = fixture.bus.transactions().len();
+    assert_eq!(fixture.call(&clock, &[5, 0, 8, 0xFF, 0]), [0x85, 2]);
+    assert_eq!(fixture.call(&clock, &[15, 0, 6, 0, 3, 1, 0b111]), [0x8F, 2]);
+    assert_eq!(fixture.call(&clock, &[1, 0, 0, 0, 9]), [0x81, 2]);
+    assert_eq!(fixture.bus.transactions().len(), writes);
+}
+
+#[test]
+fn maps_discrete_inputs_to_levels() {
+    let clock = Clock::take();
+    let fixture = Fixture::new(CONFIGS);
+    fixture.inputs.set_level(DigitalInput::DI2, true);
+    fixture.inputs.set_level(DigitalInput::DI8, true);
+    assert_eq!(fixture.call(&clock, &[2, 0, 0, 0, 8]), [2, 1, 0b1000_0010]);
+    assert_eq!(fixture.call(&clock, &[2, 0, 1, 0, 1]), [2, 1, 1]);
+    assert_eq!(fixture.call(&clock, &[2, 0, 7, 0, 2]), [0x82, 2]);
+    assert_eq!(fixture.call(&clock, &[4, 0, 0, 0, 1]), [0x84, 1]);
+}
+
+#[test]
+fn exposes_status_and_sequence_registers() {
+    let clock = Clock::take();
+    let fixture = Fixture::new(CONFIGS);
+    fixture.inputs.set_level(DigitalInput::DI3, true);
+    clock
+        .block_on(
+            fixture
+                .relays
+                .set_relay(RelayOutput::Relay1, RelayState::High),
+        )
+        .unwrap();
+
+    assert_eq!(
+        fixture.call(&clock, &[3, 0, 0, 0, 6]),
+        [3, 12, 0, 1, 0, 0b100, 0, 0, 0, 0, 0, 2, 0, 0]
+    );
+
+    // Start "strobe"
+    assert_eq!(fixture.call(&clock, &[6, 0, 101, 0, 1]), [6, 0, 101, 0, 1]);
+    assert_eq!(fixture.runner.running(), [Some("strobe")]);
+    assert_eq!(fixture.call(&clock, &[3, 0, 100, 0, 2]), [3, 4, 0, 0, 0, 1]);
+    assert_eq!(fixture.call(&clock, &[3, 0, 2, 0, 1]), [3, 2, 0, 1]);
+
+    // Past the last sequence, and a gap in the map
+    assert_eq!(fixture.call(&clock, &[3, 0, 100, 0, 3]), [0x83, 2]);
+    assert_eq!(fixture.call(&clock, &[3, 0, 5, 0, 2]), [0x83, 2]);
+
+    // All off
+    assert_eq!(fixture.call(&clock, &[6, 0, 5, 0, 1]), [6, 0, 5, 0, 1]);
+    assert_eq!(fixture.last_write(), [0x01, 0]);
+}
+
+#[test]
+fn reads_and_writes_cooldowns() {
+    let clock = Clock::take();
+    let fixture = Fixture::new(CONFIGS);
+    // DI1: 70 000 ms = 0x0001_1170
+    assert_eq!(
+        fixture.call(&clock, &[3, 0, 200, 0, 4]),
+        [3, 8, 0, 1, 0x11, 0x70, 0, 0, 0, 0]
+    );
+
+    assert_eq!(
+        fixture.call(&clock, &[16, 0, 202, 0, 2, 4, 0, 0, 0x03, 0xE8]),
+        [16, 0, 202, 0, 2]
+    );
+    assert_eq!(
+        fixture.call(&clock, &[6, 0, 201, 0x27, 0x10]),
+        [6, 0, 201, 0x27, 0x10]
+    );
+    fixture.dispatcher.lock(|dispatcher| {
+        let dispatcher = dispatcher.borrow();
+        assert_eq!(dispatcher.cooldown_ms(DigitalInput::DI1), 0x0001_2710);
+        assert_eq!(dispatcher.cooldown_ms(DigitalInput::DI2), 1000);
+    });
+
+    fixture
+        .dispatcher
+        .lock(|dispatcher| dispatcher.borrow_mut().mark_triggered(DigitalInput::DI2));
+    clock.advance_ms(400);
+    assert_eq!(
+        fixture.call(&clock, &[3, 1, 46, 0, 2]),
+        [3, 4, 0, 0, 0x02, 0x58]
+    );
+
+    // Read-only registers refuse the whole write
+    assert_eq!(
+        fixture.call(&clock, &[16, 0, 215, 0, 2, 4, 0, 0, 0, 0]),
+        [0x90, 2]
+    );
+    assert_eq!(fixture.call(&clock, &[6, 1, 44, 0, 0]), [0x86, 2]);
+    // The last register address, alone or in a block
+    assert_eq!(fixture.call(&clock, &[6, 0xFF, 0xFF, 0, 1]), [0x86, 2]);
+    assert_eq!(
+        fixture.call(&clock, &[16, 0xFF, 0xFF, 0, 1, 2, 0, 1]),
+        [0x90, 2]
+    );
+    fixture.dispatcher.lock(|dispatcher| {
+        assert_eq!(dispatcher.borrow().cooldown_ms(DigitalInput::DI8), 0);
+    });
+}
+
+#[test]
+fn checks_rtu_frames() {
+    let request = [0x11, 3, 0, 0, 0, 2, 0xC6, 0x9B];
+    assert_eq!(crc16(&request[..6]), 0x9BC6);
+    assert_eq!(
+        parse_rtu(&request),
+        Ok(RtuFrame {
+            address: 0x11,
+            pdu: &[3, 0, 0, 0, 2]
+        })
+    );
+    let mut corrupt = request;
+    corrupt[5] = 3;
+    assert_eq!(parse_rtu(&corrupt), Err(FrameError::BadCrc));
+    assert_eq!(parse_rtu(&request[..3]), Err(FrameError::BadHeader));
+
+    let mut frame = [0; MAX_RTU_FRAME];
+    let len = write_rtu(0x11, &[3, 0, 0, 0, 2], &mut frame);
+    assert_eq!(frame[..len], request);
+}
+
+#[test]
+fn times_rtu_characters() {
+    assert_eq!(
+        RtuTiming::new(9600),
+        RtuTiming {
+            char_us: 1146,
+            t15_us: 1719,
+            t35_us: 4011
+        }
+    );
+    assert_eq!(
+        RtuTiming::new(115_200),
+        RtuTiming {
+            char_us: 96,
+            t15_us: 750,
+            t35_us: 1750
+        }
+    );
+}
+
+#[test]
+fn delimits_rtu_frames_by_silence() {
+    let timing = RtuTiming::new(9600);
+    let char_us = timing.char_us as u64;
+    let mut receiver = RtuReceiver::new(timing);
+    assert_eq!(receiver.take(1_000_000), None);
+
+    // Back to back, then two characters read at once
+    receiver.receive(&[1, 3], 10_000);
+    receiver.receive(&[0], 10_000 + char_us);
+    receiver.receive(&[0, 0], 10_000 + 3 * char_us);
+    let end = 10_000 + 3 * char_us;
+    assert_eq!(receiver.deadline_us(), Some(end + 4011));
+    assert_eq!(receiver.take(end + 4010), None);
+    assert_eq!(receiver.take(end + 4011), Some(&[1, 3, 0, 0, 0][..]));
+    assert_eq!(receiver.deadline_us(), None);
+
+    // A gap over t1.5 spoils the frame
+    receiver.receive(&[1], 100_000);
+    receiver.receive(&[3], 100_000 + char_us + 1720);
+    receiver.receive(&[0], 100_000 + 2 * char_us + 1720);
+    assert_eq!(receiver.take(200_000), None);
+    assert_eq!(receiver.deadline_us(), None);
+
+    // Over t3.5 ends it, even when nobody took it in time
+    receiver.receive(&[9, 9], 300_000);
+    receiver.receive(&[1], 300_000 + 4012 + char_us);
+    assert_eq!(receiver.take(400_000), Some(&[1][..]));
+
+    receiver.receive(&[1, 3], 500_000);
+    receiver.fault(500_000 + char_us);
+    assert_eq!(receiver.take(600_000), None);
+
+    for n in 0..=MAX_RTU_FRAME as u64 {
+        receiver.receive(&[0], 700_000 + n * char_us);
+    }
+    assert_eq!(receiver.take(1_000_000), None);
+}
+
+#[test]
+fn serves_the_map_over_rtu() {
+    let clock = Clock::take();
+    let fixture = Fixture::new(CONFIGS);
+    let request = rtu(7, &[5, 0, 1, 0xFF, 0]);
+    let frame = parse_rtu(&request).unwrap();
+    let response = fixture.call(&clock, frame.pdu);
+    assert_eq!(rtu(frame.address, &response), request);
+    assert_eq!(fixture.last_write(), [0x01, 0b10]);
+}
+
+fn rtu(address: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = [0; MAX_RTU_FRAME];
+    let len = write_rtu(address, pdu, &mut frame);
+    frame[..len].to_vec()
+}
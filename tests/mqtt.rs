@@ -0,0 +1,425 @@
+//! Host tests for the MQTT codec and client against a mock broker
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use core::future::Future;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_time::Timer;
+use prop_relay_control::hardware::{DigitalInput, RelayId, RelayOutput, RelayState};
+use prop_relay_control::input::{InputEvent, InputEventChannel, InputEventKind, InputTriggers};
+use prop_relay_control::mock::{Clock, MockBroker, MockI2c};
+use prop_relay_control::mqtt::{
+    decode, encode_connect, encode_publish, encode_subscribe, CommandError, Connect, MqttClient,
+    MqttCommand, MqttConfig, MqttError, Packet, Publish, QoS, Slug, Will,
+};
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::runner::SequenceRunner;
+use prop_relay_control::sequence::SequenceConfig;
+
+use common::SNAKE;
+
+const BASE: &str = "prop/prop-0a0b0c";
+
+const CONFIGS: &[SequenceConfig] = &[SequenceConfig::new(
+    DigitalInput::DI2,
+    3000,
+    SNAKE,
+    "Snake Pit",
+)];
+
+struct Fixture {
+    clock: Clock,
+    bus: MockI2c,
+    relays: RelayController<MockI2c>,
+    runner: SequenceRunner<1>,
+    inputs: InputEventChannel,
+    broker: MockBroker,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let clock = Clock::take();
+        let (bus, relays) = common::relay_board();
+        Self {
+            clock,
+            bus,
+            relays,
+            runner: SequenceRunner::new(),
+            inputs: InputEventChannel::new(),
+            broker: MockBroker::new(),
+        }
+    }
+
+    fn client(&self, config: MqttConfig<'static>) -> MqttClient<'_, MockI2c, 1, 1> {
+        MqttClient::new(config, &self.relays, &self.runner, CONFIGS)
+    }
+
+    /// Connect `client` to the broker and run `script` next to it; `Err`
+    /// if the connection ended first
+    fn session<F: Future>(
+        &self,
+        client: &mut MqttClient<'_, MockI2c, 1, 1>,
+        script: F,
+    ) -> Result<F::Output, MqttError> {
+        self.clock.block_on(async {
+            let mut connection = self.broker.connect();
+            let triggers = InputTriggers::<1>::new();
+            let slot = self.runner.run_slot(0, &self.relays, &triggers);
+            match select3(client.run(&mut connection, &self.inputs), slot, script).await {
+                Either3::First(error) => Err(error),
+                Either3::Second(never) => never,
+                Either3::Third(output) => Ok(output),
+            }
+        })
+    }
+}
+
+fn config() -> MqttConfig<'static> {
+    MqttConfig::new("prop-0a0b0c")
+}
+
+fn topic(suffix: &str) -> String {
+    format!("{BASE}/{suffix}")
+}
+
+/// Payloads published on `suffix` under the base topic
+fn payloads(broker: &MockBroker, suffix: &str) -> Vec<String> {
+    broker
+        .published_on(&topic(suffix))
+        .iter()
+        .map(|message| message.payload_str().to_string())
+        .collect()
+}
+
+#[test]
+fn encodes_and_decodes_packets() {
+    let mut buf = [0; 512];
+    let len = encode_connect(
+        &mut buf,
+        &Connect {
+            client_id: "p",
+            keep_alive_s: 30,
+            will: Some(Will {
+                topic: "s",
+                payload: b"x",
+                retain: true,
+            }),
+            username: Some("u"),
+            password: Some("pw"),
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        &buf[..len],
+        b"\x10\x1a\0\x04MQTT\x04\xee\0\x1e\0\x01p\0\x01s\0\x01x\0\x01u\0\x02pw"
+    );
+
+    let len = encode_subscribe(&mut buf, 7, &["a/+", "b"]).unwrap();
+    assert_eq!(&buf[..len], b"\x82\x0c\0\x07\0\x03a/+\x01\0\x01b\x01");
+
+    // Two-byte remaining length
+    let payload = [b'x'; 200];
+    let publish = Publish {
+        topic: "t/1",
+        payload: &payload,
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        dup: true,
+        packet_id: Some(0x1234),
+    };
+    let len = encode_publish(&mut buf, &publish).unwrap();
+    assert_eq!(&buf[..8], b"\x3b\xcf\x01\0\x03t/1");
+    assert_eq!(len, 210);
+    assert_eq!(
+        decode(&buf[..len]),
+        Ok(Some((Packet::Publish(publish), len)))
+    );
+    assert_eq!(decode(&buf[..len - 1]), Ok(None));
+    assert_eq!(
+        encode_publish(&mut buf[..100], &publish),
+        Err(MqttError::TooLarge)
+    );
+
+    assert_eq!(
+        decode(b"\x20\x02\x00\x05"),
+        Ok(Some((
+            Packet::ConnAck {
+                session_present: false,
+                code: 5
+            },
+            4
+        )))
+    );
+    assert_eq!(
+        decode(b"\x40\x02\x00\x09"),
+        Ok(Some((Packet::PubAck(9), 4)))
+    );
+    assert_eq!(decode(b"\xd0\x00"), Ok(Some((Packet::PingResp, 2))));
+    assert_eq!(decode(b"\x40\x01\x00"), Err(MqttError::Malformed));
+    assert_eq!(
+        decode(b"\x30\xff\xff\xff\xff\x01"),
+        Err(MqttError::Malformed)
+    );
+}
+
+#[test]
+fn parses_command_topics() {
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("relay/3/set"), b"ON"),
+        Ok(MqttCommand::SetRelay(
+            RelayId::from_index(2).unwrap(),
+            RelayState::High
+        ))
+    );
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("relay/3/set"), b"off\n"),
+        Ok(MqttCommand::SetRelay(
+            RelayId::from_index(2).unwrap(),
+            RelayState::Low
+        ))
+    );
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("relay/3/set"), b"maybe"),
+        Err(CommandError::BadPayload)
+    );
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("relay/0/set"), b"ON"),
+        Err(CommandError::NoSuchRelay)
+    );
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("sequence/snake_pit/fire"), b""),
+        Ok(MqttCommand::Fire("snake_pit"))
+    );
+    assert_eq!(
+        MqttCommand::parse(BASE, &topic("all-off/set"), b"PRESS"),
+        Ok(MqttCommand::AllOff)
+    );
+    for suffix in ["relay/3", "sequence//fire", "all-off/set/x", "status"] {
+        assert_eq!(
+            MqttCommand::parse(BASE, &topic(suffix), b""),
+            Err(CommandError::UnknownTopic),
+            "{suffix}"
+        );
+    }
+    assert_eq!(
+        MqttCommand::parse(BASE, "prop/other/all-off/set", b""),
+        Err(CommandError::UnknownTopic)
+    );
+
+    assert_eq!(Slug("Snake Pit #2").to_string(), "snake_pit__2");
+    assert!(Slug("Snake Pit").matches("snake_pit"));
+}
+
+#[test]
+fn announces_itself_on_connect() {
+    let fixture = Fixture::new();
+    let broker = &fixture.broker;
+    let mut client = fixture.client(config().with_keep_alive(60));
+    fixture
+        .session(&mut client, Timer::after_millis(10))
+        .unwrap();
+
+    let connects = broker.connects();
+    assert_eq!(connects.len(), 1);
+    assert_eq!(connects[0].client_id, "prop-0a0b0c");
+    assert_eq!(connects[0].keep_alive_s, 60);
+    assert!(connects[0].clean_session);
+    assert_eq!(
+        connects[0].will,
+        Some((topic("status"), b"offline".to_vec(), true))
+    );
+    assert_eq!(
+        broker.subscriptions(),
+        [
+            topic("relay/+/set"),
+            topic("sequence/+/fire"),
+            topic("all-off/set")
+        ]
+    );
+
+    let discovery: Vec<_> = broker
+        .published()
+        .into_iter()
+        .filter(|message| message.topic.starts_with("homeassistant/"))
+        .collect();
+    // 8 relays, button and sensor per sequence, 8 inputs, all-off
+    assert_eq!(discovery.len(), 8 + 2 + 8 + 1);
+    assert!(discovery
+        .iter()
+        .all(|message| message.retain && message.qos == QoS::AtMostOnce));
+    let relay = &discovery[2];
+    assert_eq!(
+        relay.topic,
+        "homeassistant/switch/prop-0a0b0c/relay_3/config"
+    );
+    assert!(relay.payload_str().starts_with(
+        "{\"name\":\"Relay 3\",\"unique_id\":\"prop-0a0b0c_relay_3\",\
+         \"state_topic\":\"prop/prop-0a0b0c/relay/3\",\
+         \"command_topic\":\"prop/prop-0a0b0c/relay/3/set\""
+    ));
+    assert!(relay
+        .payload_str()
+        .ends_with("\"model\":\"ESP32-S3-ETH-8DI-8RO\"}}"));
+    assert_eq!(
+        discovery[8].topic,
+        "homeassistant/button/prop-0a0b0c/sequence_snake_pit/config"
+    );
+    assert!(discovery[8]
+        .payload_str()
+        .starts_with("{\"name\":\"Snake Pit\","));
+
+    assert_eq!(payloads(broker, "status"), ["online"]);
+    let relays: Vec<_> = (1..=8)
+        .flat_map(|n| broker.published_on(&topic(&format!("relay/{n}"))))
+        .collect();
+    assert_eq!(relays.len(), 8);
+    assert!(relays
+        .iter()
+        .all(|message| message.payload == b"OFF" && message.retain));
+    assert_eq!(client.inflight(), 0);
+}
+
+#[test]
+fn publishes_inputs_relays_and_sequences() {
+    let fixture = Fixture::new();
+    let broker = &fixture.broker;
+    let mut client = fixture.client(config());
+    fixture
+        .session(&mut client, async {
+            Timer::after_millis(10).await;
+            broker.clear_published();
+
+            fixture
+                .inputs
+                .send(InputEvent {
+                    input: DigitalInput::DI3,
+                    timestamp_ms: 1234,
+                    kind: InputEventKind::Triggered,
+                })
+                .await;
+            fixture
+                .relays
+                .set_relay(RelayOutput::Relay2, RelayState::High)
+                .await
+                .unwrap();
+            fixture.runner.start(&CONFIGS[0]);
+            Timer::after_millis(1000).await;
+        })
+        .unwrap();
+
+    assert_eq!(
+        payloads(broker, "input/3"),
+        ["{\"input\":\"DI3\",\"timestamp_ms\":1234}"]
+    );
+    assert_eq!(payloads(broker, "relay/2"), ["ON"]);
+    assert_eq!(payloads(broker, "relay/6"), ["ON", "OFF"]);
+    assert_eq!(
+        payloads(broker, "sequence/snake_pit"),
+        ["started", "finished"]
+    );
+    assert!(broker
+        .published()
+        .iter()
+        .all(|message| message.qos == QoS::AtLeastOnce && !message.dup));
+    assert_eq!(client.inflight(), 0);
+}
+
+#[test]
+fn follows_commands() {
+    let fixture = Fixture::new();
+    let broker = &fixture.broker;
+    let mut client = fixture.client(config());
+    fixture
+        .session(&mut client, async {
+            Timer::after_millis(10).await;
+            broker.publish(&topic("relay/3/set"), b"ON", QoS::AtLeastOnce);
+            Timer::after_millis(10).await;
+            assert_eq!(
+                fixture.bus.transactions().last().unwrap().bytes,
+                [0x01, 0b100]
+            );
+            assert_eq!(payloads(broker, "relay/3"), ["OFF", "ON"]);
+
+            broker.publish(&topic("relay/9/set"), b"ON", QoS::AtMostOnce);
+            broker.publish(&topic("sequence/snake_pit/fire"), b"", QoS::AtMostOnce);
+            Timer::after_millis(10).await;
+            assert_eq!(fixture.runner.running(), [Some("Snake Pit")]);
+
+            broker.publish(&topic("all-off/set"), b"PRESS", QoS::AtLeastOnce);
+            Timer::after_millis(10).await;
+        })
+        .unwrap();
+
+    assert_eq!(
+        fixture.bus.transactions().last().unwrap().bytes,
+        [0x01, 0x00]
+    );
+    assert_eq!(
+        payloads(broker, "sequence/snake_pit"),
+        ["started", "cancelled"]
+    );
+    // Both QoS 1 commands acknowledged
+    assert_eq!(broker.acked().len(), 2);
+}
+
+#[test]
+fn resends_unacknowledged_messages_after_reconnect() {
+    let fixture = Fixture::new();
+    let broker = &fixture.broker;
+    let mut client = fixture.client(config());
+    let error = fixture
+        .session(&mut client, async {
+            Timer::after_millis(10).await;
+            broker.set_ack_publishes(false);
+            fixture
+                .inputs
+                .send(InputEvent {
+                    input: DigitalInput::DI1,
+                    timestamp_ms: 5,
+                    kind: InputEventKind::Triggered,
+                })
+                .await;
+            Timer::after_millis(10).await;
+            broker.disconnect();
+            core::future::pending::<()>().await;
+        })
+        .unwrap_err();
+    assert_eq!(error, MqttError::Closed);
+    assert!(client.established());
+    assert_eq!(client.inflight(), 1);
+
+    broker.set_ack_publishes(true);
+    broker.clear_published();
+    fixture
+        .session(&mut client, Timer::after_millis(10))
+        .unwrap();
+    let resent = broker.published_on(&topic("input/1"));
+    assert_eq!(resent.len(), 1);
+    assert!(resent[0].dup);
+    assert_eq!(broker.connects().len(), 2);
+    assert_eq!(client.inflight(), 0);
+}
+
+#[test]
+fn times_out_without_ping_responses() {
+    let fixture = Fixture::new();
+    let broker = &fixture.broker;
+    let mut client = fixture.client(config().with_keep_alive(2));
+
+    fixture
+        .session(&mut client, Timer::after_millis(4500))
+        .unwrap();
+    assert!(broker.pings() >= 3);
+
+    broker.set_answer_pings(false);
+    let started = fixture.clock.now_ms();
+    let error = fixture
+        .session(&mut client, core::future::pending::<()>())
+        .unwrap_err();
+    assert_eq!(error, MqttError::Timeout);
+    // Ping after half the keep-alive, then a full keep-alive for the answer
+    assert!((3000..3100).contains(&(fixture.clock.now_ms() - started)));
+}
@@ -0,0 +1,148 @@
+//! Host tests for Ethernet link supervision
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::mock::Clock;
+use prop_relay_control::network::{
+    Ipv4Setup, LinkState, LinkSupervisor, StaticIpv4, DHCP_RETRY_MS, DHCP_TIMEOUT_MS,
+};
+use prop_relay_control::storage::NetworkSettings;
+
+const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+
+fn fixed() -> NetworkSettings {
+    NetworkSettings::fixed([10, 0, 0, 20], 24, [10, 0, 0, 1])
+}
+
+#[test]
+fn dhcp_lease_binds_after_link_up() {
+    let clock = Clock::take();
+    let mut supervisor = LinkSupervisor::new(NetworkSettings::DHCP, MAC);
+
+    assert_eq!(supervisor.update(false, false), None);
+    assert_eq!(supervisor.state(), LinkState::Down);
+
+    assert_eq!(supervisor.update(true, false), Some(Ipv4Setup::Dhcp));
+    clock.advance_ms(1500);
+    assert_eq!(supervisor.update(true, false), None);
+    assert_eq!(supervisor.update(true, true), None);
+    assert_eq!(supervisor.state(), LinkState::Bound);
+}
+
+#[test]
+fn missing_dhcp_server_falls_back_to_link_local() {
+    let clock = Clock::take();
+    let mut supervisor = LinkSupervisor::new(NetworkSettings::DHCP, MAC);
+
+    supervisor.update(true, false);
+    clock.advance_ms(DHCP_TIMEOUT_MS - 1);
+    assert_eq!(supervisor.update(true, false), None);
+
+    clock.advance_ms(1);
+    let fallback = StaticIpv4 {
+        address: [169, 254, 0x35, 0x56],
+        prefix_len: 16,
+        gateway: None,
+        dns: None,
+    };
+    assert_eq!(
+        supervisor.update(true, false),
+        Some(Ipv4Setup::Static(fallback))
+    );
+    assert!(matches!(supervisor.state(), LinkState::Fallback { .. }));
+    assert_eq!(supervisor.update(true, true), None);
+}
+
+#[test]
+fn configured_address_is_the_dhcp_fallback() {
+    let clock = Clock::take();
+    let settings = NetworkSettings {
+        dhcp: true,
+        ..fixed()
+    };
+    let mut supervisor = LinkSupervisor::new(settings, MAC).with_dhcp_timeout(500);
+
+    supervisor.update(true, false);
+    clock.advance_ms(500);
+    match supervisor.update(true, false) {
+        Some(Ipv4Setup::Static(fixed)) => {
+            assert_eq!(fixed.address, [10, 0, 0, 20]);
+            assert_eq!(fixed.gateway, Some([10, 0, 0, 1]));
+        }
+        other => panic!("expected fallback, got {other:?}"),
+    }
+}
+
+#[test]
+fn static_settings_skip_dhcp() {
+    let _clock = Clock::take();
+    let mut supervisor = LinkSupervisor::new(fixed(), MAC);
+
+    let expected = StaticIpv4 {
+        address: [10, 0, 0, 20],
+        prefix_len: 24,
+        gateway: Some([10, 0, 0, 1]),
+        dns: Some([10, 0, 0, 1]),
+    };
+    assert_eq!(
+        supervisor.update(true, true),
+        Some(Ipv4Setup::Static(expected))
+    );
+    assert_eq!(supervisor.state(), LinkState::Static);
+}
+
+#[test]
+fn reconnect_requests_a_new_lease() {
+    let clock = Clock::take();
+    let mut supervisor = LinkSupervisor::new(NetworkSettings::DHCP, MAC);
+
+    // Fell back while the DHCP server was unreachable
+    supervisor.update(true, false);
+    clock.advance_ms(DHCP_TIMEOUT_MS);
+    supervisor.update(true, false);
+    assert!(matches!(supervisor.state(), LinkState::Fallback { .. }));
+
+    // Cable replugged: DHCP is tried again
+    assert_eq!(supervisor.update(false, true), None);
+    assert_eq!(supervisor.state(), LinkState::Down);
+    assert_eq!(supervisor.update(true, false), Some(Ipv4Setup::Dhcp));
+    supervisor.update(true, true);
+    assert_eq!(supervisor.state(), LinkState::Bound);
+
+    // Lease expires without renewal: wait for a new one, fall back if none
+    assert_eq!(supervisor.update(true, false), None);
+    assert!(matches!(supervisor.state(), LinkState::Requesting { .. }));
+    clock.advance_ms(DHCP_TIMEOUT_MS);
+    assert!(matches!(
+        supervisor.update(true, false),
+        Some(Ipv4Setup::Static(_))
+    ));
+}
+
+#[test]
+fn fallback_retries_dhcp_while_the_link_stays_up() {
+    let clock = Clock::take();
+    let mut supervisor = LinkSupervisor::new(NetworkSettings::DHCP, MAC);
+
+    supervisor.update(true, false);
+    clock.advance_ms(DHCP_TIMEOUT_MS);
+    supervisor.update(true, false);
+    clock.advance_ms(DHCP_RETRY_MS - 1);
+    assert_eq!(supervisor.update(true, true), None);
+
+    // Still no server: back to the fallback address after the timeout
+    clock.advance_ms(1);
+    assert_eq!(supervisor.update(true, true), Some(Ipv4Setup::Dhcp));
+    clock.advance_ms(DHCP_TIMEOUT_MS);
+    assert!(matches!(
+        supervisor.update(true, false),
+        Some(Ipv4Setup::Static(_))
+    ));
+
+    // The server came up in the meantime
+    clock.advance_ms(DHCP_RETRY_MS);
+    assert_eq!(supervisor.update(true, true), Some(Ipv4Setup::Dhcp));
+    assert_eq!(supervisor.update(true, false), None);
+    assert_eq!(supervisor.update(true, true), None);
+    assert_eq!(supervisor.state(), LinkState::Bound);
+}
@@ -0,0 +1,212 @@
+//! Host tests for OSC decoding, encoding and relay/sequence control
+//!
+//! Run with `cargo +stable host-test`.
+
+mod common;
+
+use prop_relay_control::hardware::{DigitalInput, RelayId, RelayState};
+use prop_relay_control::mock::Clock;
+use prop_relay_control::osc::{
+    encode, encode_trigger, Messages, OscArg, OscCommand, OscControl, OscError, OscMessage,
+};
+use prop_relay_control::runner::SequenceRunner;
+use prop_relay_control::sequence::SequenceConfig;
+
+use common::SNAKE;
+
+const CONFIGS: &[SequenceConfig] = &[SequenceConfig::new(DigitalInput::DI2, 3000, SNAKE, "snake")];
+
+fn message(address: &str, args: &[OscArg<'_>]) -> Vec<u8> {
+    let mut buf = [0; 256];
+    let len = encode(address, args, &mut buf).unwrap();
+    buf[..len].to_vec()
+}
+
+fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+    let mut packet = b"#bundle\0".to_vec();
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    for element in elements {
+        packet.extend_from_slice(&(element.len() as u32).to_be_bytes());
+        packet.extend_from_slice(element);
+    }
+    packet
+}
+
+fn command(packet: &[u8]) -> Result<OscCommand<'_>, OscError> {
+    OscCommand::parse(&OscMessage::parse(packet)?)
+}
+
+#[test]
+fn encodes_padded_messages() {
+    assert_eq!(
+        message("/relay/3", &[OscArg::Int(1)]),
+        b"/relay/3\0\0\0\0,i\0\0\0\0\0\x01"
+    );
+    let mut buf = [0; 64];
+    let len = encode_trigger(DigitalInput::DI7, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"/input/7\0\0\0\0,i\0\0\0\0\0\x01");
+    assert_eq!(
+        encode("/relay/3", &[OscArg::Int(1)], &mut buf[..15]),
+        Err(OscError::TooLarge)
+    );
+}
+
+#[test]
+fn decodes_every_argument_type() {
+    let args = [
+        OscArg::Int(-2),
+        OscArg::Float(0.75),
+        OscArg::Str("snake"),
+        OscArg::Blob(&[1, 2, 3, 4, 5]),
+        OscArg::Bool(true),
+        OscArg::Bool(false),
+        OscArg::Nil,
+    ];
+    let packet = message("/test", &args);
+    assert_eq!(packet.len() % 4, 0);
+    let decoded = OscMessage::parse(&packet).unwrap();
+    assert_eq!(decoded.address, "/test");
+    assert_eq!(decoded.args().collect::<Vec<_>>(), args);
+
+    // No type tag string at all
+    assert_eq!(OscMessage::parse(b"/allOff\0").unwrap().arg(), None);
+}
+
+#[test]
+fn rejects_malformed_messages() {
+    let good = message("/relay/1", &[OscArg::Int(1)]);
+    for len in [3, 8, 11, 13, 15] {
+        assert_eq!(
+            OscMessage::parse(&good[..len]),
+            Err(OscError::Malformed),
+            "{len}"
+        );
+    }
+    assert_eq!(
+        OscMessage::parse(b"relay\0\0\0,i\0\0\0\0\0\x01"),
+        Err(OscError::Malformed)
+    );
+    assert_eq!(
+        OscMessage::parse(b"/relay/1\0\0\0\0,d\0\0\0\0\0\0\0\0\0\0"),
+        Err(OscError::UnsupportedType(b'd'))
+    );
+    assert_eq!(
+        OscMessage::parse(b"/x\0\0,b\0\0\0\0\0\x09abcd"),
+        Err(OscError::Malformed)
+    );
+    // Lengths near the top of the address space must not wrap around
+    for len in [b"\xff\xff\xff\xfd", b"\xff\xff\xff\xff"] {
+        let packet = [&b"/x\0\0,b\0\0"[..], len, b"abcd"].concat();
+        assert_eq!(OscMessage::parse(&packet), Err(OscError::Malformed));
+    }
+}
+
+#[test]
+fn maps_addresses_to_commands() {
+    let on = RelayId::from_index(2).unwrap();
+    assert_eq!(
+        command(&message("/relay/3", &[OscArg::Int(1)])),
+        Ok(OscCommand::SetRelay(on, RelayState::High))
+    );
+    assert_eq!(
+        command(&message("/relay/3", &[OscArg::Float(0.0)])),
+        Ok(OscCommand::SetRelay(on, RelayState::Low))
+    );
+    assert_eq!(
+        command(&message("/relay/3", &[OscArg::Str("on")])),
+        Ok(OscCommand::SetRelay(on, RelayState::High))
+    );
+    assert_eq!(
+        command(&message("/relay/3", &[])),
+        Err(OscError::BadArgument)
+    );
+    assert_eq!(
+        command(&message("/relay/0", &[OscArg::Int(1)])),
+        Err(OscError::NoSuchRelay)
+    );
+
+    assert_eq!(
+        command(&message("/sequence/snake/go", &[])),
+        Ok(OscCommand::Trigger("snake"))
+    );
+    assert_eq!(
+        command(&message("/sequence/snake/go", &[OscArg::Float(1.0)])),
+        Ok(OscCommand::Trigger("snake"))
+    );
+    // Button release
+    assert_eq!(
+        command(&message("/sequence/snake/go", &[OscArg::Int(0)])),
+        Ok(OscCommand::Ignore)
+    );
+    assert_eq!(command(&message("/allOff", &[])), Ok(OscCommand::AllOff));
+    assert_eq!(
+        command(&message("/allOff", &[OscArg::Bool(false)])),
+        Ok(OscCommand::Ignore)
+    );
+
+    for address in ["/", "/relay", "/sequence/snake", "/sequence//go", "/alloff"] {
+        assert_eq!(
+            command(&message(address, &[])),
+            Err(OscError::UnknownAddress),
+            "{address}"
+        );
+    }
+}
+
+#[test]
+fn unpacks_nested_bundles() {
+    let packet = bundle(&[
+        message("/relay/1", &[OscArg::Int(1)]),
+        bundle(&[
+            message("/allOff", &[]),
+            message("/relay/2", &[OscArg::Int(0)]),
+        ]),
+        message("/sequence/snake/go", &[]),
+    ]);
+    let addresses: Vec<_> = Messages::new(&packet)
+        .map(|message| message.unwrap().address)
+        .collect();
+    assert_eq!(
+        addresses,
+        ["/relay/1", "/allOff", "/relay/2", "/sequence/snake/go"]
+    );
+
+    // Element size past the end of the bundle
+    let mut truncated = bundle(&[message("/allOff", &[])]);
+    truncated.pop();
+    assert_eq!(
+        Messages::new(&truncated).collect::<Vec<_>>(),
+        [Err(OscError::Malformed)]
+    );
+
+    // Deeper than MAX_BUNDLE_DEPTH
+    let mut deep = bundle(&[message("/allOff", &[])]);
+    for _ in 0..4 {
+        deep = bundle(&[deep]);
+    }
+    assert_eq!(
+        Messages::new(&deep).collect::<Vec<_>>(),
+        [Err(OscError::Malformed)]
+    );
+}
+
+#[test]
+fn controls_relays_and_sequences() {
+    let clock = Clock::take();
+    let (bus, relays) = common::relay_board();
+    let runner = SequenceRunner::<1>::new();
+    let control = OscControl::new(&relays, &runner, CONFIGS);
+
+    let packet = bundle(&[
+        message("/relay/3", &[OscArg::Int(1)]),
+        message("/relay/9", &[OscArg::Int(1)]),
+        message("/sequence/nope/go", &[]),
+        message("/sequence/snake/go", &[]),
+    ]);
+    assert_eq!(clock.block_on(control.handle(&packet)), 2);
+    assert_eq!(bus.transactions().last().unwrap().bytes, vec![0x01, 0b100]);
+    assert_eq!(runner.running(), [Some("snake")]);
+
+    assert_eq!(clock.block_on(control.handle(&message("/allOff", &[]))), 1);
+    assert_eq!(bus.transactions().last().unwrap().bytes, vec![0x01, 0x00]);
+}
@@ -0,0 +1,322 @@
+//! Host tests for sequence programs and their interpreter
+//!
+//! Run with `cargo +stable host-test`.
+
+use embassy_futures::join::join;
+use embassy_time::Timer;
+use prop_relay_control::hardware::{DigitalInput, RelayOutput, RelayState};
+use prop_relay_control::input::InputTriggers;
+use prop_relay_control::mock::{Clock, MockI2c};
+use prop_relay_control::random::{Prng, RandomSource};
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::runner::SequenceRunner;
+use prop_relay_control::sequence::{
+    validate, Choice, Instruction, Program, ProgramError, SequenceConfig, LOOP_FOREVER,
+};
+use prop_relay_control::tca9554::{Tca9554, TCA9554_ADDRESS};
+
+const BLINK: &[Instruction] = &[
+    Instruction::set(RelayOutput::Relay1, RelayState::High),
+    Instruction::Wait(100),
+    Instruction::set(RelayOutput::Relay1, RelayState::Low),
+    Instruction::Wait(100),
+];
+
+const LIBRARY: &[Program] = &[
+    Program::new("blink", BLINK),
+    Program::new(
+        "twice",
+        &[Instruction::Call("blink"), Instruction::Call("blink")],
+    ),
+    Program::new("recursive", &[Instruction::Call("recursive")]),
+];
+
+/// Output port writes as (time, value)
+fn outputs(bus: &MockI2c) -> Vec<(u64, u8)> {
+    bus.transactions()
+        .into_iter()
+        .map(|t| (t.timestamp_ms, t.bytes[1]))
+        .collect()
+}
+
+#[test]
+fn loop_repeats_its_body() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::Loop(3),
+        Instruction::Call("blink"),
+        Instruction::End,
+        Instruction::set(RelayOutput::Relay2, RelayState::High),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    clock
+        .block_on(relays.run_program(PROGRAM, LIBRARY, &inputs, &mut Prng::new(1)))
+        .unwrap();
+
+    assert_eq!(
+        outputs(&bus),
+        vec![
+            (0, 0x01),
+            (100, 0x00),
+            (200, 0x01),
+            (300, 0x00),
+            (400, 0x01),
+            (500, 0x00),
+            (600, 0x02)
+        ]
+    );
+}
+
+#[test]
+fn nested_loops_and_calls() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::Loop(2),
+        Instruction::Loop(2),
+        Instruction::set(RelayOutput::Relay3, RelayState::High),
+        Instruction::Wait(10),
+        Instruction::End,
+        Instruction::Call("twice"),
+        Instruction::End,
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    assert_eq!(validate(PROGRAM, LIBRARY), Ok(()));
+    clock
+        .block_on(relays.run_program(PROGRAM, LIBRARY, &inputs, &mut Prng::new(1)))
+        .unwrap();
+
+    // (2 x 10ms + 2 x 200ms blink) twice
+    assert_eq!(clock.now_ms(), 840);
+    assert_eq!(bus.transactions().len(), 2 * (2 + 4));
+}
+
+#[test]
+fn wait_input_holds_until_triggered() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::WaitInput(DigitalInput::DI3),
+        Instruction::set(RelayOutput::Relay4, RelayState::High),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    let (result, ()) = clock.block_on(join(
+        relays.run_program(PROGRAM, LIBRARY, &inputs, &mut Prng::new(1)),
+        async {
+            Timer::after_millis(50).await;
+            // Other inputs do not release the wait
+            inputs.notify(DigitalInput::DI1);
+            Timer::after_millis(50).await;
+            inputs.notify(DigitalInput::DI3);
+        },
+    ));
+
+    result.unwrap();
+    assert_eq!(outputs(&bus), vec![(100, 0x08)]);
+}
+
+#[test]
+fn endless_loop_runs_until_cancelled() {
+    const AMBIENT: &[Instruction] = &[
+        Instruction::Loop(LOOP_FOREVER),
+        Instruction::Call("blink"),
+        Instruction::End,
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+    let runner = SequenceRunner::<1>::new();
+    runner.set_library(LIBRARY);
+    let ambient = SequenceConfig::new(DigitalInput::DI1, 0, &[], "ambient").with_program(AMBIENT);
+
+    clock.block_on(async {
+        let worker = runner.run_slot(0, &relays, &inputs);
+        let script = async {
+            let handle = runner.start(&ambient).handle().unwrap();
+            Timer::after_millis(1050).await;
+            assert!(runner.is_active(handle));
+            runner.cancel(handle);
+            runner.wait_idle().await;
+        };
+        embassy_futures::select::select(worker, script).await;
+    });
+
+    let writes = outputs(&bus);
+    assert_eq!(writes.len(), 12);
+    // Five full blinks, the sixth cancelled while on and released
+    assert_eq!(writes[10], (1000, 0x01));
+    assert_eq!(writes[11], (1050, 0x00));
+}
+
+#[test]
+fn validate_reports_broken_programs() {
+    assert_eq!(
+        validate(&[Instruction::Call("missing")], LIBRARY),
+        Err(ProgramError::UnknownProgram)
+    );
+    assert_eq!(
+        validate(&[Instruction::Call("recursive")], LIBRARY),
+        Err(ProgramError::CallTooDeep)
+    );
+    assert_eq!(
+        validate(&[Instruction::Loop(2), Instruction::Wait(1)], LIBRARY),
+        Err(ProgramError::UnterminatedLoop)
+    );
+    assert_eq!(
+        validate(&[Instruction::Loop(2); 5], LIBRARY),
+        Err(ProgramError::LoopTooDeep)
+    );
+}
+
+#[test]
+fn interpreter_stops_runaway_recursion() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    let result = clock.block_on(relays.run_program(
+        &[Instruction::Call("recursive")],
+        LIBRARY,
+        &inputs,
+        &mut Prng::new(1),
+    ));
+
+    assert_eq!(result, Err(ProgramError::CallTooDeep));
+}
+
+#[test]
+fn prng_is_reproducible_and_in_range() {
+    let mut a = Prng::new(42);
+    let mut b = Prng::new(42);
+    for _ in 0..100 {
+        let value = a.range(200, 1500);
+        assert_eq!(value, b.range(200, 1500));
+        assert!((200..=1500).contains(&value));
+    }
+    assert_ne!(Prng::new(1).next_u64(), Prng::new(2).next_u64());
+
+    let mut rng = Prng::new(7);
+    let mut picks = [0u32; 3];
+    for _ in 0..4000 {
+        picks[rng.weighted([1u16, 0, 3].into_iter()).unwrap()] += 1;
+    }
+    assert_eq!(picks[1], 0);
+    assert!((800..1200).contains(&picks[0]), "{picks:?}");
+    assert_eq!(rng.weighted([0u16, 0].into_iter()), None);
+}
+
+#[test]
+fn random_wait_choice_and_skip_replay_with_seed() {
+    const SHOW: &[Instruction] = &[
+        Instruction::Loop(20),
+        Instruction::WaitRandom {
+            min_ms: 10,
+            max_ms: 50,
+        },
+        Instruction::Skip(50),
+        Instruction::Choose(&[Choice::new(1, "on"), Choice::new(1, "off")]),
+        Instruction::End,
+    ];
+    const LIBRARY: &[Program] = &[
+        Program::new(
+            "on",
+            &[Instruction::set(RelayOutput::Relay6, RelayState::High)],
+        ),
+        Program::new(
+            "off",
+            &[Instruction::set(RelayOutput::Relay6, RelayState::Low)],
+        ),
+    ];
+
+    let play = |seed| {
+        let clock = Clock::take();
+        let bus = MockI2c::new();
+        let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+        let inputs = InputTriggers::<1>::new();
+        clock
+            .block_on(relays.run_program(SHOW, LIBRARY, &inputs, &mut Prng::new(seed)))
+            .unwrap();
+        (outputs(&bus), clock.now_ms())
+    };
+
+    assert_eq!(validate(SHOW, LIBRARY), Ok(()));
+    let (writes, elapsed) = play(5);
+    assert_eq!(play(5), (writes.clone(), elapsed));
+    assert_ne!(play(6).0, writes);
+
+    // Some iterations skip, the rest pick either program
+    assert!(writes.len() > 2 && writes.len() < 20, "{writes:?}");
+    assert!(writes.iter().any(|&(_, v)| v == 0x20));
+    assert!(writes.iter().any(|&(_, v)| v == 0x00));
+    assert!((200..=1000).contains(&elapsed));
+}
+
+#[test]
+fn skip_passes_over_a_whole_loop() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::Skip(100),
+        Instruction::Loop(2),
+        Instruction::set(RelayOutput::Relay1, RelayState::High),
+        Instruction::End,
+        Instruction::set(RelayOutput::Relay2, RelayState::High),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    clock
+        .block_on(relays.run_program(PROGRAM, LIBRARY, &inputs, &mut Prng::new(1)))
+        .unwrap();
+
+    assert_eq!(outputs(&bus), vec![(0, 0x02)]);
+}
+
+#[test]
+fn skip_before_end_keeps_the_loop() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::Loop(3),
+        Instruction::set(RelayOutput::Relay1, RelayState::High),
+        Instruction::Wait(100),
+        Instruction::set(RelayOutput::Relay1, RelayState::Low),
+        Instruction::Skip(100),
+        Instruction::End,
+        Instruction::set(RelayOutput::Relay2, RelayState::High),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+
+    clock
+        .block_on(relays.run_program(PROGRAM, LIBRARY, &inputs, &mut Prng::new(1)))
+        .unwrap();
+
+    assert_eq!(
+        outputs(&bus),
+        vec![
+            (0, 0x01),
+            (100, 0x00),
+            (100, 0x01),
+            (200, 0x00),
+            (200, 0x01),
+            (300, 0x00),
+            (300, 0x02)
+        ]
+    );
+}
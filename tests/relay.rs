@@ -0,0 +1,352 @@
+//! Host tests for the relay controller and TCA9554 driver
+//!
+//! Run with `cargo +stable host-test`.
+
+use embassy_futures::join::join;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use embedded_hal_async::i2c::{ErrorKind, NoAcknowledgeSource};
+use prop_relay_control::bus::{BusError, RetryPolicy, SharedI2c};
+use prop_relay_control::hardware::{RelayId, RelayMask, RelayOutput, RelayState};
+use prop_relay_control::input::InputTriggers;
+use prop_relay_control::mock::{Clock, MockI2c, MockTca9554};
+use prop_relay_control::random::Prng;
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::sequence::{
+    Instruction, ProgramError, SequenceStep, JUMP_SCARE, SNAKE_SEQUENCE,
+};
+use prop_relay_control::tca9554::{expander_address, Tca9554, TCA9554A_ADDRESS, TCA9554_ADDRESS};
+
+fn writes(bus: &MockI2c) -> Vec<(u64, Vec<u8>)> {
+    bus.transactions()
+        .into_iter()
+        .inspect(|t| assert_eq!(t.address, TCA9554_ADDRESS))
+        .map(|t| (t.timestamp_ms, t.bytes))
+        .collect()
+}
+
+#[test]
+fn init_configures_outputs_low() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let controller = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+
+    clock.block_on(controller.init()).unwrap();
+
+    assert_eq!(
+        writes(&bus),
+        vec![(0, vec![0x01, 0x00]), (0, vec![0x03, 0x00])]
+    );
+}
+
+#[test]
+fn set_relay_keeps_other_outputs() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let controller = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+
+    clock
+        .block_on(async {
+            controller
+                .set_relay(RelayOutput::Relay1, RelayState::High)
+                .await?;
+            controller
+                .set_relay(RelayOutput::Relay8, RelayState::High)
+                .await?;
+            controller
+                .set_relay(RelayOutput::Relay1, RelayState::Low)
+                .await
+        })
+        .unwrap();
+
+    let bytes: Vec<u8> = writes(&bus).into_iter().map(|(_, b)| b[1]).collect();
+    assert_eq!(bytes, vec![0x01, 0x81, 0x80]);
+}
+
+#[test]
+fn sequence_timing_follows_step_durations() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let controller = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+
+    clock
+        .block_on(controller.execute_sequence(JUMP_SCARE))
+        .unwrap();
+
+    assert_eq!(
+        writes(&bus),
+        vec![(0, vec![0x01, 0x01]), (1000, vec![0x01, 0x00])]
+    );
+    assert_eq!(clock.now_ms(), 1000);
+}
+
+#[test]
+fn snake_sequence_waveform() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    clock
+        .block_on(async {
+            controller.init().await?;
+            controller.execute_sequence(SNAKE_SEQUENCE).await
+        })
+        .unwrap();
+
+    let relay2 = RelayOutput::Relay2 as u8;
+    assert_eq!(
+        device.pin_waveform(relay2),
+        vec![
+            (0, false),
+            (0, true),
+            (100, false),
+            (1000, true),
+            (1200, false),
+            (2000, true),
+            (2200, false),
+            (3000, true),
+            (3250, false),
+            (3500, true),
+            (3750, false),
+            (4000, true),
+            (4500, false),
+        ]
+    );
+    // No other relay moved
+    assert!(device
+        .waveform()
+        .iter()
+        .all(|s| s.outputs & !(1 << relay2) == 0));
+}
+
+#[test]
+fn injected_nak_is_reported_and_leaves_outputs_unchanged() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    clock.block_on(controller.init()).unwrap();
+    device.inject_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data));
+
+    let result = clock.block_on(controller.set_relay(RelayOutput::Relay3, RelayState::High));
+
+    assert_eq!(result, Err(BusError::Nak));
+    assert_eq!(device.outputs(), 0x00);
+    assert_eq!(
+        device.transactions().last().unwrap().error,
+        Some(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data))
+    );
+}
+
+#[test]
+fn wrong_address_is_not_acknowledged() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(0x21);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    let result = clock.block_on(controller.init());
+
+    assert_eq!(result, Err(BusError::Nak));
+    assert_eq!(device.register(0x03), 0xFF);
+}
+
+#[test]
+fn failed_step_switches_relays_off() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    clock.block_on(controller.init()).unwrap();
+    clock
+        .block_on(controller.set_relay(RelayOutput::Relay5, RelayState::High))
+        .unwrap();
+    device.inject_error(ErrorKind::ArbitrationLoss);
+
+    let result = clock.block_on(controller.execute_sequence(SNAKE_SEQUENCE));
+
+    assert_eq!(result, Err(BusError::ArbitrationLoss));
+    assert_eq!(device.outputs(), 0x00);
+}
+
+#[test]
+fn failed_program_step_switches_relays_off() {
+    const PROGRAM: &[Instruction] = &[
+        Instruction::set(RelayOutput::Relay1, RelayState::High),
+        Instruction::Wait(100),
+        Instruction::set(RelayOutput::Relay2, RelayState::High),
+    ];
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+    let inputs = InputTriggers::<1>::new();
+    let mut rng = Prng::new(1);
+
+    clock.block_on(controller.init()).unwrap();
+    let result = clock.block_on(async {
+        let program = controller.run_program(PROGRAM, &[], &inputs, &mut rng);
+        let fault = async {
+            Timer::after_millis(50).await;
+            device.inject_error(ErrorKind::ArbitrationLoss);
+        };
+        join(program, fault).await.0
+    });
+
+    assert_eq!(result, Err(ProgramError::Bus(BusError::ArbitrationLoss)));
+    assert_eq!(device.outputs(), 0x00);
+}
+
+#[test]
+fn verified_controller_recovers_from_hung_bus() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS))
+        .with_verify(RetryPolicy::default());
+
+    clock.block_on(controller.init()).unwrap();
+    device.hang_bus();
+
+    clock
+        .block_on(controller.set_relay(RelayOutput::Relay4, RelayState::High))
+        .unwrap();
+
+    assert_eq!(device.outputs(), 0x08);
+    assert_eq!(device.recoveries(), 1);
+}
+
+#[test]
+fn multi_relay_step_switches_in_one_write() {
+    const BOTH: RelayMask = RelayMask::of(&[RelayOutput::Relay3, RelayOutput::Relay4]);
+    const FLASH: &[SequenceStep] = &[
+        SequenceStep::group(BOTH, RelayState::High, 300),
+        // Relay3 off and Relay4 stays on, in the same instant
+        SequenceStep::pattern(BOTH, RelayMask::of(&[RelayOutput::Relay4]), 200),
+        SequenceStep::group(BOTH, RelayState::Low, 0),
+    ];
+
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    clock.block_on(controller.init()).unwrap();
+    device.clear_log();
+    clock.block_on(controller.execute_sequence(FLASH)).unwrap();
+
+    assert_eq!(device.transactions().len(), 3);
+    let outputs: Vec<(u64, u8)> = device
+        .waveform()
+        .iter()
+        .map(|s| (s.timestamp_ms, s.outputs))
+        .collect();
+    assert_eq!(
+        outputs,
+        vec![(0, 0b0000), (0, 0b1100), (300, 0b1000), (500, 0b0000)]
+    );
+}
+
+#[test]
+fn mask_operations_leave_other_relays_alone() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let controller = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+
+    clock
+        .block_on(async {
+            controller.init().await?;
+            controller.apply_pattern(RelayMask(0b1010_0001)).await?;
+            controller.toggle_relays(RelayMask(0b0000_0011)).await?;
+            controller
+                .set_relays(RelayMask(0b1000_0000), RelayState::Low)
+                .await
+        })
+        .unwrap();
+
+    assert_eq!(device.outputs(), 0b0010_0010);
+    assert_eq!(clock.block_on(controller.output_state()), 0b0010_0010);
+}
+
+#[test]
+fn relays_are_addressed_across_banks() {
+    const EXPANSION: u8 = 0x3B;
+    const CHASE: &[SequenceStep] = &[
+        SequenceStep::relay(RelayId::new(1, 7).unwrap(), RelayState::High, 100),
+        SequenceStep::new(RelayOutput::Relay1, RelayState::High, 100),
+        SequenceStep::on_bank(1, RelayMask::ALL, RelayMask::NONE, 0),
+    ];
+
+    let clock = Clock::take();
+    let onboard = MockTca9554::new(TCA9554_ADDRESS);
+    let expansion = MockTca9554::new(EXPANSION);
+    let controller = RelayController::with_banks([
+        Tca9554::new(onboard.clone(), TCA9554_ADDRESS),
+        Tca9554::new(expansion.clone(), expander_address(TCA9554A_ADDRESS, 3)),
+    ]);
+    assert_eq!(controller.relay_count(), 16);
+
+    clock.block_on(controller.init()).unwrap();
+    clock
+        .block_on(controller.set_relay(RelayId::from_index(10).unwrap(), RelayState::High))
+        .unwrap();
+    assert_eq!(expansion.outputs(), 0b0000_0100);
+    assert_eq!(onboard.outputs(), 0x00);
+
+    clock.block_on(controller.execute_sequence(CHASE)).unwrap();
+    assert_eq!(
+        expansion.pin_waveform(7),
+        vec![(0, false), (0, true), (200, false)]
+    );
+    assert_eq!(onboard.outputs(), 0x01);
+
+    clock.block_on(controller.all_off()).unwrap();
+    assert_eq!(onboard.outputs(), 0x00);
+    assert_eq!(clock.block_on(controller.bank_state(2)), None);
+    assert_eq!(
+        clock.block_on(controller.set_relay(RelayId::from_index(16).unwrap(), RelayState::High)),
+        Err(BusError::NoSuchBank)
+    );
+    assert_eq!(
+        clock.block_on(controller.toggle_bank(2, RelayMask::ALL)),
+        Err(BusError::NoSuchBank)
+    );
+}
+
+#[test]
+fn relay_ids_stay_in_range() {
+    assert_eq!(RelayId::from_index(127), RelayId::new(15, 7));
+    assert_eq!(RelayId::from_index(128), None);
+    assert_eq!(RelayId::from_index(2048), None);
+    assert_eq!(RelayId::new(0, 8), None);
+    assert_eq!(RelayId::new(16, 0), None);
+}
+
+#[test]
+fn expanders_share_one_bus() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let shared: Mutex<CriticalSectionRawMutex, MockI2c> = Mutex::new(bus.clone());
+    let second = expander_address(TCA9554_ADDRESS, 1);
+    let controller = RelayController::with_banks([
+        Tca9554::new(SharedI2c::new(&shared), TCA9554_ADDRESS),
+        Tca9554::new(SharedI2c::new(&shared), second),
+    ]);
+
+    clock
+        .block_on(controller.set_relay(RelayId::new(1, 0).unwrap(), RelayState::High))
+        .unwrap();
+    clock
+        .block_on(controller.set_relay(RelayOutput::Relay2, RelayState::High))
+        .unwrap();
+
+    let writes: Vec<(u8, Vec<u8>)> = bus
+        .transactions()
+        .into_iter()
+        .map(|t| (t.address, t.bytes))
+        .collect();
+    assert_eq!(
+        writes,
+        vec![
+            (0x21, vec![0x01, 0x01]),
+            (TCA9554_ADDRESS, vec![0x01, 0x02])
+        ]
+    );
+}
@@ -0,0 +1,344 @@
+//! Host tests for the concurrent sequence runner
+//!
+//! Run with `cargo +stable host-test`.
+
+use core::future::Future;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_time::Timer;
+use prop_relay_control::bus::BusError;
+use prop_relay_control::hardware::{DigitalInput, RelayMask, RelayOutput, RelayState};
+use prop_relay_control::input::InputTriggers;
+use prop_relay_control::mock::MockTca9554;
+use prop_relay_control::mock::{Clock, MockI2c};
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::runner::{SequenceRunner, StartOutcome, ALL_OFF_TIMEOUT_MS};
+use prop_relay_control::sequence::{Cleanup, ConflictPolicy, SequenceConfig, SequenceStep};
+use prop_relay_control::tca9554::{Tca9554, TCA9554_ADDRESS};
+
+const RELAY1_LONG: &[SequenceStep] = &[
+    SequenceStep::new(RelayOutput::Relay1, RelayState::High, 1000),
+    SequenceStep::new(RelayOutput::Relay1, RelayState::Low, 0),
+];
+
+const RELAY1_SHORT: &[SequenceStep] = &[
+    SequenceStep::new(RelayOutput::Relay1, RelayState::High, 200),
+    SequenceStep::new(RelayOutput::Relay1, RelayState::Low, 0),
+];
+
+const RELAY2_SHORT: &[SequenceStep] = &[
+    SequenceStep::new(RelayOutput::Relay2, RelayState::High, 500),
+    SequenceStep::new(RelayOutput::Relay2, RelayState::Low, 0),
+];
+
+fn config(
+    sequence: &'static [SequenceStep],
+    name: &'static str,
+    conflict: ConflictPolicy,
+) -> SequenceConfig {
+    SequenceConfig::new(DigitalInput::DI1, 0, sequence, name).with_conflict(conflict)
+}
+
+/// Output port writes as (time, value)
+fn outputs(bus: &MockI2c) -> Vec<(u64, u8)> {
+    bus.transactions()
+        .into_iter()
+        .map(|t| (t.timestamp_ms, t.bytes[1]))
+        .collect()
+}
+
+/// Run `script` against a two-slot runner until it and all sequences finish
+fn run<F: Future>(
+    clock: &Clock,
+    runner: &SequenceRunner<2>,
+    relays: &RelayController<MockI2c>,
+    script: F,
+) -> F::Output {
+    clock.block_on(async {
+        let inputs = InputTriggers::<2>::new();
+        let workers = join(
+            runner.run_slot(0, relays, &inputs),
+            runner.run_slot(1, relays, &inputs),
+        );
+        let script = async {
+            let output = script.await;
+            runner.wait_idle().await;
+            output
+        };
+        match select(workers, script).await {
+            Either::First((never, _)) => never,
+            Either::Second(output) => output,
+        }
+    })
+}
+
+#[test]
+fn disjoint_sequences_play_in_parallel() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcomes = run(&clock, &runner, &relays, async {
+        let a = runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        let b = runner.start(&config(RELAY2_SHORT, "short", ConflictPolicy::Queue));
+        (a, b)
+    });
+
+    assert!(matches!(
+        outcomes,
+        (StartOutcome::Started(_), StartOutcome::Started(_))
+    ));
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (100, 0x03), (600, 0x01), (1000, 0x00)]
+    );
+    assert!(runner.is_idle());
+}
+
+#[test]
+fn queued_sequence_waits_for_shared_relays() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcome = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        let outcome = runner.start(&config(RELAY1_SHORT, "short", ConflictPolicy::Queue));
+        assert_eq!(runner.queued_count(), 1);
+        outcome
+    });
+
+    assert!(matches!(outcome, StartOutcome::Queued(_)));
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (1000, 0x00), (1000, 0x01), (1200, 0x00)]
+    );
+}
+
+#[test]
+fn preempt_cancels_conflicting_sequence() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcome = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        runner.start(&config(RELAY1_SHORT, "short", ConflictPolicy::Preempt))
+    });
+
+    assert!(matches!(outcome, StartOutcome::Preempting(_)));
+    // The cancelled sequence releases its relay before the new one starts
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (100, 0x00), (100, 0x01), (300, 0x00)]
+    );
+}
+
+#[test]
+fn preempting_only_queued_sequences_starts_at_once() {
+    const RELAYS12: &[SequenceStep] = &[
+        SequenceStep::new(RelayOutput::Relay1, RelayState::High, 0),
+        SequenceStep::new(RelayOutput::Relay2, RelayState::High, 100),
+    ];
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let (queued, outcome) = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        let queued = runner.start(&config(RELAYS12, "both", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        let outcome = runner.start(&config(RELAY2_SHORT, "short", ConflictPolicy::Preempt));
+        (queued, outcome)
+    });
+
+    assert!(matches!(queued, StartOutcome::Queued(_)));
+    assert!(matches!(outcome, StartOutcome::Started(_)));
+    // "both" was dropped; "short" took the free slot without waiting
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (100, 0x03), (600, 0x01), (1000, 0x00)]
+    );
+}
+
+#[test]
+fn merge_runs_alongside_conflicting_sequence() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcome = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        runner.start(&config(RELAY1_SHORT, "short", ConflictPolicy::Merge))
+    });
+
+    assert!(matches!(outcome, StartOutcome::Started(_)));
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (100, 0x01), (300, 0x00), (1000, 0x00)]
+    );
+}
+
+#[test]
+fn reject_drops_conflicting_trigger() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcome = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+        runner.start(&config(RELAY1_SHORT, "short", ConflictPolicy::Reject))
+    });
+
+    assert_eq!(outcome, StartOutcome::Rejected);
+    assert_eq!(outputs(&bus), vec![(0, 0x01), (1000, 0x00)]);
+}
+
+#[test]
+fn sequences_queue_when_all_slots_are_busy() {
+    const RELAY3_SHORT: &[SequenceStep] = &[
+        SequenceStep::new(RelayOutput::Relay3, RelayState::High, 100),
+        SequenceStep::new(RelayOutput::Relay3, RelayState::Low, 0),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    let outcome = run(&clock, &runner, &relays, async {
+        runner.start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue));
+        runner.start(&config(RELAY2_SHORT, "short", ConflictPolicy::Queue));
+        let outcome = runner.start(&config(RELAY3_SHORT, "third", ConflictPolicy::Queue));
+        assert_eq!(runner.running(), [Some("long"), Some("short")]);
+        outcome
+    });
+
+    assert!(matches!(outcome, StartOutcome::Queued(_)));
+    assert_eq!(
+        outputs(&bus),
+        vec![
+            (0, 0x01),
+            (0, 0x03),
+            (500, 0x01),
+            (500, 0x05),
+            (600, 0x01),
+            (1000, 0x00)
+        ]
+    );
+}
+
+#[test]
+fn cancel_releases_touched_relays() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    run(&clock, &runner, &relays, async {
+        let long = runner
+            .start(&config(RELAY1_LONG, "long", ConflictPolicy::Queue))
+            .handle()
+            .unwrap();
+        let short = runner
+            .start(&config(RELAY2_SHORT, "short", ConflictPolicy::Queue))
+            .handle()
+            .unwrap();
+        Timer::after_millis(300).await;
+        assert!(runner.cancel(long));
+        Timer::after_millis(1).await;
+        assert!(!runner.is_active(long));
+        assert!(runner.is_active(short));
+        assert!(!runner.cancel(long));
+    });
+
+    // Relay2 keeps playing after Relay1 is cancelled
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (0, 0x03), (300, 0x02), (500, 0x00)]
+    );
+}
+
+#[test]
+fn cancel_runs_cleanup_steps() {
+    const WIND_DOWN: &[SequenceStep] = &[
+        SequenceStep::new(RelayOutput::Relay3, RelayState::High, 50),
+        SequenceStep::group(RelayMask(0b0000_0101), RelayState::Low, 0),
+    ];
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+    let fog =
+        config(RELAY1_LONG, "fog", ConflictPolicy::Queue).with_cleanup(Cleanup::Steps(WIND_DOWN));
+
+    run(&clock, &runner, &relays, async {
+        let handle = runner.start(&fog).handle().unwrap();
+        Timer::after_millis(100).await;
+        runner.cancel(handle);
+    });
+
+    assert_eq!(outputs(&bus), vec![(0, 0x01), (100, 0x05), (150, 0x00)]);
+}
+
+#[test]
+fn abort_stops_everything_and_switches_all_off() {
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+    let slow_cleanup = config(RELAY1_LONG, "long", ConflictPolicy::Queue)
+        .with_cleanup(Cleanup::Steps(RELAY1_LONG));
+
+    run(&clock, &runner, &relays, async {
+        runner.start(&slow_cleanup);
+        runner.start(&config(RELAY2_SHORT, "short", ConflictPolicy::Queue));
+        let queued = runner.start(&config(RELAY1_SHORT, "queued", ConflictPolicy::Queue));
+        Timer::after_millis(100).await;
+
+        runner.abort(&relays).await.unwrap();
+
+        assert!(runner.is_idle());
+        assert!(!runner.is_active(queued.handle().unwrap()));
+        // New triggers are accepted again once the abort is done
+        let after = runner.start(&config(RELAY1_SHORT, "after", ConflictPolicy::Queue));
+        assert!(matches!(after, StartOutcome::Started(_)));
+    });
+
+    // No cleanup steps, no queued sequence; everything off at the abort
+    assert_eq!(
+        outputs(&bus),
+        vec![(0, 0x01), (0, 0x03), (100, 0x00), (100, 0x01), (300, 0x00)]
+    );
+}
+
+#[test]
+fn abort_gives_up_on_a_stuck_bus() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let relays = RelayController::new(Tca9554::new(device.clone(), TCA9554_ADDRESS));
+    let runner = SequenceRunner::<2>::new();
+
+    device.inject_stall();
+    let result = clock.block_on(runner.abort(&relays));
+    assert_eq!(result, Err(BusError::Timeout));
+    assert_eq!(clock.now_ms(), ALL_OFF_TIMEOUT_MS);
+    // The runner takes sequences again
+    assert!(matches!(
+        runner.start(&config(RELAY1_SHORT, "after", ConflictPolicy::Queue)),
+        StartOutcome::Started(_)
+    ));
+}
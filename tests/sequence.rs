@@ -0,0 +1,46 @@
+//! Host tests for sequence configuration and dispatch
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::hardware::DigitalInput;
+use prop_relay_control::mock::Clock;
+use prop_relay_control::sequence::{
+    SequenceConfig, SequenceDispatcher, JUMP_SCARE, SNAKE_SEQUENCE,
+};
+
+const CONFIGS: &[SequenceConfig] = &[
+    SequenceConfig::new(DigitalInput::DI1, 5000, JUMP_SCARE, "Jump Scare"),
+    SequenceConfig::new(DigitalInput::DI2, 30000, SNAKE_SEQUENCE, "Snake Attack"),
+];
+
+#[test]
+fn find_config_matches_trigger() {
+    let dispatcher = SequenceDispatcher::new(CONFIGS);
+
+    let config = dispatcher.find_config(CONFIGS, DigitalInput::DI2).unwrap();
+    assert_eq!(config.name, "Snake Attack");
+    assert!(dispatcher.find_config(CONFIGS, DigitalInput::DI8).is_none());
+}
+
+#[test]
+fn cooldown_uses_per_sequence_duration() {
+    let clock = Clock::take();
+    let mut dispatcher = SequenceDispatcher::new(CONFIGS);
+
+    dispatcher.mark_triggered(DigitalInput::DI1);
+    dispatcher.mark_triggered(DigitalInput::DI2);
+
+    clock.advance_ms(5000);
+    assert!(!dispatcher.is_cooling_down(DigitalInput::DI1));
+    assert!(dispatcher.is_cooling_down(DigitalInput::DI2));
+    assert_eq!(dispatcher.remaining_ms(DigitalInput::DI2), 25000);
+}
+
+#[test]
+fn unmapped_input_has_no_cooldown() {
+    let _clock = Clock::take();
+    let mut dispatcher = SequenceDispatcher::new(CONFIGS);
+
+    dispatcher.mark_triggered(DigitalInput::DI5);
+    assert!(!dispatcher.is_cooling_down(DigitalInput::DI5));
+}
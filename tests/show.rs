@@ -0,0 +1,339 @@
+//! Host tests for the show file parser
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::gesture::Gesture;
+use prop_relay_control::hardware::{DigitalInput, RelayMask};
+use prop_relay_control::input::InputTriggers;
+use prop_relay_control::mock::{Clock, MockI2c};
+use prop_relay_control::random::Prng;
+use prop_relay_control::relay::RelayController;
+use prop_relay_control::sequence::{Cleanup, ConflictPolicy, Instruction, LOOP_FOREVER};
+use prop_relay_control::show::{
+    parse_show, ParseError, ParseErrorKind, Show, ShowStorage, MAX_SHOW_NAME_LEN,
+};
+use prop_relay_control::tca9554::{Tca9554, TCA9554_ADDRESS};
+
+const DEFAULT_SHOW: &str = include_str!("../shows/default.show");
+
+fn parse(source: &'static str) -> Result<Show, ParseError> {
+    parse_show(source, Box::leak(Box::new(ShowStorage::new())))
+}
+
+fn error(source: &'static str) -> (u32, u32, ParseErrorKind) {
+    let error = parse(source).unwrap_err();
+    (error.line, error.column, error.kind)
+}
+
+#[test]
+fn default_show_matches_builtin_sequences() {
+    let show = parse(DEFAULT_SHOW).unwrap();
+
+    assert_eq!(show.configs.len(), 2);
+    let snake = &show.configs[1];
+    assert_eq!(snake.trigger, DigitalInput::DI2);
+    assert_eq!(snake.cooldown_ms, 30000);
+    assert_eq!(snake.name, "snake_attack");
+    assert_eq!(snake.conflict, ConflictPolicy::Queue);
+
+    // Same waveform as SNAKE_SEQUENCE: 12 writes over 5 seconds
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    clock
+        .block_on(relays.run_program(
+            snake.program.unwrap(),
+            show.programs,
+            &InputTriggers::<1>::new(),
+            &mut Prng::new(0),
+        ))
+        .unwrap();
+    let times: Vec<u64> = bus.transactions().iter().map(|t| t.timestamp_ms).collect();
+    assert_eq!(
+        times,
+        vec![0, 100, 1000, 1200, 2000, 2200, 3000, 3250, 3500, 3750, 4000, 4500]
+    );
+}
+
+#[test]
+fn instructions_and_options_are_parsed() {
+    let show = parse(
+        "# comment line\n\
+         program a\n\
+         \x20   set R1 R3 on R2 off   # trailing comment\n\
+         end\n\
+         program b\n\
+         \x20   loop forever\n\
+         \x20       wait 200..1500\n\
+         \x20       wait DI3\n\
+         \x20       skip 25%\n\
+         \x20       choose a:3 a:0\n\
+         \x20       set R10 on\n\
+         \x20   end\n\
+         \x20   call a\n\
+         end\n\
+         trigger DI4 b cooldown 2s conflict preempt cleanup none\n",
+    )
+    .unwrap();
+
+    assert_eq!(show.programs.len(), 2);
+    match show.programs[0].code {
+        [Instruction::Set {
+            bank: 0,
+            relays,
+            levels,
+        }] => {
+            assert_eq!(*relays, RelayMask(0b0000_0111));
+            assert_eq!(*levels, RelayMask(0b0000_0101));
+        }
+        code => panic!("unexpected code {code:?}"),
+    }
+
+    let code = show.programs[1].code;
+    assert_eq!(code.len(), 8);
+    assert!(matches!(code[0], Instruction::Loop(LOOP_FOREVER)));
+    assert!(matches!(
+        code[1],
+        Instruction::WaitRandom {
+            min_ms: 200,
+            max_ms: 1500
+        }
+    ));
+    assert!(matches!(code[2], Instruction::WaitInput(DigitalInput::DI3)));
+    assert!(matches!(code[3], Instruction::Skip(25)));
+    match code[4] {
+        Instruction::Choose(choices) => {
+            assert_eq!(choices.len(), 2);
+            assert_eq!((choices[0].weight, choices[0].program), (3, "a"));
+        }
+        other => panic!("expected choose, got {other:?}"),
+    }
+    assert!(matches!(code[5], Instruction::Set { bank: 1, .. }));
+    assert!(matches!(code[6], Instruction::End));
+    assert!(matches!(code[7], Instruction::Call("a")));
+
+    let config = &show.configs[0];
+    assert_eq!(config.trigger, DigitalInput::DI4);
+    assert_eq!(config.cooldown_ms, 2000);
+    assert_eq!(config.conflict, ConflictPolicy::Preempt);
+    assert!(matches!(config.cleanup, Cleanup::None));
+}
+
+#[test]
+fn skip_before_end_still_repeats_the_loop() {
+    let show = parse(
+        "program blink\n\
+         \x20   loop 3\n\
+         \x20       set R1 on\n\
+         \x20       wait 100\n\
+         \x20       set R1 off\n\
+         \x20       skip 100%\n\
+         \x20   end\n\
+         \x20   set R2 on\n\
+         end\n",
+    )
+    .unwrap();
+
+    let clock = Clock::take();
+    let bus = MockI2c::new();
+    let relays = RelayController::new(Tca9554::new(bus.clone(), TCA9554_ADDRESS));
+    clock
+        .block_on(relays.run_program(
+            show.programs[0].code,
+            show.programs,
+            &InputTriggers::<1>::new(),
+            &mut Prng::new(0),
+        ))
+        .unwrap();
+    let values: Vec<u8> = bus.transactions().iter().map(|t| t.bytes[1]).collect();
+    assert_eq!(values, [0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02]);
+}
+
+#[test]
+fn errors_report_line_and_column() {
+    use ParseErrorKind::*;
+
+    assert_eq!(
+        error("program a\n  sett R1 on\nend\n"),
+        (2, 3, UnknownCommand)
+    );
+    assert_eq!(error("program a\n  set R0 on\nend\n"), (2, 7, InvalidRelay));
+    assert_eq!(error("program a\n  set R1\nend\n"), (2, 9, MissingArgument));
+    assert_eq!(
+        error("program a\n  set R1 R9 on\nend\n"),
+        (2, 10, MixedBanks)
+    );
+    assert_eq!(
+        error("program a\n  wait 5x\nend\n"),
+        (2, 8, InvalidDuration)
+    );
+    assert_eq!(error("program a\n  wait DI9\nend\n"), (2, 8, InvalidInput));
+    assert_eq!(error("program a\n  call b\nend\n"), (2, 8, UnknownProgram));
+    assert_eq!(error("program a\n  call a\nend\n"), (2, 8, UnknownProgram));
+    assert_eq!(error("program a\n  choose\nend\n"), (2, 9, MissingArgument));
+    assert_eq!(
+        error("program a\nend\nprogram b\n  choose a:0\nend\n"),
+        (4, 3, EmptyChoice)
+    );
+    assert_eq!(
+        error("program a\n  skip 101%\nend\n"),
+        (2, 8, InvalidNumber)
+    );
+    assert_eq!(
+        error("program a\n  loop 0\n  end\nend\n"),
+        (2, 8, InvalidNumber)
+    );
+    assert_eq!(error("program a\n  loop 2\n"), (2, 3, UnclosedBlock));
+    assert_eq!(error("program a\n  set R1 on\n"), (1, 1, UnclosedBlock));
+    assert_eq!(error("set R1 on\n"), (1, 1, MisplacedCommand));
+    assert_eq!(error("end\n"), (1, 1, MisplacedCommand));
+    assert_eq!(
+        error("program a\nend\nprogram a\nend\n"),
+        (3, 9, DuplicateProgram)
+    );
+    assert_eq!(error("program a\nend x\n"), (2, 5, UnexpectedArgument));
+    assert_eq!(
+        error("program a\nend\ntrigger DI1 a\ntrigger DI1 a\n"),
+        (4, 9, DuplicateTrigger)
+    );
+    assert_eq!(
+        error("program a\nend\ntrigger DI1 a conflict never\n"),
+        (3, 24, InvalidOption)
+    );
+    assert_eq!(
+        error("program a\nend\ntrigger DI1 a cooldown\n"),
+        (3, 23, MissingArgument)
+    );
+    // Multibyte text is an error, not a panic
+    assert_eq!(error("program a\n  set €1 on\nend\n"), (2, 7, InvalidRelay));
+    assert_eq!(
+        error("program a\n  wait Dé\nend\n"),
+        (2, 8, InvalidDuration)
+    );
+    assert_eq!(
+        error("program a\nend\ntrigger €1 a\n"),
+        (3, 9, InvalidInput)
+    );
+}
+
+#[test]
+fn gesture_options_are_parsed() {
+    use DigitalInput::*;
+    use ParseErrorKind::*;
+
+    let show = parse(
+        "program a
+end
+         trigger DI1 a
+         trigger DI1 a hold 3s cooldown 5s
+         trigger DI1 a release 2s
+         trigger DI2 a double 500ms
+         trigger DI1 a after DI3,di5 within 10s
+         trigger DI1 a after DI2
+",
+    )
+    .unwrap();
+    let gestures: Vec<_> = show.configs.iter().map(|config| config.gesture).collect();
+    assert_eq!(
+        gestures,
+        [
+            Gesture::Trigger,
+            Gesture::Hold(3000),
+            Gesture::Release(2000),
+            Gesture::Double(500),
+            Gesture::Pattern {
+                after: &[DI3, DI5],
+                within_ms: 10_000
+            },
+            Gesture::Pattern {
+                after: &[DI2],
+                within_ms: 0
+            },
+        ]
+    );
+    assert_eq!(show.configs[1].cooldown_ms, 5000);
+
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a hold 1s
+trigger DI1 a hold 1s
+"
+        ),
+        (4, 9, DuplicateTrigger)
+    );
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a after DI2 within 1s
+trigger DI1 a within 1s after DI2
+"
+        ),
+        (4, 9, DuplicateTrigger)
+    );
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a hold 1s double 1s
+"
+        ),
+        (3, 23, InvalidOption)
+    );
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a within 1s
+"
+        ),
+        (3, 15, InvalidOption)
+    );
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a after DI3,DI9
+"
+        ),
+        (3, 25, InvalidInput)
+    );
+    assert_eq!(
+        error(
+            "program a
+end
+trigger DI1 a hold soon
+"
+        ),
+        (3, 20, InvalidDuration)
+    );
+}
+
+#[test]
+fn nesting_limits_are_enforced() {
+    assert_eq!(
+        error("program a\nloop 2\nloop 2\nloop 2\nloop 2\nloop 2\n"),
+        (6, 1, ParseErrorKind::LoopTooDeep)
+    );
+    assert_eq!(
+        error(
+            "program a\nend\nprogram b\ncall a\nend\nprogram c\ncall b\nend\n\
+             program d\ncall c\nend\nprogram e\ncall d\nend\n"
+        ),
+        (13, 6, ParseErrorKind::CallTooDeep)
+    );
+    let name = "n".repeat(MAX_SHOW_NAME_LEN);
+    assert!(parse(format!("program {name}\nend\n").leak()).is_ok());
+    assert_eq!(
+        error(format!("program {name}n\nend\n").leak()),
+        (1, 9, ParseErrorKind::TooLarge)
+    );
+}
+
+#[test]
+fn error_display_is_line_column_message() {
+    let error = parse("program a\n  bogus\nend\n").unwrap_err();
+    assert_eq!(error.to_string(), "2:3: unknown command");
+}
@@ -0,0 +1,291 @@
+//! Host tests for the A/B configuration store
+//!
+//! Run with `cargo +stable host-test`.
+
+use prop_relay_control::hardware::DigitalInput;
+use prop_relay_control::mock::MockFlash;
+use prop_relay_control::storage::{
+    ConfigStore, DmxSettings, Flash, InputPull, InputSettings, ModbusRtuSettings, MqttSettings,
+    NetworkSettings, OscSettings, Parity, Settings, SignalLoss, StorageError, TriggerMode,
+    WifiSettings, MAX_SHOW_BYTES,
+};
+
+const PARTITION: u32 = 64 * 1024;
+const SLOT: u32 = PARTITION / 2;
+
+fn store() -> (ConfigStore<MockFlash>, MockFlash) {
+    let flash = MockFlash::new(PARTITION);
+    (ConfigStore::new(flash.clone()), flash)
+}
+
+fn settings(debounce_ms: u16) -> Settings {
+    Settings {
+        debounce_ms: [debounce_ms; 8],
+        network: NetworkSettings::fixed([192, 168, 1, 50], 24, [192, 168, 1, 1]),
+        osc: OscSettings {
+            port: 53000,
+            target: [192, 168, 1, 20],
+            target_port: 53001,
+        },
+        mqtt: MqttSettings {
+            broker: [192, 168, 1, 5],
+            port: 1884,
+        },
+        dmx: DmxSettings {
+            artnet: true,
+            sacn: true,
+            universe: 7,
+            start_address: 101,
+            threshold: 200,
+            trigger_level: 64,
+            on_loss: SignalLoss::AllOff,
+        },
+        modbus_rtu: ModbusRtuSettings {
+            address: 17,
+            baud: 115_200,
+            parity: Parity::None,
+        },
+        wifi: WifiSettings::new("Haunted House", "b00-b00-b00").unwrap(),
+        inputs: [InputSettings {
+            mode: TriggerMode::BothEdges,
+            pull: InputPull::Down,
+            inverted: true,
+        }; 8],
+        ble_enabled: true,
+    }
+}
+
+fn load(store: &mut ConfigStore<MockFlash>) -> Option<(Settings, String, u32)> {
+    let mut buffer = vec![0; MAX_SHOW_BYTES];
+    store
+        .load(&mut buffer)
+        .unwrap()
+        .map(|config| (config.settings, config.show.to_string(), config.sequence))
+}
+
+#[test]
+fn blank_partition_has_no_config() {
+    let (mut store, _) = store();
+    assert!(load(&mut store).is_none());
+    assert_eq!(Settings::default().debounce_ms(DigitalInput::DI3), 100);
+    assert_eq!(
+        Settings::default().input(DigitalInput::DI3),
+        InputSettings::DEFAULT
+    );
+}
+
+#[test]
+fn saved_config_reads_back() {
+    let (mut store, _) = store();
+    let mut expected = settings(40);
+    expected.debounce_ms[2] = 250;
+    expected.inputs[5] = InputSettings {
+        mode: TriggerMode::LevelLow,
+        pull: InputPull::None,
+        inverted: false,
+    };
+    // Odd length so the show ends in a partial flash word
+    let show = "program a\n  set R1 on\nend\ntrigger DI1 a\n";
+
+    assert_eq!(store.save(&expected, show), Ok(1));
+
+    let (settings, text, sequence) = load(&mut store).unwrap();
+    assert_eq!(settings, expected);
+    assert_eq!(settings.debounce_ms(DigitalInput::DI3), 250);
+    assert_eq!(
+        settings.input(DigitalInput::DI6).mode,
+        TriggerMode::LevelLow
+    );
+    assert_eq!(text, show);
+    assert_eq!(sequence, 1);
+}
+
+#[test]
+fn saves_alternate_between_slots() {
+    let (mut store, flash) = store();
+
+    for round in 1..=6 {
+        assert_eq!(store.save(&settings(round), "show"), Ok(round as u32));
+        assert_eq!(load(&mut store).unwrap().0, settings(round));
+    }
+
+    // Every sector of both slots was erased equally often
+    assert!(flash.erase_counts().iter().all(|&count| count == 3));
+}
+
+#[test]
+fn corrupt_record_falls_back_to_previous() {
+    let (mut store, flash) = store();
+    store.save(&settings(10), "first").unwrap();
+    store.save(&settings(20), "second").unwrap();
+
+    // Second record is in slot B; damage one byte of its show text
+    flash.flip_bit(SLOT + 24 + 32 + 2, 0);
+
+    let (settings_read, text, sequence) = load(&mut store).unwrap();
+    assert_eq!(
+        (settings_read, text.as_str(), sequence),
+        (settings(10), "first", 1)
+    );
+
+    // The damaged slot is the one overwritten next
+    store.save(&settings(30), "third").unwrap();
+    assert_eq!(load(&mut store).unwrap().2, 2);
+    assert_eq!(load(&mut store).unwrap().1, "third");
+}
+
+#[test]
+fn power_loss_during_save_keeps_previous_config() {
+    for operations in 0..12 {
+        let (mut store, flash) = store();
+        store.save(&settings(10), "first").unwrap();
+
+        flash.power_loss_after(operations);
+        let result = store.save(&settings(20), "second");
+        flash.restore_power();
+
+        let (settings_read, text, _) = load(&mut store).unwrap();
+        match result {
+            Ok(_) => assert_eq!(text, "second"),
+            Err(error) => {
+                assert!(matches!(error, StorageError::Flash(_)));
+                assert_eq!((settings_read, text.as_str()), (settings(10), "first"));
+            }
+        }
+    }
+}
+
+#[test]
+fn factory_reset_erases_everything() {
+    let (mut store, flash) = store();
+    store.save(&settings(10), "first").unwrap();
+    store.save(&settings(20), "second").unwrap();
+
+    store.factory_reset().unwrap();
+
+    assert!(load(&mut store).is_none());
+    assert!(flash.contents().iter().all(|&byte| byte == 0xFF));
+    assert_eq!(store.save(&settings(30), "fresh"), Ok(1));
+}
+
+#[test]
+fn newer_schema_is_ignored() {
+    let (mut store, flash) = store();
+    store.save(&settings(10), "first").unwrap();
+    store.save(&settings(20), "second").unwrap();
+
+    // Slot B now claims schema version 0x8008, as if a later firmware
+    // had written it
+    flash.flip_bit(SLOT + 5, 7);
+
+    assert_eq!(load(&mut store).unwrap().1, "first");
+}
+
+/// Hand-built record of an older schema in slot A; `show` must fill
+/// whole flash words
+fn write_record(flash: &mut MockFlash, version: u16, settings: &[u8], show: &str) {
+    let mut header = [0; 24];
+    header[0..4].copy_from_slice(b"PRCF");
+    header[4..6].copy_from_slice(&version.to_le_bytes());
+    header[6..8].copy_from_slice(&(settings.len() as u16).to_le_bytes());
+    header[8..12].copy_from_slice(&1u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(show.len() as u32).to_le_bytes());
+    let crc = crc32(&[&header[4..16], settings, show.as_bytes()].concat());
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+    let mut payload = settings.to_vec();
+    payload.resize(settings.len().next_multiple_of(4), 0xFF);
+    payload.extend_from_slice(show.as_bytes());
+    flash.write(24, &payload).unwrap();
+    flash.write(0, &header).unwrap();
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn older_schema_is_migrated() {
+    // Schema 1: debounce times and network settings only
+    let mut v1 = [0; 30];
+    v1[..16].copy_from_slice(&[40, 0].repeat(8));
+    v1[16..30].copy_from_slice(&[0, 24, 192, 168, 1, 50, 192, 168, 1, 1, 192, 168, 1, 1]);
+    let (mut store, mut flash) = store();
+    write_record(&mut flash, 1, &v1, "v1 show\n");
+
+    let (loaded, text, _) = load(&mut store).unwrap();
+    assert_eq!(
+        loaded,
+        Settings {
+            debounce_ms: [40; 8],
+            network: settings(40).network,
+            ..Settings::default()
+        }
+    );
+    assert_eq!(text, "v1 show\n");
+
+    // Saving writes the current schema, read back in full
+    store.save(&settings(40), "v1 show\n").unwrap();
+    assert_eq!(load(&mut store).unwrap().0, settings(40));
+}
+
+#[test]
+fn record_shorter_than_its_schema_is_ignored() {
+    let (mut store, mut flash) = store();
+    write_record(&mut flash, 2, &[0; 30], "show");
+    assert!(load(&mut store).is_none());
+}
+
+#[test]
+fn oversized_show_is_rejected() {
+    let (mut store, _) = store();
+    let show = "#".repeat(MAX_SHOW_BYTES + 1);
+    assert_eq!(
+        store.save(&Settings::default(), &show),
+        Err(StorageError::TooLarge)
+    );
+
+    let mut small = ConfigStore::new(MockFlash::new(16 * 1024));
+    let show = "#".repeat(small.show_capacity() + 1);
+    assert_eq!(
+        small.save(&Settings::default(), &show),
+        Err(StorageError::TooLarge)
+    );
+
+    // A stored show that does not fit the caller's buffer
+    store.save(&Settings::default(), "0123456789").unwrap();
+    let mut buffer = [0; 8];
+    assert!(matches!(
+        store.load(&mut buffer),
+        Err(StorageError::TooLarge)
+    ));
+}
+
+#[test]
+fn wifi_credentials_must_fit() {
+    let open = WifiSettings::new("Haunted House", "").unwrap();
+    assert_eq!(open.ssid(), "Haunted House");
+    assert_eq!(open.password(), "");
+    assert!(open.is_configured());
+    assert!(!WifiSettings::UNCONFIGURED.is_configured());
+
+    assert!(WifiSettings::new(&"s".repeat(32), &"p".repeat(64)).is_some());
+    assert_eq!(WifiSettings::new(&"s".repeat(33), ""), None);
+    assert_eq!(WifiSettings::new("ssid", &"p".repeat(65)), None);
+    assert_eq!(WifiSettings::new("", "password"), None);
+
+    // The password never shows up in debug output
+    let debug = format!("{:?}", WifiSettings::new("Haunted House", "b00-b00-b00"));
+    assert!(debug.contains("Haunted House") && !debug.contains("b00"));
+}
@@ -0,0 +1,152 @@
+//! Host tests for the TCA9554 driver against the simulated expander
+//!
+//! Run with `cargo +stable host-test`.
+
+use embedded_hal_async::i2c::{ErrorKind, NoAcknowledgeSource};
+use prop_relay_control::bus::{BusError, RetryPolicy};
+use prop_relay_control::mock::{Clock, MockTca9554};
+use prop_relay_control::tca9554::{PinDirection, Tca9554, TCA9554_ADDRESS};
+
+#[test]
+fn output_port_reads_back_latched_value() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+
+    let output = clock
+        .block_on(async {
+            expander.init().await?;
+            expander.set_pin_high(2).await?;
+            expander.set_pin_high(5).await?;
+            expander.read_output_port().await
+        })
+        .unwrap();
+
+    assert_eq!(output, 0b0010_0100);
+    assert_eq!(device.register(0x01), 0b0010_0100);
+}
+
+#[test]
+fn spare_pins_as_inverted_inputs() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+
+    // Pin 7 as an active-low input, pin 6 as a plain input
+    clock
+        .block_on(async {
+            expander.init().await?;
+            expander.set_pin_direction(7, PinDirection::Input).await?;
+            expander.set_pin_direction(6, PinDirection::Input).await?;
+            expander.set_polarity(0b1000_0000).await?;
+            expander.set_pin_high(0).await
+        })
+        .unwrap();
+    assert_eq!(expander.get_configuration(), 0b1100_0000);
+
+    device.set_pin_levels(0b0100_0000);
+    let levels = clock.block_on(expander.read_input_port()).unwrap();
+    assert_eq!(levels, 0b1100_0001);
+
+    device.set_pin_levels(0b1000_0000);
+    assert!(!clock.block_on(expander.is_pin_high(7)).unwrap());
+    assert!(!clock.block_on(expander.is_pin_high(6)).unwrap());
+}
+
+#[test]
+fn sync_from_device_after_brown_out() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+
+    clock
+        .block_on(async {
+            expander.init().await?;
+            expander.set_pin_high(1).await
+        })
+        .unwrap();
+    device.power_cycle();
+    assert_eq!(expander.get_output_state(), 0x02);
+
+    clock.block_on(expander.sync_from_device()).unwrap();
+
+    assert_eq!(expander.get_output_state(), 0xFF);
+    assert_eq!(expander.get_configuration(), 0xFF);
+    assert_eq!(expander.get_polarity(), 0x00);
+}
+
+#[test]
+fn verified_write_retries_read_back_mismatch() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+    let policy = RetryPolicy::new(3, 10, 50);
+
+    clock.block_on(expander.init()).unwrap();
+    device.inject_bit_flip(0b0000_0100);
+
+    clock
+        .block_on(expander.write_outputs_verified(0b0000_0011, &policy))
+        .unwrap();
+
+    assert_eq!(device.outputs(), 0b0000_0011);
+    assert_eq!(expander.get_output_state(), 0b0000_0011);
+    assert_eq!(device.recoveries(), 1);
+    assert_eq!(clock.now_ms(), 10);
+}
+
+#[test]
+fn verified_write_times_out_stalled_transfer() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+    let policy = RetryPolicy::new(2, 5, 20);
+
+    clock.block_on(expander.init()).unwrap();
+    device.inject_stall();
+    device.inject_stall();
+
+    let result = clock.block_on(expander.write_outputs_verified(0x01, &policy));
+
+    assert_eq!(result, Err(BusError::Timeout));
+    assert_eq!(clock.now_ms(), 20 + 5 + 20);
+    assert_eq!(expander.get_output_state(), 0x00);
+}
+
+#[test]
+fn verified_write_gives_up_after_persistent_nak() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+    let policy = RetryPolicy::new(4, 5, 50);
+
+    device.set_stuck_error(Some(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)));
+
+    let result = clock.block_on(expander.write_outputs_verified(0x80, &policy));
+
+    assert_eq!(result, Err(BusError::Nak));
+    assert_eq!(device.recoveries(), 3);
+    // Backoff doubles: 5 + 10 + 20
+    assert_eq!(clock.now_ms(), 35);
+}
+
+#[test]
+fn mask_writes_are_single_transactions() {
+    let clock = Clock::take();
+    let device = MockTca9554::new(TCA9554_ADDRESS);
+    let mut expander = Tca9554::new(device.clone(), TCA9554_ADDRESS);
+
+    clock.block_on(expander.init()).unwrap();
+    device.clear_log();
+    clock
+        .block_on(async {
+            expander.set_pins_high(0b0000_1111).await?;
+            expander.set_pins_low(0b0000_0101).await?;
+            expander.toggle_pins(0b1000_0010).await?;
+            expander.update_pins(0b1111_0000, 0b0101_1111).await
+        })
+        .unwrap();
+
+    assert_eq!(device.transactions().len(), 4);
+    assert_eq!(device.outputs(), 0b0101_1000);
+}
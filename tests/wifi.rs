@@ -0,0 +1,82 @@
+//! Host tests for Wi-Fi reconnect backoff and the setup access point
+//!
+//! Run with `cargo +stable host-test`.
+
+use embassy_time::Instant;
+use prop_relay_control::mock::Clock;
+use prop_relay_control::storage::WifiSettings;
+use prop_relay_control::wifi::{backoff_ms, WifiState, WifiSupervisor, FAILURES_BEFORE_SETUP};
+
+fn waiting_for(clock: &Clock, ms: u64) -> WifiState {
+    WifiState::Waiting {
+        until: Instant::from_millis(clock.now_ms() + ms),
+    }
+}
+
+#[test]
+fn unconfigured_only_runs_the_access_point() {
+    let _clock = Clock::take();
+    let mut supervisor = WifiSupervisor::new(&WifiSettings::UNCONFIGURED);
+    assert_eq!(supervisor.state(), WifiState::Unconfigured);
+    assert!(supervisor.access_point());
+
+    supervisor.failed();
+    supervisor.connected();
+    supervisor.retry();
+    assert_eq!(supervisor.state(), WifiState::Unconfigured);
+    assert!(supervisor.access_point());
+}
+
+#[test]
+fn failures_back_off_then_open_the_access_point() {
+    let clock = Clock::take();
+    let settings = WifiSettings::new("Haunted House", "b00-b00-b00").unwrap();
+    let mut supervisor = WifiSupervisor::new(&settings);
+    assert_eq!(supervisor.state(), WifiState::Connecting);
+    assert!(!supervisor.access_point());
+
+    supervisor.failed();
+    assert_eq!(supervisor.state(), waiting_for(&clock, 1000));
+    supervisor.retry();
+    assert_eq!(supervisor.state(), WifiState::Connecting);
+    supervisor.failed();
+    assert_eq!(supervisor.state(), waiting_for(&clock, 2000));
+    assert!(!supervisor.access_point());
+
+    supervisor.retry();
+    supervisor.failed();
+    assert_eq!(supervisor.failures(), FAILURES_BEFORE_SETUP);
+    assert!(supervisor.access_point());
+
+    // Retries go on with the access point open; it closes once connected
+    supervisor.retry();
+    assert_eq!(supervisor.state(), WifiState::Connecting);
+    supervisor.connected();
+    assert_eq!(supervisor.state(), WifiState::Connected);
+    assert_eq!(supervisor.failures(), 0);
+    assert!(!supervisor.access_point());
+}
+
+#[test]
+fn lost_connection_retries_quickly() {
+    let clock = Clock::take();
+    let settings = WifiSettings::new("Haunted House", "").unwrap();
+    let mut supervisor = WifiSupervisor::new(&settings);
+    supervisor.connected();
+    clock.advance_ms(3_600_000);
+
+    supervisor.failed();
+    assert_eq!(supervisor.state(), waiting_for(&clock, 1000));
+    assert!(!supervisor.access_point());
+    supervisor.retry();
+    assert_eq!(supervisor.state(), WifiState::Connecting);
+}
+
+#[test]
+fn backoff_doubles_up_to_a_minute() {
+    assert_eq!(backoff_ms(1), 1000);
+    assert_eq!(backoff_ms(2), 2000);
+    assert_eq!(backoff_ms(6), 32_000);
+    assert_eq!(backoff_ms(7), 60_000);
+    assert_eq!(backoff_ms(u32::MAX), 60_000);
+}